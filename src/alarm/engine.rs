@@ -0,0 +1,460 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use influxdb2::models::DataPoint;
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use super::rule::{AlarmRule, Limit, Severity};
+use crate::shift_calendar;
+
+// アラームの状態
+// Normal → Active(発生・未確認) → Acknowledged(発生・確認済み) → Normal(復帰)
+//            └→ Cleared(復帰・未確認) → Normal(確認)
+// Clearedの間に再び発生した場合はActiveに戻る
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlarmState {
+    Normal,
+    Active,
+    Acknowledged,
+    Cleared,
+}
+
+impl AlarmState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Active => "active",
+            Self::Acknowledged => "acknowledged",
+            Self::Cleared => "cleared",
+        }
+    }
+
+    // 発生中の場合はtrue
+    fn is_raised(&self) -> bool {
+        matches!(self, Self::Active | Self::Acknowledged)
+    }
+}
+
+// アラームの状態。遷移毎にイベントとして書き込む
+#[derive(Clone, Debug)]
+pub struct AlarmRecord {
+    pub name: String,
+    pub source: String,
+    pub tag: String,
+    pub severity: Severity,
+    pub message: String,
+    pub state: AlarmState,
+    pub previous_state: AlarmState,
+    pub value: Option<f64>,
+    pub limit: Option<Limit>,
+    pub limit_value: Option<f64>,
+    pub raised_at: Option<DateTime<Local>>,
+    pub changed_at: DateTime<Local>,
+    pub acknowledged_by: Option<String>,
+}
+
+impl AlarmRecord {
+    // 遷移した時刻をタイムスタンプにする
+    fn to_data_point(&self) -> anyhow::Result<DataPoint> {
+        let time = match self.changed_at.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("AlarmRecord::to_data_pointでエラー"),
+        };
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder("alarm"), self.changed_at)
+                .tag("info_type", "alarm_event")
+                .tag("name", self.name.as_str())
+                .tag("source", self.source.as_str())
+                .tag("tag", self.tag.as_str())
+                .tag("severity", self.severity.as_str())
+                .field("state", self.state.as_str())
+                .field("previous_state", self.previous_state.as_str())
+                .field("message", self.message.as_str());
+        if let Some(value) = self.value {
+            builder = builder.field("value", value);
+        }
+        if let Some(limit) = self.limit {
+            builder = builder.field("limit", limit.as_str());
+        }
+        if let Some(limit_value) = self.limit_value {
+            builder = builder.field("limit_value", limit_value);
+        }
+        if let Some(raised_at) = self.raised_at {
+            let duration = (self.changed_at - raised_at).num_milliseconds() as f64 / 1000.0;
+            builder = builder.field("duration_second", duration);
+        }
+        if let Some(acknowledged_by) = self.acknowledged_by.as_deref() {
+            builder = builder.field("acknowledged_by", acknowledged_by);
+        }
+        Ok(builder.timestamp(time).build()?)
+    }
+}
+
+struct Alarm {
+    rule: AlarmRule,
+    state: AlarmState,
+    previous_state: AlarmState,
+    // 最後に受信した値
+    value: Option<f64>,
+    // on_delay_sec,off_delay_secの計測を始めた時刻
+    pending_since: Option<DateTime<Local>>,
+    limit: Option<Limit>,
+    raised_at: Option<DateTime<Local>>,
+    changed_at: DateTime<Local>,
+    acknowledged_by: Option<String>,
+    // 取得元が通信断になり最後の値が古い。次の値を受信するまで判定しない
+    stale: bool,
+}
+
+impl Alarm {
+    fn new(rule: AlarmRule, dt: DateTime<Local>) -> Self {
+        Self {
+            rule,
+            state: AlarmState::Normal,
+            previous_state: AlarmState::Normal,
+            value: None,
+            pending_since: None,
+            limit: None,
+            raised_at: None,
+            changed_at: dt,
+            acknowledged_by: None,
+            stale: false,
+        }
+    }
+
+    fn record(&self) -> AlarmRecord {
+        AlarmRecord {
+            name: self.rule.name.to_owned(),
+            source: self.rule.source.to_owned(),
+            tag: self.rule.tag.to_owned(),
+            severity: self.rule.severity,
+            message: self.rule.message.to_owned(),
+            state: self.state,
+            previous_state: self.previous_state,
+            value: self.value,
+            limit: self.limit,
+            limit_value: self.limit.and_then(|t| self.rule.limit_value(t)),
+            raised_at: self.raised_at,
+            changed_at: self.changed_at,
+            acknowledged_by: self.acknowledged_by.to_owned(),
+        }
+    }
+
+    fn transition(&mut self, state: AlarmState, dt: DateTime<Local>) -> AlarmRecord {
+        self.previous_state = self.state;
+        self.state = state;
+        self.changed_at = dt;
+        self.record()
+    }
+
+    // 条件が遅延時間続いた場合はtrue。条件を満たした最初の時刻から計る
+    fn delay_elapsed(&mut self, dt: DateTime<Local>, delay_second: f64) -> bool {
+        let since = *self.pending_since.get_or_insert(dt);
+        (dt - since).num_milliseconds() as f64 >= delay_second * 1000.0
+    }
+
+    // 最後の値で発生・復帰を判定する。遷移した場合は遷移後の状態を返す
+    fn evaluate(&mut self, dt: DateTime<Local>) -> Option<AlarmRecord> {
+        if self.stale {
+            return None;
+        }
+        let value = self.value?;
+        if self.state.is_raised() {
+            // 不感帯の内側に戻るまでは発生したまま
+            if !self.rule.returned(value) {
+                self.pending_since = None;
+                return None;
+            }
+            if !self.delay_elapsed(dt, self.rule.off_delay_sec) {
+                return None;
+            }
+            self.pending_since = None;
+            let next = match self.state {
+                AlarmState::Acknowledged => AlarmState::Normal,
+                _ => AlarmState::Cleared,
+            };
+            return Some(self.transition(next, dt));
+        }
+        let Some(limit) = self.rule.exceeded(value) else {
+            self.pending_since = None;
+            return None;
+        };
+        if !self.delay_elapsed(dt, self.rule.on_delay_sec) {
+            return None;
+        }
+        self.pending_since = None;
+        self.limit = Some(limit);
+        self.raised_at = Some(dt);
+        self.acknowledged_by = None;
+        Some(self.transition(AlarmState::Active, dt))
+    }
+
+    fn acknowledge(
+        &mut self,
+        requested_by: &str,
+        dt: DateTime<Local>,
+    ) -> anyhow::Result<AlarmRecord> {
+        let next = match self.state {
+            AlarmState::Active => AlarmState::Acknowledged,
+            AlarmState::Cleared => AlarmState::Normal,
+            AlarmState::Normal | AlarmState::Acknowledged => {
+                anyhow::bail!("確認待ちのアラームではない:{}", self.rule.name)
+            }
+        };
+        self.acknowledged_by = Some(requested_by.to_string());
+        Ok(self.transition(next, dt))
+    }
+}
+
+// しきい値アラームの判定と状態の保持
+// データマネージャー・ランナー・APIで共有する
+#[derive(Clone)]
+pub struct AlarmEngine {
+    alarms: Arc<Mutex<Vec<Alarm>>>,
+    sender: mpsc::Sender<Vec<DataPoint>>,
+}
+
+impl AlarmEngine {
+    pub fn new(rules: Vec<AlarmRule>, sender: mpsc::Sender<Vec<DataPoint>>) -> Self {
+        let now = Local::now();
+        let alarms = rules.into_iter().map(|t| Alarm::new(t, now)).collect();
+        Self {
+            alarms: Arc::new(Mutex::new(alarms)),
+            sender,
+        }
+    }
+
+    // 値の受信毎に呼ぶ。取得元とタグが一致するアラームを判定する
+    // dtは受信時刻。tickと同じ時計で遅延時間を計るためサンプルの時刻は使わない
+    pub async fn update(
+        &self,
+        source: &str,
+        values: &[(&str, f64)],
+        dt: DateTime<Local>,
+    ) -> anyhow::Result<()> {
+        let records: Vec<AlarmRecord> = {
+            let mut alarms = self.alarms.lock().unwrap();
+            alarms
+                .iter_mut()
+                .filter(|t| t.rule.source == source)
+                .filter_map(|alarm| {
+                    let (_, value) = values.iter().find(|(tag, _)| *tag == alarm.rule.tag)?;
+                    alarm.value = Some(*value);
+                    alarm.stale = false;
+                    alarm.evaluate(dt)
+                })
+                .collect()
+        };
+        self.send(records).await
+    }
+
+    // 値が届かない間も遅延時間を判定するため定期的に呼ぶ
+    pub async fn tick(&self, dt: DateTime<Local>) -> anyhow::Result<()> {
+        let records: Vec<AlarmRecord> = {
+            let mut alarms = self.alarms.lock().unwrap();
+            alarms.iter_mut().filter_map(|t| t.evaluate(dt)).collect()
+        };
+        self.send(records).await
+    }
+
+    // 取得元が通信断になった場合に呼ぶ。次の値を受信するまで最後の値で判定しない
+    // 発生中・未確認の状態はそのまま、計測中の遅延時間は破棄する
+    pub fn set_stale(&self, source: &str) {
+        let mut alarms = self.alarms.lock().unwrap();
+        for alarm in alarms.iter_mut().filter(|t| t.rule.source == source) {
+            alarm.stale = true;
+            alarm.pending_since = None;
+        }
+    }
+
+    // 一定周期でtickを呼ぶスレッドを起動する
+    pub fn start_tick(&self, interval_msec: u64) -> JoinHandle<()> {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(interval_msec));
+            loop {
+                interval.tick().await;
+                if let Err(r) = engine.tick(Local::now()).await {
+                    error!("アラームの判定でエラー:{:?}", r);
+                }
+            }
+        })
+    }
+
+    // オペレーターがアラームを確認する
+    pub async fn acknowledge(&self, name: &str, requested_by: &str) -> anyhow::Result<AlarmRecord> {
+        let record = {
+            let mut alarms = self.alarms.lock().unwrap();
+            let Some(alarm) = alarms.iter_mut().find(|t| t.rule.name == name) else {
+                anyhow::bail!("アラームがない:{}", name)
+            };
+            alarm.acknowledge(requested_by, Local::now())?
+        };
+        self.send(vec![record.clone()]).await?;
+        Ok(record)
+    }
+
+    // 発生中または未確認のアラーム
+    pub fn get_alarms(&self) -> Vec<AlarmRecord> {
+        self.alarms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.state != AlarmState::Normal)
+            .map(|t| t.record())
+            .collect()
+    }
+
+    async fn send(&self, records: Vec<AlarmRecord>) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        for record in &records {
+            match record.state {
+                AlarmState::Active => warn!(
+                    "アラーム発生:{}:{}:{}:{:?}",
+                    record.name,
+                    record.severity.as_str(),
+                    record.tag,
+                    record.value
+                ),
+                _ => info!(
+                    "アラームの状態が変化:{}:{}→{}",
+                    record.name,
+                    record.previous_state.as_str(),
+                    record.state.as_str()
+                ),
+            }
+        }
+        let points = records
+            .iter()
+            .map(|t| t.to_data_point())
+            .collect::<anyhow::Result<Vec<DataPoint>>>()?;
+        self.sender.send(points).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn rule(on_delay_sec: f64, off_delay_sec: f64) -> AlarmRule {
+        AlarmRule {
+            name: "temp_high".to_string(),
+            source: "demo_machine".to_string(),
+            tag: "temp".to_string(),
+            high: Some(100.0),
+            low: None,
+            deadband: 5.0,
+            on_delay_sec,
+            off_delay_sec,
+            severity: Severity::Major,
+            message: String::new(),
+        }
+    }
+
+    fn at(second: i64) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 4, 1, 8, 0, 0).unwrap() + chrono::Duration::seconds(second)
+    }
+
+    fn engine(rule: AlarmRule) -> (AlarmEngine, mpsc::Receiver<Vec<DataPoint>>) {
+        let (sender, receiver) = mpsc::channel(32);
+        (AlarmEngine::new(vec![rule], sender), receiver)
+    }
+
+    async fn update(engine: &AlarmEngine, value: f64, second: i64) {
+        engine
+            .update("demo_machine", &[("temp", value)], at(second))
+            .await
+            .unwrap();
+    }
+
+    fn state(engine: &AlarmEngine) -> AlarmState {
+        engine
+            .get_alarms()
+            .first()
+            .map(|t| t.state)
+            .unwrap_or(AlarmState::Normal)
+    }
+
+    #[tokio::test]
+    async fn raise_acknowledge_and_return() {
+        let (engine, mut receiver) = engine(rule(0.0, 0.0));
+        update(&engine, 101.0, 0).await;
+        assert_eq!(state(&engine), AlarmState::Active);
+        assert_eq!(receiver.try_recv().unwrap().len(), 1);
+        engine.acknowledge("temp_high", "operator").await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Acknowledged);
+        assert_eq!(
+            engine.get_alarms()[0].acknowledged_by.as_deref(),
+            Some("operator")
+        );
+        // 不感帯の内側に戻るまでは発生したまま
+        update(&engine, 97.0, 1).await;
+        assert_eq!(state(&engine), AlarmState::Acknowledged);
+        update(&engine, 95.0, 2).await;
+        assert_eq!(state(&engine), AlarmState::Normal);
+        assert!(engine.acknowledge("temp_high", "operator").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cleared_until_acknowledged() {
+        let (engine, _receiver) = engine(rule(0.0, 0.0));
+        update(&engine, 101.0, 0).await;
+        update(&engine, 90.0, 1).await;
+        assert_eq!(state(&engine), AlarmState::Cleared);
+        // 未確認の間に再び発生した場合はActiveに戻る
+        update(&engine, 101.0, 2).await;
+        assert_eq!(state(&engine), AlarmState::Active);
+        update(&engine, 90.0, 3).await;
+        engine.acknowledge("temp_high", "operator").await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Normal);
+    }
+
+    #[tokio::test]
+    async fn on_and_off_delay() {
+        let (engine, _receiver) = engine(rule(10.0, 5.0));
+        update(&engine, 101.0, 0).await;
+        engine.tick(at(9)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Normal);
+        // 遅延時間の途中で戻った場合は計り直す
+        update(&engine, 90.0, 9).await;
+        update(&engine, 101.0, 10).await;
+        engine.tick(at(19)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Normal);
+        engine.tick(at(20)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Active);
+        update(&engine, 90.0, 21).await;
+        engine.tick(at(25)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Active);
+        engine.tick(at(26)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Cleared);
+    }
+
+    #[tokio::test]
+    async fn stale_source_is_not_evaluated() {
+        let (engine, _receiver) = engine(rule(10.0, 0.0));
+        update(&engine, 101.0, 0).await;
+        engine.set_stale("demo_machine");
+        engine.tick(at(60)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Normal);
+        // 他の取得元の値では解除しない
+        engine
+            .update("gateway", &[("temp", 101.0)], at(61))
+            .await
+            .unwrap();
+        engine.tick(at(62)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Normal);
+        // 再び受信した時刻から遅延時間を計る
+        update(&engine, 101.0, 70).await;
+        engine.tick(at(79)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Normal);
+        engine.tick(at(80)).await.unwrap();
+        assert_eq!(state(&engine), AlarmState::Active);
+    }
+}
@@ -0,0 +1,67 @@
+// タグ毎のしきい値アラーム
+// ALARM_RULES_PATHでJSONファイルを指定する。未設定の場合はアラームなし
+#[allow(dead_code)]
+mod engine;
+#[allow(dead_code)]
+mod rule;
+
+use std::sync::OnceLock;
+
+use chrono::Local;
+use influxdb2::models::DataPoint;
+use log::info;
+use tokio::sync::mpsc;
+
+#[allow(unused_imports)]
+pub use engine::{AlarmEngine, AlarmRecord, AlarmState};
+#[allow(unused_imports)]
+pub use rule::{AlarmRule, Limit, Severity};
+
+// 遅延時間の判定周期(msec)
+const TICK_INTERVAL_MSEC: u64 = 1000;
+
+// ゲートウェイ内部の状態の取得元
+pub const GATEWAY_SOURCE: &str = "gateway";
+// PLCとの通信断。切断中は1、接続中は0
+// ex) "high": 0.5, "on_delay_sec": 300 で5分以上の通信断
+pub const PLC_OFFLINE_TAG: &str = "plc_offline";
+
+static ENGINE: OnceLock<Option<AlarmEngine>> = OnceLock::new();
+
+// 起動時に1回読み込む。判定のスレッドはプロセス終了まで動かす
+pub fn init_from_env(sender: mpsc::Sender<Vec<DataPoint>>) -> anyhow::Result<()> {
+    let engine = match std::env::var("ALARM_RULES_PATH") {
+        Ok(path) => {
+            let rules = AlarmRule::load(&path)?;
+            info!("アラーム設定を読み込み:{}:{}件", path, rules.len());
+            let engine = AlarmEngine::new(rules, sender);
+            engine.start_tick(TICK_INTERVAL_MSEC);
+            Some(engine)
+        }
+        Err(_) => None,
+    };
+    if ENGINE.set(engine).is_err() {
+        anyhow::bail!("アラーム設定は読み込み済み")
+    }
+    Ok(())
+}
+
+pub fn get() -> Option<&'static AlarmEngine> {
+    ENGINE.get().and_then(|t| t.as_ref())
+}
+
+// アラーム設定がある場合は値を判定する
+// 遅延時間はtickと同じくゲートウェイの時計で計る
+pub async fn update(source: &str, values: &[(&str, f64)]) -> anyhow::Result<()> {
+    match get() {
+        Some(engine) => engine.update(source, values, Local::now()).await,
+        None => Ok(()),
+    }
+}
+
+// 取得元との通信断。次の値を受信するまでその取得元のアラームは判定しない
+pub fn set_stale(source: &str) {
+    if let Some(engine) = get() {
+        engine.set_stale(source);
+    }
+}
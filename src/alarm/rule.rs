@@ -0,0 +1,235 @@
+use serde::Deserialize;
+
+// アラーム設定ファイル(JSON)
+// {
+//   "rules": [
+//     { "name": "temp1_high", "source": "demo_machine", "tag": "tempureture_1",
+//       "high": 220, "deadband": 2, "on_delay_sec": 5, "off_delay_sec": 10, "severity": "major" },
+//     { "name": "plc_offline", "source": "gateway", "tag": "plc_offline",
+//       "high": 0.5, "on_delay_sec": 300, "severity": "critical", "message": "PLC offline > 5 min" }
+//   ]
+// }
+// high,lowの片方または両方を指定する。値がhighを超えるかlowを下回った状態がon_delay_sec続くと発生
+// 復帰はhigh-deadband以下かつlow+deadband以上に戻った状態がoff_delay_sec続いた場合(ヒステリシス)
+// severityはinfo,warning,major,critical。デフォルトはwarning
+#[derive(Deserialize)]
+struct RuleFile {
+    rules: Vec<AlarmRule>,
+}
+
+// アラームの重要度
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Major,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Major => "major",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+fn default_severity() -> Severity {
+    Severity::Warning
+}
+
+// 超えたしきい値
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    High,
+    Low,
+}
+
+impl Limit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Low => "low",
+        }
+    }
+}
+
+// タグ毎のアラームの条件
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlarmRule {
+    // アラームの識別名。確認(ack)に使う
+    pub name: String,
+    // 値の取得元 ex) demo_cpb16, demo_machine, gateway
+    pub source: String,
+    pub tag: String,
+    #[serde(default)]
+    pub high: Option<f64>,
+    #[serde(default)]
+    pub low: Option<f64>,
+    // 復帰側のヒステリシス幅
+    #[serde(default)]
+    pub deadband: f64,
+    #[serde(default)]
+    pub on_delay_sec: f64,
+    #[serde(default)]
+    pub off_delay_sec: f64,
+    #[serde(default = "default_severity")]
+    pub severity: Severity,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl AlarmRule {
+    pub fn load(path: &str) -> anyhow::Result<Vec<Self>> {
+        let text = std::fs::read_to_string(path)?;
+        let file: RuleFile = serde_json::from_str(&text)?;
+        let mut names: Vec<&str> = Vec::new();
+        for rule in &file.rules {
+            rule.validate()?;
+            if names.contains(&rule.name.as_str()) {
+                anyhow::bail!("アラーム名が重複:{}", rule.name)
+            }
+            names.push(&rule.name);
+        }
+        Ok(file.rules)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.name.trim().is_empty() || self.source.is_empty() || self.tag.is_empty() {
+            anyhow::bail!("アラームの名前・取得元・タグが空:{:?}", self)
+        }
+        match (self.high, self.low) {
+            (None, None) => anyhow::bail!("アラームのhigh,lowが未設定:{}", self.name),
+            (Some(high), Some(low)) if high - self.deadband <= low + self.deadband => {
+                anyhow::bail!("アラームのhigh,low,deadbandが不正:{}", self.name)
+            }
+            _ => {}
+        }
+        if self.deadband < 0.0 || self.on_delay_sec < 0.0 || self.off_delay_sec < 0.0 {
+            anyhow::bail!(
+                "アラームのdeadband,on_delay_sec,off_delay_secが不正:{}",
+                self.name
+            )
+        }
+        Ok(())
+    }
+
+    // 発生条件を満たす場合は超えたしきい値
+    pub fn exceeded(&self, value: f64) -> Option<Limit> {
+        if self.high.is_some_and(|high| value > high) {
+            return Some(Limit::High);
+        }
+        if self.low.is_some_and(|low| value < low) {
+            return Some(Limit::Low);
+        }
+        None
+    }
+
+    // しきい値から不感帯の分だけ内側に戻った場合はtrue
+    pub fn returned(&self, value: f64) -> bool {
+        let below_high = self
+            .high
+            .map(|high| value <= high - self.deadband)
+            .unwrap_or(true);
+        let above_low = self
+            .low
+            .map(|low| value >= low + self.deadband)
+            .unwrap_or(true);
+        below_high && above_low
+    }
+
+    // 現在の値が超えている側のしきい値
+    pub fn limit_value(&self, limit: Limit) -> Option<f64> {
+        match limit {
+            Limit::High => self.high,
+            Limit::Low => self.low,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(high: Option<f64>, low: Option<f64>, deadband: f64) -> AlarmRule {
+        AlarmRule {
+            name: "temp".to_string(),
+            source: "demo_machine".to_string(),
+            tag: "temp".to_string(),
+            high,
+            low,
+            deadband,
+            on_delay_sec: 0.0,
+            off_delay_sec: 0.0,
+            severity: Severity::Warning,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn read_rule_with_defaults() {
+        let text = r#"{ "rules": [
+            { "name": "temp_high", "source": "demo_machine", "tag": "temp", "high": 220 },
+            { "name": "plc_offline", "source": "gateway", "tag": "plc_offline",
+              "high": 0.5, "on_delay_sec": 300, "severity": "critical" }
+        ] }"#;
+        let file: RuleFile = serde_json::from_str(text).unwrap();
+        assert_eq!(file.rules[0].severity, Severity::Warning);
+        assert_eq!(file.rules[0].low, None);
+        assert_eq!(file.rules[0].deadband, 0.0);
+        assert_eq!(file.rules[1].severity, Severity::Critical);
+        assert_eq!(file.rules[1].on_delay_sec, 300.0);
+        assert!(file.rules.iter().all(|t| t.validate().is_ok()));
+    }
+
+    #[test]
+    fn validate_rule() {
+        assert!(rule(Some(10.0), Some(5.0), 2.0).validate().is_ok());
+        assert!(rule(None, Some(5.0), 0.0).validate().is_ok());
+        assert!(rule(None, None, 0.0).validate().is_err());
+        // 復帰の範囲がなくなる不感帯
+        assert!(rule(Some(10.0), Some(5.0), 2.5).validate().is_err());
+        assert!(rule(Some(5.0), Some(10.0), 0.0).validate().is_err());
+        assert!(rule(Some(10.0), None, -1.0).validate().is_err());
+
+        let mut invalid = rule(Some(10.0), None, 0.0);
+        invalid.name = " ".to_string();
+        assert!(invalid.validate().is_err());
+        let mut invalid = rule(Some(10.0), None, 0.0);
+        invalid.off_delay_sec = -1.0;
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn exceeded_limit() {
+        let rule = rule(Some(100.0), Some(0.0), 5.0);
+        // しきい値と同じ値は超えていない
+        assert_eq!(rule.exceeded(100.0), None);
+        assert_eq!(rule.exceeded(100.1), Some(Limit::High));
+        assert_eq!(rule.exceeded(0.0), None);
+        assert_eq!(rule.exceeded(-0.1), Some(Limit::Low));
+        assert_eq!(rule.limit_value(Limit::High), Some(100.0));
+        assert_eq!(rule.limit_value(Limit::Low), Some(0.0));
+    }
+
+    #[test]
+    fn returned_with_hysteresis() {
+        let rule = rule(Some(100.0), Some(0.0), 5.0);
+        // 不感帯の境界は復帰とする
+        assert!(rule.returned(95.0));
+        assert!(!rule.returned(95.1));
+        assert!(!rule.returned(100.0));
+        assert!(rule.returned(5.0));
+        assert!(!rule.returned(4.9));
+        assert!(rule.returned(50.0));
+
+        // 片側のみの場合はもう一方を判定しない
+        let high_only = self::rule(Some(100.0), None, 5.0);
+        assert!(high_only.returned(-1000.0));
+        assert_eq!(high_only.exceeded(-1000.0), None);
+    }
+}
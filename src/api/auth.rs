@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+
+use super::api_error;
+
+// トークンの最小の長さ
+const MIN_TOKEN_LENGTH: usize = 16;
+
+// APIの利用者名とトークン
+// API_TOKENSに"利用者名=トークン"をカンマ区切りで指定する ex) mes=xxxxxxxxxxxxxxxx,operator=yyyyyyyyyyyyyyyy
+// リクエストは"Authorization: Bearer トークン"で認証する
+#[derive(Clone, Default)]
+pub struct ApiTokens {
+    entries: Arc<Vec<(String, String)>>,
+}
+
+// 認証した利用者名。監査ログや確認者の記録に使う
+#[derive(Clone, Debug)]
+pub struct ApiIdentity(pub String);
+
+impl ApiTokens {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut entries: Vec<(String, String)> = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let Some((identity, token)) = item.split_once('=') else {
+                anyhow::bail!("API_TOKENSの設定が不正")
+            };
+            let (identity, token) = (identity.trim(), token.trim());
+            if identity.is_empty() {
+                anyhow::bail!("API_TOKENSの利用者名が空")
+            }
+            if token.len() < MIN_TOKEN_LENGTH {
+                anyhow::bail!("API_TOKENSのトークンが短い:{}", identity)
+            }
+            if entries.iter().any(|(_, t)| t == token) {
+                anyhow::bail!("API_TOKENSのトークンが重複:{}", identity)
+            }
+            entries.push((identity.to_string(), token.to_string()));
+        }
+        Ok(Self {
+            entries: Arc::new(entries),
+        })
+    }
+
+    // 環境変数が未設定の場合は空(書き込みのAPIは起動しない)
+    pub fn create_from_env() -> anyhow::Result<Self> {
+        match std::env::var("API_TOKENS") {
+            Ok(t) => Self::parse(&t),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn identify(&self, token: &str) -> Option<String> {
+        // 一致した位置で比較時間が変わらないように全て比較する
+        let mut found = None;
+        for (identity, expected) in self.entries.iter() {
+            if constant_time_eq(expected.as_bytes(), token.as_bytes()) {
+                found = Some(identity.to_owned());
+            }
+        }
+        found
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// トークンを確認して利用者名をリクエストに付ける
+pub async fn authenticate(
+    State(tokens): State<ApiTokens>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let identity = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|t| t.to_str().ok())
+        .and_then(|t| t.strip_prefix("Bearer "))
+        .and_then(|t| tokens.identify(t.trim()));
+    let Some(identity) = identity else {
+        warn!(
+            "APIの認証に失敗:{} {}",
+            request.method(),
+            request.uri().path()
+        );
+        return api_error(StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    };
+    request.extensions_mut().insert(ApiIdentity(identity));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identify_by_token() {
+        let tokens = ApiTokens::parse("mes=0123456789abcdef, operator=fedcba9876543210").unwrap();
+        assert_eq!(tokens.identify("0123456789abcdef"), Some("mes".to_string()));
+        assert_eq!(
+            tokens.identify("fedcba9876543210"),
+            Some("operator".to_string())
+        );
+        assert_eq!(tokens.identify("0123456789abcdeX"), None);
+        assert_eq!(tokens.identify(""), None);
+    }
+
+    #[test]
+    fn reject_invalid_tokens() {
+        assert!(ApiTokens::parse("mes").is_err());
+        assert!(ApiTokens::parse("=0123456789abcdef").is_err());
+        assert!(ApiTokens::parse("mes=short").is_err());
+        assert!(ApiTokens::parse("a=0123456789abcdef,b=0123456789abcdef").is_err());
+        assert!(ApiTokens::parse("").unwrap().is_empty());
+    }
+}
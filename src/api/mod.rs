@@ -0,0 +1,328 @@
+mod auth;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{middleware, Extension, Json, Router};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::alarm::{AlarmEngine, AlarmRecord};
+use crate::collector::demo_cpb16::{DowntimeRegistry, StopEpisode};
+use crate::collector::host_link::SetpointWriter;
+use auth::{ApiIdentity, ApiTokens};
+
+// MESやオペレーターからの操作を受け付けるHTTP API
+// API_ADDRESSが設定されている場合のみ起動する ex) 0.0.0.0:8090
+// API_TOKENSを設定した場合は全てのAPIでトークンの認証が必要
+//  - 設定値(/demo_cpb16/setpoints) : 書き込みはAPI_TOKENSが未設定の場合は起動しない
+//  - 停止理由(/demo_cpb16/downtime) : API_TOKENSが未設定の場合は認証なしで受け付け、記録者はrequested_by
+//  - アラーム(/alarms)               : 停止理由と同じ
+pub struct ApiServer {
+    address: String,
+    tokens: ApiTokens,
+    thread: Option<JoinHandle<()>>,
+}
+
+// 各ハンドラで共有する状態
+#[derive(Clone, Default)]
+pub struct ApiState {
+    pub demo_cpb16_setpoint_writer: Option<SetpointWriter>,
+    pub demo_cpb16_downtime: Option<DowntimeRegistry>,
+    pub alarm: Option<AlarmEngine>,
+}
+
+type ApiError = (StatusCode, Json<ErrorResponse>);
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    error: String,
+}
+
+fn api_error(status: StatusCode, message: impl ToString) -> ApiError {
+    (
+        status,
+        Json(ErrorResponse {
+            error: message.to_string(),
+        }),
+    )
+}
+
+// 認証した場合は利用者名、認証なしの場合はリクエストの記録者名(省略時はapi)
+fn requested_by(identity: Option<Extension<ApiIdentity>>, requested_by: Option<String>) -> String {
+    match identity {
+        Some(Extension(identity)) => identity.0,
+        None => requested_by.unwrap_or_else(|| "api".to_string()),
+    }
+}
+
+impl ApiServer {
+    pub fn create_from_env() -> anyhow::Result<Self> {
+        let address = std::env::var("API_ADDRESS")?;
+        let tokens = ApiTokens::create_from_env()?;
+        Ok(Self {
+            address,
+            tokens,
+            thread: None,
+        })
+    }
+
+    pub async fn start(&mut self, state: ApiState) -> anyhow::Result<()> {
+        if self.thread.is_some() {
+            anyhow::bail!("already started in ApiServer::start")
+        }
+        // PLCへの書き込みは認証した利用者のみ受け付ける
+        let mut setpoints =
+            Router::new().route("/demo_cpb16/setpoints", get(get_demo_cpb16_setpoints));
+        if self.tokens.is_empty() {
+            warn!("API_TOKENSが未設定のため設定値の書き込みのAPIは起動しない");
+        } else {
+            setpoints = setpoints.route(
+                "/demo_cpb16/setpoints/:name",
+                post(post_demo_cpb16_setpoint),
+            );
+        }
+        let downtime = Router::new()
+            .route(
+                "/demo_cpb16/downtime/reasons",
+                get(get_demo_cpb16_downtime_reasons),
+            )
+            .route(
+                "/demo_cpb16/downtime/unclassified",
+                get(get_demo_cpb16_unclassified_downtime),
+            )
+            .route(
+                "/demo_cpb16/downtime/:id",
+                post(post_demo_cpb16_downtime_reason),
+            );
+        let alarms = Router::new()
+            .route("/alarms", get(get_alarms))
+            .route("/alarms/:name/ack", post(post_alarm_ack));
+        let router = Router::new()
+            .merge(self.authenticated(setpoints))
+            .merge(self.authenticated(downtime))
+            .merge(self.authenticated(alarms))
+            .with_state(state);
+
+        let listener = tokio::net::TcpListener::bind(&self.address).await?;
+        info!("API start:{}", self.address);
+        let thread = tokio::spawn(async move {
+            if let Err(r) = axum::serve(listener, router).await {
+                error!("API server error:{:?}", r);
+            }
+        });
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    // API_TOKENSを設定した場合はトークンの認証を必要にする
+    fn authenticated(&self, router: Router<ApiState>) -> Router<ApiState> {
+        if self.tokens.is_empty() {
+            return router;
+        }
+        router.route_layer(middleware::from_fn_with_state(
+            self.tokens.clone(),
+            auth::authenticate,
+        ))
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.abort();
+        }
+    }
+}
+
+async fn get_demo_cpb16_setpoints(
+    State(state): State<ApiState>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let Some(writer) = state.demo_cpb16_setpoint_writer else {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "setpoint writer is not configured",
+        ));
+    };
+    Ok(Json(writer.get_writable_names()))
+}
+
+#[derive(Deserialize)]
+struct SetpointRequest {
+    value: i64,
+}
+
+#[derive(Serialize)]
+struct SetpointResponse {
+    name: String,
+    device: String,
+    old_value: i64,
+    new_value: i64,
+}
+
+async fn post_demo_cpb16_setpoint(
+    State(state): State<ApiState>,
+    Extension(identity): Extension<ApiIdentity>,
+    Path(name): Path<String>,
+    Json(request): Json<SetpointRequest>,
+) -> Result<Json<SetpointResponse>, ApiError> {
+    let Some(writer) = state.demo_cpb16_setpoint_writer else {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "setpoint writer is not configured",
+        ));
+    };
+    match writer.write(&name, request.value, &identity.0).await {
+        Ok(result) => Ok(Json(SetpointResponse {
+            name: result.name,
+            device: result.device,
+            old_value: result.old_value,
+            new_value: result.new_value,
+        })),
+        Err(r) => Err(api_error(StatusCode::BAD_REQUEST, r)),
+    }
+}
+
+fn get_downtime_registry(state: ApiState) -> Result<DowntimeRegistry, ApiError> {
+    match state.demo_cpb16_downtime {
+        Some(t) => Ok(t),
+        None => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "downtime registry is not configured",
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct DowntimeResponse {
+    id: i64,
+    start: String,
+    end: Option<String>,
+    duration_second: f64,
+    stop_code: Option<i64>,
+    reason: Option<String>,
+    classified_by: Option<String>,
+}
+
+impl From<StopEpisode> for DowntimeResponse {
+    fn from(episode: StopEpisode) -> Self {
+        Self {
+            id: episode.id,
+            start: episode.start.to_rfc3339(),
+            end: episode.end.map(|t| t.to_rfc3339()),
+            duration_second: episode.duration_second(),
+            stop_code: episode.stop_code,
+            reason: episode.reason,
+            classified_by: episode.classified_by,
+        }
+    }
+}
+
+async fn get_demo_cpb16_downtime_reasons(
+    State(state): State<ApiState>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let registry = get_downtime_registry(state)?;
+    Ok(Json(registry.get_reasons()))
+}
+
+async fn get_demo_cpb16_unclassified_downtime(
+    State(state): State<ApiState>,
+) -> Result<Json<Vec<DowntimeResponse>>, ApiError> {
+    let registry = get_downtime_registry(state)?;
+    let episodes = registry.get_unclassified();
+    Ok(Json(
+        episodes.into_iter().map(DowntimeResponse::from).collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct DowntimeReasonRequest {
+    reason: String,
+    requested_by: Option<String>,
+}
+
+async fn post_demo_cpb16_downtime_reason(
+    State(state): State<ApiState>,
+    identity: Option<Extension<ApiIdentity>>,
+    Path(id): Path<i64>,
+    Json(request): Json<DowntimeReasonRequest>,
+) -> Result<Json<DowntimeResponse>, ApiError> {
+    let registry = get_downtime_registry(state)?;
+    let requested_by = requested_by(identity, request.requested_by);
+    match registry.assign(id, &request.reason, &requested_by).await {
+        Ok(episode) => Ok(Json(DowntimeResponse::from(episode))),
+        Err(r) => Err(api_error(StatusCode::BAD_REQUEST, r)),
+    }
+}
+
+fn get_alarm_engine(state: ApiState) -> Result<AlarmEngine, ApiError> {
+    match state.alarm {
+        Some(t) => Ok(t),
+        None => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "alarm rules are not configured",
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct AlarmResponse {
+    name: String,
+    source: String,
+    tag: String,
+    severity: String,
+    message: String,
+    state: String,
+    value: Option<f64>,
+    limit: Option<String>,
+    limit_value: Option<f64>,
+    raised_at: Option<String>,
+    changed_at: String,
+    acknowledged_by: Option<String>,
+}
+
+impl From<AlarmRecord> for AlarmResponse {
+    fn from(record: AlarmRecord) -> Self {
+        Self {
+            name: record.name,
+            source: record.source,
+            tag: record.tag,
+            severity: record.severity.as_str().to_string(),
+            message: record.message,
+            state: record.state.as_str().to_string(),
+            value: record.value,
+            limit: record.limit.map(|t| t.as_str().to_string()),
+            limit_value: record.limit_value,
+            raised_at: record.raised_at.map(|t| t.to_rfc3339()),
+            changed_at: record.changed_at.to_rfc3339(),
+            acknowledged_by: record.acknowledged_by,
+        }
+    }
+}
+
+// 発生中または未確認のアラーム
+async fn get_alarms(State(state): State<ApiState>) -> Result<Json<Vec<AlarmResponse>>, ApiError> {
+    let engine = get_alarm_engine(state)?;
+    let alarms = engine.get_alarms();
+    Ok(Json(alarms.into_iter().map(AlarmResponse::from).collect()))
+}
+
+#[derive(Deserialize)]
+struct AlarmAckRequest {
+    requested_by: Option<String>,
+}
+
+async fn post_alarm_ack(
+    State(state): State<ApiState>,
+    identity: Option<Extension<ApiIdentity>>,
+    Path(name): Path<String>,
+    Json(request): Json<AlarmAckRequest>,
+) -> Result<Json<AlarmResponse>, ApiError> {
+    let engine = get_alarm_engine(state)?;
+    let requested_by = requested_by(identity, request.requested_by);
+    match engine.acknowledge(&name, &requested_by).await {
+        Ok(record) => Ok(Json(AlarmResponse::from(record))),
+        Err(r) => Err(api_error(StatusCode::BAD_REQUEST, r)),
+    }
+}
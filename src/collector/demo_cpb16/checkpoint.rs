@@ -0,0 +1,303 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::processing::counter::CounterState;
+
+const DEFAULT_STATE_PATH: &str = "state/demo_cpb16.json";
+const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// ゲートウェイの再起動をまたいで引き継ぐデータハンドラーの状態
+// 項目を追加する前の状態ファイルも読めるように、ない項目は初期値にする
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandlerCheckpoint {
+    pub is_running: bool,
+    pub last_started_run_id: Option<u32>,
+    pub last_result_run_id: Option<u32>,
+    pub chunk: ChunkCheckpoint,
+    // 停止中の停止
+    pub stop_episode: Option<EpisodeCheckpoint>,
+    // オペレーターの割り当て待ちの停止
+    pub unclassified: Vec<EpisodeCheckpoint>,
+    pub oee: Option<OeeCheckpoint>,
+}
+
+impl HandlerCheckpoint {
+    // 稼働・停止の状態と送信済みの記録が同じか。チャンクやOEEの集計値は比較しない
+    fn is_same_state(&self, other: &Self) -> bool {
+        self.is_running == other.is_running
+            && self.last_started_run_id == other.last_started_run_id
+            && self.last_result_run_id == other.last_result_run_id
+            && self.stop_episode == other.stop_episode
+            && self.unclassified == other.unclassified
+    }
+}
+
+// 送信前のチャンクの集計
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChunkCheckpoint {
+    pub window_start: Option<i64>,
+    pub last_sample_time: Option<i64>,
+    pub last_sample_running: bool,
+    pub production: u32,
+    pub defect: u32,
+    pub working_msec: i64,
+    pub time_msec: i64,
+    pub planned_stop_msec: i64,
+    pub unplanned_stop_msec: i64,
+    pub last_working_id: Option<u32>,
+    pub restarted: bool,
+    pub production_counter: CounterState,
+    pub defect_counter: CounterState,
+}
+
+// 1回の停止。時刻はミリ秒タイムスタンプ
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeCheckpoint {
+    pub id: i64,
+    pub start: i64,
+    pub end: Option<i64>,
+    pub stop_code: Option<i64>,
+    pub reason: Option<String>,
+    pub classified_by: Option<String>,
+}
+
+// 出力前のシフト・日毎のOEEの集計
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OeeCheckpoint {
+    pub last_sample_time: Option<i64>,
+    pub last_sample_running: bool,
+    pub production_counter: CounterState,
+    pub defect_counter: CounterState,
+    // 集計中のシフト。時刻はミリ秒タイムスタンプ
+    pub shift_name: Option<String>,
+    pub shift_start: Option<i64>,
+    pub shift_end: Option<i64>,
+    pub shift_run_second: f64,
+    pub shift_production: u32,
+    pub shift_defect: u32,
+    // 途中から集計した場合は最初の受信からの計画時間
+    pub shift_planned_second: f64,
+    // 集計中の日 ex) 2026-10-19
+    pub day: Option<String>,
+    pub day_run_second: f64,
+    pub day_production: u32,
+    pub day_defect: u32,
+    pub day_planned_second: f64,
+    pub next_day_planned_second: f64,
+}
+
+// 状態ファイル
+// DemoCpb16StatePath            : 保存先。デフォルトはstate/demo_cpb16.json
+// DemoCpb16StateSaveIntervalSec : 集計値のみが変わった場合の保存間隔(sec)。デフォルト10
+// 稼働・停止や送信済みの稼働IDが変わった場合は間隔によらず保存する
+// 書き込み途中で止まっても壊れないように一時ファイルに書いてディスクに同期してから置き換える
+pub struct CheckpointStore {
+    path: String,
+    interval: Duration,
+    last_saved: Option<HandlerCheckpoint>,
+    last_saved_at: Option<Instant>,
+}
+
+impl CheckpointStore {
+    pub fn create_from_env() -> anyhow::Result<Self> {
+        let path =
+            std::env::var("DemoCpb16StatePath").unwrap_or_else(|_| DEFAULT_STATE_PATH.to_string());
+        let interval = match std::env::var("DemoCpb16StateSaveIntervalSec") {
+            Ok(t) => Duration::from_secs(t.parse()?),
+            Err(_) => DEFAULT_SAVE_INTERVAL,
+        };
+        Ok(Self {
+            path,
+            interval,
+            last_saved: None,
+            last_saved_at: None,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            interval: DEFAULT_SAVE_INTERVAL,
+            last_saved: None,
+            last_saved_at: None,
+        }
+    }
+
+    // ファイルがない場合はNone
+    pub fn load(&mut self) -> anyhow::Result<Option<HandlerCheckpoint>> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(t) => t,
+            Err(r) if r.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(r) => anyhow::bail!("状態ファイルの読み込みに失敗:{}:{:?}", self.path, r),
+        };
+        let checkpoint: HandlerCheckpoint = serde_json::from_str(&text)?;
+        info!("状態ファイルから復元:{}", self.path);
+        self.last_saved = Some(checkpoint.clone());
+        Ok(Some(checkpoint))
+    }
+
+    // 前回から変化がある場合のみ書き込む
+    // 集計値のみの変化は保存間隔が経過するまで書き込まない。forceの場合は間隔によらず書き込む
+    pub async fn save(&mut self, checkpoint: HandlerCheckpoint, force: bool) -> anyhow::Result<()> {
+        let Some(last_saved) = self.last_saved.as_ref() else {
+            return self.write(checkpoint).await;
+        };
+        if *last_saved == checkpoint {
+            return Ok(());
+        }
+        let waiting = self
+            .last_saved_at
+            .is_some_and(|t| t.elapsed() < self.interval);
+        if !force && waiting && last_saved.is_same_state(&checkpoint) {
+            return Ok(());
+        }
+        self.write(checkpoint).await
+    }
+
+    async fn write(&mut self, checkpoint: HandlerCheckpoint) -> anyhow::Result<()> {
+        let path = std::path::Path::new(&self.path);
+        let dir = match path.parent() {
+            Some(t) if !t.as_os_str().is_empty() => t,
+            _ => std::path::Path::new("."),
+        };
+        tokio::fs::create_dir_all(dir).await?;
+        let temp_path = format!("{}.tmp", self.path);
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(serde_json::to_string(&checkpoint)?.as_bytes())
+            .await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&temp_path, path).await?;
+        // 置き換えたことをディレクトリにも同期する
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+        self.last_saved = Some(checkpoint);
+        self.last_saved_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_state_file_without_new_items() {
+        let text = r#"{"is_running":true,"last_started_run_id":3,"last_result_run_id":2,
+            "chunk":{"window_start":null,"last_sample_time":null,"last_sample_running":false,
+            "production":0,"defect":0,"working_msec":0,"time_msec":0,"planned_stop_msec":0,
+            "unplanned_stop_msec":0,"last_working_id":null,"restarted":false,
+            "production_counter":{"last":null,"total":0},"defect_counter":{"last":null,"total":0}}}"#;
+        let checkpoint: HandlerCheckpoint = serde_json::from_str(text).unwrap();
+        assert!(checkpoint.is_running);
+        assert_eq!(checkpoint.stop_episode, None);
+        assert!(checkpoint.unclassified.is_empty());
+        assert_eq!(checkpoint.oee, None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let checkpoint = HandlerCheckpoint {
+            stop_episode: Some(EpisodeCheckpoint {
+                id: 1,
+                start: 1,
+                stop_code: Some(2),
+                ..Default::default()
+            }),
+            oee: Some(OeeCheckpoint {
+                day: Some("2026-10-19".to_string()),
+                day_run_second: 12.5,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let text = serde_json::to_string(&checkpoint).unwrap();
+        assert_eq!(
+            serde_json::from_str::<HandlerCheckpoint>(&text).unwrap(),
+            checkpoint
+        );
+    }
+
+    fn state_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("checkpoint_{}_{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    fn chunk_checkpoint(working_msec: i64) -> HandlerCheckpoint {
+        HandlerCheckpoint {
+            is_running: true,
+            last_started_run_id: Some(3),
+            chunk: ChunkCheckpoint {
+                working_msec,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_saved_file() {
+        let path = state_path("restore");
+        let checkpoint = HandlerCheckpoint {
+            unclassified: vec![EpisodeCheckpoint {
+                id: 10,
+                start: 10,
+                end: Some(20),
+                ..Default::default()
+            }],
+            ..chunk_checkpoint(1000)
+        };
+        let mut store = CheckpointStore::new(&path);
+        store.save(checkpoint.clone(), false).await.unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+
+        let mut store = CheckpointStore::new(&path);
+        assert_eq!(store.load().unwrap(), Some(checkpoint));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(CheckpointStore::new(&path).load().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn throttle_accumulator_changes() {
+        let path = state_path("throttle");
+        let load = || CheckpointStore::new(&path).load().unwrap().unwrap();
+        let mut store = CheckpointStore::new(&path);
+        store.save(chunk_checkpoint(1000), false).await.unwrap();
+
+        // 集計値のみの変化は保存間隔まで書き込まない
+        store.save(chunk_checkpoint(2000), false).await.unwrap();
+        assert_eq!(load(), chunk_checkpoint(1000));
+
+        // 状態の変化はすぐに書き込む
+        let stopped = HandlerCheckpoint {
+            is_running: false,
+            ..chunk_checkpoint(3000)
+        };
+        store.save(stopped.clone(), false).await.unwrap();
+        assert_eq!(load(), stopped);
+
+        let stopped_later = HandlerCheckpoint {
+            is_running: false,
+            ..chunk_checkpoint(4000)
+        };
+        store.save(stopped_later.clone(), true).await.unwrap();
+        assert_eq!(load(), stopped_later);
+
+        // 保存間隔が経過した後は集計値のみの変化も書き込む
+        store.interval = Duration::ZERO;
+        let stopped_last = HandlerCheckpoint {
+            is_running: false,
+            ..chunk_checkpoint(5000)
+        };
+        store.save(stopped_last.clone(), false).await.unwrap();
+        assert_eq!(load(), stopped_last);
+        let _ = std::fs::remove_file(&path);
+    }
+}
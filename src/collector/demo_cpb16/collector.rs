@@ -4,11 +4,15 @@ use tokio::sync::mpsc;
 
 use super::config::DemoCpb16Config;
 use super::data_manager::DemoCpb16DataManager;
+use super::downtime::DowntimeRegistry;
 use super::interface::DemoCpb16Interface;
+use crate::collector::host_link::{DisconnectReason, SetpointWriter};
 
 pub struct DemoCpb16Collector {
+    data_sender: mpsc::Sender<Vec<DataPoint>>,
     interface: DemoCpb16Interface,
     manager: DemoCpb16DataManager,
+    setpoint_writer: SetpointWriter,
 }
 
 impl DemoCpb16Collector {
@@ -16,14 +20,30 @@ impl DemoCpb16Collector {
         data_sender: mpsc::Sender<Vec<DataPoint>>,
     ) -> anyhow::Result<Self> {
         let config = DemoCpb16Config::create_from_env()?;
+        let setpoint_writer = config.get_setpoint_writer()?;
+        let manager = DemoCpb16DataManager::create(data_sender.clone(), &config)?;
         let interface = DemoCpb16Interface::create_from_config(config)?;
-        let manager = DemoCpb16DataManager::create(data_sender)?;
-        Ok(Self { interface, manager })
+        Ok(Self {
+            data_sender,
+            interface,
+            manager,
+            setpoint_writer,
+        })
+    }
+
+    // APIスレッドに渡すため、コレクターとは独立して使える
+    pub fn get_setpoint_writer(&self) -> SetpointWriter {
+        self.setpoint_writer.clone()
+    }
+
+    // 未分類の停止に理由を割り当てるためAPIスレッドに渡す
+    pub fn get_downtime_registry(&self) -> DowntimeRegistry {
+        self.manager.get_downtime_registry()
     }
 
     pub async fn start_data_collection(
         &mut self,
-        disconnect_sender: mpsc::Sender<()>,
+        disconnect_sender: mpsc::Sender<DisconnectReason>,
     ) -> anyhow::Result<()> {
         if self.interface.is_monitoring() {
             anyhow::bail!("start_data_collection can not execute: interface is monitoring")
@@ -35,7 +55,7 @@ impl DemoCpb16Collector {
         self.manager.create_thread(point_receiver).await?;
         match self
             .interface
-            .start_monitor(point_sender, disconnect_sender)
+            .start_monitor(point_sender, disconnect_sender, self.data_sender.clone())
             .await
         {
             Ok(()) => {}
@@ -2,7 +2,16 @@ use chrono::Local;
 #[allow(unused_imports)]
 use log::{debug, error};
 
-pub use super::data_manager::SET_MONITOR_COMMAND;
+use super::data_manager::MONITOR_DEVICES;
+use super::downtime::{DowntimeConfig, STOP_CAUSE_FIELD};
+use super::oee::OeeConfig;
+use crate::collector::host_link::{
+    Device, DeviceMap, HostLinkTarget, ReadPlan, SetpointWriter, WritableDeviceMap,
+};
+use crate::collector::scheduler::OverrunPolicy;
+use crate::processing::counter::CounterConfig;
+use crate::processing::rate::RateConfig;
+
 // 機械稼働時は1000msec間隔
 pub const MONITOR_INTERVAL: u64 = 1000;
 // 機械停止時時は1秒間隔
@@ -15,20 +24,113 @@ pub const CHECK_COMMAND: &[u8] = b"?K\r";
 pub const CHECK_RESPONSE: &str = "55";
 pub const OK_RESPONSE: &str = "OK";
 pub const COMMAND_ABNORMAL_RESPONSE: &str = "E1";
-pub const MONITOR_READOUT_COMMAND: &[u8] = b"MWR\r";
 pub const TIME_PREFERENCE_COMMAND: &[u8] = b"WRT ";
+// 稼働状況のデバイス
+pub const RUNNING_DEVICE: &str = "DM0.U";
+// 生産数のデバイス。不良数も同じビット幅
+pub const PRODUCTION_DEVICE: &str = "DM100.U";
+// 稼働状況のチャンクの区間(sec)のデフォルト
+const DEFAULT_CHUNK_WINDOW_SECOND: i64 = 10;
 
 #[derive(Clone)]
 pub struct DemoCpb16Config {
-    address: String,
+    target: HostLinkTarget,
+    // アラームやインターロックなどのビットデバイス ex) main_alarm=MR100,door_interlock=R002
+    // 停止要因コードのデバイスはstop_causeとして後ろに追加する
+    status_devices: DeviceMap,
+    // DemoCpb16ReadMode(auto/monitor/block)とDemoCpb16BlockReadGapで読み出し方法を指定
+    read_plan: ReadPlan,
+    // MESから書き込みを許可するデバイス ex) target_bag_count=DM200.U:0:5000:stop
+    writable_devices: WritableDeviceMap,
+    // DemoCpb16OverrunPolicy(skip/burst) : 読み出しが周期を超えた場合の動作
+    overrun_policy: OverrunPolicy,
+    // DemoCpb16IdealCycleRateが設定されている場合はOEEを計算する
+    oee: Option<OeeConfig>,
+    // DemoCpb16StopCauseDeviceとDemoCpb16StopReasonsで停止理由を分類する
+    downtime: DowntimeConfig,
+    // DemoCpb16RateWindowSec等で生産速度の平滑化と速度低下の判定を設定する
+    rate: RateConfig,
+    // DemoCpb16CounterMaxStepで生産数・不良数の異常な増加を判定する
+    counter: CounterConfig,
+    // DemoCpb16ChunkWindowSec : 稼働状況のチャンクの区間(sec)。0時から区切るので1日を割り切れる値
+    chunk_window_second: i64,
 }
 impl DemoCpb16Config {
     pub fn create_from_env() -> anyhow::Result<Self> {
-        let address = std::env::var("DemoCpb16StatusConfigAddress")?;
-        Ok(Self { address })
+        let target = HostLinkTarget::create_from_env("DemoCpb16")?;
+        let mut status_devices = DeviceMap::create_from_env("DemoCpb16StatusDevices")?;
+        let downtime = DowntimeConfig::create_from_env()?;
+        if let Some(device) = downtime.get_device() {
+            status_devices.push(STOP_CAUSE_FIELD, device);
+        }
+
+        let mut devices = MONITOR_DEVICES
+            .iter()
+            .map(|t| Device::parse(t))
+            .collect::<anyhow::Result<Vec<Device>>>()?;
+        devices.extend(status_devices.devices());
+        let read_plan = ReadPlan::create_from_env(&devices, "DemoCpb16")?;
+        let writable_devices = WritableDeviceMap::create_from_env("DemoCpb16WritableDevices")?;
+        let overrun_policy = OverrunPolicy::create_from_env("DemoCpb16OverrunPolicy")?;
+        let oee = OeeConfig::create_from_env()?;
+        let rate = RateConfig::create_from_env("DemoCpb16")?;
+        let bit_width = Device::parse(PRODUCTION_DEVICE)?.width() * 16;
+        let counter = CounterConfig::create_from_env("DemoCpb16", bit_width)?;
+        let chunk_window_second = match std::env::var("DemoCpb16ChunkWindowSec") {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_CHUNK_WINDOW_SECOND,
+        };
+        if chunk_window_second <= 0 || 86400 % chunk_window_second != 0 {
+            anyhow::bail!("DemoCpb16ChunkWindowSecが不正:{}", chunk_window_second)
+        }
+
+        Ok(Self {
+            target,
+            status_devices,
+            read_plan,
+            writable_devices,
+            overrun_policy,
+            oee,
+            downtime,
+            rate,
+            counter,
+            chunk_window_second,
+        })
+    }
+    pub fn get_target(&self) -> HostLinkTarget {
+        self.target.to_owned()
+    }
+    pub fn get_status_devices(&self) -> &DeviceMap {
+        &self.status_devices
+    }
+    pub fn get_read_plan(&self) -> ReadPlan {
+        self.read_plan.to_owned()
+    }
+    pub fn get_overrun_policy(&self) -> OverrunPolicy {
+        self.overrun_policy
+    }
+    pub fn get_oee(&self) -> Option<OeeConfig> {
+        self.oee.to_owned()
+    }
+    pub fn get_counter(&self) -> CounterConfig {
+        self.counter.to_owned()
+    }
+    pub fn get_chunk_window_second(&self) -> i64 {
+        self.chunk_window_second
+    }
+    pub fn get_rate(&self) -> RateConfig {
+        self.rate.to_owned()
+    }
+    pub fn get_downtime(&self) -> DowntimeConfig {
+        self.downtime.to_owned()
     }
-    pub fn get_address(&self) -> String {
-        self.address.to_owned()
+    pub fn get_setpoint_writer(&self) -> anyhow::Result<SetpointWriter> {
+        Ok(SetpointWriter::create(
+            "demo_cpb16",
+            self.get_target(),
+            self.writable_devices.to_owned(),
+            Device::parse(RUNNING_DEVICE)?,
+        ))
     }
 
     pub fn get_time_preference_command(&self) -> Vec<u8> {
@@ -1,19 +1,33 @@
 use chrono::{DateTime, Local, TimeZone};
+use influxdb2::models::data_point::DataPointBuilder;
 use influxdb2::models::DataPoint;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::task::JoinHandle;
 
-//
-pub const SET_MONITOR_COMMAND: &[u8] = b"\
-    MWS DM0.U DM50.U DM100.U DM102.U DM104.U DM106.U \
-    DM10.U DM12.U DM14.U DM16.U DM18.U DM20.U \
-    DM22.U DM24.U DM26.U DM28.U DM30.U DM32.U \
-    DM34.U DM36.U DM38.U DM40.U DM42.U DM44.U \
-    DM2.U\r";
-
-// pub const SET_MONITOR_COMMAND: &[u8] = b"MWS DM0.U DM50.U DM100.U DM102.U DM104.U DM106.U\r";
+use super::checkpoint::{CheckpointStore, ChunkCheckpoint, HandlerCheckpoint};
+use super::config::DemoCpb16Config;
+use super::downtime::{find_stop_code, DowntimeRegistry, StopEpisode};
+use super::oee::OeeEngine;
+use crate::alarm;
+use crate::collector::host_link::{DeviceMap, DeviceValue};
+use crate::processing::counter::{CounterConfig, CounterTracker};
+use crate::processing::rate::{RateCalculator, RateConfig};
+use crate::shift_calendar;
+
+// モニタ登録するデバイス
+// DemoCpb16StatusDevicesで設定したビットデバイスはこの後ろに追加される
+#[rustfmt::skip]
+pub const MONITOR_DEVICES: &[&str] = &[
+    "DM0.U", "DM50.U", "DM100.U", "DM102.U", "DM104.U", "DM106.U",
+    "DM10.U", "DM12.U", "DM14.U", "DM16.U", "DM18.U", "DM20.U",
+    "DM22.U", "DM24.U", "DM26.U", "DM28.U", "DM30.U", "DM32.U",
+    "DM34.U", "DM36.U", "DM38.U", "DM40.U", "DM42.U", "DM44.U",
+    "DM2.U",
+];
+
+// pub const MONITOR_DEVICES: &[&str] = &["DM0.U", "DM50.U", "DM100.U", "DM102.U", "DM104.U", "DM106.U"];
 // DM1002を稼働状況にする　⇒　DemoCpb16ReceiveData::create()で確認している
 // 00000 : 停止流、00001 : 稼働中
 //
@@ -55,16 +69,26 @@ const RESPONSE_LENGTH: usize = 149;
 pub struct DemoCpb16DataManager {
     thread: Option<JoinHandle<DemoCpb16DataHandler>>,
     state: Option<DemoCpb16DataHandler>,
+    downtime: DowntimeRegistry,
 }
 impl DemoCpb16DataManager {
-    pub fn create(data_sender: mpsc::Sender<Vec<DataPoint>>) -> anyhow::Result<Self> {
-        let state: DemoCpb16DataHandler = DemoCpb16DataHandler::create(data_sender)?;
+    pub fn create(
+        data_sender: mpsc::Sender<Vec<DataPoint>>,
+        config: &DemoCpb16Config,
+    ) -> anyhow::Result<Self> {
+        let downtime = DowntimeRegistry::new(config.get_downtime(), data_sender.clone());
+        let state: DemoCpb16DataHandler =
+            DemoCpb16DataHandler::create(data_sender, config, downtime.clone())?;
 
         Ok(Self {
             thread: None,
             state: Some(state),
+            downtime,
         })
     }
+    pub fn get_downtime_registry(&self) -> DowntimeRegistry {
+        self.downtime.clone()
+    }
     pub fn have_thread(&self) -> bool {
         self.thread.is_some()
     }
@@ -76,6 +100,8 @@ impl DemoCpb16DataManager {
             anyhow::bail!("thread is already created")
         }
         let mut state = self.state.take().unwrap();
+        // 接続毎に最初の受信で状態を合わせる
+        state.attached = false;
         let thread = tokio::spawn(async move {
             while let Some(data) = point_receiver.recv().await {
                 match state.receive_response(data).await {
@@ -103,6 +129,8 @@ impl DemoCpb16DataManager {
         let mut state = thread.await?;
         // 接続が切れたので一度データを送信しておく
         state.force_send_data().await?;
+        // 保存間隔を待たずに状態を保存する
+        state.save_checkpoint(true).await;
 
         self.state = Some(state);
         Ok(())
@@ -118,27 +146,130 @@ impl DemoCpb16DataManager {
 struct DemoCpb16DataHandler {
     sender: mpsc::Sender<Vec<DataPoint>>,
     last_machine_status: DemoCpb16Status,
+    last_device_values: Vec<(String, DeviceValue)>,
     operating_states_chunk: DemoCpb16OperationChunkData,
     send_data_length: usize,
     operating_send_data: Vec<DataPoint>,
+    oee: Option<OeeEngine>,
+    downtime: DowntimeRegistry,
+    // 稼働→停止で開始し、停止→稼働で記録する
+    stop_episode: Option<StopEpisode>,
+    // 稼働ID(DM50)毎に1回だけ送信する。再接続時に送り直した場合もPLCの時刻をタイムスタンプにして上書きする
+    last_started_run_id: Option<u32>,
+    last_result_run_id: Option<u32>,
+    // 再起動で稼働中の状態や集計途中のチャンクを失わないように受信毎に保存する
+    checkpoint_store: CheckpointStore,
+    // 起動後・再接続後の最初の受信を処理したか
+    attached: bool,
 }
 
 impl DemoCpb16DataHandler {
-    fn create(sender: mpsc::Sender<Vec<DataPoint>>) -> anyhow::Result<Self> {
+    fn create(
+        sender: mpsc::Sender<Vec<DataPoint>>,
+        config: &DemoCpb16Config,
+        downtime: DowntimeRegistry,
+    ) -> anyhow::Result<Self> {
         // TODO:定数はConfigに
-        Ok(Self {
+        let mut handler = Self {
             sender,
             last_machine_status: DemoCpb16Status::Stopping,
-            operating_states_chunk: DemoCpb16OperationChunkData::new(),
+            last_device_values: Vec::new(),
+            operating_states_chunk: DemoCpb16OperationChunkData::new(
+                config.get_chunk_window_second(),
+                config.get_rate(),
+                config.get_counter(),
+            ),
             send_data_length: 6,
             operating_send_data: Vec::<DataPoint>::new(),
-        })
+            oee: config
+                .get_oee()
+                .map(|t| OeeEngine::new(t, config.get_counter())),
+            downtime,
+            stop_episode: None,
+            last_started_run_id: None,
+            last_result_run_id: None,
+            checkpoint_store: CheckpointStore::create_from_env()?,
+            attached: false,
+        };
+        handler.load_checkpoint();
+        Ok(handler)
+    }
+
+    fn load_checkpoint(&mut self) {
+        match self.checkpoint_store.load() {
+            Ok(Some(checkpoint)) => self.restore(checkpoint),
+            Ok(None) => {}
+            // 状態ファイルが壊れている場合は初期状態から始める
+            Err(r) => warn!("状態ファイルを復元できない:{:?}", r),
+        }
+    }
+
+    fn checkpoint(&self) -> HandlerCheckpoint {
+        HandlerCheckpoint {
+            is_running: self.last_machine_status == DemoCpb16Status::Running,
+            last_started_run_id: self.last_started_run_id,
+            last_result_run_id: self.last_result_run_id,
+            chunk: self.operating_states_chunk.checkpoint(),
+            stop_episode: self.stop_episode.as_ref().map(|t| t.checkpoint()),
+            unclassified: self.downtime.checkpoint(),
+            oee: self.oee.as_ref().map(|t| t.checkpoint()),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: HandlerCheckpoint) {
+        self.last_machine_status = match checkpoint.is_running {
+            true => DemoCpb16Status::Running,
+            false => DemoCpb16Status::Stopping,
+        };
+        self.last_started_run_id = checkpoint.last_started_run_id;
+        self.last_result_run_id = checkpoint.last_result_run_id;
+        self.operating_states_chunk.restore(checkpoint.chunk);
+        // 停止中に再起動した場合は同じ停止として続ける
+        self.stop_episode = checkpoint.stop_episode.and_then(StopEpisode::restore);
+        self.downtime.restore(checkpoint.unclassified);
+        if let (Some(oee), Some(oee_checkpoint)) = (self.oee.as_mut(), checkpoint.oee) {
+            oee.restore(oee_checkpoint);
+        }
     }
 
-    async fn receive_response(&mut self, data: DemoCpb16ReceiveData) -> anyhow::Result<()> {
+    async fn receive_response(&mut self, mut data: DemoCpb16ReceiveData) -> anyhow::Result<()> {
         // debug!("receive_response");
+        // アラーム等のビットデバイスは変化時のみ送信
+        let device_values = std::mem::take(&mut data.device_values);
+        let stop_code = find_stop_code(&device_values);
+        if device_values != self.last_device_values {
+            self.send_device_status(&device_values, data.dt).await?;
+            self.last_device_values = device_values;
+        }
+
         // 5秒毎にデータ収集してる
-        let state = DemoCpb16ReceiveState::new(data)?;
+        let state = DemoCpb16ReceiveState::new(data, stop_code)?;
+        // 状態デバイスと生産数・不良数をアラームで判定
+        let mut values: Vec<(&str, f64)> = self
+            .last_device_values
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_i64() as f64))
+            .collect();
+        values.push(("production_count", state.production_count as f64));
+        values.push(("defect_count", state.defect_count as f64));
+        alarm::update("demo_cpb16", &values).await?;
+        if !self.attached {
+            self.attach(&state).await?;
+        }
+        // シフト・日毎のOEE
+        if let Some(oee) = self.oee.as_mut() {
+            let is_running = state.status == DemoCpb16Status::Running;
+            let points = oee.push(
+                state.receive_time,
+                is_running,
+                state.production_count,
+                state.defect_count,
+            )?;
+            if !points.is_empty() {
+                info!("OEEを送信");
+                self.sender.send(points).await?;
+            }
+        }
         #[allow(unreachable_patterns)]
         match self.last_machine_status {
             DemoCpb16Status::Running => match state.status {
@@ -155,6 +286,63 @@ impl DemoCpb16DataHandler {
             },
             _ => anyhow::bail!("データマネージャーのMachineStatusが不正"),
         }
+        self.save_checkpoint(false).await;
+        Ok(())
+    }
+    // 保存に失敗しても収集は続ける
+    async fn save_checkpoint(&mut self, force: bool) {
+        let checkpoint = self.checkpoint();
+        if let Err(r) = self.checkpoint_store.save(checkpoint, force).await {
+            warn!("状態ファイルの保存に失敗:{:?}", r);
+        }
+    }
+    // 起動後・再接続後の最初の受信
+    // 稼働中なら現在の生産数とDM10～の開始時刻を基準にし、それ以降の増分のみを集計する
+    // 切断前と同じ稼働が続いている場合は切断中の増分もその稼働のものとして数える
+    async fn attach(&mut self, state: &DemoCpb16ReceiveState) -> anyhow::Result<()> {
+        self.attached = true;
+        let was_running = self.last_machine_status == DemoCpb16Status::Running;
+        match state.status {
+            DemoCpb16Status::Running => {
+                let continued = was_running && self.last_started_run_id == Some(state.working_id);
+                if !continued {
+                    self.operating_states_chunk.attach(state);
+                }
+                if let Some(oee) = self.oee.as_mut() {
+                    oee.attach(
+                        state.receive_time,
+                        state.production_count,
+                        state.defect_count,
+                        continued,
+                    );
+                }
+                // 切断中に停止が終わっている。終了はPLCの稼働開始時刻とする
+                if let Some(episode) = self.stop_episode.take() {
+                    let end = state
+                        .start_time
+                        .unwrap_or(state.receive_time)
+                        .max(episode.start);
+                    self.downtime.finish(episode, end).await?;
+                }
+                info!("稼働中のPLCに接続:{}:{}", state.working_id, continued);
+                let point = state.make_run_resumed(continued)?;
+                self.sender.send(vec![point]).await?;
+                // 開始は観測していないので稼働開始の記録は送らない
+                self.last_started_run_id = Some(state.working_id);
+                self.last_machine_status = DemoCpb16Status::Running;
+            }
+            DemoCpb16Status::Stopping => {
+                // 切断中に稼働が終わっている。停止はPLCの稼働終了時刻からとする
+                if was_running {
+                    let start = match state.last_working_data {
+                        Some(t) => t.last_end_time,
+                        None => state.receive_time,
+                    };
+                    self.stop_episode = Some(StopEpisode::start(start));
+                    self.last_machine_status = DemoCpb16Status::Stopping;
+                }
+            }
+        }
         Ok(())
     }
     // 内部関数
@@ -171,6 +359,12 @@ impl DemoCpb16DataHandler {
 
     async fn receive_in_stopping(&mut self, state: DemoCpb16ReceiveState) -> anyhow::Result<()> {
         // debug!("receive_in_stopping");
+        // 停止要因コードは停止直後に確定する場合があるので停止中は読み続ける
+        if let Some(episode) = self.stop_episode.as_mut() {
+            self.downtime.classify(episode, state.stop_code);
+        }
+        // 停止中に再接続した場合も送信していない稼働結果を送る
+        self.send_worked_result(&state).await?;
         if let Some(data_point) = self.operating_states_chunk.push_stopping_data(&state)? {
             self.push_send_data(data_point).await?
         }
@@ -187,30 +381,47 @@ impl DemoCpb16DataHandler {
         debug!("生産機の運転停止");
         self.last_machine_status = DemoCpb16Status::Stopping;
         // debug!("receive_to_stopping");
+        self.stop_episode = Some(StopEpisode::start(state.receive_time));
 
-        // 稼働結果の送信
-        if state.last_working_data.is_some() {
-            // 稼働結果の送信
-            let worked_result = state.make_worked_result()?;
-            // let mut send_result = Vec::<DataPoint>::new();
-            // send_result.push(worked_result);
-            let send_result = vec![worked_result; 1];
-            info!("稼働結果を送信");
-            self.sender.send(send_result).await?;
-        } else {
-            debug!("receive_to_stopping:過去の稼働データがない")
-        }
-
+        // 稼働結果の送信はreceive_in_stoppingで行う
         // オペレーション記録をチャンクにプッシュ
         self.receive_in_stopping(state).await?;
 
         Ok(())
     }
+    // 前回稼働の結果が未送信なら送信する
+    async fn send_worked_result(&mut self, state: &DemoCpb16ReceiveState) -> anyhow::Result<()> {
+        let Some(data) = state.last_working_data else {
+            return Ok(());
+        };
+        // 停止中のDM50は直前の稼働のID
+        if self.last_result_run_id == Some(state.working_id) {
+            return Ok(());
+        }
+        let mut send_result = vec![
+            state.make_worked_result()?,
+            make_run_event(state.working_id, "end", data.last_end_time)?,
+        ];
+        // 稼働毎のOEE
+        if let Some(oee) = self.oee.as_ref() {
+            send_result.push(oee.make_run_point(
+                state.working_id,
+                data.last_start_time,
+                data.last_end_time,
+                data.last_production_count,
+                data.last_defect_count,
+            )?);
+        }
+        info!("稼働結果を送信:{}", state.working_id);
+        self.sender.send(send_result).await?;
+        self.last_result_run_id = Some(state.working_id);
+        Ok(())
+    }
     async fn receive_to_running(&mut self, state: DemoCpb16ReceiveState) -> anyhow::Result<()> {
         self.last_machine_status = DemoCpb16Status::Running;
         debug!("生産機の運転開始");
         // debug!("receive_to_running");
-        // NOTE:モニタプログラム起動時かつPLCが稼働中の場合のハンドリングを追加
+        // モニタプログラム起動時・再接続時にPLCが稼働中の場合はattachで処理する
         // 停止の記録は送信しない
         // if state.last_working_data.is_some() {
         //     // 稼働結果の送信
@@ -223,11 +434,46 @@ impl DemoCpb16DataHandler {
         //     debug!("receive_to_running:過去の稼働データがない")
         // }
 
+        // 停止の記録
+        if let Some(episode) = self.stop_episode.take() {
+            self.downtime.finish(episode, state.receive_time).await?;
+        }
+        // 稼働開始の記録
+        if self.last_started_run_id != Some(state.working_id) {
+            let start_time = state.start_time.unwrap_or(state.receive_time);
+            let point = make_run_event(state.working_id, "start", start_time)?;
+            info!("稼働開始を送信:{}", state.working_id);
+            self.sender.send(vec![point]).await?;
+            self.last_started_run_id = Some(state.working_id);
+        }
+
         // オペレーション記録をチャンクにプッシュ
         self.receive_in_running(state).await?;
 
         Ok(())
     }
+    async fn send_device_status(
+        &mut self,
+        device_values: &[(String, DeviceValue)],
+        dt: DateTime<Local>,
+    ) -> anyhow::Result<()> {
+        if device_values.is_empty() {
+            return Ok(());
+        }
+        let time = match dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("in match dt.timestamp_nanos_opt()"),
+        };
+        let mut builder = shift_calendar::add_shift_tag(DataPoint::builder("demo_cpb16"), dt)
+            .tag("info_type", "device_status");
+        for (name, value) in device_values {
+            builder = value.add_field(builder, name);
+        }
+        let point = builder.timestamp(time).build()?;
+        self.sender.send(vec![point]).await?;
+        Ok(())
+    }
+
     // 切断時などの強制送信
     async fn force_send_data(&mut self) -> anyhow::Result<()> {
         let send_data = std::mem::take(&mut self.operating_send_data);
@@ -241,6 +487,11 @@ impl DemoCpb16DataHandler {
 
     // NOTE:Drop時に実行する
     async fn send_chunk_data_when_drop(&mut self) -> anyhow::Result<()> {
+        // 再起動後に同じ区間を集計し直せるように出力前の集計を保存する
+        self.save_checkpoint(true).await;
+        if let Some(data_point) = self.operating_states_chunk.make_working_data_when_drop()? {
+            self.operating_send_data.push(data_point);
+        }
         let send_data = std::mem::take(&mut self.operating_send_data);
         if !send_data.is_empty() {
             self.sender.send(send_data).await?;
@@ -270,9 +521,25 @@ pub struct DemoCpb16ReceiveData {
     dt: DateTime<Local>,
     data: String,
     status: DemoCpb16Status,
+    device_values: Vec<(String, DeviceValue)>,
 }
 impl DemoCpb16ReceiveData {
-    pub fn create(dt: DateTime<Local>, data: String) -> anyhow::Result<Self> {
+    pub fn create(
+        dt: DateTime<Local>,
+        mut data: String,
+        status_devices: &DeviceMap,
+    ) -> anyhow::Result<Self> {
+        // MONITOR_DEVICESの後ろに追加したデバイスを切り離す
+        let mut device_values = Vec::new();
+        if !status_devices.is_empty() {
+            let res: Vec<&str> = data.split(' ').collect();
+            if res.len() != DATA_LENGTH + status_devices.len() {
+                anyhow::bail!("データ点数の異常:{:?}", data)
+            }
+            device_values = status_devices.decode(&res[DATA_LENGTH..])?;
+            data = res[..DATA_LENGTH].join(" ");
+        }
+
         // TODO:Stringが短い場合(受信データが不正な場合)のエラーハンドリング
         if data.len() != RESPONSE_LENGTH {
             anyhow::bail!(
@@ -291,7 +558,12 @@ impl DemoCpb16ReceiveData {
             _ => DemoCpb16Status::Stopping,
         };
         // debug!("{}", &data[18..22]);
-        Ok(Self { dt, data, status })
+        Ok(Self {
+            dt,
+            data,
+            status,
+            device_values,
+        })
     }
 
     pub fn get_status(&self) -> DemoCpb16Status {
@@ -338,11 +610,13 @@ struct DemoCpb16ReceiveState {
     production_count: u32,
     defect_count: u32,
     start_time: Option<DateTime<Local>>,
+    // 停止要因コード。デバイス未設定やコード0の場合はNone
+    stop_code: Option<i64>,
 
     last_working_data: Option<LastWakingData>,
 }
 impl DemoCpb16ReceiveState {
-    fn new(data: DemoCpb16ReceiveData) -> anyhow::Result<Self> {
+    fn new(data: DemoCpb16ReceiveData, stop_code: Option<i64>) -> anyhow::Result<Self> {
         let res: Vec<&str> = data.data.split(' ').collect();
         if res.len() != DATA_LENGTH {
             anyhow::bail!("データ点数の異常")
@@ -385,6 +659,7 @@ impl DemoCpb16ReceiveState {
             production_count,
             defect_count,
             start_time,
+            stop_code,
             last_working_data,
         })
     }
@@ -394,10 +669,10 @@ impl DemoCpb16ReceiveState {
         let Some(data) = self.last_working_data else {
             anyhow::bail!("make_worked_result:データがないのに呼ばれている")
         };
-        // NOTE: 初回起動時にPLCが稼働状態だった場合のハンドリング
-        let time = match self.receive_time.timestamp_nanos_opt() {
+        // 同じ稼働の結果を送り直しても上書きされるようにPLCの終了時刻をタイムスタンプにする
+        let time = match data.last_end_time.timestamp_nanos_opt() {
             Some(t) => t,
-            None => anyhow::bail!("in match self.receive_time.timestamp_nanos_opt()"),
+            None => anyhow::bail!("in match data.last_end_time.timestamp_nanos_opt()"),
         };
 
         let start_time = match data.last_start_time.timestamp_nanos_opt() {
@@ -423,18 +698,39 @@ impl DemoCpb16ReceiveState {
         //     .timestamp(time)
         //     .build()?;
 
-        let worked_result = DataPoint::builder("demo_cpb16")
-            .tag("info_type", "result")
-            .field("start_time", start_time)
-            .field("end_time", end_time)
-            .field("worked_second", delta)
-            .field("result_production_count", data.last_production_count as i64)
-            .field("result_defect_count", data.last_defect_count as i64)
-            .timestamp(time)
-            .build()?;
+        let worked_result =
+            shift_calendar::add_shift_tag(DataPoint::builder("demo_cpb16"), data.last_end_time)
+                .tag("info_type", "result")
+                .tag("run_id", self.working_id.to_string())
+                .field("start_time", start_time)
+                .field("end_time", end_time)
+                .field("worked_second", delta)
+                .field("result_production_count", data.last_production_count as i64)
+                .field("result_defect_count", data.last_defect_count as i64)
+                .timestamp(time)
+                .build()?;
 
         Ok(worked_result)
     }
+    // 稼働中のPLCに接続した記録。基準にした生産数と稼働の開始時刻を持つ
+    fn make_run_resumed(&self, continued: bool) -> anyhow::Result<DataPoint> {
+        let time = match self.receive_time.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("in match self.receive_time.timestamp_nanos_opt()"),
+        };
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder("demo_cpb16"), self.receive_time)
+                .tag("info_type", "run_event")
+                .tag("run_id", self.working_id.to_string())
+                .field("event", "resumed")
+                .field("continued", continued)
+                .field("baseline_production_count", self.production_count as i64)
+                .field("baseline_defect_count", self.defect_count as i64);
+        if let Some(start_time) = self.start_time.and_then(|t| t.timestamp_nanos_opt()) {
+            builder = builder.field("start_time", start_time);
+        }
+        Ok(builder.timestamp(time).build()?)
+    }
     // 現状は不要なので実装しない
     // fn make_stopped_result(&self) -> anyhow::Result<DataPoint> {
     //     let time = match self.receive_time.timestamp_nanos_opt() {
@@ -467,6 +763,22 @@ impl DemoCpb16ReceiveState {
     // }
 }
 
+// 稼働の開始・終了の記録
+// タイムスタンプはPLCの開始・終了時刻なので再送信しても上書きになる
+fn make_run_event(run_id: u32, event: &str, dt: DateTime<Local>) -> anyhow::Result<DataPoint> {
+    let time = match dt.timestamp_nanos_opt() {
+        Some(t) => t,
+        None => anyhow::bail!("in match dt.timestamp_nanos_opt()"),
+    };
+    let point = shift_calendar::add_shift_tag(DataPoint::builder("demo_cpb16"), dt)
+        .tag("info_type", "run_event")
+        .tag("run_id", run_id.to_string())
+        .field("event", event)
+        .timestamp(time)
+        .build()?;
+    Ok(point)
+}
+
 fn parse_datetime(
     year: &str,
     month: &str,
@@ -505,29 +817,54 @@ pub enum DemoCpb16Status {
     Running,
     Stopping,
 }
+// 受信間隔がこれを超える場合は切断とみなし、間の時間はどの状態にも含めない
+const MAX_CHUNK_SAMPLE_GAP_MSEC: i64 = 5000;
 
+// 稼働状況のチャンク
+// 時計に合わせた区間(10秒なら:00,:10,…)毎に集計する
+// 前回の受信から今回の受信までは前回の状態が続いていたとして、受信時刻の差から時間を求める
+// 切断や起動直後で区間の全てを集計できなかった場合はis_partialとする
 struct DemoCpb16OperationChunkData {
-    operating_states_chunk_size: u32,
-    operating_states_chunk_count: u32,
-    chunk_last_production_count: u32,
-    chunk_last_defect_count: u32,
+    window_msec: i64,
+    // 集計中の区間の開始(ミリ秒タイムスタンプ)
+    window_start: Option<i64>,
+    // 前回の受信時刻と稼働中かどうか
+    last_sample: Option<(DateTime<Local>, bool)>,
+    // 生産数・不良数の桁あふれや異常値を判定して増分を求める
+    production_counter: CounterTracker,
+    defect_counter: CounterTracker,
+    // 停止後の最初の稼働データや稼働IDの変化はPLCが0から数え直している
+    last_working_id: Option<u32>,
+    restarted: bool,
     chunk_production: u32,
     chunk_defect: u32,
-    chunk_working_second: i64,
-    chunk_time_second: i64,
+    chunk_working_msec: i64,
+    chunk_time_msec: i64,
+    // 停止時間のうちシフトカレンダーの計画停止(休憩・休日・計画保全・シフト外)の時間
+    // カレンダーがない場合は全て計画外とする
+    chunk_planned_stop_msec: i64,
+    chunk_unplanned_stop_msec: i64,
+    // 生産数の増分から生産速度とサイクルタイムを求める
+    rate: RateCalculator,
 }
 
 impl DemoCpb16OperationChunkData {
-    fn new() -> Self {
+    fn new(window_second: i64, rate_config: RateConfig, counter_config: CounterConfig) -> Self {
         Self {
-            operating_states_chunk_size: 10,
-            operating_states_chunk_count: 0,
-            chunk_last_production_count: 0,
-            chunk_last_defect_count: 0,
+            window_msec: window_second * 1000,
+            window_start: None,
+            last_sample: None,
+            production_counter: CounterTracker::new("production", counter_config.clone()),
+            defect_counter: CounterTracker::new("defect", counter_config),
+            last_working_id: None,
+            restarted: true,
             chunk_production: 0,
             chunk_defect: 0,
-            chunk_working_second: 0,
-            chunk_time_second: 0,
+            chunk_working_msec: 0,
+            chunk_time_msec: 0,
+            chunk_planned_stop_msec: 0,
+            chunk_unplanned_stop_msec: 0,
+            rate: RateCalculator::new(rate_config),
         }
     }
 
@@ -535,109 +872,580 @@ impl DemoCpb16OperationChunkData {
         &mut self,
         data: &DemoCpb16ReceiveState,
     ) -> anyhow::Result<Option<DataPoint>> {
-        self.operating_states_chunk_count += 1;
-        if data.production_count >= self.chunk_last_production_count {
-            let num = data.production_count - self.chunk_last_production_count;
-            self.chunk_production += num;
-        } else {
-            // 停止中も現在稼働の生産数がデータメモリに残っている
-            // 仮想したときにdata.production_count - self.chunk_last_production_countが負になる
-            self.chunk_production += data.production_count;
-        }
-        if data.defect_count >= self.chunk_last_defect_count {
-            let num = data.defect_count - self.chunk_last_defect_count;
-            self.chunk_defect += num;
-        } else {
-            // 停止中も現在稼働の不良数がデータメモリに残っている
-            // 仮想したときにdata.production_count - self.chunk_last_production_countが負になる
-            self.chunk_defect += data.defect_count;
-        }
+        let data_point = self.push_sample(data.receive_time)?;
 
-        self.chunk_last_production_count = data.production_count;
-        self.chunk_last_defect_count = data.defect_count;
-        self.chunk_working_second += 1;
-        self.chunk_time_second += 1;
+        let reset = self.restarted || self.last_working_id != Some(data.working_id);
+        self.restarted = false;
+        self.last_working_id = Some(data.working_id);
+        let production = self
+            .production_counter
+            .push(data.production_count as i64, reset)
+            .delta as u32;
+        let defect = self
+            .defect_counter
+            .push(data.defect_count as i64, reset)
+            .delta as u32;
+        self.chunk_production += production;
+        self.chunk_defect += defect;
+        self.rate.push(data.receive_time, production);
 
-        if self.operating_states_chunk_count == 10 {
-            let data = self.make_working_data(data)?;
-            Ok(Some(data))
-        } else {
-            Ok(None)
-        }
+        self.last_sample = Some((data.receive_time, true));
+        Ok(data_point)
     }
 
     fn push_stopping_data(
         &mut self,
         data: &DemoCpb16ReceiveState,
     ) -> anyhow::Result<Option<DataPoint>> {
-        self.operating_states_chunk_count += 1;
-        self.chunk_time_second += 1;
+        let data_point = self.push_sample(data.receive_time)?;
+        self.rate.pause();
+
+        // 停止中の生産数は0として受信するのでカウンタには渡さない
+        self.restarted = true;
+
+        self.last_sample = Some((data.receive_time, false));
+        Ok(data_point)
+    }
+
+    // 時計に合わせた区間の開始。区間は0時を基準にするのでローカル時刻で揃える
+    fn align_window(&self, dt: DateTime<Local>) -> i64 {
+        let offset_msec = dt.offset().local_minus_utc() as i64 * 1000;
+        (dt.timestamp_millis() + offset_msec).div_euclid(self.window_msec) * self.window_msec
+            - offset_msec
+    }
+
+    // 前回の受信から今回の受信までの時間を区間に振り分ける
+    // 今回の受信が次の区間に入った場合は集計中の区間を出力する
+    fn push_sample(&mut self, dt: DateTime<Local>) -> anyhow::Result<Option<DataPoint>> {
+        let now = dt.timestamp_millis();
+        let Some(window_start) = self.window_start else {
+            self.window_start = Some(self.align_window(dt));
+            return Ok(None);
+        };
+        let window_end = window_start + self.window_msec;
+        // 受信間隔が長すぎる場合と時刻が戻った場合は振り分けない
+        let last = self
+            .last_sample
+            .map(|(t, is_running)| (t, t.timestamp_millis(), is_running))
+            .filter(|(_, t, _)| *t <= now && now - *t <= MAX_CHUNK_SAMPLE_GAP_MSEC);
+        if let Some((last_dt, last_msec, is_running)) = last {
+            self.add_time(
+                last_dt,
+                last_msec.max(window_start),
+                now.min(window_end),
+                is_running,
+            );
+        }
+        if (window_start..window_end).contains(&now) {
+            return Ok(None);
+        }
 
-        self.chunk_last_defect_count = 0;
-        self.chunk_last_defect_count = 0;
+        let data_point = self.make_working_data()?;
+        let window_start = self.align_window(dt);
+        self.window_start = Some(window_start);
+        if let Some((last_dt, last_msec, is_running)) = last {
+            self.add_time(last_dt, last_msec.max(window_start), now, is_running);
+        }
+        Ok(Some(data_point))
+    }
 
-        if self.operating_states_chunk_count == 10 {
-            let data = self.make_working_data(data)?;
-            Ok(Some(data))
-        } else {
-            Ok(None)
+    // 停止時間は前回の受信時刻で計画停止かどうかを判定する
+    fn add_time(&mut self, last_dt: DateTime<Local>, from: i64, to: i64, is_running: bool) {
+        let msec = to - from;
+        if msec <= 0 {
+            return;
+        }
+        self.chunk_time_msec += msec;
+        if is_running {
+            self.chunk_working_msec += msec;
+            return;
+        }
+        let calendar = shift_calendar::get();
+        match calendar.and_then(|t| t.planned_downtime_at(last_dt)) {
+            Some(_) => self.chunk_planned_stop_msec += msec,
+            None => self.chunk_unplanned_stop_msec += msec,
         }
     }
 
-    fn reset_chunk_from_data(&mut self, data: &DemoCpb16ReceiveState) {
-        self.operating_states_chunk_count = 0;
-        self.chunk_working_second = 0;
-        self.chunk_time_second = 0;
+    fn reset_chunk(&mut self) {
+        self.chunk_working_msec = 0;
+        self.chunk_time_msec = 0;
+        self.chunk_planned_stop_msec = 0;
+        self.chunk_unplanned_stop_msec = 0;
         self.chunk_defect = 0;
         self.chunk_production = 0;
+    }
+
+    // 稼働中のPLCに接続した場合は現在の値を基準にする
+    fn attach(&mut self, data: &DemoCpb16ReceiveState) {
+        self.production_counter.clear();
+        self.production_counter
+            .push(data.production_count as i64, false);
+        self.defect_counter.clear();
+        self.defect_counter.push(data.defect_count as i64, false);
+        self.last_working_id = Some(data.working_id);
+        self.restarted = false;
+        self.rate.pause();
+    }
+
+    fn checkpoint(&self) -> ChunkCheckpoint {
+        ChunkCheckpoint {
+            window_start: self.window_start,
+            last_sample_time: self.last_sample.map(|(t, _)| t.timestamp_millis()),
+            last_sample_running: self.last_sample.is_some_and(|(_, t)| t),
+            production: self.chunk_production,
+            defect: self.chunk_defect,
+            working_msec: self.chunk_working_msec,
+            time_msec: self.chunk_time_msec,
+            planned_stop_msec: self.chunk_planned_stop_msec,
+            unplanned_stop_msec: self.chunk_unplanned_stop_msec,
+            last_working_id: self.last_working_id,
+            restarted: self.restarted,
+            production_counter: self.production_counter.get_state(),
+            defect_counter: self.defect_counter.get_state(),
+        }
+    }
 
-        self.chunk_last_production_count = data.production_count;
-        self.chunk_last_defect_count = data.defect_count;
+    // 再起動までの間は切断として扱われ、次の受信で区間が変わっていれば一部のみの区間として出力する
+    fn restore(&mut self, checkpoint: ChunkCheckpoint) {
+        self.window_start = checkpoint.window_start;
+        self.last_sample = checkpoint
+            .last_sample_time
+            .and_then(|t| Local.timestamp_millis_opt(t).single())
+            .map(|t| (t, checkpoint.last_sample_running));
+        self.chunk_production = checkpoint.production;
+        self.chunk_defect = checkpoint.defect;
+        self.chunk_working_msec = checkpoint.working_msec;
+        self.chunk_time_msec = checkpoint.time_msec;
+        self.chunk_planned_stop_msec = checkpoint.planned_stop_msec;
+        self.chunk_unplanned_stop_msec = checkpoint.unplanned_stop_msec;
+        self.last_working_id = checkpoint.last_working_id;
+        self.restarted = checkpoint.restarted;
+        self.production_counter
+            .restore(checkpoint.production_counter);
+        self.defect_counter.restore(checkpoint.defect_counter);
     }
-    // チャンクが10になった場合、実行される。
-    fn make_working_data(&mut self, data: &DemoCpb16ReceiveState) -> anyhow::Result<DataPoint> {
-        let is_working = match data.status {
-            DemoCpb16Status::Running => true,
-            DemoCpb16Status::Stopping => false,
+
+    fn add_counter_fields(&mut self, builder: DataPointBuilder) -> DataPointBuilder {
+        let builder = self
+            .production_counter
+            .take_flags()
+            .add_fields(builder, "production_counter");
+        self.defect_counter
+            .take_flags()
+            .add_fields(builder, "defect_counter")
+    }
+
+    // 集計中の区間を出力する。タイムスタンプは区間の開始
+    // 区間の出力時と終了時に実行される
+    fn make_working_data(&mut self) -> anyhow::Result<DataPoint> {
+        let Some(window_start) = self.window_start else {
+            anyhow::bail!("make_working_data:集計中の区間がない")
         };
-        let time = match data.receive_time.timestamp_nanos_opt() {
+        let Some(dt) = Local.timestamp_millis_opt(window_start).single() else {
+            anyhow::bail!("make_working_dataでエラー")
+        };
+        let time = match dt.timestamp_nanos_opt() {
             Some(t) => t,
-            None => anyhow::bail!("parse_operation_dataでエラー"),
+            None => anyhow::bail!("make_working_dataでエラー"),
         };
+        // 区間内の最後の状態
+        let is_working = self.last_sample.is_some_and(|(_, t)| t);
 
-        let working_data = DataPoint::builder("demo_cpb16")
+        let mut builder = shift_calendar::add_shift_tag(DataPoint::builder("demo_cpb16"), dt);
+        if let Some(run_id) = self.last_working_id {
+            builder = builder.tag("run_id", run_id.to_string());
+        }
+        let builder = self.add_counter_fields(builder);
+        let working_data = self
+            .rate
+            .take_metrics()
+            .add_fields(builder)
             .tag("info_type", "chunk_working_data")
             .field("is_working_last_data", is_working)
-            .field("chunk_working_second", self.chunk_working_second)
-            .field("chunk_time_second", self.chunk_time_second)
+            .field("is_partial", self.chunk_time_msec < self.window_msec)
+            .field("window_second", self.window_msec / 1000)
+            .field(
+                "chunk_working_second",
+                msec_to_second(self.chunk_working_msec),
+            )
+            .field("chunk_time_second", msec_to_second(self.chunk_time_msec))
+            .field("chunk_working_msec", self.chunk_working_msec)
+            .field("chunk_time_msec", self.chunk_time_msec)
+            .field(
+                "chunk_planned_stop_second",
+                msec_to_second(self.chunk_planned_stop_msec),
+            )
+            .field(
+                "chunk_unplanned_stop_second",
+                msec_to_second(self.chunk_unplanned_stop_msec),
+            )
             .field("chunk_production", self.chunk_production as i64)
             .field("chunk_defect", self.chunk_defect as i64)
             .timestamp(time)
             .build()?;
         // debug!("make_working_data chunk_defect:{}", self.chunk_defect);
-        // debug!("make_working_data data.defect_count:{}", data.defect_count);
-        self.reset_chunk_from_data(data);
+        self.reset_chunk();
 
         Ok(working_data)
     }
 
-    fn make_working_data_when_drop(self) -> anyhow::Result<DataPoint> {
-        let is_working = !matches!(self.chunk_last_production_count, 0);
-        let time = match Local::now().timestamp_nanos_opt() {
-            Some(t) => t,
-            None => anyhow::bail!("parse_operation_dataでエラー"),
+    // 終了時は集計中の区間を一部のみの区間として出力する
+    // 再起動後に同じ区間を集計した場合は同じタイムスタンプで上書きされる
+    fn make_working_data_when_drop(&mut self) -> anyhow::Result<Option<DataPoint>> {
+        if self.window_start.is_none() || self.chunk_time_msec == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.make_working_data()?))
+    }
+}
+
+// 四捨五入した秒数
+fn msec_to_second(msec: i64) -> i64 {
+    (msec + 500).div_euclid(1000)
+}
+
+#[cfg(test)]
+impl DemoCpb16DataHandler {
+    // 10秒のチャンクで、状態ファイルがある場合は復元する
+    fn new(sender: mpsc::Sender<Vec<DataPoint>>, state_path: &str) -> Self {
+        let counter_config = CounterConfig::new(16, 1000);
+        let mut handler = Self {
+            sender: sender.clone(),
+            last_machine_status: DemoCpb16Status::Stopping,
+            last_device_values: Vec::new(),
+            operating_states_chunk: DemoCpb16OperationChunkData::new(
+                10,
+                RateConfig::new(60.0, None),
+                counter_config,
+            ),
+            send_data_length: 6,
+            operating_send_data: Vec::new(),
+            oee: None,
+            downtime: DowntimeRegistry::new(Default::default(), sender),
+            stop_episode: None,
+            last_started_run_id: None,
+            last_result_run_id: None,
+            checkpoint_store: CheckpointStore::new(state_path),
+            attached: false,
         };
+        handler.load_checkpoint();
+        handler
+    }
+}
 
-        let working_data = DataPoint::builder("demo_cpb16")
-            .tag("info_type", "chunk_working_data")
-            .field("is_working_last_data", is_working)
-            .field("chunk_working_second", self.chunk_working_second)
-            .field("chunk_time_second", self.chunk_time_second)
-            .field("chunk_production", self.chunk_production as i64)
-            .field("chunk_defect", self.chunk_defect as i64)
-            .timestamp(time)
-            .build()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, Duration, Timelike};
+    use influxdb2::models::WriteDataPoint;
 
-        Ok(working_data)
+    // 10秒の区間の境界に揃った時刻からの秒数
+    fn at(second: i64) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 10, 19, 8, 0, 0).unwrap() + Duration::seconds(second)
+    }
+
+    // 前回稼働の(生産数, 不良数, 開始, 終了)
+    type LastRun = (u32, u32, DateTime<Local>, DateTime<Local>);
+
+    // MONITOR_DEVICESの順に並べた受信データ
+    fn receive_data(
+        dt: DateTime<Local>,
+        running: bool,
+        working_id: u32,
+        production: u32,
+        defect: u32,
+        start: DateTime<Local>,
+        last: Option<LastRun>,
+    ) -> DemoCpb16ReceiveData {
+        let datetime = |t: DateTime<Local>| {
+            [
+                t.year() as u32 - 2000,
+                t.month(),
+                t.day(),
+                t.hour(),
+                t.minute(),
+                t.second(),
+            ]
+        };
+        let mut values = vec![running as u32, working_id, production, defect];
+        let (last_production, last_defect, last_start, last_end) =
+            last.unwrap_or((0, 0, start, start));
+        values.extend([last_production, last_defect]);
+        values.extend(datetime(start));
+        values.extend(datetime(last_start));
+        values.extend(datetime(last_end));
+        values.push(last.is_some() as u32);
+        let data: Vec<String> = values.iter().map(|t| format!("{:05}", t)).collect();
+        DemoCpb16ReceiveData {
+            dt,
+            data: data.join(" "),
+            status: match running {
+                true => DemoCpb16Status::Running,
+                false => DemoCpb16Status::Stopping,
+            },
+            device_values: Vec::new(),
+        }
+    }
+
+    fn line(point: &DataPoint) -> String {
+        let mut line = Vec::new();
+        point.write_data_point_to(&mut line).unwrap();
+        String::from_utf8(line).unwrap()
+    }
+
+    // 送信済みのデータポイントのうちinfo_typeが一致するもの
+    fn received(receiver: &mut mpsc::Receiver<Vec<DataPoint>>, info_type: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(points) = receiver.try_recv() {
+            lines.extend(points.iter().map(line));
+        }
+        let tag = format!("info_type={}", info_type);
+        lines.into_iter().filter(|t| t.contains(&tag)).collect()
+    }
+
+    fn state_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("demo_cpb16_{}_{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    // DropでブロッキングするのでマルチスレッドのランタイムでDropする
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_run_result_once() {
+        let path = state_path("result_once");
+        let (sender, mut receiver) = mpsc::channel(100);
+        let mut handler = DemoCpb16DataHandler::new(sender.clone(), &path);
+        let last = Some((120, 4, at(-600), at(-30)));
+
+        handler
+            .receive_response(receive_data(at(0), false, 7, 0, 0, at(0), last))
+            .await
+            .unwrap();
+        handler
+            .receive_response(receive_data(at(1), false, 7, 0, 0, at(0), last))
+            .await
+            .unwrap();
+        let results = received(&mut receiver, "result");
+        assert_eq!(results.len(), 1);
+        assert!(results[0].contains("run_id=7"));
+        assert!(results[0].contains("result_production_count=120i"));
+        // PLCの終了時刻をタイムスタンプにする
+        let end_time = at(-30).timestamp_nanos_opt().unwrap().to_string();
+        assert!(results[0].trim_end().ends_with(&end_time));
+
+        // 再接続しても送り直さない
+        handler.attached = false;
+        handler
+            .receive_response(receive_data(at(2), false, 7, 0, 0, at(0), last))
+            .await
+            .unwrap();
+        assert!(received(&mut receiver, "result").is_empty());
+        drop(handler);
+
+        // 再起動後も状態ファイルから送信済みの稼働IDを引き継ぐ
+        let mut handler = DemoCpb16DataHandler::new(sender, &path);
+        handler
+            .receive_response(receive_data(at(3), false, 7, 0, 0, at(0), last))
+            .await
+            .unwrap();
+        assert!(received(&mut receiver, "result").is_empty());
+
+        // 稼働の開始は稼働ID毎に1回
+        for second in 4..7 {
+            handler
+                .receive_response(receive_data(at(second), true, 8, 0, 0, at(4), last))
+                .await
+                .unwrap();
+        }
+        let events = received(&mut receiver, "run_event");
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("run_id=8"));
+        assert!(events[0].contains("event=\"start\""));
+
+        // 次の稼働の結果は送信する
+        let last = Some((10, 0, at(4), at(7)));
+        for second in 7..9 {
+            handler
+                .receive_response(receive_data(at(second), false, 8, 0, 0, at(0), last))
+                .await
+                .unwrap();
+        }
+        let results = received(&mut receiver, "result");
+        assert_eq!(results.len(), 1);
+        assert!(results[0].contains("run_id=8"));
+        drop(handler);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn attach_to_running_machine() {
+        let path = state_path("attach");
+        let (sender, mut receiver) = mpsc::channel(100);
+        let mut handler = DemoCpb16DataHandler::new(sender.clone(), &path);
+
+        // 10分前に始まった稼働の途中で接続した。現在の生産数を基準にする
+        for (second, production) in [(5, 100), (6, 102), (7, 104), (8, 106), (9, 108), (10, 110)] {
+            handler
+                .receive_response(receive_data(
+                    at(second),
+                    true,
+                    5,
+                    production,
+                    3,
+                    at(-600),
+                    None,
+                ))
+                .await
+                .unwrap();
+        }
+        let events = received(&mut receiver, "run_event");
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("event=\"resumed\""));
+        assert!(events[0].contains("continued=f"));
+        assert!(events[0].contains("baseline_production_count=100i"));
+        let chunk = line(&handler.operating_send_data[0]);
+        assert!(chunk.contains("chunk_production=8i"));
+        assert!(chunk.contains("chunk_time_msec=5000i"));
+        drop(handler);
+
+        // 再起動中も同じ稼働が続いていれば切断中の増分もその稼働として数える
+        let mut handler = DemoCpb16DataHandler::new(sender.clone(), &path);
+        for (second, production) in [(13, 130), (17, 136), (20, 140)] {
+            handler
+                .receive_response(receive_data(
+                    at(second),
+                    true,
+                    5,
+                    production,
+                    3,
+                    at(-600),
+                    None,
+                ))
+                .await
+                .unwrap();
+        }
+        let events = received(&mut receiver, "run_event");
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("continued=t"));
+        let chunk = line(&handler.operating_send_data[0]);
+        assert!(chunk.contains("chunk_production=28i"));
+        assert!(chunk.contains("is_partial=f"));
+        drop(handler);
+
+        // 別の稼働に変わっていた場合は基準にし直し、稼働開始は送らない
+        let mut handler = DemoCpb16DataHandler::new(sender, &path);
+        for (second, production) in [(21, 50), (22, 53), (30, 60)] {
+            handler
+                .receive_response(receive_data(
+                    at(second),
+                    true,
+                    6,
+                    production,
+                    0,
+                    at(15),
+                    None,
+                ))
+                .await
+                .unwrap();
+        }
+        let events = received(&mut receiver, "run_event");
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("run_id=6"));
+        assert!(events[0].contains("continued=f"));
+        // 再起動前の:20の増分4と基準にし直した後の増分3
+        let chunk = line(&handler.operating_send_data[0]);
+        assert!(chunk.contains("chunk_production=7i"));
+        drop(handler);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn chunk_data() -> DemoCpb16OperationChunkData {
+        DemoCpb16OperationChunkData::new(
+            10,
+            RateConfig::new(60.0, None),
+            CounterConfig::new(16, 1000),
+        )
+    }
+
+    fn receive_state(second: i64, running: bool, production: u32) -> DemoCpb16ReceiveState {
+        let data = receive_data(at(second), running, 1, production, 0, at(0), None);
+        DemoCpb16ReceiveState::new(data, None).unwrap()
+    }
+
+    fn push(
+        chunk: &mut DemoCpb16OperationChunkData,
+        state: &DemoCpb16ReceiveState,
+    ) -> Option<String> {
+        let point = match state.status {
+            DemoCpb16Status::Running => chunk.push_running_data(state),
+            DemoCpb16Status::Stopping => chunk.push_stopping_data(state),
+        };
+        point.unwrap().as_ref().map(line)
+    }
+
+    #[test]
+    fn align_to_wall_clock() {
+        let mut chunk = chunk_data();
+        assert_eq!(chunk.align_window(at(3)), at(0).timestamp_millis());
+        assert_eq!(chunk.align_window(at(10)), at(10).timestamp_millis());
+        chunk.window_msec = 60_000;
+        assert_eq!(chunk.align_window(at(125)), at(120).timestamp_millis());
+    }
+
+    #[test]
+    fn partial_window_at_start_and_stop() {
+        let mut chunk = chunk_data();
+        // :03に稼働中で開始し、1秒毎に2袋生産
+        let mut points = Vec::new();
+        for second in 3..23 {
+            let state = receive_state(second, true, (second as u32 - 3) * 2);
+            points.extend(push(&mut chunk, &state));
+        }
+        assert_eq!(points.len(), 2);
+        // 開始時は区間の途中からなので一部のみ
+        let time = |t: i64| format!(" {}\n", at(t).timestamp_nanos_opt().unwrap());
+        assert!(points[0].ends_with(&time(0)));
+        assert!(points[0].contains("chunk_time_msec=7000i"));
+        assert!(points[0].contains("chunk_production=12i"));
+        assert!(points[0].contains("is_partial=t"));
+        assert!(points[1].ends_with(&time(10)));
+        assert!(points[1].contains("chunk_time_msec=10000i"));
+        assert!(points[1].contains("chunk_production=20i"));
+        assert!(points[1].contains("is_partial=f"));
+
+        // :23に停止し、:25に終了した
+        for second in 23..26 {
+            assert!(push(&mut chunk, &receive_state(second, false, 0)).is_none());
+        }
+        let point = line(&chunk.make_working_data_when_drop().unwrap().unwrap());
+        assert!(point.ends_with(&time(20)));
+        assert!(point.contains("chunk_working_msec=3000i"));
+        assert!(point.contains("chunk_time_msec=5000i"));
+        assert!(point.contains("chunk_unplanned_stop_second=2i"));
+        assert!(point.contains("is_working_last_data=f"));
+        assert!(point.contains("is_partial=t"));
+        assert!(chunk.make_working_data_when_drop().unwrap().is_none());
+    }
+
+    #[test]
+    fn exclude_disconnected_time() {
+        let mut chunk = chunk_data();
+        for second in 0..4 {
+            assert!(push(&mut chunk, &receive_state(second, true, 0)).is_none());
+        }
+        // :03から:09までは切断中
+        assert!(push(&mut chunk, &receive_state(9, true, 0)).is_none());
+        let point = push(&mut chunk, &receive_state(10, true, 0)).unwrap();
+        assert!(point.contains("chunk_time_msec=4000i"));
+        assert!(point.contains("is_partial=t"));
+
+        // 区間を跨いで切断した場合は途中の区間を出力しない
+        assert!(push(&mut chunk, &receive_state(11, true, 0)).is_none());
+        let point = push(&mut chunk, &receive_state(35, true, 0)).unwrap();
+        assert!(point.ends_with(&format!(" {}\n", at(10).timestamp_nanos_opt().unwrap())));
+        assert!(point.contains("chunk_time_msec=1000i"));
+        assert_eq!(chunk.window_start, Some(at(30).timestamp_millis()));
     }
 }
@@ -3,25 +3,38 @@ use tokio::sync::mpsc;
 
 use super::config::DemoCpb16Config;
 use super::interface::DemoCpb16Interface;
+use crate::collector::host_link::SetpointWriter;
 
 pub struct DemoCpb16Debugger {
     interface: DemoCpb16Interface,
+    setpoint_writer: SetpointWriter,
 }
 
 impl DemoCpb16Debugger {
     pub async fn create_from_env() -> anyhow::Result<Self> {
         let config = DemoCpb16Config::create_from_env()?;
+        let setpoint_writer = config.get_setpoint_writer()?;
         let interface = DemoCpb16Interface::create_from_config(config)?;
-        Ok(Self { interface })
+        Ok(Self {
+            interface,
+            setpoint_writer,
+        })
     }
 
     pub async fn start_debug_monitor(&mut self) -> anyhow::Result<()> {
         let (point_sender, mut point_receiver) = mpsc::channel(32);
         let (disconnect_sender, _) = mpsc::channel(32);
+        let (diagnostics_sender, mut diagnostics_receiver) = mpsc::channel(32);
         self.interface
-            .start_monitor(point_sender, disconnect_sender)
+            .start_monitor(point_sender, disconnect_sender, diagnostics_sender)
             .await?;
 
+        tokio::spawn(async move {
+            while let Some(points) = diagnostics_receiver.recv().await {
+                debug!("diagnostics:{:?}", points);
+            }
+        });
+
         tokio::spawn(async move {
             while let Some(points) = point_receiver.recv().await {
                 // debug!("receive:{}", points.get_data());
@@ -44,4 +57,10 @@ impl DemoCpb16Debugger {
         self.interface.set_time_dummy().await?;
         Ok(())
     }
+
+    pub async fn write_setpoint(&mut self, name: &str, value: i64) -> anyhow::Result<()> {
+        let result = self.setpoint_writer.write(name, value, "debugger").await?;
+        debug!("write:{:?}", result);
+        Ok(())
+    }
 }
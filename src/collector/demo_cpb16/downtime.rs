@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local, TimeZone};
+use influxdb2::models::DataPoint;
+use log::{info, warn};
+use tokio::sync::mpsc;
+
+use super::checkpoint::EpisodeCheckpoint;
+use crate::collector::host_link::{Device, DeviceValue};
+use crate::shift_calendar;
+
+// 停止要因コードのフィールド名。DemoCpb16StatusDevicesの後ろに追加して読み出す
+pub const STOP_CAUSE_FIELD: &str = "stop_cause";
+// 未分類の停止の理由
+pub const UNCLASSIFIED: &str = "unclassified";
+// オペレーターの割り当て待ちとして保持する停止の上限。超えた場合は古いものから破棄
+const MAX_UNCLASSIFIED: usize = 200;
+
+// 停止理由の設定
+// DemoCpb16StopCauseDevice : 停止要因コードのデバイス ex) DM300.U。未設定の場合は全て未分類
+// DemoCpb16StopReasons     : コードと停止理由の対応 ex) 1=film_out,2=jam,3=heater_alarm
+// コード0は要因なし。表にないコードは未分類としてコードのみ記録する
+#[derive(Clone, Debug, Default)]
+pub struct DowntimeConfig {
+    device: Option<Device>,
+    reasons: HashMap<i64, String>,
+}
+
+impl DowntimeConfig {
+    pub fn create_from_env() -> anyhow::Result<Self> {
+        let device = match std::env::var("DemoCpb16StopCauseDevice") {
+            Ok(t) => Some(Device::parse(&t)?),
+            Err(_) => None,
+        };
+        let mut reasons = HashMap::new();
+        if let Ok(value) = std::env::var("DemoCpb16StopReasons") {
+            for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+                let Some((code, reason)) = item.split_once('=') else {
+                    anyhow::bail!("DemoCpb16StopReasonsが不正:{}", item)
+                };
+                reasons.insert(code.trim().parse()?, reason.trim().to_string());
+            }
+        }
+        Ok(Self { device, reasons })
+    }
+
+    pub fn get_device(&self) -> Option<Device> {
+        self.device
+    }
+
+    // 停止理由の一覧(コード順)
+    pub fn get_reasons(&self) -> Vec<String> {
+        let mut codes: Vec<&i64> = self.reasons.keys().collect();
+        codes.sort();
+        codes.iter().map(|t| self.reasons[*t].to_owned()).collect()
+    }
+
+    fn reason_of(&self, code: i64) -> Option<String> {
+        self.reasons.get(&code).cloned()
+    }
+}
+
+// 受信データの停止要因コード。デバイス未設定やコード0の場合はNone
+pub fn find_stop_code(device_values: &[(String, DeviceValue)]) -> Option<i64> {
+    device_values
+        .iter()
+        .find(|(name, _)| name == STOP_CAUSE_FIELD)
+        .map(|(_, value)| value.as_i64())
+        .filter(|t| *t != 0)
+}
+
+// 1回の停止
+#[derive(Clone, Debug)]
+pub struct StopEpisode {
+    // 停止開始のミリ秒タイムスタンプ。APIで理由を割り当てる際のID
+    pub id: i64,
+    pub start: DateTime<Local>,
+    pub end: Option<DateTime<Local>>,
+    pub stop_code: Option<i64>,
+    pub reason: Option<String>,
+    // 理由の取得元 plc/operator
+    pub classified_by: Option<String>,
+}
+
+impl StopEpisode {
+    pub fn start(dt: DateTime<Local>) -> Self {
+        Self {
+            id: dt.timestamp_millis(),
+            start: dt,
+            end: None,
+            stop_code: None,
+            reason: None,
+            classified_by: None,
+        }
+    }
+
+    pub fn duration_second(&self) -> f64 {
+        match self.end {
+            Some(end) => (end - self.start).num_milliseconds() as f64 / 1000.0,
+            None => 0.0,
+        }
+    }
+
+    pub fn is_classified(&self) -> bool {
+        self.reason.is_some()
+    }
+
+    pub fn checkpoint(&self) -> EpisodeCheckpoint {
+        EpisodeCheckpoint {
+            id: self.id,
+            start: self.start.timestamp_millis(),
+            end: self.end.map(|t| t.timestamp_millis()),
+            stop_code: self.stop_code,
+            reason: self.reason.to_owned(),
+            classified_by: self.classified_by.to_owned(),
+        }
+    }
+
+    // 時刻を変換できない場合はNone
+    pub fn restore(checkpoint: EpisodeCheckpoint) -> Option<Self> {
+        let start = Local.timestamp_millis_opt(checkpoint.start).single()?;
+        let end = match checkpoint.end {
+            Some(t) => Some(Local.timestamp_millis_opt(t).single()?),
+            None => None,
+        };
+        Some(Self {
+            id: checkpoint.id,
+            start,
+            end,
+            stop_code: checkpoint.stop_code,
+            reason: checkpoint.reason,
+            classified_by: checkpoint.classified_by,
+        })
+    }
+
+    // 停止開始時刻をタイムスタンプにする
+    // 理由を後から割り当てた場合も同じタイムスタンプ・タグで書き込み、前の記録を上書きする
+    fn to_data_point(&self) -> anyhow::Result<DataPoint> {
+        let Some(end) = self.end else {
+            anyhow::bail!("StopEpisode::to_data_point:停止が終了していない")
+        };
+        let (Some(time), Some(end_time)) =
+            (self.start.timestamp_nanos_opt(), end.timestamp_nanos_opt())
+        else {
+            anyhow::bail!("StopEpisode::to_data_pointでエラー")
+        };
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder("demo_cpb16"), self.start)
+                .tag("info_type", "downtime")
+                .field("episode_id", self.id)
+                .field("start_time", time)
+                .field("end_time", end_time)
+                .field("duration_second", self.duration_second())
+                .field("reason", self.reason.as_deref().unwrap_or(UNCLASSIFIED))
+                .field(
+                    "classified_by",
+                    self.classified_by.as_deref().unwrap_or("none"),
+                );
+        if let Some(code) = self.stop_code {
+            builder = builder.field("stop_code", code);
+        }
+        Ok(builder.timestamp(time).build()?)
+    }
+}
+
+// 停止の記録と未分類の停止の保持
+// データマネージャーとAPIで共有する
+#[derive(Clone)]
+pub struct DowntimeRegistry {
+    config: DowntimeConfig,
+    sender: mpsc::Sender<Vec<DataPoint>>,
+    unclassified: Arc<Mutex<Vec<StopEpisode>>>,
+}
+
+impl DowntimeRegistry {
+    pub fn new(config: DowntimeConfig, sender: mpsc::Sender<Vec<DataPoint>>) -> Self {
+        Self {
+            config,
+            sender,
+            unclassified: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn get_reasons(&self) -> Vec<String> {
+        self.config.get_reasons()
+    }
+
+    // 停止中に読み出したコードで理由を決める。最初に読めた0以外のコードを採用する
+    pub fn classify(&self, episode: &mut StopEpisode, stop_code: Option<i64>) {
+        let Some(code) = stop_code else {
+            return;
+        };
+        if episode.stop_code.is_some() {
+            return;
+        }
+        episode.stop_code = Some(code);
+        if let Some(reason) = self.config.reason_of(code) {
+            episode.reason = Some(reason);
+            episode.classified_by = Some("plc".to_string());
+        }
+    }
+
+    // 停止の終了時に呼ぶ。未分類ならオペレーターの割り当て待ちにする
+    pub async fn finish(
+        &self,
+        mut episode: StopEpisode,
+        end: DateTime<Local>,
+    ) -> anyhow::Result<()> {
+        episode.end = Some(end);
+        let point = episode.to_data_point()?;
+        info!(
+            "停止を記録:{}:{}秒:{}",
+            episode.start,
+            episode.duration_second(),
+            episode.reason.as_deref().unwrap_or(UNCLASSIFIED)
+        );
+        if !episode.is_classified() {
+            let mut unclassified = self.unclassified.lock().unwrap();
+            unclassified.push(episode);
+            if unclassified.len() > MAX_UNCLASSIFIED {
+                let dropped = unclassified.remove(0);
+                warn!("未分類の停止を破棄:{}", dropped.start);
+            }
+        }
+        self.sender.send(vec![point]).await?;
+        Ok(())
+    }
+
+    pub fn get_unclassified(&self) -> Vec<StopEpisode> {
+        self.unclassified.lock().unwrap().clone()
+    }
+
+    pub fn checkpoint(&self) -> Vec<EpisodeCheckpoint> {
+        self.unclassified
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|t| t.checkpoint())
+            .collect()
+    }
+
+    // 再起動前の未分類の停止を割り当て待ちに戻す
+    pub fn restore(&self, checkpoints: Vec<EpisodeCheckpoint>) {
+        let episodes: Vec<StopEpisode> = checkpoints
+            .into_iter()
+            .filter_map(StopEpisode::restore)
+            .collect();
+        *self.unclassified.lock().unwrap() = episodes;
+    }
+
+    // オペレーターが未分類の停止に理由を割り当てる
+    // 停止理由の表がある場合は表の理由のみ受け付ける
+    pub async fn assign(
+        &self,
+        id: i64,
+        reason: &str,
+        requested_by: &str,
+    ) -> anyhow::Result<StopEpisode> {
+        let reason = reason.trim();
+        if reason.is_empty() || reason == UNCLASSIFIED {
+            anyhow::bail!("停止理由が不正:{}", reason)
+        }
+        let reasons = self.config.get_reasons();
+        if !reasons.is_empty() && !reasons.iter().any(|t| t == reason) {
+            anyhow::bail!("停止理由が登録されていない:{}", reason)
+        }
+        let mut episode = {
+            let mut unclassified = self.unclassified.lock().unwrap();
+            let Some(position) = unclassified.iter().position(|t| t.id == id) else {
+                anyhow::bail!("未分類の停止がない:{}", id)
+            };
+            unclassified.remove(position)
+        };
+        episode.reason = Some(reason.to_string());
+        episode.classified_by = Some("operator".to_string());
+        let point = episode.to_data_point()?;
+        info!(
+            "停止理由を割り当て:{}:{}:{}",
+            episode.start, reason, requested_by
+        );
+        self.sender.send(vec![point]).await?;
+        Ok(episode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb2::models::WriteDataPoint;
+
+    fn registry(capacity: usize) -> (DowntimeRegistry, mpsc::Receiver<Vec<DataPoint>>) {
+        let config = DowntimeConfig {
+            device: None,
+            reasons: HashMap::from([(1, "film_out".to_string()), (2, "jam".to_string())]),
+        };
+        let (sender, receiver) = mpsc::channel(capacity);
+        (DowntimeRegistry::new(config, sender), receiver)
+    }
+
+    fn at(second: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_790_000_000 + second, 0).unwrap()
+    }
+
+    // ラインプロトコルの(測定名とタグ, フィールド, タイムスタンプ)
+    fn parse(point: &DataPoint) -> (String, String, String) {
+        let mut line = Vec::new();
+        point.write_data_point_to(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        let parts: Vec<&str> = line.trim().split(' ').collect();
+        (
+            parts[0].to_string(),
+            parts[1].to_string(),
+            parts[2].to_string(),
+        )
+    }
+
+    #[test]
+    fn find_nonzero_stop_code() {
+        let values = |code| {
+            vec![
+                ("running".to_string(), DeviceValue::Bool(false)),
+                (STOP_CAUSE_FIELD.to_string(), DeviceValue::Int(code)),
+            ]
+        };
+        assert_eq!(find_stop_code(&values(2)), Some(2));
+        assert_eq!(find_stop_code(&values(0)), None);
+        assert_eq!(find_stop_code(&values(2)[..1]), None);
+    }
+
+    #[test]
+    fn classify_first_code() {
+        let (registry, _receiver) = registry(1);
+        let mut episode = StopEpisode::start(at(0));
+        registry.classify(&mut episode, None);
+        assert_eq!(episode.stop_code, None);
+        registry.classify(&mut episode, Some(2));
+        registry.classify(&mut episode, Some(1));
+        assert_eq!(episode.stop_code, Some(2));
+        assert_eq!(episode.reason.as_deref(), Some("jam"));
+        assert_eq!(episode.classified_by.as_deref(), Some("plc"));
+
+        // 表にないコードはコードのみ記録して未分類
+        let mut episode = StopEpisode::start(at(0));
+        registry.classify(&mut episode, Some(9));
+        assert_eq!(episode.stop_code, Some(9));
+        assert!(!episode.is_classified());
+    }
+
+    #[tokio::test]
+    async fn finish_keeps_only_unclassified() {
+        let (registry, mut receiver) = registry(10);
+        let mut episode = StopEpisode::start(at(0));
+        registry.classify(&mut episode, Some(1));
+        registry.finish(episode, at(30)).await.unwrap();
+        registry
+            .finish(StopEpisode::start(at(60)), at(75))
+            .await
+            .unwrap();
+
+        let unclassified = registry.get_unclassified();
+        assert_eq!(unclassified.len(), 1);
+        assert_eq!(unclassified[0].id, at(60).timestamp_millis());
+        assert_eq!(unclassified[0].duration_second(), 15.0);
+
+        let (_, fields, _) = parse(&receiver.recv().await.unwrap()[0]);
+        assert!(fields.contains("reason=\"film_out\""));
+        assert!(fields.contains("duration_second=30"));
+        let (_, fields, _) = parse(&receiver.recv().await.unwrap()[0]);
+        assert!(fields.contains("reason=\"unclassified\""));
+        assert!(fields.contains("classified_by=\"none\""));
+    }
+
+    #[tokio::test]
+    async fn assign_overwrites_point() {
+        let (registry, mut receiver) = registry(10);
+        registry
+            .finish(StopEpisode::start(at(0)), at(30))
+            .await
+            .unwrap();
+        let id = at(0).timestamp_millis();
+
+        // 表にない理由・未分類・未知のIDは受け付けない
+        assert!(registry.assign(id, "other", "op").await.is_err());
+        assert!(registry.assign(id, UNCLASSIFIED, "op").await.is_err());
+        assert!(registry.assign(id + 1, "jam", "op").await.is_err());
+        assert_eq!(registry.get_unclassified().len(), 1);
+
+        let episode = registry.assign(id, " jam ", "op").await.unwrap();
+        assert_eq!(episode.reason.as_deref(), Some("jam"));
+        assert!(registry.get_unclassified().is_empty());
+        // 割り当て済みのIDは再度割り当てできない
+        assert!(registry.assign(id, "film_out", "op").await.is_err());
+
+        // 同じタグ・タイムスタンプで書き込み、未分類の記録を上書きする
+        let (tags, fields, time) = parse(&receiver.recv().await.unwrap()[0]);
+        assert!(fields.contains("reason=\"unclassified\""));
+        let (assigned_tags, assigned_fields, assigned_time) =
+            parse(&receiver.recv().await.unwrap()[0]);
+        assert_eq!(assigned_tags, tags);
+        assert_eq!(assigned_time, time);
+        assert!(assigned_fields.contains("reason=\"jam\""));
+        assert!(assigned_fields.contains("classified_by=\"operator\""));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn evict_oldest_unclassified() {
+        let (registry, _receiver) = registry(MAX_UNCLASSIFIED + 10);
+        for i in 0..MAX_UNCLASSIFIED as i64 + 5 {
+            registry
+                .finish(StopEpisode::start(at(i * 60)), at(i * 60 + 10))
+                .await
+                .unwrap();
+        }
+        let unclassified = registry.get_unclassified();
+        assert_eq!(unclassified.len(), MAX_UNCLASSIFIED);
+        assert_eq!(unclassified[0].id, at(5 * 60).timestamp_millis());
+        // 破棄した停止には割り当てできない
+        assert!(registry
+            .assign(at(0).timestamp_millis(), "jam", "op")
+            .await
+            .is_err());
+    }
+}
@@ -1,46 +1,43 @@
 use chrono::Local;
+use influxdb2::models::DataPoint;
 use log::{debug, error, warn};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::task::JoinHandle;
-use tokio::time::{timeout, Duration, Instant};
 
 use super::config;
 use super::config::DemoCpb16Config;
 use super::data_manager::DemoCpb16ReceiveData;
 use super::data_manager::DemoCpb16Status;
+use crate::collector::host_link::{DisconnectReason, HostLinkSession};
+use crate::collector::scheduler::PollScheduler;
 
 pub struct DemoCpb16Interface {
     config: DemoCpb16Config,
+    // チェック、時刻設定、ポーリングで同じ接続を使う
+    session: HostLinkSession,
     is_checked: bool,
     thread: Option<ConnectionThread>,
 }
 impl DemoCpb16Interface {
     pub fn create_from_config(config: DemoCpb16Config) -> anyhow::Result<Self> {
         // コンフィグからインターフェイスを作成。動作チェック
+        let session = HostLinkSession::get(&config.get_target())?;
         Ok(Self {
             config,
+            session,
             is_checked: false,
             thread: None,
         })
     }
 
     pub async fn check_connection(&mut self) -> anyhow::Result<()> {
-        let mut stream = TcpStream::connect(&self.config.get_address()).await?;
         // debug!(
-        //     "To:{:?},command:{:?} ",
-        //     &self.config.get_address(),
+        //     "To:{},command:{:?} ",
+        //     &self.config.get_target(),
         //     std::str::from_utf8(config::CHECK_COMMAND).unwrap()
         // );
-        stream.write_all(config::CHECK_COMMAND).await?;
-        let mut buf = [0; 4];
-        let n = stream.read(&mut buf).await?;
-        let res = std::str::from_utf8(&buf[..n])
-            .unwrap()
-            .trim_end_matches("\r\n")
-            .to_string();
+        let res = self.session.request(config::CHECK_COMMAND).await?;
         // debug!("チェックコマンドのレスポンス:{:?}", res);
 
         if res == config::CHECK_RESPONSE {
@@ -57,7 +54,8 @@ impl DemoCpb16Interface {
     pub async fn start_monitor(
         &mut self,
         data_sender: mpsc::Sender<DemoCpb16ReceiveData>,
-        disconnect_sender: mpsc::Sender<()>,
+        disconnect_sender: mpsc::Sender<DisconnectReason>,
+        diagnostics_sender: mpsc::Sender<Vec<DataPoint>>,
     ) -> anyhow::Result<()> {
         if self.thread.is_some() {
             anyhow::bail!("already started monitor in DemoCpb16Interface::start_monitor")
@@ -66,8 +64,13 @@ impl DemoCpb16Interface {
             self.check_connection().await?;
             self.set_time_now().await?;
         }
-        let connection_thread =
-            ConnectionThread::start(data_sender, disconnect_sender, self.config.clone()).await?;
+        let connection_thread = ConnectionThread::start(
+            data_sender,
+            disconnect_sender,
+            diagnostics_sender,
+            self.session.clone(),
+            self.config.clone(),
+        );
         self.thread = Some(connection_thread);
         debug!("DemoCpb16Interface collect start");
         Ok(())
@@ -94,18 +97,11 @@ impl DemoCpb16Interface {
         if !self.is_checked {
             self.check_connection().await?;
         }
-        let mut stream = TcpStream::connect(&self.config.get_address()).await?;
-
         let command = self.config.get_time_preference_command();
         // debug!("command:{:?}", std::str::from_utf8(&command).unwrap());
-        stream.write_all(&command).await?;
-        let mut buf = [0; 4];
-        let n = stream.read(&mut buf).await?;
-        let res = std::str::from_utf8(&buf[..n])
-            .unwrap()
-            .trim_end_matches("\r\n");
+        let res = self.session.request(&command).await?;
 
-        match res {
+        match res.as_str() {
             config::OK_RESPONSE => {
                 debug!("時刻設定成功");
             }
@@ -128,18 +124,11 @@ impl DemoCpb16Interface {
         if !self.is_checked {
             self.check_connection().await?;
         }
-        let mut stream = TcpStream::connect(&self.config.get_address()).await?;
-
         let command = self.config.get_time_preference_dummy_command();
         debug!("command:{:?}", std::str::from_utf8(&command).unwrap());
-        stream.write_all(&command).await?;
-        let mut buf = [0; 4];
-        let n = stream.read(&mut buf).await?;
-        let res = std::str::from_utf8(&buf[..n])
-            .unwrap()
-            .trim_end_matches("\r\n");
+        let res = self.session.request(&command).await?;
 
-        match res {
+        match res.as_str() {
             config::OK_RESPONSE => {
                 debug!("時刻設定成功");
             }
@@ -172,73 +161,41 @@ struct ConnectionThread {
     stop_sender: mpsc::Sender<()>,
 }
 impl ConnectionThread {
-    async fn start(
+    fn start(
         data_sender: mpsc::Sender<DemoCpb16ReceiveData>,
-        disconnect_sender: mpsc::Sender<()>,
+        disconnect_sender: mpsc::Sender<DisconnectReason>,
+        diagnostics_sender: mpsc::Sender<Vec<DataPoint>>,
+        session: HostLinkSession,
         config: DemoCpb16Config,
-    ) -> anyhow::Result<Self> {
+    ) -> Self {
         let (stop_sender, mut stop_receiver) = mpsc::channel(32);
-        let mut stream = TcpStream::connect(&config.get_address()).await?;
-        // モニタの登録処理
-        stream.write_all(config::SET_MONITOR_COMMAND).await?;
-        let mut buf = [0; 5];
-        let n = stream.read(&mut buf).await?;
-        let res = std::str::from_utf8(&buf[..n])
-            .unwrap()
-            .trim_end_matches("\r\n")
-            .to_string();
-        match res.as_str() {
-            "OK" => {}
-            "E0" => return Err(anyhow::anyhow!("モニタ登録失敗：デバイス番号異常")),
-            "E1" => return Err(anyhow::anyhow!("モニタ登録失敗：コマンド異常")),
-            t => return Err(anyhow::anyhow!("モニタ登録失敗：想定外の返り値:{}", t)),
-        }
+        let read_plan = config.get_read_plan();
         let mut state = DemoCpb16State::new();
-        let mut interval = state.get_interval();
+        let mut scheduler = PollScheduler::new(
+            "demo_cpb16",
+            state.get_interval(),
+            config.get_overrun_policy(),
+        );
 
         let connection_thread = tokio::spawn(async move {
-            let mut next_loop_start_time = Instant::now();
-            let mut duration: Duration;
             loop {
-                let now = Instant::now();
-                if next_loop_start_time > now {
-                    duration = next_loop_start_time - now;
-                } else {
-                    duration = Duration::from_millis(0);
-                }
-
                 tokio::select! {
                     _ = stop_receiver.recv() => {
                         break;
                     }
-                    _ = tokio::time::sleep(duration) =>{
+                    _ = scheduler.tick() =>{
                         let result: anyhow::Result<()> = async {
-                            // let write_result = timeout(Duration::from_secs(10), stream.write_all(&command)).await;
-                            // タイムアウト処理を行う必要がある
-                            // stream.write_all(&command).await?;
-                            let _ = timeout(Duration::from_secs(5), stream.write_all(config::MONITOR_READOUT_COMMAND)).await?;
-
-                            // NOTE:データ点数が多くなった場合、バッファサイズを大きくする必要がある
-                            let mut buf = [0; 1024];
-                            // let n = stream.read(&mut buf).await?;
-                            let n = match timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
-                                Ok(Ok(n)) => n,
-                                Ok(Err(e)) => anyhow::bail!("error in stream.read:{}",e),
-                                Err(_) => anyhow::bail!("timeout in stream.read"),
-                            };
+                            // 応答待ちの打ち切りはセッションのウォッチドッグで行う
+                            let res = read_plan.read(&session).await?;
                             let dt = Local::now();
-                            let res = std::str::from_utf8(&buf[..n])
-                                .unwrap()
-                                .trim_end_matches("\r\n")
-                                .to_string();
 
                             // NOTE:想定外のデータについてのハンドリングが必要
-                            let receive_data = DemoCpb16ReceiveData::create(dt, res)?;
+                            let receive_data = DemoCpb16ReceiveData::create(dt, res, config.get_status_devices())?;
 
                             let now_status = receive_data.get_status();
                             if state.get_status() != now_status {
                                 state.set_status(now_status);
-                                interval = state.get_interval();
+                                scheduler.set_period(state.get_interval());
                             }
 
                             data_sender.send(receive_data).await?;
@@ -247,20 +204,31 @@ impl ConnectionThread {
 
                         // receive_data等のエラーハンドリング
                         if let Err(err) = result {
-                            warn!("Error: {}", err);
-                            disconnect_sender.send(()).await.unwrap();
+                            let reason = DisconnectReason::from_error(&err);
+                            warn!("PLCとの通信を中断:{}:{:?}", reason, err);
+                            disconnect_sender.send(reason).await.unwrap();
+                        }
+
+                        // ポーリング周期の超過回数
+                        if let Some(diagnostics) = scheduler.report(Local::now()) {
+                            match diagnostics.to_data_point("demo_cpb16") {
+                                Ok(point) => {
+                                    if let Err(r) = diagnostics_sender.send(vec![point]).await {
+                                        warn!("ポーリングの診断を送信できない:{:?}", r);
+                                    }
+                                }
+                                Err(r) => warn!("ポーリングの診断を作成できない:{:?}", r),
+                            }
                         }
                     }
                 }
-
-                next_loop_start_time += Duration::from_millis(interval);
             }
         });
 
-        Ok(Self {
+        Self {
             connection_thread,
             stop_sender,
-        })
+        }
     }
 
     async fn stop(self) -> anyhow::Result<()> {
@@ -1,10 +1,15 @@
+mod checkpoint;
 mod collector;
 mod config;
 mod data_manager;
 mod debugger;
+mod downtime;
 mod interface;
+mod oee;
 
 #[allow(unused_imports)]
 pub use collector::DemoCpb16Collector;
 #[allow(unused_imports)]
 pub use debugger::DemoCpb16Debugger;
+#[allow(unused_imports)]
+pub use downtime::{DowntimeRegistry, StopEpisode};
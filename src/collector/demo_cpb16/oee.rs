@@ -0,0 +1,647 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime, TimeZone};
+use influxdb2::models::DataPoint;
+use log::debug;
+
+use super::checkpoint::OeeCheckpoint;
+use crate::processing::counter::{CounterConfig, CounterTracker};
+use crate::shift_calendar;
+use crate::shift_calendar::ShiftSpan;
+
+// 受信間隔がこれより空いた場合(切断中等)は稼働時間に加算しない
+const MAX_SAMPLE_GAP_SEC: f64 = 60.0;
+
+// OEE = 時間稼働率(availability) × 性能稼働率(performance) × 良品率(quality)
+// DemoCpb16IdealCycleRate      : 理想的な生産速度(袋/分)。未設定の場合はOEEを計算しない
+// DemoCpb16ShiftStarts         : シフト名と開始時刻 ex) day=08:00,night=20:00
+// DemoCpb16PlannedProductionSec : 1シフトの計画生産時間(秒)。未設定の場合はシフトの長さ
+// シフトカレンダーがある場合はシフトと計画生産時間(休憩・休日・計画保全を除く)をカレンダーから取得する
+#[derive(Clone, Debug)]
+pub struct OeeConfig {
+    ideal_cycle_rate: f64,
+    shifts: Vec<ShiftStart>,
+    planned_production_sec: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+struct ShiftStart {
+    name: String,
+    start: NaiveTime,
+}
+
+impl OeeConfig {
+    pub fn create_from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(rate) = std::env::var("DemoCpb16IdealCycleRate") else {
+            return Ok(None);
+        };
+        let ideal_cycle_rate: f64 = rate.parse()?;
+        if ideal_cycle_rate <= 0.0 {
+            anyhow::bail!("DemoCpb16IdealCycleRateが不正:{}", rate)
+        }
+
+        let shift_starts =
+            std::env::var("DemoCpb16ShiftStarts").unwrap_or_else(|_| "day=00:00".to_string());
+        let mut shifts = Vec::new();
+        for item in shift_starts
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+        {
+            let Some((name, start)) = item.split_once('=') else {
+                anyhow::bail!("DemoCpb16ShiftStartsが不正:{}", item)
+            };
+            shifts.push(ShiftStart {
+                name: name.trim().to_string(),
+                start: NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+            });
+        }
+        if shifts.is_empty() {
+            anyhow::bail!("DemoCpb16ShiftStartsが空")
+        }
+        shifts.sort_by_key(|t| t.start);
+
+        let planned_production_sec = match std::env::var("DemoCpb16PlannedProductionSec") {
+            Ok(t) => Some(t.parse()?),
+            Err(_) => None,
+        };
+
+        Ok(Some(Self {
+            ideal_cycle_rate,
+            shifts,
+            planned_production_sec,
+        }))
+    }
+
+    // 生産1袋あたりの理想サイクルタイム(秒)
+    fn ideal_cycle_time(&self) -> f64 {
+        60.0 / self.ideal_cycle_rate
+    }
+
+    // dtが属するシフト
+    fn shift_at(&self, dt: DateTime<Local>) -> anyhow::Result<ShiftSpan> {
+        let date = dt.date_naive();
+        let time = dt.time();
+        // 当日の最初のシフトより前なら前日の最後のシフト
+        let (position, date) = match self.shifts.iter().rposition(|t| t.start <= time) {
+            Some(t) => (t, date),
+            None => (self.shifts.len() - 1, date - Duration::days(1)),
+        };
+        let start = local_datetime(date, self.shifts[position].start)?;
+        let end = match self.shifts.get(position + 1) {
+            Some(next) => local_datetime(date, next.start)?,
+            None => local_datetime(date + Duration::days(1), self.shifts[0].start)?,
+        };
+        Ok(ShiftSpan {
+            name: self.shifts[position].name.clone(),
+            start,
+            end,
+        })
+    }
+}
+
+fn local_datetime(date: NaiveDate, time: NaiveTime) -> anyhow::Result<DateTime<Local>> {
+    match Local.from_local_datetime(&date.and_time(time)) {
+        chrono::LocalResult::Single(t) => Ok(t),
+        chrono::LocalResult::Ambiguous(t, _) => Ok(t),
+        chrono::LocalResult::None => anyhow::bail!("時刻変換に失敗:{}", date.and_time(time)),
+    }
+}
+
+// 集計期間内の稼働時間と生産数
+#[derive(Debug, Default)]
+struct OeePeriod {
+    run_second: f64,
+    production: u32,
+    defect: u32,
+}
+
+impl OeePeriod {
+    // 計画生産時間に対するOEEのデータポイント
+    fn to_data_point(
+        &self,
+        config: &OeeConfig,
+        period: &str,
+        // 期間を識別するタグ shift=シフト名、run_id=稼働ID
+        tag: Option<(&str, &str)>,
+        start: DateTime<Local>,
+        planned_second: f64,
+    ) -> anyhow::Result<DataPoint> {
+        let time = match start.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("OeePeriod::to_data_pointでエラー"),
+        };
+        // 分母が0の場合は0とする
+        let availability = ratio(self.run_second, planned_second);
+        let performance = ratio(
+            config.ideal_cycle_time() * self.production as f64,
+            self.run_second,
+        );
+        let good = self.production.saturating_sub(self.defect);
+        let quality = ratio(good as f64, self.production as f64);
+
+        // シフトのOEEはそのシフト名、それ以外は開始時刻のシフト名をshiftタグに付ける
+        let builder = DataPoint::builder("demo_cpb16");
+        let builder = match tag {
+            Some(("shift", name)) => builder.tag("shift", name),
+            Some((key, value)) => shift_calendar::add_shift_tag(builder, start).tag(key, value),
+            None => shift_calendar::add_shift_tag(builder, start),
+        };
+        Ok(builder
+            .tag("info_type", "oee")
+            .tag("period", period)
+            .field("availability", availability)
+            .field("performance", performance)
+            .field("quality", quality)
+            .field("oee", availability * performance * quality)
+            .field("planned_second", planned_second)
+            .field("run_second", self.run_second)
+            .field("production", self.production as i64)
+            .field("good", good as i64)
+            .field("defect", self.defect as i64)
+            .timestamp(time)
+            .build()?)
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator <= 0.0 {
+        return 0.0;
+    }
+    numerator / denominator
+}
+
+// 受信データからシフト・日毎の稼働時間と生産数を集計し
+// 期間が切り替わったらOEEを出力する
+pub struct OeeEngine {
+    config: OeeConfig,
+    last_sample: Option<(DateTime<Local>, bool)>,
+    production_counter: CounterTracker,
+    defect_counter: CounterTracker,
+    shift: Option<ShiftSpan>,
+    shift_period: OeePeriod,
+    day: Option<NaiveDate>,
+    day_period: OeePeriod,
+    // 集計中のシフトの計画時間。途中から集計した場合は最初の受信からの分
+    shift_planned_second: f64,
+    // 日を跨ぐシフトの計画時間は日毎に按分する
+    day_planned_second: f64,
+    next_day_planned_second: f64,
+}
+
+impl OeeEngine {
+    pub fn new(config: OeeConfig, counter_config: CounterConfig) -> Self {
+        Self {
+            config,
+            last_sample: None,
+            production_counter: CounterTracker::new("oee_production", counter_config.clone()),
+            defect_counter: CounterTracker::new("oee_defect", counter_config),
+            shift: None,
+            shift_period: OeePeriod::default(),
+            day: None,
+            day_period: OeePeriod::default(),
+            shift_planned_second: 0.0,
+            day_planned_second: 0.0,
+            next_day_planned_second: 0.0,
+        }
+    }
+
+    // 受信毎に呼ぶ。期間が終わった場合はそのOEEを返す
+    pub fn push(
+        &mut self,
+        dt: DateTime<Local>,
+        is_running: bool,
+        production_count: u32,
+        defect_count: u32,
+    ) -> anyhow::Result<Vec<DataPoint>> {
+        let mut points = Vec::new();
+
+        // カレンダーのシフト外の時間はシフトのOEEに含めない
+        let shift = self.current_shift(dt)?;
+        if self.shift.as_ref().map(|t| t.start) != shift.as_ref().map(|t| t.start) {
+            if let Some(last_shift) = self.shift.take() {
+                points.push(self.finish_shift(&last_shift)?);
+            }
+        }
+        if let Some(day) = self.day {
+            if day != dt.date_naive() {
+                points.push(self.finish_day(day)?);
+            }
+        }
+        self.day = Some(dt.date_naive());
+        if self.shift.is_none() {
+            if let Some(shift) = &shift {
+                // 起動時や切断中にシフトが始まった場合は観測していない時間を計画時間に含めない
+                let observed = self.last_sample.is_some_and(|(t, _)| {
+                    (shift.start - t).num_milliseconds() as f64 / 1000.0 <= MAX_SAMPLE_GAP_SEC
+                });
+                let from = match observed {
+                    true => shift.start,
+                    false => dt.max(shift.start),
+                };
+                self.add_planned_second(shift, from, dt.date_naive())?;
+            }
+            self.shift = shift;
+        }
+
+        // 前回の受信から稼働していた時間
+        let last_running = matches!(self.last_sample, Some((_, true)));
+        if let Some((last_dt, last_running)) = self.last_sample {
+            let gap = (dt - last_dt).num_milliseconds() as f64 / 1000.0;
+            if last_running && gap > 0.0 && gap <= MAX_SAMPLE_GAP_SEC {
+                if self.shift.is_some() {
+                    self.shift_period.run_second += gap;
+                }
+                self.day_period.run_second += gap;
+            }
+        }
+        self.last_sample = Some((dt, is_running));
+
+        // 生産数は稼働毎に0から数え直す
+        if is_running {
+            let production = self
+                .production_counter
+                .push(production_count as i64, !last_running)
+                .delta as u32;
+            let defect = self
+                .defect_counter
+                .push(defect_count as i64, !last_running)
+                .delta as u32;
+            if self.shift.is_some() {
+                self.shift_period.production += production;
+                self.shift_period.defect += defect;
+            }
+            self.day_period.production += production;
+            self.day_period.defect += defect;
+        }
+
+        Ok(points)
+    }
+
+    // 起動時・再接続時にPLCが稼働中だった場合にpushの前に呼ぶ
+    // 現在の生産数を基準にして、それ以降の増分のみを数える
+    // 追跡中の稼働が続いている場合(continued)は切断中の増分も同じ稼働のものとして数える
+    pub fn attach(
+        &mut self,
+        dt: DateTime<Local>,
+        production_count: u32,
+        defect_count: u32,
+        continued: bool,
+    ) {
+        if continued && self.last_sample.is_some() {
+            return;
+        }
+        self.production_counter.clear();
+        self.production_counter.push(production_count as i64, false);
+        self.defect_counter.clear();
+        self.defect_counter.push(defect_count as i64, false);
+        self.last_sample = Some((dt, true));
+    }
+
+    // 稼働完了時に呼ぶ。稼働と重なる計画生産時間に対するOEEを計算する
+    pub fn make_run_point(
+        &self,
+        run_id: u32,
+        start_time: DateTime<Local>,
+        end_time: DateTime<Local>,
+        production: u32,
+        defect: u32,
+    ) -> anyhow::Result<DataPoint> {
+        let run_second = (end_time - start_time).num_milliseconds() as f64 / 1000.0;
+        let period = OeePeriod {
+            run_second,
+            production,
+            defect,
+        };
+        let run_id = run_id.to_string();
+        period.to_data_point(
+            &self.config,
+            "run",
+            Some(("run_id", &run_id)),
+            start_time,
+            self.planned_second_between(start_time, end_time)?,
+        )
+    }
+
+    pub fn checkpoint(&self) -> OeeCheckpoint {
+        OeeCheckpoint {
+            last_sample_time: self.last_sample.map(|(t, _)| t.timestamp_millis()),
+            last_sample_running: self.last_sample.is_some_and(|(_, t)| t),
+            production_counter: self.production_counter.get_state(),
+            defect_counter: self.defect_counter.get_state(),
+            shift_name: self.shift.as_ref().map(|t| t.name.to_owned()),
+            shift_start: self.shift.as_ref().map(|t| t.start.timestamp_millis()),
+            shift_end: self.shift.as_ref().map(|t| t.end.timestamp_millis()),
+            shift_run_second: self.shift_period.run_second,
+            shift_production: self.shift_period.production,
+            shift_defect: self.shift_period.defect,
+            shift_planned_second: self.shift_planned_second,
+            day: self.day.map(|t| t.format("%Y-%m-%d").to_string()),
+            day_run_second: self.day_period.run_second,
+            day_production: self.day_period.production,
+            day_defect: self.day_period.defect,
+            day_planned_second: self.day_planned_second,
+            next_day_planned_second: self.next_day_planned_second,
+        }
+    }
+
+    // 再起動までの間は切断として扱い、次の受信で期間が変わっていればその期間のOEEを出力する
+    pub fn restore(&mut self, checkpoint: OeeCheckpoint) {
+        let to_datetime = |t: Option<i64>| t.and_then(|t| Local.timestamp_millis_opt(t).single());
+        self.last_sample =
+            to_datetime(checkpoint.last_sample_time).map(|t| (t, checkpoint.last_sample_running));
+        self.production_counter
+            .restore(checkpoint.production_counter);
+        self.defect_counter.restore(checkpoint.defect_counter);
+        self.shift = match (
+            checkpoint.shift_name,
+            to_datetime(checkpoint.shift_start),
+            to_datetime(checkpoint.shift_end),
+        ) {
+            (Some(name), Some(start), Some(end)) => Some(ShiftSpan { name, start, end }),
+            _ => None,
+        };
+        self.shift_period = OeePeriod {
+            run_second: checkpoint.shift_run_second,
+            production: checkpoint.shift_production,
+            defect: checkpoint.shift_defect,
+        };
+        self.shift_planned_second = checkpoint.shift_planned_second;
+        self.day = checkpoint
+            .day
+            .and_then(|t| NaiveDate::parse_from_str(&t, "%Y-%m-%d").ok());
+        self.day_period = OeePeriod {
+            run_second: checkpoint.day_run_second,
+            production: checkpoint.day_production,
+            defect: checkpoint.day_defect,
+        };
+        self.day_planned_second = checkpoint.day_planned_second;
+        self.next_day_planned_second = checkpoint.next_day_planned_second;
+    }
+
+    fn current_shift(&self, dt: DateTime<Local>) -> anyhow::Result<Option<ShiftSpan>> {
+        match shift_calendar::get() {
+            Some(calendar) => Ok(calendar.shift_at(dt)),
+            None => Ok(Some(self.config.shift_at(dt)?)),
+        }
+    }
+
+    // fromからtoまでの計画生産時間(秒)
+    // カレンダーがない場合は1シフトの計画生産時間をシフトの長さに対する割合で按分する
+    fn planned_second_between(
+        &self,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> anyhow::Result<f64> {
+        if from >= to {
+            return Ok(0.0);
+        }
+        if let Some(calendar) = shift_calendar::get() {
+            return Ok(calendar.planned_production_second(from, to));
+        }
+        let mut total = 0.0;
+        let mut start = from;
+        while start < to {
+            let shift = self.config.shift_at(start)?;
+            let end = shift.end.min(to);
+            let length = (shift.end - shift.start).num_milliseconds() as f64 / 1000.0;
+            let planned = self.config.planned_production_sec.unwrap_or(length);
+            let overlap = (end - start).num_milliseconds() as f64 / 1000.0;
+            total += planned * ratio(overlap, length);
+            start = end;
+        }
+        Ok(total)
+    }
+
+    fn finish_shift(&mut self, shift: &ShiftSpan) -> anyhow::Result<DataPoint> {
+        let period = std::mem::take(&mut self.shift_period);
+        debug!("シフトのOEEを出力:{}:{}", shift.name, shift.start);
+        period.to_data_point(
+            &self.config,
+            "shift",
+            Some(("shift", &shift.name)),
+            shift.start,
+            std::mem::take(&mut self.shift_planned_second),
+        )
+    }
+
+    // fromからシフトの終わりまでの計画時間を当日と翌日に按分する
+    fn add_planned_second(
+        &mut self,
+        shift: &ShiftSpan,
+        from: DateTime<Local>,
+        today: NaiveDate,
+    ) -> anyhow::Result<()> {
+        let tomorrow = local_datetime(today + Duration::days(1), NaiveTime::MIN)?;
+        let before_today = self.planned_second_between(from, tomorrow - Duration::days(1))?;
+        let today_second = self.planned_second_between(
+            from.max(tomorrow - Duration::days(1)),
+            shift.end.min(tomorrow),
+        )?;
+        let tomorrow_second = self.planned_second_between(from.max(tomorrow), shift.end)?;
+        self.shift_planned_second = before_today + today_second + tomorrow_second;
+        self.day_planned_second += today_second;
+        self.next_day_planned_second += tomorrow_second;
+        Ok(())
+    }
+
+    fn finish_day(&mut self, day: NaiveDate) -> anyhow::Result<DataPoint> {
+        let period = std::mem::take(&mut self.day_period);
+        // 集計したシフトの計画時間の合計。観測していないシフトは含めない
+        let planned_second = std::mem::replace(
+            &mut self.day_planned_second,
+            std::mem::take(&mut self.next_day_planned_second),
+        );
+        debug!("日毎のOEEを出力:{}", day);
+        let start = local_datetime(day, NaiveTime::MIN)?;
+        period.to_data_point(&self.config, "day", None, start, planned_second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::counter::CounterConfig;
+    use influxdb2::models::WriteDataPoint;
+
+    // 08:00と20:00に交代し、1シフト12時間のうち10時間が計画生産時間
+    fn engine() -> OeeEngine {
+        let config = OeeConfig {
+            ideal_cycle_rate: 60.0,
+            shifts: vec![
+                ShiftStart {
+                    name: "day".to_string(),
+                    start: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+                },
+                ShiftStart {
+                    name: "night".to_string(),
+                    start: NaiveTime::from_hms_opt(20, 0, 0).unwrap(),
+                },
+            ],
+            planned_production_sec: Some(36000.0),
+        };
+        OeeEngine::new(config, CounterConfig::new(16, 1000))
+    }
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 10, 19, hour, minute, second)
+            .unwrap()
+    }
+
+    type Pairs = Vec<(String, String)>;
+
+    // ラインプロトコルのタグ・フィールドとタイムスタンプ
+    fn parse(point: &DataPoint) -> (Pairs, Pairs, DateTime<Local>) {
+        let mut line = Vec::new();
+        point.write_data_point_to(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        let parts: Vec<&str> = line.trim().split(' ').collect();
+        let pairs = |t: &str| {
+            t.split(',')
+                .filter_map(|t| t.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Pairs>()
+        };
+        let time = Local.timestamp_nanos(parts[2].parse::<i64>().unwrap());
+        (pairs(parts[0]), pairs(parts[1]), time)
+    }
+
+    fn value<'a>(pairs: &'a [(String, String)], key: &str) -> &'a str {
+        pairs
+            .iter()
+            .find(|t| t.0 == key)
+            .map(|t| t.1.as_str())
+            .unwrap()
+    }
+
+    fn number(pairs: &[(String, String)], key: &str) -> f64 {
+        value(pairs, key).trim_end_matches('i').parse().unwrap()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn shift_factors() {
+        let mut engine = engine();
+        // 08:00から4時間稼働し、30秒毎に12袋生産・1袋不良
+        let mut dt = at(8, 0, 0);
+        let mut step = 0;
+        while dt < at(12, 0, 0) {
+            assert!(engine.push(dt, true, step * 12, step).unwrap().is_empty());
+            dt += Duration::seconds(30);
+            step += 1;
+        }
+        let mut points = Vec::new();
+        while dt <= at(20, 0, 0) {
+            points.extend(engine.push(dt, false, 0, 0).unwrap());
+            dt += Duration::seconds(30);
+        }
+        assert_eq!(points.len(), 1);
+        let (tags, fields, time) = parse(&points[0]);
+        assert_eq!(value(&tags, "period"), "shift");
+        assert_eq!(value(&tags, "shift"), "day");
+        assert_eq!(time, at(8, 0, 0));
+
+        let production = (step - 1) * 12;
+        let defect = step - 1;
+        assert_eq!(number(&fields, "production"), production as f64);
+        assert_eq!(number(&fields, "defect"), defect as f64);
+        assert_near(number(&fields, "run_second"), 14400.0);
+        assert_near(number(&fields, "planned_second"), 36000.0);
+        let availability = 14400.0 / 36000.0;
+        // 理想サイクルタイムは1秒
+        let performance = production as f64 / 14400.0;
+        let quality = (production - defect) as f64 / production as f64;
+        assert_near(number(&fields, "availability"), availability);
+        assert_near(number(&fields, "performance"), performance);
+        assert_near(number(&fields, "quality"), quality);
+        assert_near(number(&fields, "oee"), availability * performance * quality);
+    }
+
+    #[test]
+    fn day_and_shift_rollover() {
+        let mut engine = engine();
+        // 20:00からの夜勤で2時間稼働
+        let mut dt = at(20, 0, 0);
+        let mut points = Vec::new();
+        while dt <= at(8, 0, 0) + Duration::days(1) {
+            let is_running = dt < at(22, 0, 0);
+            points.extend(engine.push(dt, is_running, 0, 0).unwrap());
+            dt += Duration::seconds(30);
+        }
+        assert_eq!(points.len(), 2);
+
+        // 日毎は0時に出力し、夜勤の計画時間のうち当日分(4/12)のみを含める
+        let (tags, fields, time) = parse(&points[0]);
+        assert_eq!(value(&tags, "period"), "day");
+        assert_eq!(time, at(0, 0, 0));
+        assert_near(number(&fields, "planned_second"), 12000.0);
+        assert_near(number(&fields, "run_second"), 7200.0);
+        assert_near(number(&fields, "availability"), 0.6);
+
+        // シフトは交代時に出力し、日を跨いでも計画時間は1シフト分
+        let (tags, fields, time) = parse(&points[1]);
+        assert_eq!(value(&tags, "period"), "shift");
+        assert_eq!(value(&tags, "shift"), "night");
+        assert_eq!(time, at(20, 0, 0));
+        assert_near(number(&fields, "planned_second"), 36000.0);
+        assert_near(number(&fields, "run_second"), 7200.0);
+        assert_near(number(&fields, "availability"), 0.2);
+        assert_near(number(&fields, "performance"), 0.0);
+
+        // 翌日は夜勤の残りと日勤の計画時間
+        assert_near(engine.day_planned_second, 24000.0 + 36000.0);
+        assert_near(engine.day_period.run_second, 0.0);
+    }
+
+    #[test]
+    fn planned_second_overlapping_run() {
+        let engine = engine();
+        let planned = engine
+            .planned_second_between(at(10, 0, 0), at(11, 0, 0))
+            .unwrap();
+        assert!((planned - 3000.0).abs() < 1e-6);
+        // シフトを跨ぐ稼働
+        let planned = engine
+            .planned_second_between(at(19, 0, 0), at(21, 0, 0))
+            .unwrap();
+        assert!((planned - 6000.0).abs() < 1e-6);
+        assert_eq!(
+            engine
+                .planned_second_between(at(11, 0, 0), at(10, 0, 0))
+                .unwrap(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn prorate_shift_first_seen_midway() {
+        let mut engine = engine();
+        engine.push(at(14, 0, 0), false, 0, 0).unwrap();
+        assert!((engine.shift_planned_second - 18000.0).abs() < 1e-6);
+        // 続けて受信している場合は次のシフトの計画時間は全体
+        let mut dt = at(14, 0, 0);
+        while dt < at(20, 0, 1) {
+            dt += Duration::seconds(30);
+            engine.push(dt, false, 0, 0).unwrap();
+        }
+        assert!((engine.shift_planned_second - 36000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn prorate_after_long_gap() {
+        let mut engine = engine();
+        engine.push(at(7, 0, 0), false, 0, 0).unwrap();
+        // 切断中にシフトが始まった
+        let points = engine.push(at(9, 0, 0), false, 0, 0).unwrap();
+        assert_eq!(points.len(), 1);
+        let expected = 36000.0 * 11.0 / 12.0;
+        assert!((engine.shift_planned_second - expected).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,373 @@
+use chrono::{DateTime, Local};
+use influxdb2::models::DataPoint;
+use log::{debug, warn};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, MissedTickBehavior};
+
+use super::config::{DemoMachineConfig, RingBufferConfig};
+use crate::collector::host_link::{block_max_count, HostLinkSession, HostLinkTarget};
+use crate::shift_calendar;
+
+// 通信エラー後の再接続間隔
+const RECONNECT_INTERVAL_SEC: u64 = 5;
+
+// PLCがリングバッファに書き込んだサンプルをまとめて読み出す
+// サンプルの時刻はPLCのサンプリング周期から復元する
+pub struct BufferedAcquisitionThread {
+    buffered_thread: JoinHandle<()>,
+    stop_sender: mpsc::Sender<()>,
+}
+
+impl BufferedAcquisitionThread {
+    pub fn start(
+        config: &DemoMachineConfig,
+        ring_buffer: RingBufferConfig,
+        data_sender: mpsc::Sender<Vec<DataPoint>>,
+    ) -> Self {
+        let (stop_sender, mut stop_receiver) = mpsc::channel(32);
+        let target = config.get_target();
+        let buffered_thread = tokio::spawn(async move {
+            // 再接続後は前回の読み出し位置から続けて、切断中にPLCが書き込んだサンプルも読み出す
+            let mut reader = RingBufferReader::new(ring_buffer);
+            loop {
+                let result = tokio::select! {
+                    _ = stop_receiver.recv() => break,
+                    result = reader.run(&target, &data_sender) => result,
+                };
+                if let Err(r) = result {
+                    warn!("リングバッファの読み出しエラー:{:?}", r);
+                }
+                // 再接続
+                tokio::select! {
+                    _ = stop_receiver.recv() => break,
+                    _ = tokio::time::sleep(Duration::from_secs(RECONNECT_INTERVAL_SEC)) => {}
+                }
+            }
+        });
+        Self {
+            buffered_thread,
+            stop_sender,
+        }
+    }
+
+    pub async fn stop(self) -> anyhow::Result<()> {
+        self.stop_sender.send(()).await?;
+        // 完了を待つ処理
+        self.buffered_thread.await?;
+        Ok(())
+    }
+}
+
+struct RingBufferReader {
+    config: RingBufferConfig,
+    // 1サンプルのワード数
+    words_per_sample: u32,
+    // 次に読み出す位置とそのサンプルの時刻
+    last_index: Option<u32>,
+    next_sample_time: DateTime<Local>,
+    last_read_time: DateTime<Local>,
+}
+
+impl RingBufferReader {
+    fn new(config: RingBufferConfig) -> Self {
+        let words_per_sample = config.fields.len() as u32 * config.start_device.width();
+        let now = Local::now();
+        Self {
+            config,
+            words_per_sample,
+            last_index: None,
+            next_sample_time: now,
+            last_read_time: now,
+        }
+    }
+
+    // 通信エラーが発生するまで読み出しを続ける
+    async fn run(
+        &mut self,
+        target: &HostLinkTarget,
+        data_sender: &mpsc::Sender<Vec<DataPoint>>,
+    ) -> anyhow::Result<()> {
+        let session = HostLinkSession::get(target)?;
+        debug!("リングバッファの読み出し開始");
+
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.read_interval));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            let points =
+                match timeout(Duration::from_secs(5), self.read_new_samples(&session)).await {
+                    Ok(Ok(points)) => points,
+                    Ok(Err(e)) => anyhow::bail!("error in read_new_samples:{}", e),
+                    Err(_) => anyhow::bail!("timeout in read_new_samples"),
+                };
+            if !points.is_empty() {
+                data_sender.send(points).await?;
+            }
+        }
+    }
+
+    async fn read_new_samples(
+        &mut self,
+        session: &HostLinkSession,
+    ) -> anyhow::Result<Vec<DataPoint>> {
+        let read_time = Local::now();
+        let index = session
+            .read_device(&self.config.index_device)
+            .await?
+            .as_i64();
+        if index < 0 || index >= self.config.length as i64 {
+            anyhow::bail!("書き込み位置が範囲外:{}", index)
+        }
+        let index = index as u32;
+        let period = chrono::Duration::milliseconds(self.config.sample_period as i64);
+
+        // 初回は現在位置から読み出しを開始する
+        let Some(last_index) = self.last_index else {
+            self.last_index = Some(index);
+            self.next_sample_time = read_time;
+            self.last_read_time = read_time;
+            return Ok(Vec::new());
+        };
+
+        let length = self.config.length;
+        let count = (index + length - last_index) % length;
+
+        // 前回の読み出しからバッファ1周分以上経過していると上書きされたサンプルがある
+        let elapsed_ms = (read_time - self.last_read_time).num_milliseconds();
+        let expected = elapsed_ms / self.config.sample_period as i64;
+        let mut points = Vec::new();
+        if expected > length as i64 {
+            let lost = expected - count as i64;
+            warn!("リングバッファのオーバーフロー:{}サンプル欠損", lost);
+            points.push(make_loss_point(read_time, lost)?);
+            // 欠損後は時刻を合わせ直す
+            self.next_sample_time = read_time - period * count as i32;
+        }
+        if count == 0 {
+            self.last_read_time = read_time;
+            return Ok(points);
+        }
+
+        let samples = self.read_slots(session, last_index, count).await?;
+
+        // PLCとゲートウェイの時計のずれが大きくなったら最新サンプルを読み出し時刻に合わせる
+        let last_sample_time = self.next_sample_time + period * (count as i32 - 1);
+        let tolerance = chrono::Duration::milliseconds(
+            2 * self.config.read_interval.max(self.config.sample_period) as i64,
+        );
+        if (last_sample_time - read_time).abs() > tolerance {
+            debug!("リングバッファの時刻を補正");
+            self.next_sample_time = read_time - period * (count as i32 - 1);
+        }
+
+        for (k, sample) in samples.iter().enumerate() {
+            let dt = self.next_sample_time + period * k as i32;
+            let time = match dt.timestamp_nanos_opt() {
+                Some(t) => t,
+                None => anyhow::bail!("in match dt.timestamp_nanos_opt()"),
+            };
+            let mut builder = shift_calendar::add_shift_tag(DataPoint::builder("demo_machine"), dt)
+                .tag("info_type", "buffered");
+            for (name, value) in self.config.fields.iter().zip(sample) {
+                builder = builder.field(name.to_owned(), *value);
+            }
+            points.push(builder.timestamp(time).build()?);
+        }
+
+        self.next_sample_time += period * count as i32;
+        self.last_index = Some(index);
+        // 読み出しに失敗した場合は次の読み出しで前回からの経過時間で欠損を判定する
+        self.last_read_time = read_time;
+        Ok(points)
+    }
+
+    // last_indexからcount個のサンプルを読み出す。バッファ末尾で折り返す
+    async fn read_slots(
+        &self,
+        session: &HostLinkSession,
+        from: u32,
+        count: u32,
+    ) -> anyhow::Result<Vec<Vec<i64>>> {
+        let length = self.config.length;
+        let mut segments = vec![(from, count.min(length - from))];
+        if from + count > length {
+            segments.push((0, from + count - length));
+        }
+
+        let fields = self.config.fields.len();
+        let max_slots =
+            (block_max_count(self.config.start_device.get_format()) / fields as u32).max(1);
+        let mut samples = Vec::with_capacity(count as usize);
+        for (start, slots) in segments {
+            let mut done = 0;
+            while done < slots {
+                let n = (slots - done).min(max_slots);
+                let device = self
+                    .config
+                    .start_device
+                    .offset((start + done) * self.words_per_sample);
+                let values = session.read_block(&device, n * fields as u32).await?;
+                for sample in values.chunks(fields) {
+                    samples.push(sample.iter().map(|t| t.as_i64()).collect());
+                }
+                done += n;
+            }
+        }
+        Ok(samples)
+    }
+}
+
+fn make_loss_point(dt: DateTime<Local>, lost: i64) -> anyhow::Result<DataPoint> {
+    let time = match dt.timestamp_nanos_opt() {
+        Some(t) => t,
+        None => anyhow::bail!("in match dt.timestamp_nanos_opt()"),
+    };
+    let point = shift_calendar::add_shift_tag(DataPoint::builder("demo_machine"), dt)
+        .tag("info_type", "buffered_loss")
+        .field("lost_samples", lost)
+        .timestamp(time)
+        .build()?;
+    Ok(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::host_link::Device;
+    use influxdb2::models::WriteDataPoint;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // スロットiの値は100+i
+    #[derive(Default)]
+    struct RingBufferPlc {
+        index: u32,
+        // 次のコマンドで接続を切る
+        disconnect: bool,
+    }
+
+    // DM0に書き込み位置、DM1000からリングバッファを持つPLCの代わり
+    async fn spawn_plc(plc: Arc<Mutex<RingBufferPlc>>) -> HostLinkTarget {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let plc = plc.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut line = Vec::new();
+                    while reader.read_until(b'\r', &mut line).await.unwrap_or(0) > 0 {
+                        let res = {
+                            let mut plc = plc.lock().unwrap();
+                            if std::mem::take(&mut plc.disconnect) {
+                                break;
+                            }
+                            let command = String::from_utf8_lossy(&line).trim().to_string();
+                            let command: Vec<&str> = command.split(' ').collect();
+                            match command.as_slice() {
+                                ["RD", "DM0.U"] => plc.index.to_string(),
+                                ["RDS", start, count] => {
+                                    let start: u32 = start
+                                        .trim_start_matches("DM")
+                                        .trim_end_matches(".U")
+                                        .parse()
+                                        .unwrap();
+                                    let count: u32 = count.parse().unwrap();
+                                    (start..start + count)
+                                        .map(|t| (t - 1000 + 100).to_string())
+                                        .collect::<Vec<_>>()
+                                        .join(" ")
+                                }
+                                _ => "E1".to_string(),
+                            }
+                        };
+                        line.clear();
+                        let res = format!("{}\r\n", res);
+                        if writer.write_all(res.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        HostLinkTarget::Tcp { address }
+    }
+
+    fn config() -> RingBufferConfig {
+        RingBufferConfig {
+            index_device: Device::parse("DM0").unwrap(),
+            start_device: Device::parse("DM1000").unwrap(),
+            length: 10,
+            fields: vec!["value".to_string()],
+            sample_period: 100,
+            read_interval: 100,
+        }
+    }
+
+    // (info_type, 値)
+    fn parse(points: &[DataPoint]) -> Vec<(String, i64)> {
+        points
+            .iter()
+            .map(|point| {
+                let mut line = Vec::new();
+                point.write_data_point_to(&mut line).unwrap();
+                let line = String::from_utf8(line).unwrap();
+                let info_type = line.split("info_type=").nth(1).unwrap();
+                let info_type = info_type.split([',', ' ']).next().unwrap().to_string();
+                let field = line.split(' ').nth(1).unwrap();
+                let value = field.split('=').nth(1).unwrap().trim_end_matches('i');
+                (info_type, value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn continue_after_reconnect() {
+        let plc = Arc::new(Mutex::new(RingBufferPlc {
+            index: 2,
+            disconnect: false,
+        }));
+        let target = spawn_plc(plc.clone()).await;
+        let session = HostLinkSession::get(&target).unwrap();
+        let mut reader = RingBufferReader::new(config());
+        assert!(reader.read_new_samples(&session).await.unwrap().is_empty());
+
+        // 切断中にPLCが書き込んだサンプルは再接続後に読み出す
+        {
+            let mut plc = plc.lock().unwrap();
+            plc.index = 5;
+            plc.disconnect = true;
+        }
+        assert!(reader.read_new_samples(&session).await.is_err());
+        plc.lock().unwrap().index = 7;
+        let points = reader.read_new_samples(&session).await.unwrap();
+        let expected: Vec<(String, i64)> =
+            (102..107).map(|t| ("buffered".to_string(), t)).collect();
+        assert_eq!(parse(&points), expected);
+    }
+
+    #[tokio::test]
+    async fn record_loss_after_long_outage() {
+        let plc = Arc::new(Mutex::new(RingBufferPlc {
+            index: 2,
+            disconnect: false,
+        }));
+        let target = spawn_plc(plc.clone()).await;
+        let session = HostLinkSession::get(&target).unwrap();
+        let mut reader = RingBufferReader::new(config());
+        reader.read_new_samples(&session).await.unwrap();
+
+        // バッファ1周(1秒)を超えて読み出せなかった場合は上書きされた分を欠損にする
+        plc.lock().unwrap().index = 6;
+        reader.last_read_time -= chrono::Duration::seconds(3);
+        let points = parse(&reader.read_new_samples(&session).await.unwrap());
+        assert_eq!(points[0].0, "buffered_loss");
+        assert!((26..=27).contains(&points[0].1));
+        let values: Vec<i64> = points[1..].iter().map(|t| t.1).collect();
+        assert_eq!(values, vec![102, 103, 104, 105]);
+    }
+}
@@ -7,6 +7,7 @@ use super::data_manager::DemoMachineDataManager;
 use super::interface::DemoMachineInterface;
 
 pub struct DemoMachineCollector {
+    config: DemoMachineConfig,
     data_sender: mpsc::Sender<Vec<DataPoint>>,
     interface: DemoMachineInterface,
     manager: Option<DemoMachineDataManager>,
@@ -17,8 +18,9 @@ impl DemoMachineCollector {
         data_sender: mpsc::Sender<Vec<DataPoint>>,
     ) -> anyhow::Result<Self> {
         let config = DemoMachineConfig::create_from_env()?;
-        let interface = DemoMachineInterface::create_from_config(config).await?;
+        let interface = DemoMachineInterface::create_from_config(config.clone()).await?;
         Ok(Self {
+            config,
             data_sender,
             interface,
             manager: None,
@@ -31,8 +33,10 @@ impl DemoMachineCollector {
         }
         let (point_sender, point_receiver) = mpsc::channel(32);
         let data_sender = self.data_sender.clone();
-        let manager = DemoMachineDataManager::create(data_sender, point_receiver)?;
-        self.interface.start_moniter(point_sender).await?;
+        let manager = DemoMachineDataManager::create(data_sender, point_receiver, &self.config)?;
+        self.interface
+            .start_moniter(point_sender, self.data_sender.clone())
+            .await?;
         self.manager = Some(manager);
         Ok(())
     }
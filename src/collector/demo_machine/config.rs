@@ -1,48 +1,216 @@
-use super::data_manager::SET_MONITER_COMMAND;
+use super::data_manager::{MONITER_DEVICES, SENSOR_FIELDS};
+use super::rate_group::RateGroup;
+use crate::collector::host_link::{Device, DeviceMap, HostLinkTarget, ReadPlan};
+use crate::collector::scheduler::OverrunPolicy;
+use crate::processing::aggregate::AggregateConfig;
+use crate::processing::anomaly::AnomalyConfig;
+use crate::processing::counter::CounterConfig;
+use crate::processing::deadband::DeadbandConfig;
+use crate::processing::swinging_door::SwingingDoorConfig;
+use crate::processing::tick_monitor::TickMonitorConfig;
 
 // 機械稼働時は50msec間隔
 const MONITOR_INTERVAL: u64 = 50;
 // 機械停止時時は1000msec間隔
 const INTERVAL_WHEN_MACHINE_STOP: u64 = 5000;
+// リングバッファから読み出す場合、高速なデータはバッファから取得するのでモニタは低頻度でよい
+const MONITOR_INTERVAL_WHEN_BUFFERED: u64 = 1000;
+const DEFAULT_RING_BUFFER_READ_INTERVAL: u64 = 500;
+// DM1000,DM1100は40ms毎に加算
+const DEFAULT_TICK_PERIOD: f64 = 40.0;
+const TICK_REPORT_INTERVAL_SEC: i64 = 10;
+// 積算カウンタとして増分を監視するデバイス(MONITER_DEVICESの位置, フィールド名)
+const COUNTER_DEVICES: &[(usize, &str)] = &[(0, "dm1000"), (7, "dm1100")];
 const CHECK_COMMAND: &[u8] = b"?K\r";
 const CHECK_RESPONSE: &str = "55";
-const MONITOR_READOUT_COMMAND: &[u8] = b"MWR\r";
 
 #[derive(Clone)]
 pub struct DemoMachineConfig {
-    address: String,
+    target: HostLinkTarget,
     check_command: Vec<u8>,
     check_response: String,
-    set_moniter_command: Vec<u8>,
-    monitor_readout_command: Vec<u8>,
+    // DemoMachineReadMode(auto/monitor/block)とDemoMachineBlockReadGapで読み出し方法を指定
+    read_plan: ReadPlan,
+    // アラームやインターロックなどのビットデバイス ex) heater_alarm=MR200
+    status_devices: DeviceMap,
     monitor_interval: u64,
     interval_when_machine_stop: u64,
+    // DemoMachineOverrunPolicy(skip/burst) : 読み出しが周期を超えた場合の動作
+    overrun_policy: OverrunPolicy,
+    // 同じ接続で別周期に読み出すデバイスのグループ
+    rate_groups: Vec<RateGroup>,
+    ring_buffer: Option<RingBufferConfig>,
+    // DemoMachineTickDevice : 診断に使うティックレジスタ(MONITER_DEVICESのいずれか) ex) DM1000.U
+    // DemoMachineTickPeriod : ティックレジスタの加算周期(msec)
+    tick_monitor: Option<(usize, TickMonitorConfig)>,
+    // DemoMachineCounterMaxStep : 1回の受信で増えうる最大数
+    counters: Vec<(usize, String, CounterConfig)>,
+    // DemoMachineAggregateFields等でセンサーデータを区間毎に集計する
+    aggregate: Option<AggregateConfig>,
+    // DemoMachineDeadbandFields等でセンサーデータを変化時のみ送信する
+    deadband: Option<DeadbandConfig>,
+    // DemoMachineSwingingDoorFields等でセンサーデータをスイングドア圧縮する
+    swinging_door: Option<SwingingDoorConfig>,
+    // DemoMachineAnomalyFields等でセンサーデータの異常を検知する
+    anomaly: Option<AnomalyConfig>,
+}
+
+// PLC側のリングバッファ
+// DemoMachineRingBufferIndexDevice  : 次に書き込む位置(0～長さ-1) ex) DM2000.U
+// DemoMachineRingBufferStart        : バッファ先頭のデバイス ex) DM2010.U
+// DemoMachineRingBufferLength       : バッファのサンプル数
+// DemoMachineRingBufferFields       : 1サンプルのワードのフィールド名(カンマ区切り) ex) dm_1000,tempureture_1
+// DemoMachineRingBufferSamplePeriod : PLCのサンプリング周期(msec)
+// DemoMachineRingBufferReadInterval : 読み出し周期(msec)
+#[derive(Clone, Debug)]
+pub struct RingBufferConfig {
+    pub index_device: Device,
+    pub start_device: Device,
+    pub length: u32,
+    pub fields: Vec<String>,
+    pub sample_period: u64,
+    pub read_interval: u64,
+}
+
+impl RingBufferConfig {
+    fn create_from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(index_device) = std::env::var("DemoMachineRingBufferIndexDevice") else {
+            return Ok(None);
+        };
+        let index_device = Device::parse(&index_device)?;
+        let start_device = Device::parse(&std::env::var("DemoMachineRingBufferStart")?)?;
+        if start_device.is_bit() {
+            anyhow::bail!("DemoMachineRingBufferStartはワードデバイスを指定")
+        }
+        let length: u32 = std::env::var("DemoMachineRingBufferLength")?.parse()?;
+        if length == 0 {
+            anyhow::bail!("DemoMachineRingBufferLengthが0")
+        }
+        let fields: Vec<String> = std::env::var("DemoMachineRingBufferFields")?
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if fields.is_empty() {
+            anyhow::bail!("DemoMachineRingBufferFieldsが空")
+        }
+        let sample_period: u64 = std::env::var("DemoMachineRingBufferSamplePeriod")?.parse()?;
+        let read_interval = match std::env::var("DemoMachineRingBufferReadInterval") {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_RING_BUFFER_READ_INTERVAL,
+        };
+
+        Ok(Some(Self {
+            index_device,
+            start_device,
+            length,
+            fields,
+            sample_period,
+            read_interval,
+        }))
+    }
 }
 impl DemoMachineConfig {
     pub fn create_from_env() -> anyhow::Result<Self> {
-        let address = std::env::var("DemoMachineStatusConfigAddress")?;
+        let target = HostLinkTarget::create_from_env("DemoMachine")?;
 
         let check_command: Vec<u8> = CHECK_COMMAND.into();
         let check_response = CHECK_RESPONSE.into();
 
-        let set_moniter_command: Vec<u8> = SET_MONITER_COMMAND.into();
-        let monitor_readout_command: Vec<u8> = MONITOR_READOUT_COMMAND.into();
+        let status_devices = DeviceMap::create_from_env("DemoMachineStatusDevices")?;
+        let mut devices = MONITER_DEVICES
+            .iter()
+            .map(|t| Device::parse(t))
+            .collect::<anyhow::Result<Vec<Device>>>()?;
+        devices.extend(status_devices.devices());
+        let read_plan = ReadPlan::create_from_env(&devices, "DemoMachine")?;
 
-        let monitor_interval = MONITOR_INTERVAL;
+        let tick_monitor = match std::env::var("DemoMachineTickDevice") {
+            Ok(t) => {
+                let device = Device::parse(&t)?;
+                let position = devices[..MONITER_DEVICES.len()]
+                    .iter()
+                    .position(|t| *t == device);
+                let Some(position) = position else {
+                    anyhow::bail!("DemoMachineTickDeviceはモニタ対象のデバイスを指定:{}", t)
+                };
+                let tick_period = match std::env::var("DemoMachineTickPeriod") {
+                    Ok(t) => t.parse()?,
+                    Err(_) => DEFAULT_TICK_PERIOD,
+                };
+                let config = TickMonitorConfig {
+                    tick_period,
+                    bit_width: device.width() * 16,
+                    report_interval: TICK_REPORT_INTERVAL_SEC,
+                };
+                Some((position, config))
+            }
+            Err(_) => None,
+        };
+
+        let mut counters = Vec::new();
+        for (position, name) in COUNTER_DEVICES {
+            let bit_width = devices[*position].width() * 16;
+            let config = CounterConfig::create_from_env("DemoMachine", bit_width)?;
+            counters.push((*position, name.to_string(), config));
+        }
+
+        let aggregate = AggregateConfig::create_from_env("DemoMachine", "demo_machine")?;
+        if let Some(aggregate) = aggregate.as_ref() {
+            check_sensor_fields("DemoMachineAggregateFields", &aggregate.get_fields())?;
+        }
+        let deadband = DeadbandConfig::create_from_env("DemoMachine")?;
+        if let Some(deadband) = deadband.as_ref() {
+            check_sensor_fields("DemoMachineDeadbandFields", &deadband.get_fields())?;
+        }
+        let swinging_door = SwingingDoorConfig::create_from_env("DemoMachine")?;
+        if let Some(swinging_door) = swinging_door.as_ref() {
+            let fields = swinging_door.get_fields();
+            check_sensor_fields("DemoMachineSwingingDoorFields", &fields)?;
+            // 1つのフィールドに両方を適用すると圧縮の誤差が分からなくなる
+            let deadband_fields = deadband
+                .as_ref()
+                .map(|t| t.get_fields())
+                .unwrap_or_default();
+            if let Some(field) = fields.iter().find(|t| deadband_fields.contains(t)) {
+                anyhow::bail!("不感帯とスイングドア圧縮の両方を指定:{}", field)
+            }
+        }
+        let anomaly = AnomalyConfig::create_from_env("DemoMachine")?;
+        if let Some(anomaly) = anomaly.as_ref() {
+            check_sensor_fields("DemoMachineAnomalyFields", &anomaly.get_fields())?;
+        }
+
+        let ring_buffer = RingBufferConfig::create_from_env()?;
+        let monitor_interval = match ring_buffer {
+            Some(_) => MONITOR_INTERVAL_WHEN_BUFFERED,
+            None => MONITOR_INTERVAL,
+        };
         let interval_when_machine_stop = INTERVAL_WHEN_MACHINE_STOP;
+        let overrun_policy = OverrunPolicy::create_from_env("DemoMachineOverrunPolicy")?;
+        let rate_groups = RateGroup::create_from_env()?;
 
         Ok(Self {
-            address,
+            target,
             check_command,
             check_response,
-            set_moniter_command,
-            monitor_readout_command,
+            read_plan,
+            status_devices,
             monitor_interval,
             interval_when_machine_stop,
+            overrun_policy,
+            rate_groups,
+            ring_buffer,
+            tick_monitor,
+            counters,
+            aggregate,
+            deadband,
+            swinging_door,
+            anomaly,
         })
     }
-    pub fn get_address(&self) -> String {
-        self.address.to_owned()
+    pub fn get_target(&self) -> HostLinkTarget {
+        self.target.to_owned()
     }
 
     pub fn get_check_command(&self) -> Vec<u8> {
@@ -52,11 +220,11 @@ impl DemoMachineConfig {
     pub fn get_check_response(&self) -> String {
         self.check_response.to_owned()
     }
-    pub fn get_set_moniter_command(&self) -> Vec<u8> {
-        self.set_moniter_command.to_owned()
+    pub fn get_read_plan(&self) -> ReadPlan {
+        self.read_plan.to_owned()
     }
-    pub fn get_monitor_readout_command(&self) -> Vec<u8> {
-        self.monitor_readout_command.to_owned()
+    pub fn get_status_devices(&self) -> &DeviceMap {
+        &self.status_devices
     }
     pub fn get_monitor_interval(&self) -> u64 {
         self.monitor_interval.to_owned()
@@ -64,4 +232,41 @@ impl DemoMachineConfig {
     pub fn get_interval_when_machine_stop(&self) -> u64 {
         self.interval_when_machine_stop.to_owned()
     }
+    pub fn get_overrun_policy(&self) -> OverrunPolicy {
+        self.overrun_policy
+    }
+    pub fn get_rate_groups(&self) -> Vec<RateGroup> {
+        self.rate_groups.to_owned()
+    }
+    pub fn get_ring_buffer(&self) -> Option<RingBufferConfig> {
+        self.ring_buffer.to_owned()
+    }
+    pub fn get_tick_monitor(&self) -> Option<(usize, TickMonitorConfig)> {
+        self.tick_monitor.to_owned()
+    }
+    pub fn get_counters(&self) -> Vec<(usize, String, CounterConfig)> {
+        self.counters.to_owned()
+    }
+    pub fn get_aggregate(&self) -> Option<AggregateConfig> {
+        self.aggregate.to_owned()
+    }
+    pub fn get_deadband(&self) -> Option<DeadbandConfig> {
+        self.deadband.to_owned()
+    }
+    pub fn get_swinging_door(&self) -> Option<SwingingDoorConfig> {
+        self.swinging_door.to_owned()
+    }
+    pub fn get_anomaly(&self) -> Option<AnomalyConfig> {
+        self.anomaly.to_owned()
+    }
+}
+
+// 加工するフィールドがセンサーデータにあるか確認する
+fn check_sensor_fields(key: &str, fields: &[String]) -> anyhow::Result<()> {
+    for field in fields {
+        if !SENSOR_FIELDS.iter().any(|(name, _)| name == field) {
+            anyhow::bail!("{}はセンサーデータのフィールドを指定:{}", key, field)
+        }
+    }
+    Ok(())
 }
@@ -1,13 +1,26 @@
 use chrono::{DateTime, Local};
 use influxdb2::models::DataPoint;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::task::JoinHandle;
 
-//
-pub const SET_MONITER_COMMAND: &[u8] =
-    b"MWS DM1000.U DM1001.L DM1002.U DM1003.U DM1004.U DM1008.U DM1009.U DM1100.U\r";
+use super::config::DemoMachineConfig;
+use crate::alarm;
+use crate::collector::host_link::{DeviceMap, DeviceValue};
+use crate::processing::aggregate::Aggregator;
+use crate::processing::anomaly::AnomalyDetector;
+use crate::processing::counter::CounterTracker;
+use crate::processing::deadband::DeadbandFilter;
+use crate::processing::swinging_door::SwingingDoor;
+use crate::processing::tick_monitor::TickMonitor;
+use crate::shift_calendar;
+
+// モニタ登録するデバイス
+// DemoMachineStatusDevicesで設定したビットデバイスはこの後ろに追加される
+pub const MONITER_DEVICES: &[&str] = &[
+    "DM1000.U", "DM1001.L", "DM1002.U", "DM1003.U", "DM1004.U", "DM1008.U", "DM1009.U", "DM1100.U",
+];
 
 // "40137 +0000000000 00000 00000 00000 00000 00000 34601"
 // DM1002を稼働状況にする　⇒　DemoMachineReceiveData::create()で確認している
@@ -17,6 +30,10 @@ pub const SET_MONITER_COMMAND: &[u8] =
 // DM1003：80ms毎and稼働時に加算　センサデータ
 // DM1004：80ms毎and停止時に加算
 
+// センサーデータのフィールド名とMONITER_DEVICESの位置
+pub const SENSOR_FIELDS: &[(&str, usize)] = &[("tempureture_1", 2), ("tempureture_2", 3)];
+
+const DATA_LENGTH: usize = 8;
 const RESPONSE_LENGTH: usize = 53;
 
 // sensor data 50ms × 50chunk = 2.5s
@@ -38,8 +55,9 @@ impl DemoMachineDataManager {
     pub fn create(
         data_sender: mpsc::Sender<Vec<DataPoint>>,
         mut point_receiver: mpsc::Receiver<DemoMachineReceiveData>,
+        config: &DemoMachineConfig,
     ) -> anyhow::Result<Self> {
-        let mut state = DemoMachineDataHundler::create(data_sender)?;
+        let mut state = DemoMachineDataHundler::create(data_sender, config)?;
 
         let thread = tokio::spawn(async move {
             while let Some(data) = point_receiver.recv().await {
@@ -71,6 +89,7 @@ impl DemoMachineDataManager {
 struct DemoMachineDataHundler {
     sender: mpsc::Sender<Vec<DataPoint>>,
     last_machine_status: DemoMachineStatus,
+    last_device_values: Vec<(String, DeviceValue)>,
     send_chunk_size: usize,
 
     // 保存周期の長い稼働情報　5s毎のデータを保存
@@ -81,28 +100,95 @@ struct DemoMachineDataHundler {
 
     // 全データを保存
     // configのintervalに等しい
+    // 集計結果もここに入れて一緒に送信する
     sensor_data: Vec<DataPoint>,
+    // センサーデータの区間毎の集計
+    aggregator: Option<Aggregator>,
+    // falseの場合は集計結果のみ送信する
+    keep_raw_sensor_data: bool,
+    // 生データは不感帯を超えて変化したフィールドのみ送信する
+    deadband: Option<DeadbandFilter>,
+    // スイングドア圧縮するフィールドは保存する点のみ、その時刻で送信する
+    swinging_door: Option<SwingingDoor>,
+    // センサーデータの異常検知。停止中も判定し、ベースラインは稼働中のみ学習する
+    anomaly: Option<AnomalyDetector>,
     // last_sensor_data_time: DateTime<Local>,
+
+    // ティックレジスタの位置と診断
+    tick_monitor: Option<(usize, TickMonitor)>,
+    // 積算カウンタの位置と桁あふれを補正した積算値
+    counters: Vec<(usize, CounterTracker)>,
 }
 
 impl DemoMachineDataHundler {
-    fn create(sender: mpsc::Sender<Vec<DataPoint>>) -> anyhow::Result<Self> {
+    fn create(
+        sender: mpsc::Sender<Vec<DataPoint>>,
+        config: &DemoMachineConfig,
+    ) -> anyhow::Result<Self> {
         let dt = Local::now();
+        let tick_monitor = config
+            .get_tick_monitor()
+            .map(|(position, config)| (position, TickMonitor::new(config)));
+        let counters = config
+            .get_counters()
+            .into_iter()
+            .map(|(position, name, config)| (position, CounterTracker::new(&name, config)))
+            .collect();
+        let aggregate = config.get_aggregate();
+        let keep_raw_sensor_data = aggregate.as_ref().map(|t| t.get_keep_raw()).unwrap_or(true);
         // TODO:定数はConfigに
         Ok(Self {
             sender,
             last_machine_status: DemoMachineStatus::Stopping,
+            last_device_values: Vec::new(),
             send_chunk_size: SEND_CHUNK_SIZE,
             operating_data: Vec::<DataPoint>::new(),
             last_operating_data_time: dt,
             operating_data_interval_sec: OPERATING_DATA_INTERVAL_SEC,
             sensor_data: Vec::<DataPoint>::new(),
+            aggregator: aggregate.map(Aggregator::new),
+            keep_raw_sensor_data,
+            deadband: config.get_deadband().map(DeadbandFilter::new),
+            swinging_door: config.get_swinging_door().map(SwingingDoor::new),
+            anomaly: config.get_anomaly().map(AnomalyDetector::new),
             // last_sensor_data_time: dt,
+            tick_monitor,
+            counters,
         })
     }
 
-    async fn recceive_response(&mut self, data: DemoMachineReceiveData) -> anyhow::Result<()> {
+    async fn recceive_response(&mut self, mut data: DemoMachineReceiveData) -> anyhow::Result<()> {
         // debug!("recceive_response");
+        // アラーム等のビットデバイスは変化時のみ送信
+        let device_values = std::mem::take(&mut data.device_values);
+        if device_values != self.last_device_values {
+            if let Some(point) = data.parse_device_status(&device_values)? {
+                self.sender.send(vec![point]).await?;
+            }
+            self.last_device_values = device_values;
+        }
+        // ポーリングの取りこぼし・揺らぎの診断
+        if let Some((position, monitor)) = self.tick_monitor.as_mut() {
+            if data.get_status() != self.last_machine_status {
+                monitor.reset();
+            }
+            let tick = data.get_value(*position)?;
+            if let Some(diagnostics) = monitor.push(data.get_dt(), tick) {
+                let point = diagnostics.to_data_point("demo_machine")?;
+                self.sender.send(vec![point]).await?;
+            }
+        }
+        // 停止中も加算されるカウンタなので受信毎に増分を求める
+        for (position, counter) in self.counters.iter_mut() {
+            counter.push(data.get_value(*position)?, false);
+        }
+        self.detect_anomaly(&data).await?;
+        let values: Vec<(&str, f64)> = data
+            .get_sensor_values()?
+            .into_iter()
+            .map(|(name, value)| (name, value as f64))
+            .collect();
+        alarm::update("demo_machine", &values).await?;
         // 5秒毎にデータ収集してる
         #[allow(unreachable_patterns)]
         match self.last_machine_status {
@@ -122,6 +208,47 @@ impl DemoMachineDataHundler {
         }
         Ok(())
     }
+    // 異常の開始・終了はすぐに送信する
+    async fn detect_anomaly(&mut self, data: &DemoMachineReceiveData) -> anyhow::Result<()> {
+        let Some(detector) = self.anomaly.as_mut() else {
+            return Ok(());
+        };
+        let dt = data.get_dt();
+        let values: Vec<(&str, f64)> = data
+            .get_sensor_values()?
+            .into_iter()
+            .map(|(name, value)| (name, value as f64))
+            .collect();
+        let learn = data.get_status() == DemoMachineStatus::Running;
+        let mut points = Vec::new();
+        for event in detector.push(dt, &values, learn) {
+            match (event.is_active, event.relearned) {
+                (true, _) => warn!(
+                    "センサーデータの異常:{}:{}:{}",
+                    event.field,
+                    event.kind.as_str(),
+                    event.score
+                ),
+                (false, true) => info!(
+                    "センサーデータの異常が続いたためベースラインを学習し直す:{}:{}",
+                    event.field, event.value
+                ),
+                (false, false) => info!(
+                    "センサーデータの異常が終了:{}:{}",
+                    event.field,
+                    event.kind.as_str()
+                ),
+            }
+            points.push(event.to_data_point("demo_machine")?);
+        }
+        if let Some(scores) = detector.report(dt) {
+            points.push(scores.to_data_point("demo_machine")?);
+        }
+        if !points.is_empty() {
+            self.sender.send(points).await?;
+        }
+        Ok(())
+    }
     // 内部関数
     // 稼働状態での分岐
     async fn recceive_in_stopping(&mut self, data: DemoMachineReceiveData) -> anyhow::Result<()> {
@@ -148,7 +275,8 @@ impl DemoMachineDataHundler {
         if self.shoud_set_operating_data(data.get_dt()) {
             self.set_operation_data(data).await?;
         }
-        // 保持しているセンサー情報を一旦送信
+        // 集計中の区間・圧縮で保留中の点と保持しているセンサー情報を一旦送信
+        self.flush_sensor_stages()?;
         self.send_sensor_data().await?;
         Ok(())
     }
@@ -178,7 +306,7 @@ impl DemoMachineDataHundler {
     // DemoMachineReceiveDataを消費する
     async fn set_operation_data(&mut self, data: DemoMachineReceiveData) -> anyhow::Result<()> {
         let new_dt = data.get_dt();
-        let operation_point = data.parse_operation_data()?;
+        let operation_point = data.parse_operation_data(&mut self.counters)?;
         self.operating_data.push(operation_point);
         self.last_operating_data_time = new_dt;
 
@@ -188,8 +316,7 @@ impl DemoMachineDataHundler {
         Ok(())
     }
     async fn set_sensor_data(&mut self, data: DemoMachineReceiveData) -> anyhow::Result<()> {
-        let sensor_point = data.parse_sensor_data()?;
-        self.sensor_data.push(sensor_point);
+        self.push_sensor_data(&data)?;
 
         if self.sensor_data.len() >= self.send_chunk_size {
             self.send_sensor_data().await?;
@@ -202,12 +329,11 @@ impl DemoMachineDataHundler {
         data: DemoMachineReceiveData,
     ) -> anyhow::Result<()> {
         let new_dt = data.get_dt();
-        let operation_point = data.parse_operation_data()?;
+        let operation_point = data.parse_operation_data(&mut self.counters)?;
         self.operating_data.push(operation_point);
         self.last_operating_data_time = new_dt;
 
-        let sensor_point = data.parse_sensor_data()?;
-        self.sensor_data.push(sensor_point);
+        self.push_sensor_data(&data)?;
 
         if self.operating_data.len() >= self.send_chunk_size {
             self.send_operating_data().await?;
@@ -219,6 +345,78 @@ impl DemoMachineDataHundler {
         Ok(())
     }
 
+    fn push_sensor_data(&mut self, data: &DemoMachineReceiveData) -> anyhow::Result<()> {
+        let dt = data.get_dt();
+        let mut values = data.get_sensor_values()?;
+        if let Some(aggregator) = self.aggregator.as_mut() {
+            let values: Vec<(&str, f64)> = values
+                .iter()
+                .map(|(name, value)| (*name, *value as f64))
+                .collect();
+            let points = aggregator.push(dt, &values)?;
+            self.sensor_data.extend(points);
+        }
+        if !self.keep_raw_sensor_data {
+            return Ok(());
+        }
+        // スイングドア圧縮は間引く前の値で判定するため不感帯より先に通す
+        let mut archived = Vec::new();
+        if let Some(door) = self.swinging_door.as_mut() {
+            values.retain(|(name, value)| {
+                if !door.contains(name) {
+                    return true;
+                }
+                for (archived_dt, value) in door.push(dt, name, *value as f64) {
+                    archived.push((name.to_string(), archived_dt, value));
+                }
+                false
+            });
+            if let Some(stats) = door.report(dt) {
+                self.sensor_data.push(stats.to_data_point("demo_machine")?);
+            }
+        }
+        self.push_archived_points(archived)?;
+        if let Some(deadband) = self.deadband.as_mut() {
+            values.retain(|(name, value)| deadband.pass(dt, name, *value as f64));
+            if let Some(stats) = deadband.report(dt) {
+                self.sensor_data.push(stats.to_data_point("demo_machine")?);
+            }
+        }
+        if !values.is_empty() {
+            let sensor_point = data.parse_sensor_data(&values)?;
+            self.sensor_data.push(sensor_point);
+        }
+        Ok(())
+    }
+    // スイングドア圧縮で保存する点
+    // センサーデータは整数なので元の型に戻す
+    fn push_archived_points(
+        &mut self,
+        archived: Vec<(String, DateTime<Local>, f64)>,
+    ) -> anyhow::Result<()> {
+        for (name, dt, value) in archived {
+            let point = make_sensor_point(dt, &[(name.as_str(), value as i64)])?;
+            self.sensor_data.push(point);
+        }
+        Ok(())
+    }
+    // 稼働停止等で受信が途切れる場合に呼ぶ
+    // 稼働再開後の最初のセンサーデータは不感帯に関わらず送信する
+    fn flush_sensor_stages(&mut self) -> anyhow::Result<()> {
+        if let Some(aggregator) = self.aggregator.as_mut() {
+            let points = aggregator.flush()?;
+            self.sensor_data.extend(points);
+        }
+        if let Some(deadband) = self.deadband.as_mut() {
+            deadband.reset();
+        }
+        if let Some(door) = self.swinging_door.as_mut() {
+            let archived = door.flush();
+            self.push_archived_points(archived)?;
+        }
+        Ok(())
+    }
+
     // send data
     // NOTE:Drop時に実行する
     async fn send_operating_data(&mut self) -> anyhow::Result<()> {
@@ -247,6 +445,7 @@ impl Drop for DemoMachineDataHundler {
                 if !self.operating_data.is_empty() {
                     self.send_operating_data().await.unwrap();
                 }
+                self.flush_sensor_stages().unwrap();
                 if !self.sensor_data.is_empty() {
                     self.send_sensor_data().await.unwrap();
                 }
@@ -260,9 +459,25 @@ pub struct DemoMachineReceiveData {
     dt: DateTime<Local>,
     data: String,
     status: DemoMachineStatus,
+    device_values: Vec<(String, DeviceValue)>,
 }
 impl DemoMachineReceiveData {
-    pub fn create(dt: DateTime<Local>, data: String) -> anyhow::Result<Self> {
+    pub fn create(
+        dt: DateTime<Local>,
+        mut data: String,
+        status_devices: &DeviceMap,
+    ) -> anyhow::Result<Self> {
+        // MONITER_DEVICESの後ろに追加したデバイスを切り離す
+        let mut device_values = Vec::new();
+        if !status_devices.is_empty() {
+            let res: Vec<&str> = data.split(' ').collect();
+            if res.len() != DATA_LENGTH + status_devices.len() {
+                anyhow::bail!("データ点数の異常:{:?}", data)
+            }
+            device_values = status_devices.decode(&res[DATA_LENGTH..])?;
+            data = res[..DATA_LENGTH].join(" ");
+        }
+
         // TODO:Stringが短い場合(受信データが不正な場合)のエラーハンドリング
         if data.len() != RESPONSE_LENGTH {
             anyhow::bail!(
@@ -281,7 +496,12 @@ impl DemoMachineReceiveData {
             _ => DemoMachineStatus::Stopping,
         };
         // debug!("{}", &data[18..22]);
-        Ok(Self { dt, data, status })
+        Ok(Self {
+            dt,
+            data,
+            status,
+            device_values,
+        })
     }
 
     pub fn get_status(&self) -> DemoMachineStatus {
@@ -294,7 +514,10 @@ impl DemoMachineReceiveData {
     //     (self.dt, self.data)
     // }
 
-    fn parse_operation_data(&self) -> anyhow::Result<DataPoint> {
+    fn parse_operation_data(
+        &self,
+        counters: &mut [(usize, CounterTracker)],
+    ) -> anyhow::Result<DataPoint> {
         let time = match self.dt.timestamp_nanos_opt() {
             Some(t) => t,
             None => anyhow::bail!("parse_operation_dataでエラー"),
@@ -319,8 +542,17 @@ impl DemoMachineReceiveData {
             None => anyhow::bail!("parse_operation_dataでエラー"),
         };
 
+        // 積算値と桁あふれ・異常値の回数
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder("demo_machine"), self.dt);
+        for (_, counter) in counters.iter_mut() {
+            let name = counter.get_name();
+            builder = builder.field(format!("{}_total", name), counter.get_total() as i64);
+            builder = counter.take_flags().add_fields(builder, &name);
+        }
+
         // bool,i64,f64,String,&strが可能
-        let operation_point = DataPoint::builder("demo_machine")
+        let operation_point = builder
             .tag("info_type", "operation")
             .field("is_running", is_running)
             .field("dm_1100", dm_1100)
@@ -330,37 +562,62 @@ impl DemoMachineReceiveData {
 
         Ok(operation_point)
     }
-    fn parse_sensor_data(&self) -> anyhow::Result<DataPoint> {
+    // MONITER_DEVICESのposition番目の値
+    fn get_value(&self, position: usize) -> anyhow::Result<i64> {
+        match self.data.split(' ').nth(position) {
+            Some(t) => Ok(t.parse()?),
+            None => anyhow::bail!("get_valueでエラー:{}", position),
+        }
+    }
+    fn parse_device_status(
+        &self,
+        device_values: &[(String, DeviceValue)],
+    ) -> anyhow::Result<Option<DataPoint>> {
+        if device_values.is_empty() {
+            return Ok(None);
+        }
         let time = match self.dt.timestamp_nanos_opt() {
             Some(t) => t,
-            None => anyhow::bail!("parse_operation_dataでエラー"),
+            None => anyhow::bail!("parse_device_statusでエラー"),
         };
-
-        let res: Vec<&str> = self.data.split(' ').collect();
-
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder("demo_machine"), self.dt)
+                .tag("info_type", "device_status");
+        for (name, value) in device_values {
+            builder = value.add_field(builder, name);
+        }
+        Ok(Some(builder.timestamp(time).build()?))
+    }
+    // SENSOR_FIELDSの値
+    fn get_sensor_values(&self) -> anyhow::Result<Vec<(&'static str, i64)>> {
+        SENSOR_FIELDS
+            .iter()
+            .map(|(name, position)| Ok((*name, self.get_value(*position)?)))
+            .collect()
+    }
+    // 送信するフィールドのみ渡す
+    fn parse_sensor_data(&self, values: &[(&str, i64)]) -> anyhow::Result<DataPoint> {
         // センサーデータは稼働中のみ取得するので不要
         // let is_running = matches!(self.status, DemoMachineStatus::Running);
+        make_sensor_point(self.dt, values)
+    }
+}
 
-        let dm_1003: i64 = match res.get(2) {
-            Some(t) => t.parse()?,
-            None => anyhow::bail!("parse_sensor_dataでエラー"),
-        };
-
-        let dm_1004: i64 = match res.get(3) {
-            Some(t) => t.parse()?,
-            None => anyhow::bail!("parse_sensor_dataでエラー"),
-        };
-
-        // bool,i64,f64,String,&strが可能
-        let sensor_point = DataPoint::builder("demo_machine")
-            .tag("info_type", "sensor")
-            .field("tempureture_1", dm_1003)
-            .field("tempureture_2", dm_1004)
-            .timestamp(time)
-            .build()?;
-
-        Ok(sensor_point)
+fn make_sensor_point(dt: DateTime<Local>, values: &[(&str, i64)]) -> anyhow::Result<DataPoint> {
+    let time = match dt.timestamp_nanos_opt() {
+        Some(t) => t,
+        None => anyhow::bail!("make_sensor_pointでエラー"),
+    };
+
+    // bool,i64,f64,String,&strが可能
+    let mut builder = shift_calendar::add_shift_tag(DataPoint::builder("demo_machine"), dt)
+        .tag("info_type", "sensor");
+    for (name, value) in values {
+        builder = builder.field(*name, *value);
     }
+    let sensor_point = builder.timestamp(time).build()?;
+
+    Ok(sensor_point)
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -1,31 +1,29 @@
 use chrono::Local;
+use influxdb2::models::DataPoint;
 use log::{debug, warn};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::task::JoinHandle;
-use tokio::time::{Duration, Instant};
 
+use super::buffered::BufferedAcquisitionThread;
 use super::config::DemoMachineConfig;
 use super::data_manager::DemoMachineReceiveData;
 use super::data_manager::DemoMachineStatus;
+use crate::collector::host_link::{DisconnectReason, HostLinkSession};
+use crate::collector::scheduler;
+use crate::collector::scheduler::PollScheduler;
 
 pub struct DemoMachineInterface {
     config: DemoMachineConfig,
+    session: HostLinkSession,
     thread: Option<CollecterThread>,
+    buffered_thread: Option<BufferedAcquisitionThread>,
 }
 impl DemoMachineInterface {
     pub async fn create_from_config(config: DemoMachineConfig) -> anyhow::Result<Self> {
         // コンフィグからインターフェイスを作成。動作チェック
-        let mut stream = TcpStream::connect(&config.get_address()).await?;
-        stream.write_all(&config.get_check_command()).await?;
-        let mut buf = [0; 4];
-        let n = stream.read(&mut buf).await?;
-        let res = std::str::from_utf8(&buf[..n])
-            .unwrap()
-            .trim_end_matches("\r\n")
-            .to_string();
+        let session = HostLinkSession::get(&config.get_target())?;
+        let res = session.request(&config.get_check_command()).await?;
         debug!("チェックコマンドのレスポンス:{:?}", res);
 
         if res == config.get_check_response() {
@@ -37,19 +35,33 @@ impl DemoMachineInterface {
 
         Ok(Self {
             config,
+            session,
             thread: None,
+            buffered_thread: None,
         })
     }
 
     pub async fn start_moniter(
         &mut self,
         tx: mpsc::Sender<DemoMachineReceiveData>,
+        data_sender: mpsc::Sender<Vec<DataPoint>>,
     ) -> anyhow::Result<()> {
         if self.thread.is_some() {
             anyhow::bail!("already started moniter in DemoMachineInterface::start_moniter")
         }
-        let collecter_thread = CollecterThread::start(tx, self.config.clone()).await?;
+        let collecter_thread = CollecterThread::start(
+            tx,
+            data_sender.clone(),
+            self.session.clone(),
+            self.config.clone(),
+        );
         self.thread = Some(collecter_thread);
+        // リングバッファが設定されている場合は高速なデータをバッファから読み出す
+        if let Some(ring_buffer) = self.config.get_ring_buffer() {
+            let buffered_thread =
+                BufferedAcquisitionThread::start(&self.config, ring_buffer, data_sender);
+            self.buffered_thread = Some(buffered_thread);
+        }
         debug!("DemoMachineInterface collect start");
         Ok(())
     }
@@ -60,6 +72,9 @@ impl DemoMachineInterface {
         } else {
             anyhow::bail!("not start collect in DemoMachineInterface::stop_moniter")
         }
+        if let Some(thread) = self.buffered_thread.take() {
+            thread.stop().await?;
+        }
         debug!("DemoMachineInterface collect stop");
         Ok(())
     }
@@ -85,85 +100,91 @@ struct CollecterThread {
     stop_sender: mpsc::Sender<()>,
 }
 impl CollecterThread {
-    // TODO:インターバルがおかしいので修正が必要
-    async fn start(
+    fn start(
         tx: mpsc::Sender<DemoMachineReceiveData>,
+        data_sender: mpsc::Sender<Vec<DataPoint>>,
+        session: HostLinkSession,
         config: DemoMachineConfig,
-    ) -> anyhow::Result<Self> {
+    ) -> Self {
         let (stop_sender, mut stop_receiver) = mpsc::channel(32);
-        let mut stream = TcpStream::connect(&config.get_address()).await?;
-        // モニタの登録処理
-        stream.write_all(&config.get_set_moniter_command()).await?;
-        let mut buf = [0; 5];
-        let n = stream.read(&mut buf).await?;
-        let res = std::str::from_utf8(&buf[..n])
-            .unwrap()
-            .trim_end_matches("\r\n")
-            .to_string();
-        match res.as_str() {
-            "OK" => {}
-            "E0" => return Err(anyhow::anyhow!("モニタ登録失敗：デバイス番号異常")),
-            "E1" => return Err(anyhow::anyhow!("モニタ登録失敗：コマンド異常")),
-            t => return Err(anyhow::anyhow!("モニタ登録失敗：想定外の返り値:{}", t)),
-        }
-        let command = config.get_monitor_readout_command();
+        let read_plan = config.get_read_plan();
         let mut state = DemoMachineState::create_from_config(&config);
-        let mut interval = state.get_interval();
+
+        let policy = config.get_overrun_policy();
+        let mut scheduler = PollScheduler::new("demo_machine", state.get_interval(), policy);
+        let rate_groups = config.get_rate_groups();
+        let mut group_schedulers: Vec<PollScheduler> = rate_groups
+            .iter()
+            .map(|t| PollScheduler::new(t.get_name(), t.get_interval(), policy))
+            .collect();
 
         let collecter_thread = tokio::spawn(async move {
-            let mut next_loop_start_time = Instant::now();
             loop {
-                next_loop_start_time += Duration::from_millis(interval);
-                let now = Instant::now();
-                if next_loop_start_time > now {
-                    tokio::time::sleep(next_loop_start_time - now).await;
-                } else {
-                    next_loop_start_time = now;
-                }
-
-                tokio::select! {
+                // 0はメインのモニタ、1以降はレートグループ
+                let mut schedulers: Vec<&mut PollScheduler> = std::iter::once(&mut scheduler)
+                    .chain(group_schedulers.iter_mut())
+                    .collect();
+                let position = tokio::select! {
                     _ = stop_receiver.recv() => {
                         break;
                     }
-                    _ = tokio::time::sleep(next_loop_start_time - now) =>{
-                        let result: anyhow::Result<()> = async {
-                            stream.write_all(&command).await?;
-
-                            // NOTE:データ点数が多くなった場合、バッファサイズを大きくする必要がある
-                            let mut buf = [0; 1024];
-                            let n = stream.read(&mut buf).await?;
-                            let dt = Local::now();
-                            let res = std::str::from_utf8(&buf[..n])
-                                .unwrap()
-                                .trim_end_matches("\r\n")
-                                .to_string();
-
-                            // NOTE:想定外のデータについてのハンドリングが必要
-                            let recceive_data = DemoMachineReceiveData::create(dt, res)?;
-
-                            let now_status = recceive_data.get_status();
-                            if state.get_status() != now_status {
-                                state.set_status(now_status);
-                                interval = state.get_interval();
-                            }
-
-                            tx.send(recceive_data).await?;
-                            Ok(())
-                        }.await;
-
-                        // recceive_data等のエラーハンドリング
-                        if let Err(err) = result {
-                            warn!("Error: {}", err);
+                    position = scheduler::wait_next(&mut schedulers) => position,
+                };
+
+                let result: anyhow::Result<()> = async {
+                    if position > 0 {
+                        let point = rate_groups[position - 1].read(&session).await?;
+                        data_sender.send(vec![point]).await?;
+                        return Ok(());
+                    }
+                    let res = read_plan.read(&session).await?;
+                    let dt = Local::now();
+
+                    // NOTE:想定外のデータについてのハンドリングが必要
+                    let recceive_data =
+                        DemoMachineReceiveData::create(dt, res, config.get_status_devices())?;
+
+                    let now_status = recceive_data.get_status();
+                    if state.get_status() != now_status {
+                        state.set_status(now_status);
+                        scheduler.set_period(state.get_interval());
+                    }
+
+                    tx.send(recceive_data).await?;
+                    Ok(())
+                }
+                .await;
+
+                // recceive_data等のエラーハンドリング
+                // 切断した場合は次の読み出しでセッションが再接続する
+                if let Err(err) = result {
+                    let reason = DisconnectReason::from_error(&err);
+                    warn!("DemoMachineの読み出しに失敗:{}:{:?}", reason, err);
+                }
+
+                // レートグループ毎の周期の超過回数
+                let dt = Local::now();
+                let points = std::iter::once(&mut scheduler)
+                    .chain(group_schedulers.iter_mut())
+                    .filter_map(|t| t.report(dt))
+                    .map(|t| t.to_data_point("demo_machine"))
+                    .collect::<anyhow::Result<Vec<DataPoint>>>();
+                match points {
+                    Ok(points) if points.is_empty() => {}
+                    Ok(points) => {
+                        if let Err(r) = data_sender.send(points).await {
+                            warn!("ポーリングの診断を送信できない:{:?}", r);
                         }
                     }
+                    Err(r) => warn!("ポーリングの診断を作成できない:{:?}", r),
                 }
             }
         });
 
-        Ok(Self {
+        Self {
             collecter_thread,
             stop_sender,
-        })
+        }
     }
 
     async fn stop(self) -> anyhow::Result<()> {
@@ -1,7 +1,9 @@
+mod buffered;
 mod collector;
 mod config;
 mod data_manager;
 mod interface;
+mod rate_group;
 
 #[allow(unused_imports)]
 pub use collector::DemoMachineCollector;
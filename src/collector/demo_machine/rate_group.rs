@@ -0,0 +1,80 @@
+use chrono::Local;
+use influxdb2::models::DataPoint;
+
+use crate::collector::host_link::{DeviceMap, HostLinkSession, ReadMode, ReadPlan};
+use crate::shift_calendar;
+
+// 同じ接続でメインのモニタとは別周期で読み出すデバイスのグループ
+// MWSの登録はメインのモニタが使うので、グループは常にRDSで読み出す
+// DemoMachineRateGroups         : グループ名と周期(msec) ex) runinfo:5000,energy:1000
+// DemoMachineRateGroup_{グループ名} : 読み出すデバイス ex) shot_count=DM2000.U,total_time=DM2002.L
+#[derive(Clone, Debug)]
+pub struct RateGroup {
+    name: String,
+    interval: u64,
+    devices: DeviceMap,
+    read_plan: ReadPlan,
+}
+
+const BLOCK_READ_GAP: u32 = 8;
+
+impl RateGroup {
+    pub fn create_from_env() -> anyhow::Result<Vec<Self>> {
+        let Ok(groups) = std::env::var("DemoMachineRateGroups") else {
+            return Ok(Vec::new());
+        };
+        let mut rate_groups = Vec::new();
+        for item in groups
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+        {
+            let Some((name, interval)) = item.split_once(':') else {
+                anyhow::bail!("DemoMachineRateGroupsが不正:{}", item)
+            };
+            let interval: u64 = interval.trim().parse()?;
+            if interval == 0 {
+                anyhow::bail!("DemoMachineRateGroupsの周期が0:{}", item)
+            }
+            let key = format!("DemoMachineRateGroup_{}", name.trim());
+            let devices = DeviceMap::create_from_env(&key)?;
+            if devices.is_empty() {
+                anyhow::bail!("{}が未設定", key)
+            }
+            let read_plan = ReadPlan::create(&devices.devices(), ReadMode::Block, BLOCK_READ_GAP)?;
+            rate_groups.push(Self {
+                name: name.trim().to_string(),
+                interval,
+                devices,
+                read_plan,
+            });
+        }
+        Ok(rate_groups)
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub async fn read(&self, session: &HostLinkSession) -> anyhow::Result<DataPoint> {
+        let res = self.read_plan.read(session).await?;
+        let dt = Local::now();
+        let res: Vec<&str> = res.split(' ').collect();
+        let values = self.devices.decode(&res)?;
+
+        let time = match dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("RateGroup::readでエラー"),
+        };
+        let mut builder = shift_calendar::add_shift_tag(DataPoint::builder("demo_machine"), dt)
+            .tag("info_type", "rate_group")
+            .tag("group", self.name.as_str());
+        for (name, value) in &values {
+            builder = value.add_field(builder, name);
+        }
+        Ok(builder.timestamp(time).build()?)
+    }
+}
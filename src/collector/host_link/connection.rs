@@ -0,0 +1,184 @@
+use log::debug;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use super::target::HostLinkTarget;
+
+// シリアル通信時の通信開始・終了コマンド
+const SERIAL_OPEN_COMMAND: &[u8] = b"CR\r";
+const SERIAL_OPEN_RESPONSE: &str = "CC";
+const SERIAL_CLOSE_COMMAND: &[u8] = b"CQ\r";
+const SERIAL_CLOSE_RESPONSE: &str = "CF";
+
+enum Transport {
+    Tcp(TcpStream),
+    Serial(SerialStream),
+}
+
+// コマンドはCR終端、レスポンスはCR LF終端
+// 通常はHostLinkSession経由で使う
+// TCPでもシリアルでもレスポンスが分割されて届くことがあるのでCR LFまで読み込む
+pub struct HostLinkConnection {
+    transport: Transport,
+    buf: Vec<u8>,
+}
+
+impl HostLinkConnection {
+    pub async fn connect(target: &HostLinkTarget) -> anyhow::Result<Self> {
+        let transport = match target {
+            HostLinkTarget::Tcp { address } => Transport::Tcp(TcpStream::connect(address).await?),
+            HostLinkTarget::Serial(setting) => {
+                Transport::Serial(setting.builder().open_native_async()?)
+            }
+        };
+        let connection = Self::open(transport).await?;
+        if target.is_serial() {
+            debug!("シリアル通信開始:{}", target);
+        }
+        Ok(connection)
+    }
+
+    // シリアルの場合は通信開始要求が必要
+    async fn open(transport: Transport) -> anyhow::Result<Self> {
+        let is_serial = matches!(transport, Transport::Serial(_));
+        let mut connection = Self {
+            transport,
+            buf: Vec::new(),
+        };
+        if is_serial {
+            let res = connection.request(SERIAL_OPEN_COMMAND).await?;
+            if res != SERIAL_OPEN_RESPONSE {
+                anyhow::bail!("シリアル通信の開始に失敗:{:?}", res)
+            }
+        }
+        Ok(connection)
+    }
+
+    pub async fn request(&mut self, command: &[u8]) -> anyhow::Result<String> {
+        self.write_command(command).await?;
+        self.read_response().await
+    }
+
+    pub async fn write_command(&mut self, command: &[u8]) -> anyhow::Result<()> {
+        match &mut self.transport {
+            Transport::Tcp(stream) => stream.write_all(command).await?,
+            Transport::Serial(stream) => stream.write_all(command).await?,
+        }
+        Ok(())
+    }
+
+    // CR LFを除いたレスポンスを返す
+    pub async fn read_response(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..pos + 2).collect();
+                let res = std::str::from_utf8(&line[..pos])?.to_string();
+                return Ok(res);
+            }
+
+            // NOTE:データ点数が多くなった場合、バッファサイズを大きくする必要がある
+            let mut buf = [0; 1024];
+            let n = match &mut self.transport {
+                Transport::Tcp(stream) => stream.read(&mut buf).await?,
+                Transport::Serial(stream) => stream.read(&mut buf).await?,
+            };
+            if n == 0 {
+                let err =
+                    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "接続が切断された");
+                return Err(err.into());
+            }
+            self.buf.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    // TCPキープアライブを設定する。シリアルの場合は何もしない
+    // 無通信がtime続いた後、interval毎にプローブを送り、応答がなければ読み書きがTimedOutになる
+    pub fn set_keepalive(&self, time: Duration, interval: Duration) -> anyhow::Result<()> {
+        if let Transport::Tcp(stream) = &self.transport {
+            let keepalive = TcpKeepalive::new().with_time(time).with_interval(interval);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    // シリアルの場合は通信終了要求を送る
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        if let Transport::Serial(_) = self.transport {
+            let res = self.request(SERIAL_CLOSE_COMMAND).await?;
+            if res != SERIAL_CLOSE_RESPONSE {
+                anyhow::bail!("シリアル通信の終了に失敗:{:?}", res)
+            }
+            debug!("シリアル通信終了");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::JoinHandle;
+
+    // PLC側でCR終端のコマンドを1つ読み込む
+    async fn read_command(plc: &mut SerialStream) -> String {
+        let mut command = Vec::new();
+        let mut buf = [0; 1];
+        while command.last() != Some(&b'\r') {
+            plc.read_exact(&mut buf).await.unwrap();
+            command.extend_from_slice(&buf);
+        }
+        String::from_utf8(command).unwrap()
+    }
+
+    // コマンドを確認してレスポンスを分割して返すPLC
+    fn spawn_plc(
+        mut plc: SerialStream,
+        exchanges: Vec<(&'static str, Vec<&'static str>)>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            for (command, responses) in exchanges {
+                assert_eq!(read_command(&mut plc).await, command);
+                for response in responses {
+                    plc.write_all(response.as_bytes()).await.unwrap();
+                    plc.flush().await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn serial_handshake_and_framing() {
+        let (plc, port) = SerialStream::pair().unwrap();
+        let plc = spawn_plc(
+            plc,
+            vec![
+                ("CR\r", vec!["C", "C\r\n"]),
+                ("RD DM00000.U 2\r", vec!["000", "12 00034\r", "\n00056\r\n"]),
+                ("CQ\r", vec!["CF\r\n"]),
+            ],
+        );
+        let mut connection = HostLinkConnection::open(Transport::Serial(port))
+            .await
+            .unwrap();
+        // CR LFまで読み込み、続きのレスポンスはバッファに残す
+        let res = connection.request(b"RD DM00000.U 2\r").await.unwrap();
+        assert_eq!(res, "00012 00034");
+        assert_eq!(connection.read_response().await.unwrap(), "00056");
+        connection.close().await.unwrap();
+        plc.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serial_handshake_failure() {
+        let (plc, port) = SerialStream::pair().unwrap();
+        let plc = spawn_plc(plc, vec![("CR\r", vec!["E1\r\n"])]);
+        assert!(HostLinkConnection::open(Transport::Serial(port))
+            .await
+            .is_err());
+        plc.await.unwrap();
+    }
+}
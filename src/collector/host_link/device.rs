@@ -0,0 +1,436 @@
+use influxdb2::models::data_point::DataPointBuilder;
+
+// 上位リンクで扱うデバイス
+// R,MR,LR,CRはチャンネル(上位桁)＋ビット(下2桁:00～15)、B,Wは16進数で番号を表す
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceKind {
+    // ビットデバイス
+    R,
+    MR,
+    LR,
+    CR,
+    B,
+    // ワードデバイス
+    DM,
+    EM,
+    FM,
+    W,
+}
+
+// ワードデバイスのデータ形式
+// .U:16bit符号なし .S:16bit符号あり .D:32bit符号なし .L:32bit符号あり .H:16bit16進数
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceFormat {
+    Bit,
+    U,
+    S,
+    D,
+    L,
+    H,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Device {
+    kind: DeviceKind,
+    // チャンネル・ビット表記を通し番号に変換した値
+    index: u32,
+    format: DeviceFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceValue {
+    Bool(bool),
+    Int(i64),
+}
+
+impl DeviceKind {
+    fn parse(prefix: &str) -> anyhow::Result<Self> {
+        let kind = match prefix {
+            "R" => Self::R,
+            "MR" => Self::MR,
+            "LR" => Self::LR,
+            "CR" => Self::CR,
+            "B" => Self::B,
+            "DM" => Self::DM,
+            "EM" => Self::EM,
+            "FM" => Self::FM,
+            "W" => Self::W,
+            t => anyhow::bail!("未対応のデバイス:{}", t),
+        };
+        Ok(kind)
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Self::R => "R",
+            Self::MR => "MR",
+            Self::LR => "LR",
+            Self::CR => "CR",
+            Self::B => "B",
+            Self::DM => "DM",
+            Self::EM => "EM",
+            Self::FM => "FM",
+            Self::W => "W",
+        }
+    }
+
+    pub fn is_bit(&self) -> bool {
+        matches!(self, Self::R | Self::MR | Self::LR | Self::CR | Self::B)
+    }
+
+    // チャンネル＋ビットで番号を表すデバイス
+    fn is_channel_bit(&self) -> bool {
+        matches!(self, Self::R | Self::MR | Self::LR | Self::CR)
+    }
+
+    // 16進数で番号を表すデバイス
+    fn is_hex(&self) -> bool {
+        matches!(self, Self::B | Self::W)
+    }
+}
+
+impl Device {
+    // "DM100.U" "MR1015" "B1F" "W1A.S" などをパースする
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        let (name, suffix) = match text.split_once('.') {
+            Some((name, suffix)) => (name, Some(suffix)),
+            None => (text, None),
+        };
+        // B,Wは16進数なのでBFのように英字のみの場合がある
+        let pos = if name.starts_with('B') || name.starts_with('W') {
+            1
+        } else {
+            match name.find(|c: char| c.is_ascii_digit()) {
+                Some(pos) => pos,
+                None => anyhow::bail!("デバイス番号がない:{}", text),
+            }
+        };
+        let kind = DeviceKind::parse(&name[..pos])?;
+        let number = &name[pos..];
+
+        let index = if kind.is_hex() {
+            u32::from_str_radix(number, 16)?
+        } else if kind.is_channel_bit() {
+            let number: u32 = number.parse()?;
+            let (channel, bit) = (number / 100, number % 100);
+            if bit > 15 {
+                anyhow::bail!("ビット番号が不正:{}", text)
+            }
+            channel * 16 + bit
+        } else {
+            number.parse()?
+        };
+
+        let format = match (kind.is_bit(), suffix) {
+            (true, None) => DeviceFormat::Bit,
+            (true, Some(_)) => anyhow::bail!("ビットデバイスにデータ形式は指定できない:{}", text),
+            // 省略時は.U
+            (false, None) | (false, Some("U")) => DeviceFormat::U,
+            (false, Some("S")) => DeviceFormat::S,
+            (false, Some("D")) => DeviceFormat::D,
+            (false, Some("L")) => DeviceFormat::L,
+            (false, Some("H")) => DeviceFormat::H,
+            (false, Some(t)) => anyhow::bail!("未対応のデータ形式:{}", t),
+        };
+
+        Ok(Self {
+            kind,
+            index,
+            format,
+        })
+    }
+
+    pub fn get_kind(&self) -> DeviceKind {
+        self.kind
+    }
+    pub fn get_format(&self) -> DeviceFormat {
+        self.format
+    }
+    pub fn get_index(&self) -> u32 {
+        self.index
+    }
+    pub fn is_bit(&self) -> bool {
+        self.kind.is_bit()
+    }
+
+    // 1要素が占めるアドレス数
+    pub fn width(&self) -> u32 {
+        match self.format {
+            DeviceFormat::D | DeviceFormat::L => 2,
+            _ => 1,
+        }
+    }
+
+    // データ形式で表せる値の範囲
+    pub fn value_range(&self) -> (i64, i64) {
+        match self.format {
+            DeviceFormat::Bit => (0, 1),
+            DeviceFormat::U | DeviceFormat::H => (0, u16::MAX as i64),
+            DeviceFormat::S => (i16::MIN as i64, i16::MAX as i64),
+            DeviceFormat::D => (0, u32::MAX as i64),
+            DeviceFormat::L => (i32::MIN as i64, i32::MAX as i64),
+        }
+    }
+
+    // 同じ種類・データ形式でアドレスをずらしたデバイス
+    pub fn offset(&self, offset: u32) -> Self {
+        Self {
+            index: self.index + offset,
+            ..*self
+        }
+    }
+
+    // コマンドに使うデバイス名
+    pub fn command_name(&self) -> String {
+        let number = if self.kind.is_hex() {
+            format!("{:X}", self.index)
+        } else if self.kind.is_channel_bit() {
+            format!("{}{:02}", self.index / 16, self.index % 16)
+        } else {
+            self.index.to_string()
+        };
+        let suffix = match self.format {
+            DeviceFormat::Bit => "",
+            DeviceFormat::U => ".U",
+            DeviceFormat::S => ".S",
+            DeviceFormat::D => ".D",
+            DeviceFormat::L => ".L",
+            DeviceFormat::H => ".H",
+        };
+        format!("{}{}{}", self.kind.prefix(), number, suffix)
+    }
+
+    // レスポンスの1データを値に変換する
+    pub fn decode(&self, value: &str) -> anyhow::Result<DeviceValue> {
+        let value = value.trim();
+        let decoded = match self.format {
+            DeviceFormat::Bit => match value {
+                "1" => DeviceValue::Bool(true),
+                "0" => DeviceValue::Bool(false),
+                t => anyhow::bail!("ビットデバイスの値が不正:{}:{}", self.command_name(), t),
+            },
+            DeviceFormat::H => DeviceValue::Int(i64::from_str_radix(value, 16)?),
+            // 符号付きは"+00001"のように符号が付く
+            _ => DeviceValue::Int(value.parse()?),
+        };
+        Ok(decoded)
+    }
+}
+
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.command_name())
+    }
+}
+
+impl DeviceValue {
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            Self::Bool(t) => *t as i64,
+            Self::Int(t) => *t,
+        }
+    }
+
+    // bool,i64,f64,String,&strが可能
+    pub fn add_field(&self, builder: DataPointBuilder, name: &str) -> DataPointBuilder {
+        match self {
+            Self::Bool(t) => builder.field(name.to_owned(), *t),
+            Self::Int(t) => builder.field(name.to_owned(), *t),
+        }
+    }
+}
+
+// InfluxDBのフィールド名とデバイスの対応
+// 環境変数は"name=DEVICE"をカンマ区切りで指定する ex) main_alarm=MR100,door_interlock=R002
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMap {
+    entries: Vec<(String, Device)>,
+}
+
+impl DeviceMap {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let Some((name, device)) = item.split_once('=') else {
+                anyhow::bail!("デバイスの対応が不正:{}", item)
+            };
+            entries.push((name.trim().to_string(), Device::parse(device)?));
+        }
+        Ok(Self { entries })
+    }
+
+    // 環境変数が未設定の場合は空
+    pub fn create_from_env(key: &str) -> anyhow::Result<Self> {
+        match std::env::var(key) {
+            Ok(t) => Self::parse(&t),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn entries(&self) -> &[(String, Device)] {
+        &self.entries
+    }
+    pub fn push(&mut self, name: &str, device: Device) {
+        self.entries.push((name.to_string(), device));
+    }
+    pub fn devices(&self) -> Vec<Device> {
+        self.entries.iter().map(|(_, device)| *device).collect()
+    }
+
+    // レスポンスの各データをフィールド名付きの値に変換する
+    pub fn decode(&self, values: &[&str]) -> anyhow::Result<Vec<(String, DeviceValue)>> {
+        if values.len() != self.entries.len() {
+            anyhow::bail!(
+                "データ点数が{:?}と異なる:{:?}",
+                self.entries.len(),
+                values.len()
+            )
+        }
+        let mut decoded = Vec::with_capacity(values.len());
+        for ((name, device), value) in self.entries.iter().zip(values) {
+            decoded.push((name.to_owned(), device.decode(value)?));
+        }
+        Ok(decoded)
+    }
+}
+
+// モニタ登録コマンド MWS
+pub fn monitor_register_command(devices: &[Device]) -> Vec<u8> {
+    let mut command = String::from("MWS");
+    for device in devices {
+        command.push(' ');
+        command.push_str(&device.command_name());
+    }
+    command.push('\r');
+    command.into_bytes()
+}
+
+// 単一デバイスの読み出しコマンド RD
+pub fn read_command(device: &Device) -> Vec<u8> {
+    format!("RD {}\r", device.command_name()).into_bytes()
+}
+
+// 連続読み出しコマンド RDS
+pub fn read_block_command(start: &Device, count: u32) -> Vec<u8> {
+    format!("RDS {} {}\r", start.command_name(), count).into_bytes()
+}
+
+// 単一デバイスの書き込みコマンド WR
+// 16進数(16bit)は16bitに収める。負の値をi64のまま変換すると16桁になる
+pub fn write_command(device: &Device, value: i64) -> Vec<u8> {
+    let value = match device.get_format() {
+        DeviceFormat::H => format!("{:X}", value as u16),
+        _ => value.to_string(),
+    };
+    format!("WR {} {}\r", device.command_name(), value).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_hex_within_device_width() {
+        let device = Device::parse("DM100.H").unwrap();
+        assert_eq!(write_command(&device, 0x1A2B), b"WR DM100.H 1A2B\r");
+        assert_eq!(write_command(&device, -1), b"WR DM100.H FFFF\r");
+    }
+
+    #[test]
+    fn write_decimal() {
+        let device = Device::parse("DM100.S").unwrap();
+        assert_eq!(write_command(&device, -5), b"WR DM100.S -5\r");
+        let device = Device::parse("DM100.U").unwrap();
+        assert_eq!(write_command(&device, 65535), b"WR DM100.U 65535\r");
+    }
+
+    #[test]
+    fn value_range_of_format() {
+        assert_eq!(Device::parse("DM0.U").unwrap().value_range(), (0, 65535));
+        assert_eq!(
+            Device::parse("DM0.S").unwrap().value_range(),
+            (-32768, 32767)
+        );
+        assert_eq!(
+            Device::parse("DM0.D").unwrap().value_range(),
+            (0, 4294967295)
+        );
+        assert_eq!(
+            Device::parse("DM0.L").unwrap().value_range(),
+            (-2147483648, 2147483647)
+        );
+    }
+
+    #[test]
+    fn channel_bit_numbering() {
+        for (text, index) in [
+            ("R000", 0),
+            ("R015", 15),
+            ("MR100", 16),
+            ("LR1015", 175),
+            ("CR2", 2),
+        ] {
+            let device = Device::parse(text).unwrap();
+            assert_eq!(device.get_index(), index, "{}", text);
+            assert_eq!(device.get_format(), DeviceFormat::Bit);
+        }
+        assert!(Device::parse("MR016").is_err());
+        assert!(Device::parse("R").is_err());
+    }
+
+    #[test]
+    fn hex_numbering() {
+        let device = Device::parse("B1F").unwrap();
+        assert_eq!(
+            (device.get_kind(), device.get_index()),
+            (DeviceKind::B, 0x1F)
+        );
+        assert_eq!(Device::parse("BF").unwrap().get_index(), 0xF);
+        let device = Device::parse("W1A.S").unwrap();
+        assert_eq!(
+            (device.get_kind(), device.get_index()),
+            (DeviceKind::W, 0x1A)
+        );
+        assert_eq!(device.get_format(), DeviceFormat::S);
+        assert_eq!(Device::parse("WFF").unwrap().get_index(), 0xFF);
+        assert!(Device::parse("B1G").is_err());
+    }
+
+    #[test]
+    fn reject_format_on_bit_device() {
+        assert!(Device::parse("MR100.U").is_err());
+        assert!(Device::parse("B1F.H").is_err());
+        assert!(Device::parse("DM100.X").is_err());
+        assert_eq!(
+            Device::parse("DM100").unwrap().get_format(),
+            DeviceFormat::U
+        );
+    }
+
+    #[test]
+    fn command_name_round_trip() {
+        for text in [
+            "R000", "MR1015", "LR515", "CR1200", "B1F", "DM100.U", "EM5.S", "FM20.D", "W1A.L",
+            "W7FF.H",
+        ] {
+            let device = Device::parse(text).unwrap();
+            assert_eq!(device.command_name(), text);
+            assert_eq!(Device::parse(&device.command_name()).unwrap(), device);
+        }
+        assert_eq!(
+            Device::parse("MR100").unwrap().offset(16).command_name(),
+            "MR200"
+        );
+        assert_eq!(
+            Device::parse("W9").unwrap().offset(1).command_name(),
+            "WA.U"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::ErrorKind;
+
+// セッションが接続を切った理由
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    // 接続できなかった
+    ConnectFailed,
+    // PLC側から切断された
+    PeerClosed,
+    // TCPキープアライブで相手が応答しなくなったことを検出した
+    KeepaliveTimeout,
+    // 送受信のエラー
+    IoError,
+    // コマンドの応答が一定時間返ってこない(ハーフオープン等で送受信が止まった)
+    Watchdog,
+    // 無通信時の?Kに応答がない
+    HeartbeatFailed,
+    // 応答はあるが内容が想定外
+    InvalidResponse,
+}
+
+impl DisconnectReason {
+    // セッション以外のエラー(データ長の異常等)はInvalidResponseとする
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<Disconnected>() {
+            Some(t) => t.reason,
+            None => Self::InvalidResponse,
+        }
+    }
+
+    pub(super) fn from_io_error(err: &anyhow::Error) -> Self {
+        let Some(err) = err.downcast_ref::<std::io::Error>() else {
+            return Self::InvalidResponse;
+        };
+        match err.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => {
+                Self::PeerClosed
+            }
+            ErrorKind::TimedOut => Self::KeepaliveTimeout,
+            _ => Self::IoError,
+        }
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::ConnectFailed => "接続失敗",
+            Self::PeerClosed => "PLC側から切断",
+            Self::KeepaliveTimeout => "キープアライブ応答なし",
+            Self::IoError => "通信エラー",
+            Self::Watchdog => "応答待ちタイムアウト",
+            Self::HeartbeatFailed => "ハートビート応答なし",
+            Self::InvalidResponse => "想定外の応答",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// セッションが接続を切った場合のエラー
+// DisconnectReason::from_errorで理由を取り出す
+#[derive(Debug)]
+pub struct Disconnected {
+    pub reason: DisconnectReason,
+    pub target: String,
+    pub detail: String,
+}
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.reason, self.target, self.detail)
+    }
+}
+
+impl std::error::Error for Disconnected {}
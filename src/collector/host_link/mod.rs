@@ -0,0 +1,25 @@
+// キーエンスKVシリーズの上位リンク通信
+// Ethernet(TCP)とシリアル(RS-232C)のどちらでも同じコマンド・レスポンスで通信する
+mod connection;
+mod device;
+mod disconnect;
+mod planner;
+mod session;
+mod setpoint;
+mod target;
+
+#[allow(unused_imports)]
+pub use device::{monitor_register_command, read_block_command, read_command, write_command};
+#[allow(unused_imports)]
+pub use device::{Device, DeviceFormat, DeviceKind, DeviceMap, DeviceValue};
+#[allow(unused_imports)]
+pub use disconnect::{DisconnectReason, Disconnected};
+#[allow(unused_imports)]
+pub use planner::{block_max_count, ReadMode, ReadPlan, MONITOR_CAPACITY};
+#[allow(unused_imports)]
+pub use session::HostLinkSession;
+#[allow(unused_imports)]
+pub use setpoint::{SetpointWriteResult, SetpointWriter, WritableDeviceMap};
+pub use target::HostLinkTarget;
+
+pub const OK_RESPONSE: &str = "OK";
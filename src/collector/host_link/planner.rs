@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+
+use log::debug;
+
+use super::device::{
+    monitor_register_command, read_block_command, Device, DeviceFormat, DeviceKind,
+};
+use super::session::HostLinkSession;
+
+// MWSで登録できるデバイス数の上限
+pub const MONITOR_CAPACITY: usize = 120;
+// RDSで一度に読み出せる点数の上限
+const BLOCK_MAX_COUNT: u32 = 1000;
+const BLOCK_MAX_COUNT_32BIT: u32 = 500;
+// 未指定時に同じブロックにまとめるアドレスの隙間
+const DEFAULT_GAP_TOLERANCE: u32 = 8;
+
+// auto  : MWSの上限を超える場合のみRDSで読み出す
+// monitor : 常にMWS/MWR
+// block : 常にRDS
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadMode {
+    Auto,
+    Monitor,
+    Block,
+}
+
+impl ReadMode {
+    pub fn create_from_env(key: &str) -> anyhow::Result<Self> {
+        let mode = match std::env::var(key).as_deref() {
+            Ok("auto") | Err(_) => Self::Auto,
+            Ok("monitor") => Self::Monitor,
+            Ok("block") => Self::Block,
+            Ok(t) => anyhow::bail!("{}が不正:{}", key, t),
+        };
+        Ok(mode)
+    }
+}
+
+// RDSで連続して読み出す範囲
+// members: (デバイスリスト内の位置, ブロック先頭からの要素数)
+#[derive(Clone, Debug)]
+pub struct BlockRead {
+    start: Device,
+    count: u32,
+    members: Vec<(usize, usize)>,
+}
+
+impl BlockRead {
+    fn command(&self) -> Vec<u8> {
+        read_block_command(&self.start, self.count)
+    }
+}
+
+// デバイスリストの読み出し方法
+// read()はMWRと同じくデバイスリストの順に空白区切りで値を返すので
+// 受信データの処理側は読み出し方法を意識しなくてよい
+#[derive(Clone, Debug)]
+pub enum ReadPlan {
+    Monitor {
+        register_command: Vec<u8>,
+    },
+    Block {
+        blocks: Vec<BlockRead>,
+        device_count: usize,
+    },
+}
+
+impl ReadPlan {
+    pub fn create(devices: &[Device], mode: ReadMode, gap_tolerance: u32) -> anyhow::Result<Self> {
+        if devices.is_empty() {
+            anyhow::bail!("読み出すデバイスがない")
+        }
+        let use_monitor = match mode {
+            ReadMode::Monitor => {
+                if devices.len() > MONITOR_CAPACITY {
+                    anyhow::bail!(
+                        "MWSの登録上限{:?}を超えている:{:?}",
+                        MONITOR_CAPACITY,
+                        devices.len()
+                    )
+                }
+                true
+            }
+            ReadMode::Auto => devices.len() <= MONITOR_CAPACITY,
+            ReadMode::Block => false,
+        };
+
+        if use_monitor {
+            return Ok(Self::Monitor {
+                register_command: monitor_register_command(devices),
+            });
+        }
+
+        let blocks = plan_blocks(devices, gap_tolerance);
+        debug!(
+            "RDSで読み出し:{:?}点を{:?}ブロック",
+            devices.len(),
+            blocks.len()
+        );
+        Ok(Self::Block {
+            blocks,
+            device_count: devices.len(),
+        })
+    }
+
+    // {prefix}ReadMode、{prefix}BlockReadGapから作成する
+    pub fn create_from_env(devices: &[Device], prefix: &str) -> anyhow::Result<Self> {
+        let mode = ReadMode::create_from_env(&format!("{}ReadMode", prefix))?;
+        let gap_tolerance = match std::env::var(format!("{}BlockReadGap", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_GAP_TOLERANCE,
+        };
+        Self::create(devices, mode, gap_tolerance)
+    }
+
+    pub fn is_monitor(&self) -> bool {
+        matches!(self, Self::Monitor { .. })
+    }
+
+    // MWSの登録はセッションが必要な時に行う
+    pub async fn read(&self, session: &HostLinkSession) -> anyhow::Result<String> {
+        match self {
+            Self::Monitor { register_command } => session.monitor_read(register_command).await,
+            Self::Block {
+                blocks,
+                device_count,
+            } => {
+                let mut values = vec![String::new(); *device_count];
+                for block in blocks {
+                    let res = session.request(&block.command()).await?;
+                    match res.as_str() {
+                        "E0" => anyhow::bail!("RDS失敗：デバイス番号異常:{}", block.start),
+                        "E1" => anyhow::bail!("RDS失敗：コマンド異常:{}", block.start),
+                        _ => {}
+                    }
+                    let res: Vec<&str> = res.split(' ').collect();
+                    if res.len() != block.count as usize {
+                        anyhow::bail!("RDSのデータ点数が{:?}と異なる:{:?}", block.count, res.len())
+                    }
+                    for (position, offset) in &block.members {
+                        values[*position] = res[*offset].to_string();
+                    }
+                }
+                Ok(values.join(" "))
+            }
+        }
+    }
+}
+
+// RDSで一度に読み出せる点数
+pub fn block_max_count(format: DeviceFormat) -> u32 {
+    match format {
+        DeviceFormat::D | DeviceFormat::L => BLOCK_MAX_COUNT_32BIT,
+        _ => BLOCK_MAX_COUNT,
+    }
+}
+
+// 同じ種類・データ形式のデバイスをアドレス順に並べ
+// 隙間がgap_tolerance以下なら同じブロックにまとめる
+fn plan_blocks(devices: &[Device], gap_tolerance: u32) -> Vec<BlockRead> {
+    let mut groups: BTreeMap<(DeviceKind, DeviceFormat), Vec<(usize, Device)>> = BTreeMap::new();
+    for (position, device) in devices.iter().enumerate() {
+        let key = (device.get_kind(), device.get_format());
+        groups.entry(key).or_default().push((position, *device));
+    }
+
+    let mut blocks = Vec::new();
+    for (_, mut group) in groups {
+        group.sort_by_key(|(_, device)| device.get_index());
+        let format = group[0].1.get_format();
+        let width = group[0].1.width();
+        let max_count = block_max_count(format);
+
+        let mut current: Option<(BlockRead, u32)> = None;
+        for (position, device) in group {
+            let index = device.get_index();
+            if let Some((block, end)) = current.as_mut() {
+                let start = block.start.get_index();
+                let aligned = (index - start) % width == 0;
+                let gap = index.saturating_sub(*end + 1);
+                let count = (index - start) / width + 1;
+                if aligned && gap <= gap_tolerance && count <= max_count {
+                    block.count = block.count.max(count);
+                    block
+                        .members
+                        .push((position, ((index - start) / width) as usize));
+                    *end = (*end).max(index + width - 1);
+                    continue;
+                }
+                blocks.push(current.take().unwrap().0);
+            }
+            current = Some((
+                BlockRead {
+                    start: device,
+                    count: 1,
+                    members: vec![(position, 0)],
+                },
+                index + width - 1,
+            ));
+        }
+        if let Some((block, _)) = current {
+            blocks.push(block);
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(texts: &[&str]) -> Vec<Device> {
+        texts.iter().map(|t| Device::parse(t).unwrap()).collect()
+    }
+
+    // (先頭, 点数, メンバー)
+    type Block = (String, u32, Vec<(usize, usize)>);
+
+    fn blocks(devices: &[Device], gap_tolerance: u32) -> Vec<Block> {
+        plan_blocks(devices, gap_tolerance)
+            .into_iter()
+            .map(|t| (t.start.command_name(), t.count, t.members))
+            .collect()
+    }
+
+    #[test]
+    fn merge_within_gap() {
+        let devices = parse(&["DM10", "DM0", "DM4", "DM13", "DM13"]);
+        assert_eq!(
+            blocks(&devices, 8),
+            vec![(
+                "DM0.U".to_string(),
+                14,
+                vec![(1, 0), (2, 4), (0, 10), (3, 13), (4, 13)]
+            )]
+        );
+        // 隙間が許容値を超えた場合は分ける
+        assert_eq!(
+            blocks(&devices, 3),
+            vec![
+                ("DM0.U".to_string(), 5, vec![(1, 0), (2, 4)]),
+                ("DM10.U".to_string(), 4, vec![(0, 0), (3, 3), (4, 3)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_by_kind_and_format() {
+        let devices = parse(&["DM0", "DM1.S", "EM0", "MR000", "MR001"]);
+        let blocks = blocks(&devices, 8);
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks.contains(&("MR000".to_string(), 2, vec![(3, 0), (4, 1)])));
+        assert!(blocks.contains(&("DM1.S".to_string(), 1, vec![(1, 0)])));
+    }
+
+    #[test]
+    fn split_at_max_block_size() {
+        let devices = parse(&["DM0", "DM999", "DM1000"]);
+        assert_eq!(
+            blocks(&devices, 1000),
+            vec![
+                ("DM0.U".to_string(), 1000, vec![(0, 0), (1, 999)]),
+                ("DM1000.U".to_string(), 1, vec![(2, 0)]),
+            ]
+        );
+        // 32bitは2アドレスで1点、上限は500点
+        let devices = parse(&["DM0.D", "DM998.D", "DM1000.D", "DM1003.D"]);
+        assert_eq!(
+            blocks(&devices, 1000),
+            vec![
+                ("DM0.D".to_string(), 500, vec![(0, 0), (1, 499)]),
+                ("DM1000.D".to_string(), 1, vec![(2, 0)]),
+                ("DM1003.D".to_string(), 1, vec![(3, 0)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn auto_falls_back_to_block_read() {
+        let names: Vec<String> = (0..=MONITOR_CAPACITY)
+            .map(|t| format!("DM{}", t * 2))
+            .collect();
+        let names: Vec<&str> = names.iter().map(|t| t.as_str()).collect();
+        let within = parse(&names[..MONITOR_CAPACITY]);
+        let over = parse(&names);
+
+        assert!(ReadPlan::create(&within, ReadMode::Auto, 8)
+            .unwrap()
+            .is_monitor());
+        let plan = ReadPlan::create(&over, ReadMode::Auto, 8).unwrap();
+        let ReadPlan::Block {
+            blocks,
+            device_count,
+        } = plan
+        else {
+            panic!("RDSにならない")
+        };
+        assert_eq!(device_count, MONITOR_CAPACITY + 1);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].command(), b"RDS DM0.U 241\r");
+
+        assert!(ReadPlan::create(&over, ReadMode::Monitor, 8).is_err());
+        assert!(!ReadPlan::create(&within, ReadMode::Block, 8)
+            .unwrap()
+            .is_monitor());
+        assert!(ReadPlan::create(&[], ReadMode::Auto, 8).is_err());
+    }
+}
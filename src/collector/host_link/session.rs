@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use log::{debug, warn};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+
+use super::connection::HostLinkConnection;
+use super::device::{read_block_command, read_command, write_command, Device, DeviceValue};
+use super::disconnect::{DisconnectReason, Disconnected};
+use super::target::HostLinkTarget;
+
+const MONITOR_READOUT_COMMAND: &[u8] = b"MWR\r";
+const HEARTBEAT_COMMAND: &[u8] = b"?K\r";
+// ?Kの応答(機種コード)のデフォルト
+const DEFAULT_HEARTBEAT_RESPONSE: &str = "55";
+const QUEUE_SIZE: usize = 32;
+const DEFAULT_KEEPALIVE_SEC: u64 = 10;
+const KEEPALIVE_INTERVAL_SEC: u64 = 3;
+const DEFAULT_HEARTBEAT_SEC: u64 = 10;
+const DEFAULT_WATCHDOG_MSEC: u64 = 3000;
+
+// 1台のPLCへの接続を全てのコマンドで共有する
+// PLCのEthernetユニットは同時接続数が少ないので、同じ接続先のコレクタもこのセッションを共有する
+// コマンドはキューで直列化され、通信エラーで切断した場合は次のコマンドで再接続する
+// 切断した場合のエラーはDisconnectedで、DisconnectReason::from_errorで理由を取り出せる
+// コマンドがない間に?Kで切断を検出した場合は、次のコマンドにその理由を返してから再接続する
+// 全てのハンドルがドロップされるとセッションを終了し、シリアルの場合は通信終了要求(CQ)を送る
+#[derive(Clone)]
+pub struct HostLinkSession {
+    target: HostLinkTarget,
+    sender: mpsc::Sender<SessionCommand>,
+}
+
+enum SessionCommand {
+    Request {
+        command: Vec<u8>,
+        reply: oneshot::Sender<anyhow::Result<String>>,
+    },
+    // MWSの登録内容が異なる場合(再接続後や別のコレクタが登録した場合)は登録し直してからMWR
+    MonitorRead {
+        register_command: Vec<u8>,
+        reply: oneshot::Sender<anyhow::Result<String>>,
+    },
+}
+
+// 接続先毎のセッション
+// セッションの終了を妨げないように弱い参照で持つ
+fn sessions() -> &'static Mutex<HashMap<String, mpsc::WeakSender<SessionCommand>>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, mpsc::WeakSender<SessionCommand>>>> =
+        OnceLock::new();
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl HostLinkSession {
+    // 接続先のセッションを取得する。なければ作成する
+    // 接続は最初のコマンドで行う
+    pub fn get(target: &HostLinkTarget) -> anyhow::Result<Self> {
+        let mut sessions = sessions().lock().unwrap();
+        let key = target.to_string();
+        if let Some(sender) = sessions.get(&key).and_then(|t| t.upgrade()) {
+            if !sender.is_closed() {
+                return Ok(Self {
+                    target: target.clone(),
+                    sender,
+                });
+            }
+        }
+        let session = Self::spawn(target, SessionSetting::create_from_env()?);
+        sessions.insert(key, session.sender.downgrade());
+        Ok(session)
+    }
+
+    fn spawn(target: &HostLinkTarget, setting: SessionSetting) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let worker = SessionWorker {
+            target: target.clone(),
+            setting,
+            connection: None,
+            registered_monitor: None,
+            pending_disconnect: None,
+        };
+        tokio::spawn(worker.run(receiver));
+        debug!("セッション作成:{}", target);
+        Self {
+            target: target.clone(),
+            sender,
+        }
+    }
+
+    pub fn get_target(&self) -> &HostLinkTarget {
+        &self.target
+    }
+
+    pub async fn request(&self, command: &[u8]) -> anyhow::Result<String> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(SessionCommand::Request {
+            command: command.to_vec(),
+            reply,
+        })
+        .await?;
+        receiver.await?
+    }
+
+    // MWSで登録したデバイスの読み出し
+    pub async fn monitor_read(&self, register_command: &[u8]) -> anyhow::Result<String> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(SessionCommand::MonitorRead {
+            register_command: register_command.to_vec(),
+            reply,
+        })
+        .await?;
+        receiver.await?
+    }
+
+    async fn send(&self, command: SessionCommand) -> anyhow::Result<()> {
+        if self.sender.send(command).await.is_err() {
+            anyhow::bail!("セッションが終了している:{}", self.target)
+        }
+        Ok(())
+    }
+
+    // 単一デバイスの読み出し
+    pub async fn read_device(&self, device: &Device) -> anyhow::Result<DeviceValue> {
+        let res = self.request(&read_command(device)).await?;
+        match res.as_str() {
+            "E0" => anyhow::bail!("読み出し失敗：デバイス番号異常:{}", device),
+            "E1" => anyhow::bail!("読み出し失敗：コマンド異常:{}", device),
+            t => device.decode(t),
+        }
+    }
+
+    // 連続読み出し
+    pub async fn read_block(&self, start: &Device, count: u32) -> anyhow::Result<Vec<DeviceValue>> {
+        let res = self.request(&read_block_command(start, count)).await?;
+        match res.as_str() {
+            "E0" => anyhow::bail!("RDS失敗：デバイス番号異常:{}", start),
+            "E1" => anyhow::bail!("RDS失敗：コマンド異常:{}", start),
+            _ => {}
+        }
+        let values = res
+            .split(' ')
+            .map(|t| start.decode(t))
+            .collect::<anyhow::Result<Vec<DeviceValue>>>()?;
+        if values.len() != count as usize {
+            anyhow::bail!("RDSのデータ点数が{:?}と異なる:{:?}", count, values.len())
+        }
+        Ok(values)
+    }
+
+    // 単一デバイスの書き込み
+    pub async fn write_device(&self, device: &Device, value: i64) -> anyhow::Result<()> {
+        let res = self.request(&write_command(device, value)).await?;
+        match res.as_str() {
+            super::OK_RESPONSE => Ok(()),
+            "E0" => anyhow::bail!("書き込み失敗：デバイス番号異常:{}", device),
+            "E1" => anyhow::bail!("書き込み失敗：コマンド異常:{}", device),
+            "E4" => anyhow::bail!("書き込み失敗：書き込み禁止:{}", device),
+            t => anyhow::bail!("書き込み失敗：想定外の返り値:{}:{}", device, t),
+        }
+    }
+}
+
+// キューからコマンドを取り出して順に実行する
+struct SessionWorker {
+    target: HostLinkTarget,
+    setting: SessionSetting,
+    connection: Option<HostLinkConnection>,
+    // 現在の接続でMWS登録しているコマンド
+    registered_monitor: Option<Vec<u8>>,
+    // ハートビートで切断した理由。次のコマンドに返す
+    pending_disconnect: Option<(DisconnectReason, String)>,
+}
+
+impl SessionWorker {
+    async fn run(mut self, mut receiver: mpsc::Receiver<SessionCommand>) {
+        loop {
+            // 接続中にheartbeatの間コマンドがなければ?Kで死活確認
+            let command = match (&self.connection, self.setting.heartbeat) {
+                (Some(_), Some(heartbeat)) => tokio::select! {
+                    command = receiver.recv() => command,
+                    _ = tokio::time::sleep(heartbeat) => {
+                        self.heartbeat().await;
+                        continue;
+                    }
+                },
+                _ => receiver.recv().await,
+            };
+            let Some(command) = command else {
+                break;
+            };
+            match command {
+                SessionCommand::Request { command, reply } => {
+                    let res = self.request(&command).await;
+                    let _ = reply.send(res);
+                }
+                SessionCommand::MonitorRead {
+                    register_command,
+                    reply,
+                } => {
+                    let res = self.monitor_read(register_command).await;
+                    let _ = reply.send(res);
+                }
+            }
+        }
+        // 全てのハンドルがドロップされた
+        if let Some(connection) = self.connection.take() {
+            if let Err(r) = connection.close().await {
+                debug!("close error:{:?}", r);
+            }
+        }
+        debug!("セッション終了:{}", self.target);
+    }
+
+    // 未接続なら接続してからコマンドを送る
+    // 応答待ちはwatchdogで打ち切る
+    async fn exchange(&mut self, command: &[u8]) -> Result<String, (DisconnectReason, String)> {
+        if self.connection.is_none() {
+            let connection = match timeout(
+                self.setting.watchdog,
+                HostLinkConnection::connect(&self.target),
+            )
+            .await
+            {
+                Ok(Ok(t)) => t,
+                Ok(Err(r)) => return Err((DisconnectReason::ConnectFailed, r.to_string())),
+                Err(_) => return Err((DisconnectReason::ConnectFailed, "timeout".to_string())),
+            };
+            if let Some((time, interval)) = self.setting.keepalive {
+                if let Err(r) = connection.set_keepalive(time, interval) {
+                    warn!("キープアライブの設定に失敗:{}:{}", self.target, r);
+                }
+            }
+            debug!("接続:{}", self.target);
+            self.connection = Some(connection);
+        }
+        let connection = self.connection.as_mut().unwrap();
+        match timeout(self.setting.watchdog, connection.request(command)).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(r)) => Err((DisconnectReason::from_io_error(&r), r.to_string())),
+            Err(_) => Err((
+                DisconnectReason::Watchdog,
+                format!("{:?}応答なし", self.setting.watchdog),
+            )),
+        }
+    }
+
+    // 接続を破棄する。次のコマンドで再接続する
+    fn disconnect(&mut self, reason: DisconnectReason, detail: String) -> anyhow::Error {
+        let err = Disconnected {
+            reason,
+            target: self.target.to_string(),
+            detail,
+        };
+        if self.connection.take().is_some() {
+            warn!("セッションを切断:{}", err);
+        }
+        self.registered_monitor = None;
+        err.into()
+    }
+
+    async fn request(&mut self, command: &[u8]) -> anyhow::Result<String> {
+        if let Some((reason, detail)) = self.pending_disconnect.take() {
+            return Err(Disconnected {
+                reason,
+                target: self.target.to_string(),
+                detail,
+            }
+            .into());
+        }
+        self.exchange(command)
+            .await
+            .map_err(|(reason, detail)| self.disconnect(reason, detail))
+    }
+
+    // 応答がない場合や機種コードが異なる場合は切断する
+    async fn heartbeat(&mut self) {
+        let detail = match self.exchange(HEARTBEAT_COMMAND).await {
+            Ok(res) if res == self.setting.heartbeat_response => return,
+            Ok(res) => format!("?Kの応答が想定外:{}", res),
+            Err((_, detail)) => detail,
+        };
+        self.disconnect(DisconnectReason::HeartbeatFailed, detail.clone());
+        self.pending_disconnect = Some((DisconnectReason::HeartbeatFailed, detail));
+    }
+
+    async fn monitor_read(&mut self, register_command: Vec<u8>) -> anyhow::Result<String> {
+        if self.registered_monitor.as_ref() != Some(&register_command) {
+            let res = self.request(&register_command).await?;
+            match res.as_str() {
+                super::OK_RESPONSE => {}
+                "E0" => anyhow::bail!("モニタ登録失敗：デバイス番号異常"),
+                "E1" => anyhow::bail!("モニタ登録失敗：コマンド異常"),
+                t => anyhow::bail!("モニタ登録失敗：想定外の返り値:{}", t),
+            }
+            self.registered_monitor = Some(register_command);
+        }
+        self.request(MONITOR_READOUT_COMMAND).await
+    }
+}
+
+// HostLinkKeepaliveSec : TCPキープアライブを開始する無通信時間(0で無効)
+// HostLinkHeartbeatSec : 無通信時に?Kを送る間隔(0で無効)
+// HostLinkHeartbeatResponse : ?Kの応答(機種コード)。デフォルト55
+// HostLinkWatchdogMsec : コマンドの応答待ちの上限
+#[derive(Clone, Debug)]
+struct SessionSetting {
+    keepalive: Option<(Duration, Duration)>,
+    heartbeat: Option<Duration>,
+    heartbeat_response: String,
+    watchdog: Duration,
+}
+
+impl SessionSetting {
+    fn create_from_env() -> anyhow::Result<Self> {
+        let keepalive = match read_env("HostLinkKeepaliveSec", DEFAULT_KEEPALIVE_SEC)? {
+            0 => None,
+            t => Some((
+                Duration::from_secs(t),
+                Duration::from_secs(KEEPALIVE_INTERVAL_SEC),
+            )),
+        };
+        let heartbeat = match read_env("HostLinkHeartbeatSec", DEFAULT_HEARTBEAT_SEC)? {
+            0 => None,
+            t => Some(Duration::from_secs(t)),
+        };
+        let heartbeat_response = std::env::var("HostLinkHeartbeatResponse")
+            .unwrap_or_else(|_| DEFAULT_HEARTBEAT_RESPONSE.to_string());
+        let watchdog =
+            Duration::from_millis(read_env("HostLinkWatchdogMsec", DEFAULT_WATCHDOG_MSEC)?);
+        Ok(Self {
+            keepalive,
+            heartbeat,
+            heartbeat_response,
+            watchdog,
+        })
+    }
+}
+
+fn read_env(key: &str, default: u64) -> anyhow::Result<u64> {
+    match std::env::var(key) {
+        Ok(t) => Ok(t.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio_serial::{SerialPort, SerialStream};
+
+    use crate::collector::host_link::target::SerialSetting;
+
+    // ?Kにheartbeat_responseで、それ以外にOKで応答するPLCの代わり
+    async fn spawn_plc(heartbeat_response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut line = Vec::new();
+                    while reader.read_until(b'\r', &mut line).await.unwrap_or(0) > 0 {
+                        let res = match line.as_slice() {
+                            b"?K\r" => heartbeat_response,
+                            _ => "OK",
+                        };
+                        line.clear();
+                        let res = format!("{}\r\n", res);
+                        if writer.write_all(res.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    fn setting() -> SessionSetting {
+        SessionSetting {
+            keepalive: None,
+            heartbeat: Some(Duration::from_millis(50)),
+            heartbeat_response: "55".to_string(),
+            watchdog: Duration::from_millis(500),
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_keeps_connection() {
+        let address = spawn_plc("55").await;
+        let session = HostLinkSession::spawn(&HostLinkTarget::Tcp { address }, setting());
+        assert_eq!(session.request(b"WR DM0 1\r").await.unwrap(), "OK");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(session.request(b"WR DM0 1\r").await.unwrap(), "OK");
+    }
+
+    #[tokio::test]
+    async fn heartbeat_failure_is_returned_to_next_request() {
+        let address = spawn_plc("99").await;
+        let session = HostLinkSession::spawn(&HostLinkTarget::Tcp { address }, setting());
+        assert_eq!(session.request(b"WR DM0 1\r").await.unwrap(), "OK");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let err = session.request(b"WR DM0 1\r").await.unwrap_err();
+        assert_eq!(
+            DisconnectReason::from_error(&err),
+            DisconnectReason::HeartbeatFailed
+        );
+        // 理由を返した後は再接続する
+        assert_eq!(session.request(b"WR DM0 1\r").await.unwrap(), "OK");
+    }
+
+    #[tokio::test]
+    async fn close_serial_when_all_handles_dropped() {
+        let (mut plc, port) = SerialStream::pair().unwrap();
+        let target = HostLinkTarget::Serial(SerialSetting::new(&port.name().unwrap()));
+        // CQを受信するまでコマンドを記録して応答するPLCの代わり
+        let plc = tokio::spawn(async move {
+            let mut commands = Vec::new();
+            let mut command = Vec::new();
+            let mut buf = [0; 1];
+            loop {
+                plc.read_exact(&mut buf).await.unwrap();
+                command.push(buf[0]);
+                if buf[0] != b'\r' {
+                    continue;
+                }
+                let res: &[u8] = match command.as_slice() {
+                    b"CR\r" => b"CC\r\n",
+                    b"CQ\r" => b"CF\r\n",
+                    _ => b"00001\r\n",
+                };
+                plc.write_all(res).await.unwrap();
+                commands.push(String::from_utf8(std::mem::take(&mut command)).unwrap());
+                if res == b"CF\r\n" {
+                    return commands;
+                }
+            }
+        });
+
+        let session = HostLinkSession::get(&target).unwrap();
+        let shared = HostLinkSession::get(&target).unwrap();
+        assert!(session.sender.same_channel(&shared.sender));
+        assert_eq!(session.request(b"RD DM0.U\r").await.unwrap(), "00001");
+        drop(session);
+        assert_eq!(shared.request(b"RD DM0.U\r").await.unwrap(), "00001");
+        drop(shared);
+
+        let commands = timeout(Duration::from_secs(2), plc).await.unwrap().unwrap();
+        assert_eq!(commands, vec!["CR\r", "RD DM0.U\r", "RD DM0.U\r", "CQ\r"]);
+        drop(port);
+    }
+}
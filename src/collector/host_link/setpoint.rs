@@ -0,0 +1,270 @@
+use chrono::Local;
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+
+use super::device::Device;
+use super::session::HostLinkSession;
+use super::target::HostLinkTarget;
+
+const DEFAULT_AUDIT_LOG_PATH: &str = "log/write_audit.log";
+
+// 書き込みを許可するデバイス
+// 環境変数は"name=DEVICE:最小値:最大値:run/stop"をカンマ区切りで指定する
+// run : 稼働中も書き込み可、stop : 停止中のみ書き込み可
+// ex) target_bag_count=DM200.U:0:5000:stop,heater_setpoint=DM210.U:0:2500:run
+#[derive(Clone, Debug)]
+pub struct WritableDevice {
+    name: String,
+    device: Device,
+    min: i64,
+    max: i64,
+    allow_while_running: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WritableDeviceMap {
+    entries: Vec<WritableDevice>,
+}
+
+impl WritableDeviceMap {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let Some((name, setting)) = item.split_once('=') else {
+                anyhow::bail!("書き込みデバイスの設定が不正:{}", item)
+            };
+            let setting: Vec<&str> = setting.split(':').collect();
+            if setting.len() != 4 {
+                anyhow::bail!("書き込みデバイスの設定が不正:{}", item)
+            }
+            let allow_while_running = match setting[3] {
+                "run" => true,
+                "stop" => false,
+                t => anyhow::bail!("run/stopの指定が不正:{}", t),
+            };
+            let device = Device::parse(setting[0])?;
+            let min: i64 = setting[1].parse()?;
+            let max: i64 = setting[2].parse()?;
+            // データ形式で表せない値はPLCが受け付けないか別の値として書き込まれる
+            let (lower, upper) = device.value_range();
+            if min > max || min < lower || max > upper {
+                anyhow::bail!(
+                    "書き込みデバイスの設定範囲が不正:{}:{}～{}",
+                    item,
+                    lower,
+                    upper
+                )
+            }
+            entries.push(WritableDevice {
+                name: name.trim().to_string(),
+                device,
+                min,
+                max,
+                allow_while_running,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    // 環境変数が未設定の場合は空(書き込み不可)
+    pub fn create_from_env(key: &str) -> anyhow::Result<Self> {
+        match std::env::var(key) {
+            Ok(t) => Self::parse(&t),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.entries.iter().map(|t| t.name.to_owned()).collect()
+    }
+
+    fn get(&self, name: &str) -> Option<&WritableDevice> {
+        self.entries.iter().find(|t| t.name == name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SetpointWriteResult {
+    pub name: String,
+    pub device: String,
+    pub old_value: i64,
+    pub new_value: i64,
+}
+
+// ホワイトリストに従ってPLCに設定値を書き込む
+// 書き込み後に読み戻して確認し、結果は成否に関わらず監査ログに記録する
+#[derive(Clone)]
+pub struct SetpointWriter {
+    machine: String,
+    target: HostLinkTarget,
+    writable_devices: WritableDeviceMap,
+    // 稼働状況のデバイス。値が1なら稼働中
+    running_device: Device,
+    audit_log_path: String,
+}
+
+impl SetpointWriter {
+    pub fn create(
+        machine: &str,
+        target: HostLinkTarget,
+        writable_devices: WritableDeviceMap,
+        running_device: Device,
+    ) -> Self {
+        let audit_log_path = std::env::var("WRITE_AUDIT_LOG_PATH")
+            .unwrap_or_else(|_| DEFAULT_AUDIT_LOG_PATH.to_string());
+        Self {
+            machine: machine.to_string(),
+            target,
+            writable_devices,
+            running_device,
+            audit_log_path,
+        }
+    }
+
+    pub fn get_writable_names(&self) -> Vec<String> {
+        self.writable_devices.names()
+    }
+
+    pub async fn write(
+        &self,
+        name: &str,
+        value: i64,
+        requested_by: &str,
+    ) -> anyhow::Result<SetpointWriteResult> {
+        let result = self.write_and_verify(name, value).await;
+        let (old_value, message) = match &result {
+            Ok(t) => (Some(t.old_value), "OK".to_string()),
+            Err(r) => (None, r.to_string()),
+        };
+        if let Err(r) = self
+            .append_audit_log(name, old_value, value, requested_by, &message)
+            .await
+        {
+            // 監査ログに残せない場合も書き込み結果は返す
+            warn!("監査ログの書き込みに失敗:{:?}", r);
+        }
+        result
+    }
+
+    async fn write_and_verify(
+        &self,
+        name: &str,
+        value: i64,
+    ) -> anyhow::Result<SetpointWriteResult> {
+        let Some(writable) = self.writable_devices.get(name) else {
+            anyhow::bail!("書き込みが許可されていない:{}", name)
+        };
+        if value < writable.min || value > writable.max {
+            anyhow::bail!(
+                "設定範囲外:{}:{}～{}:{}",
+                name,
+                writable.min,
+                writable.max,
+                value
+            )
+        }
+
+        let session = HostLinkSession::get(&self.target)?;
+        let result: anyhow::Result<SetpointWriteResult> = async {
+            if !writable.allow_while_running {
+                let status = session.read_device(&self.running_device).await?;
+                if status.as_i64() == 1 {
+                    anyhow::bail!("稼働中は書き込みできない:{}", name)
+                }
+            }
+
+            let old_value = session.read_device(&writable.device).await?.as_i64();
+            session.write_device(&writable.device, value).await?;
+
+            // 読み戻して確認
+            let written = session.read_device(&writable.device).await?.as_i64();
+            if written != value {
+                anyhow::bail!("書き込み値の確認に失敗:{}:{}≠{}", name, written, value)
+            }
+
+            Ok(SetpointWriteResult {
+                name: name.to_string(),
+                device: writable.device.command_name(),
+                old_value,
+                new_value: value,
+            })
+        }
+        .await;
+
+        let result = result?;
+        info!(
+            "設定値を書き込み:{}:{}:{}→{}",
+            self.machine, name, result.old_value, result.new_value
+        );
+        Ok(result)
+    }
+
+    // 日時,機械,名前,デバイス,変更前,変更後,要求元,結果
+    async fn append_audit_log(
+        &self,
+        name: &str,
+        old_value: Option<i64>,
+        new_value: i64,
+        requested_by: &str,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let device = match self.writable_devices.get(name) {
+            Some(t) => t.device.command_name(),
+            None => String::new(),
+        };
+        let old_value = match old_value {
+            Some(t) => t.to_string(),
+            None => String::new(),
+        };
+        let line = format!(
+            "{},{},{},{},{},{},{},{}\n",
+            Local::now().to_rfc3339(),
+            self.machine,
+            name,
+            device,
+            old_value,
+            new_value,
+            requested_by.replace(',', " "),
+            message.replace(',', " ")
+        );
+
+        if let Some(dir) = std::path::Path::new(&self.audit_log_path).parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log_path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_writable_devices() {
+        let map = WritableDeviceMap::parse(
+            "target_bag_count=DM200.U:0:5000:stop,offset=DM210.S:-100:100:run",
+        )
+        .unwrap();
+        assert_eq!(map.names(), vec!["target_bag_count", "offset"]);
+        assert!(map.get("offset").unwrap().allow_while_running);
+    }
+
+    #[test]
+    fn reject_range_outside_device_format() {
+        assert!(WritableDeviceMap::parse("a=DM200.U:-1:100:stop").is_err());
+        assert!(WritableDeviceMap::parse("a=DM200.U:0:65536:stop").is_err());
+        assert!(WritableDeviceMap::parse("a=DM200.S:-32769:0:stop").is_err());
+        assert!(WritableDeviceMap::parse("a=DM200.U:100:0:stop").is_err());
+        assert!(WritableDeviceMap::parse("a=DM200.D:0:65536:stop").is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use tokio_serial::{DataBits, Parity, StopBits};
+
+// 上位リンクの接続先
+// {prefix}SerialPortが設定されていればシリアル、なければ{prefix}StatusConfigAddressへTCP接続
+#[derive(Clone, Debug)]
+pub enum HostLinkTarget {
+    Tcp { address: String },
+    Serial(SerialSetting),
+}
+
+#[derive(Clone, Debug)]
+pub struct SerialSetting {
+    path: String,
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+}
+
+// KV上位リンクの初期設定：9600bps、データ長8bit、偶数パリティ、ストップビット1
+const DEFAULT_BAUD_RATE: u32 = 9600;
+
+impl HostLinkTarget {
+    pub fn create_from_env(prefix: &str) -> anyhow::Result<Self> {
+        let Ok(path) = std::env::var(format!("{}SerialPort", prefix)) else {
+            let address = std::env::var(format!("{}StatusConfigAddress", prefix))?;
+            return Ok(Self::Tcp { address });
+        };
+
+        let baud_rate = match std::env::var(format!("{}SerialBaudRate", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_BAUD_RATE,
+        };
+        let data_bits = match std::env::var(format!("{}SerialDataBits", prefix)).as_deref() {
+            Ok("7") => DataBits::Seven,
+            Ok("8") | Err(_) => DataBits::Eight,
+            Ok(t) => anyhow::bail!("{}SerialDataBitsが不正:{}", prefix, t),
+        };
+        let parity = match std::env::var(format!("{}SerialParity", prefix)).as_deref() {
+            Ok("even") | Err(_) => Parity::Even,
+            Ok("odd") => Parity::Odd,
+            Ok("none") => Parity::None,
+            Ok(t) => anyhow::bail!("{}SerialParityが不正:{}", prefix, t),
+        };
+        let stop_bits = match std::env::var(format!("{}SerialStopBits", prefix)).as_deref() {
+            Ok("1") | Err(_) => StopBits::One,
+            Ok("2") => StopBits::Two,
+            Ok(t) => anyhow::bail!("{}SerialStopBitsが不正:{}", prefix, t),
+        };
+
+        Ok(Self::Serial(SerialSetting {
+            path,
+            baud_rate,
+            data_bits,
+            parity,
+            stop_bits,
+        }))
+    }
+
+    pub fn is_serial(&self) -> bool {
+        matches!(self, Self::Serial(_))
+    }
+}
+
+impl std::fmt::Display for HostLinkTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp { address } => write!(f, "tcp://{}", address),
+            Self::Serial(setting) => write!(f, "serial://{}", setting.path),
+        }
+    }
+}
+
+// テスト用。初期設定の通信条件でpathを開く
+#[cfg(test)]
+impl SerialSetting {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            data_bits: DataBits::Eight,
+            parity: Parity::Even,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl SerialSetting {
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+    pub fn builder(&self) -> tokio_serial::SerialPortBuilder {
+        tokio_serial::new(&self.path, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+    }
+}
@@ -6,3 +6,15 @@ pub mod dummy_maker;
 
 #[allow(dead_code)]
 pub mod demo_cpb16;
+
+#[allow(dead_code)]
+pub mod host_link;
+
+#[allow(dead_code)]
+pub mod mqtt_sensor;
+
+#[allow(dead_code)]
+pub mod scheduler;
+
+#[allow(dead_code)]
+pub mod snapshot_trigger;
@@ -0,0 +1,283 @@
+use chrono::Local;
+use influxdb2::models::DataPoint;
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::task::JoinHandle;
+
+use super::config::MqttSensorConfig;
+use super::payload::parse_payload;
+
+// MQTTで送信されるセンサーデータをPLCのコレクターと同じ送信経路に流す
+pub struct MqttSensorCollector {
+    config: MqttSensorConfig,
+    data_sender: mpsc::Sender<Vec<DataPoint>>,
+    thread: Option<SubscribeThread>,
+}
+
+impl MqttSensorCollector {
+    pub fn create_from_env(data_sender: mpsc::Sender<Vec<DataPoint>>) -> anyhow::Result<Self> {
+        let config = MqttSensorConfig::create_from_env()?;
+        Ok(Self {
+            config,
+            data_sender,
+            thread: None,
+        })
+    }
+
+    pub async fn start_data_collection(&mut self) -> anyhow::Result<()> {
+        if self.thread.is_some() {
+            anyhow::bail!("start_data_collection can not execute: already subscribing")
+        }
+        let thread = SubscribeThread::start(self.config.clone(), self.data_sender.clone())?;
+        self.thread = Some(thread);
+        debug!("MqttSensorCollector collect start");
+        Ok(())
+    }
+
+    pub async fn stop_data_collection(&mut self) -> anyhow::Result<()> {
+        if let Some(thread) = self.thread.take() {
+            thread.stop().await?;
+        } else {
+            anyhow::bail!("stop_data_collection can not execute: not subscribing")
+        }
+        debug!("MqttSensorCollector collect stop");
+        Ok(())
+    }
+}
+
+impl Drop for MqttSensorCollector {
+    fn drop(&mut self) {
+        task::block_in_place(|| {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                if self.thread.is_some() {
+                    self.stop_data_collection().await.unwrap();
+                }
+            });
+        });
+    }
+}
+
+struct SubscribeThread {
+    subscribe_thread: JoinHandle<()>,
+    stop_sender: mpsc::Sender<()>,
+}
+
+impl SubscribeThread {
+    fn start(
+        config: MqttSensorConfig,
+        data_sender: mpsc::Sender<Vec<DataPoint>>,
+    ) -> anyhow::Result<Self> {
+        let (stop_sender, mut stop_receiver) = mpsc::channel(32);
+
+        let mut options = MqttOptions::new(
+            config.get_client_id(),
+            config.get_broker_host(),
+            config.get_broker_port(),
+        );
+        options.set_keep_alive(config.get_keep_alive());
+        // QoS1のメッセージを再接続時に取りこぼさないようにセッションを維持する
+        options.set_clean_session(false);
+        let (client, mut eventloop) = AsyncClient::new(options, 32);
+
+        let subscribe_thread = tokio::spawn(async move {
+            let mut points = Vec::<DataPoint>::new();
+            let mut flush_interval = tokio::time::interval(config.get_flush_interval());
+
+            loop {
+                tokio::select! {
+                    _ = stop_receiver.recv() => {
+                        break;
+                    }
+                    _ = flush_interval.tick() => {
+                        if !points.is_empty() {
+                            let send_data = std::mem::take(&mut points);
+                            if let Err(r) = data_sender.send(send_data).await {
+                                warn!("MQTTデータの送信に失敗:{:?}", r);
+                            }
+                        }
+                    }
+                    event = eventloop.poll() => {
+                        match event {
+                            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                                info!("MQTTブローカーに接続");
+                                // 再接続時にも購読し直す
+                                for topic in config.get_topics() {
+                                    if let Err(r) = client.subscribe(topic.clone(), QoS::AtLeastOnce).await {
+                                        warn!("購読に失敗:{}:{:?}", topic, r);
+                                    }
+                                }
+                            }
+                            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                                match parse_payload(&config, &publish.topic, &publish.payload, Local::now()) {
+                                    Ok(Some(point)) => points.push(point),
+                                    Ok(None) => {}
+                                    Err(r) => warn!("MQTTメッセージの変換に失敗:{}:{:?}", publish.topic, r),
+                                }
+                                if points.len() >= config.get_send_chunk_size() {
+                                    let send_data = std::mem::take(&mut points);
+                                    if let Err(r) = data_sender.send(send_data).await {
+                                        warn!("MQTTデータの送信に失敗:{:?}", r);
+                                    }
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(r) => {
+                                // 次のpoll()で再接続される
+                                warn!("MQTTブローカーとの接続エラー:{:?}", r);
+                                tokio::select! {
+                                    _ = stop_receiver.recv() => break,
+                                    _ = tokio::time::sleep(config.get_reconnect_interval()) => {}
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            if !points.is_empty() {
+                let _ = data_sender.send(points).await;
+            }
+            let _ = client.try_disconnect();
+        });
+
+        Ok(Self {
+            subscribe_thread,
+            stop_sender,
+        })
+    }
+
+    async fn stop(self) -> anyhow::Result<()> {
+        self.stop_sender.send(()).await?;
+        // 完了を待つ処理
+        self.subscribe_thread.await?;
+        Ok(())
+    }
+}
+
+// ブローカーが必要なため通常は実行しない
+// MqttTestBrokerHost(デフォルトlocalhost)の1883番で動くブローカーに接続する
+// cargo test mqtt_sensor -- --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb2::models::WriteDataPoint;
+    use rumqttc::Outgoing;
+    use tokio::time::{timeout, Duration};
+
+    const TOPIC: &str = "iot_gateway_test/sensor";
+
+    fn broker_host() -> String {
+        std::env::var("MqttTestBrokerHost").unwrap_or_else(|_| "localhost".to_string())
+    }
+
+    fn config(client_id: &str) -> MqttSensorConfig {
+        MqttSensorConfig::new(&broker_host(), client_id, &[TOPIC], "seq=seq", "", None).unwrap()
+    }
+
+    fn client_id(name: &str) -> String {
+        format!("iot_gateway_test_{}_{}", name, std::process::id())
+    }
+
+    // 接続してすぐ切断する。clean_sessionがtrueの場合はブローカーのセッションが破棄される
+    async fn connect_once(client_id: &str, clean_session: bool, publish: Option<u64>) {
+        let mut options = MqttOptions::new(client_id, broker_host(), 1883);
+        options.set_clean_session(clean_session);
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        if let Some(seq) = publish {
+            let payload = format!(r#"{{"seq": {}}}"#, seq);
+            client
+                .publish(TOPIC, QoS::AtLeastOnce, false, payload)
+                .await
+                .unwrap();
+        }
+        loop {
+            match eventloop.poll().await.unwrap() {
+                Event::Incoming(Packet::ConnAck(_)) if publish.is_none() => break,
+                Event::Incoming(Packet::PubAck(_)) => break,
+                _ => {}
+            }
+        }
+        client.disconnect().await.unwrap();
+        while !matches!(
+            eventloop.poll().await,
+            Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_)
+        ) {}
+    }
+
+    async fn publish(seq: u64) {
+        connect_once(&client_id("publisher"), true, Some(seq)).await;
+    }
+
+    // seqの値のポイントを受信するまで待つ
+    async fn received(receiver: &mut mpsc::Receiver<Vec<DataPoint>>, seq: u64) -> bool {
+        let field = format!("seq={}", seq);
+        let wait = async {
+            while let Some(points) = receiver.recv().await {
+                for point in points {
+                    let mut line = Vec::new();
+                    point.write_data_point_to(&mut line).unwrap();
+                    if String::from_utf8(line).unwrap().contains(&field) {
+                        return true;
+                    }
+                }
+            }
+            false
+        };
+        timeout(Duration::from_secs(3), wait).await.unwrap_or(false)
+    }
+
+    // 購読が始まるまで送り直す
+    async fn wait_subscribed(receiver: &mut mpsc::Receiver<Vec<DataPoint>>, seq: u64) {
+        for _ in 0..10 {
+            publish(seq).await;
+            if received(receiver, seq).await {
+                return;
+            }
+        }
+        panic!("購読が始まらない")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn keep_qos1_messages_while_offline() {
+        let client_id = client_id("qos1");
+        let (sender, mut receiver) = mpsc::channel(32);
+        let thread = SubscribeThread::start(config(&client_id), sender.clone()).unwrap();
+        wait_subscribed(&mut receiver, 1).await;
+        thread.stop().await.unwrap();
+
+        // 切断中のQoS1のメッセージはセッションに残り、再接続時に届く
+        publish(2).await;
+        let thread = SubscribeThread::start(config(&client_id), sender).unwrap();
+        assert!(received(&mut receiver, 2).await);
+        thread.stop().await.unwrap();
+        connect_once(&client_id, true, None).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn resubscribe_on_connack() {
+        let client_id = client_id("resubscribe");
+        let (sender, mut receiver) = mpsc::channel(32);
+        let thread = SubscribeThread::start(config(&client_id), sender).unwrap();
+        wait_subscribed(&mut receiver, 1).await;
+
+        // 同じクライアントIDで接続して切断させ、セッション(購読)も破棄する
+        connect_once(&client_id, true, None).await;
+        // 再接続のConnAckで購読し直していれば届く
+        let mut resubscribed = false;
+        for seq in 2..12 {
+            publish(seq).await;
+            if received(&mut receiver, seq).await {
+                resubscribed = true;
+                break;
+            }
+        }
+        assert!(resubscribed);
+        thread.stop().await.unwrap();
+        connect_once(&client_id, true, None).await;
+    }
+}
@@ -0,0 +1,163 @@
+use tokio::time::Duration;
+
+const DEFAULT_BROKER_PORT: u16 = 1883;
+const DEFAULT_CLIENT_ID: &str = "iot_gateway";
+const DEFAULT_MEASUREMENT: &str = "mqtt_sensor";
+const KEEP_ALIVE_SEC: u64 = 30;
+// ブローカーとの接続が切れた場合の再接続間隔
+const RECONNECT_INTERVAL_SEC: u64 = 5;
+// 受信データをまとめて送信する件数と周期
+const SEND_CHUNK_SIZE: usize = 50;
+const FLUSH_INTERVAL_MSEC: u64 = 1000;
+
+// JSONのパス(ドット区切り)とInfluxDBのフィールド・タグ名の対応
+#[derive(Clone, Debug)]
+pub struct PathMapping {
+    pub path: Vec<String>,
+    pub name: String,
+}
+
+// MqttSensorTopics    : 購読するトピック(カンマ区切り)     ex) sensors/vibration/#,sensors/humidity/#
+// MqttSensorFields    : JSONパス=フィールド名(カンマ区切り) ex) rms=vibration_rms,humidity.value=humidity
+// MqttSensorTags      : JSONパス=タグ名(カンマ区切り)       ex) sensor_id=sensor_id
+// MqttSensorTimestamp : UNIX時刻(msec)のJSONパス。未設定なら受信時刻
+#[derive(Clone)]
+pub struct MqttSensorConfig {
+    broker_host: String,
+    broker_port: u16,
+    client_id: String,
+    topics: Vec<String>,
+    measurement: String,
+    fields: Vec<PathMapping>,
+    tags: Vec<PathMapping>,
+    timestamp_path: Option<Vec<String>>,
+}
+
+impl MqttSensorConfig {
+    pub fn create_from_env() -> anyhow::Result<Self> {
+        let broker_host = std::env::var("MqttSensorBrokerHost")?;
+        let broker_port = match std::env::var("MqttSensorBrokerPort") {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_BROKER_PORT,
+        };
+        let client_id =
+            std::env::var("MqttSensorClientId").unwrap_or_else(|_| DEFAULT_CLIENT_ID.to_string());
+        let topics: Vec<String> = std::env::var("MqttSensorTopics")?
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if topics.is_empty() {
+            anyhow::bail!("MqttSensorTopicsが空")
+        }
+        let measurement = std::env::var("MqttSensorMeasurement")
+            .unwrap_or_else(|_| DEFAULT_MEASUREMENT.to_string());
+        let fields = parse_mappings(&std::env::var("MqttSensorFields")?)?;
+        if fields.is_empty() {
+            anyhow::bail!("MqttSensorFieldsが空")
+        }
+        let tags = match std::env::var("MqttSensorTags") {
+            Ok(t) => parse_mappings(&t)?,
+            Err(_) => Vec::new(),
+        };
+        let timestamp_path = std::env::var("MqttSensorTimestamp")
+            .ok()
+            .map(|t| parse_path(&t));
+
+        Ok(Self {
+            broker_host,
+            broker_port,
+            client_id,
+            topics,
+            measurement,
+            fields,
+            tags,
+            timestamp_path,
+        })
+    }
+
+    pub fn get_broker_host(&self) -> String {
+        self.broker_host.to_owned()
+    }
+    pub fn get_broker_port(&self) -> u16 {
+        self.broker_port
+    }
+    pub fn get_client_id(&self) -> String {
+        self.client_id.to_owned()
+    }
+    pub fn get_topics(&self) -> Vec<String> {
+        self.topics.to_owned()
+    }
+    pub fn get_measurement(&self) -> String {
+        self.measurement.to_owned()
+    }
+    pub fn get_fields(&self) -> &[PathMapping] {
+        &self.fields
+    }
+    pub fn get_tags(&self) -> &[PathMapping] {
+        &self.tags
+    }
+    pub fn get_timestamp_path(&self) -> Option<&[String]> {
+        self.timestamp_path.as_deref()
+    }
+    pub fn get_keep_alive(&self) -> Duration {
+        Duration::from_secs(KEEP_ALIVE_SEC)
+    }
+    pub fn get_reconnect_interval(&self) -> Duration {
+        Duration::from_secs(RECONNECT_INTERVAL_SEC)
+    }
+    pub fn get_send_chunk_size(&self) -> usize {
+        SEND_CHUNK_SIZE
+    }
+    pub fn get_flush_interval(&self) -> Duration {
+        Duration::from_millis(FLUSH_INTERVAL_MSEC)
+    }
+}
+
+// テスト用。環境変数を使わずに設定する
+#[cfg(test)]
+impl MqttSensorConfig {
+    pub fn new(
+        broker_host: &str,
+        client_id: &str,
+        topics: &[&str],
+        fields: &str,
+        tags: &str,
+        timestamp_path: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            broker_host: broker_host.to_string(),
+            broker_port: DEFAULT_BROKER_PORT,
+            client_id: client_id.to_string(),
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            measurement: DEFAULT_MEASUREMENT.to_string(),
+            fields: parse_mappings(fields)?,
+            tags: parse_mappings(tags)?,
+            timestamp_path: timestamp_path.map(parse_path),
+        })
+    }
+}
+
+fn parse_mappings(value: &str) -> anyhow::Result<Vec<PathMapping>> {
+    let mut mappings = Vec::new();
+    for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        let Some((path, name)) = item.split_once('=') else {
+            anyhow::bail!("JSONパスの対応が不正:{}", item)
+        };
+        mappings.push(PathMapping {
+            path: parse_path(path),
+            name: name.trim().to_string(),
+        });
+    }
+    Ok(mappings)
+}
+
+// "$.a.b" と "a.b" のどちらも受け付ける
+fn parse_path(path: &str) -> Vec<String> {
+    path.trim()
+        .trim_start_matches('$')
+        .split('.')
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect()
+}
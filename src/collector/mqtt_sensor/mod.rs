@@ -0,0 +1,8 @@
+mod collector;
+mod config;
+mod payload;
+
+#[allow(unused_imports)]
+pub use collector::MqttSensorCollector;
+#[allow(unused_imports)]
+pub use config::MqttSensorConfig;
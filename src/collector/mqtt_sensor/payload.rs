@@ -0,0 +1,147 @@
+use chrono::{DateTime, Local, TimeZone};
+use influxdb2::models::DataPoint;
+use log::debug;
+use serde_json::Value;
+
+use super::config::MqttSensorConfig;
+use crate::shift_calendar;
+
+// 受信したJSONをDataPointに変換する
+// 対応するフィールドが1つもない場合はNoneを返す
+pub fn parse_payload(
+    config: &MqttSensorConfig,
+    topic: &str,
+    payload: &[u8],
+    receive_time: DateTime<Local>,
+) -> anyhow::Result<Option<DataPoint>> {
+    let json: Value = serde_json::from_slice(payload)?;
+
+    let time = match config.get_timestamp_path() {
+        Some(path) => match lookup(&json, path).and_then(|t| t.as_i64()) {
+            Some(msec) => msec * 1_000_000,
+            None => anyhow::bail!("タイムスタンプがない:{}", topic),
+        },
+        None => match receive_time.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("in match receive_time.timestamp_nanos_opt()"),
+        },
+    };
+
+    let builder = DataPoint::builder(config.get_measurement()).tag("topic", topic);
+    let mut builder = shift_calendar::add_shift_tag(builder, Local.timestamp_nanos(time));
+    for tag in config.get_tags() {
+        match lookup(&json, &tag.path) {
+            Some(Value::String(t)) => builder = builder.tag(tag.name.clone(), t.clone()),
+            Some(Value::Null) | None => {}
+            Some(t) => builder = builder.tag(tag.name.clone(), t.to_string()),
+        }
+    }
+
+    let mut field_count = 0;
+    for field in config.get_fields() {
+        // bool,i64,f64,String,&strが可能
+        match lookup(&json, &field.path) {
+            Some(Value::Bool(t)) => builder = builder.field(field.name.clone(), *t),
+            Some(Value::Number(t)) => match t.as_f64() {
+                Some(t) => builder = builder.field(field.name.clone(), t),
+                None => continue,
+            },
+            Some(Value::String(t)) => builder = builder.field(field.name.clone(), t.clone()),
+            _ => continue,
+        }
+        field_count += 1;
+    }
+    if field_count == 0 {
+        debug!("対象フィールドがないメッセージ:{}", topic);
+        return Ok(None);
+    }
+
+    Ok(Some(builder.timestamp(time).build()?))
+}
+
+fn lookup<'a>(json: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut value = json;
+    for key in path {
+        value = match value {
+            Value::Object(map) => map.get(key)?,
+            Value::Array(list) => list.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb2::models::WriteDataPoint;
+
+    fn config(timestamp_path: Option<&str>) -> MqttSensorConfig {
+        MqttSensorConfig::new(
+            "localhost",
+            "iot_gateway",
+            &["sensors/#"],
+            "$.rms=vibration_rms,humidity.value=humidity,axes.1=axis_y,ok=ok,state=state",
+            "sensor_id=sensor_id,meta.line=line",
+            timestamp_path,
+        )
+        .unwrap()
+    }
+
+    fn line(point: &DataPoint) -> String {
+        let mut line = Vec::new();
+        point.write_data_point_to(&mut line).unwrap();
+        String::from_utf8(line).unwrap().trim().to_string()
+    }
+
+    fn receive_time() -> DateTime<Local> {
+        Local.timestamp_millis_opt(1_700_000_000_000).unwrap()
+    }
+
+    #[test]
+    fn map_json_paths() {
+        let payload = br#"{"rms": 0.25, "humidity": {"value": 40}, "axes": [1.5, 2.5],
+            "ok": true, "state": "run", "sensor_id": "vib-01", "meta": {"line": 3}}"#;
+        let point = parse_payload(&config(None), "sensors/vib", payload, receive_time())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            line(&point),
+            "mqtt_sensor,line=3,sensor_id=vib-01,topic=sensors/vib \
+             axis_y=2.5,humidity=40,ok=t,state=\"run\",vibration_rms=0.25 \
+             1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn skip_missing_fields() {
+        let payload =
+            br#"{"rms": null, "humidity": {"value": "x"}, "axes": [1.5], "sensor_id": null}"#;
+        let point = parse_payload(&config(None), "sensors/vib", payload, receive_time())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            line(&point),
+            "mqtt_sensor,topic=sensors/vib humidity=\"x\" 1700000000000000000"
+        );
+        let payload = br#"{"temperature": 20.5}"#;
+        assert!(
+            parse_payload(&config(None), "sensors/vib", payload, receive_time())
+                .unwrap()
+                .is_none()
+        );
+        assert!(parse_payload(&config(None), "sensors/vib", b"rms=1", receive_time()).is_err());
+    }
+
+    #[test]
+    fn timestamp_from_payload() {
+        let config = config(Some("$.ts"));
+        let payload = br#"{"rms": 0.5, "ts": 1700000001234}"#;
+        let point = parse_payload(&config, "sensors/vib", payload, receive_time())
+            .unwrap()
+            .unwrap();
+        assert!(line(&point).ends_with(" 1700000001234000000"));
+        let payload = br#"{"rms": 0.5}"#;
+        assert!(parse_payload(&config, "sensors/vib", payload, receive_time()).is_err());
+    }
+}
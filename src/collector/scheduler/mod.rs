@@ -0,0 +1,226 @@
+use chrono::{DateTime, Local};
+use influxdb2::models::DataPoint;
+use log::warn;
+use tokio::time::{Duration, Instant};
+
+use crate::shift_calendar;
+
+// 超過回数の出力周期(sec)
+const DIAGNOSTICS_INTERVAL_SECOND: i64 = 60;
+
+// 処理が周期を超えた場合の動作
+// skip  : 間に合わなかった周期を飛ばし、元の位相で再開する
+// burst : 間に合わなかった周期の分を間隔を空けずに実行して追いつく
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverrunPolicy {
+    Skip,
+    Burst,
+}
+
+impl OverrunPolicy {
+    pub fn create_from_env(key: &str) -> anyhow::Result<Self> {
+        let policy = match std::env::var(key).as_deref() {
+            Ok("skip") | Err(_) => Self::Skip,
+            Ok("burst") => Self::Burst,
+            Ok(t) => anyhow::bail!("{}が不正:{}", key, t),
+        };
+        Ok(policy)
+    }
+}
+
+// 固定周期のポーリングスケジューラ
+// 次の実行時刻を前回の予定時刻から計算するので処理時間によって周期がずれない
+pub struct PollScheduler {
+    name: String,
+    period: Duration,
+    policy: OverrunPolicy,
+    next: Instant,
+    // 周期を超えた回数
+    overrun_count: u64,
+    // Skipで飛ばした周期の数
+    skipped_ticks: u64,
+    // 周期の超過から追いついていない間はtrue。Burstで同じ超過を数え直さない
+    in_overrun: bool,
+    // 出力済みの回数
+    reported: (u64, u64),
+    report_start: Option<DateTime<Local>>,
+}
+
+// 出力周期内のポーリングの超過
+pub struct SchedulerDiagnostics {
+    pub dt: DateTime<Local>,
+    pub name: String,
+    pub period_ms: u64,
+    pub overrun_count: u64,
+    pub skipped_ticks: u64,
+    pub total_overrun_count: u64,
+    pub total_skipped_ticks: u64,
+}
+
+impl SchedulerDiagnostics {
+    pub fn to_data_point(&self, measurement: &str) -> anyhow::Result<DataPoint> {
+        let time = match self.dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("SchedulerDiagnostics::to_data_pointでエラー"),
+        };
+        let point = shift_calendar::add_shift_tag(DataPoint::builder(measurement), self.dt)
+            .tag("info_type", "diagnostics")
+            .tag("group", self.name.as_str())
+            .field("period_ms", self.period_ms as i64)
+            .field("overrun_count", self.overrun_count as i64)
+            .field("skipped_ticks", self.skipped_ticks as i64)
+            .field("total_overrun_count", self.total_overrun_count as i64)
+            .field("total_skipped_ticks", self.total_skipped_ticks as i64)
+            .timestamp(time)
+            .build()?;
+        Ok(point)
+    }
+}
+
+impl PollScheduler {
+    pub fn new(name: &str, period_ms: u64, policy: OverrunPolicy) -> Self {
+        Self {
+            name: name.to_string(),
+            period: Duration::from_millis(period_ms),
+            policy,
+            next: Instant::now(),
+            overrun_count: 0,
+            skipped_ticks: 0,
+            in_overrun: false,
+            reported: (0, 0),
+            report_start: None,
+        }
+    }
+
+    // 周期の変更は次の実行から反映する
+    pub fn set_period(&mut self, period_ms: u64) {
+        let period = Duration::from_millis(period_ms);
+        if period == self.period {
+            return;
+        }
+        self.next = self.next - self.period + period;
+        self.period = period;
+    }
+
+    pub fn get_deadline(&self) -> Instant {
+        self.next
+    }
+    pub fn get_overrun_count(&self) -> u64 {
+        self.overrun_count
+    }
+    pub fn get_skipped_ticks(&self) -> u64 {
+        self.skipped_ticks
+    }
+
+    // 出力周期に達した場合は前回の出力からの超過回数を返す
+    pub fn report(&mut self, dt: DateTime<Local>) -> Option<SchedulerDiagnostics> {
+        let report_start = *self.report_start.get_or_insert(dt);
+        if (dt - report_start).num_seconds() < DIAGNOSTICS_INTERVAL_SECOND {
+            return None;
+        }
+        self.report_start = Some(dt);
+        let (overrun_count, skipped_ticks) =
+            std::mem::replace(&mut self.reported, (self.overrun_count, self.skipped_ticks));
+        Some(SchedulerDiagnostics {
+            dt,
+            name: self.name.to_owned(),
+            period_ms: self.period.as_millis() as u64,
+            overrun_count: self.overrun_count - overrun_count,
+            skipped_ticks: self.skipped_ticks - skipped_ticks,
+            total_overrun_count: self.overrun_count,
+            total_skipped_ticks: self.skipped_ticks,
+        })
+    }
+
+    // 次の予定時刻まで待って予定時刻を返す
+    // 待機中にキャンセルされても状態は変わらないのでselect!で使ってよい
+    pub async fn tick(&mut self) -> Instant {
+        let now = Instant::now();
+        if now >= self.next + self.period {
+            let missed = ((now - self.next).as_nanos() / self.period.as_nanos().max(1)) as u32;
+            if self.policy == OverrunPolicy::Skip {
+                self.skipped_ticks += missed as u64;
+                self.next += self.period * missed;
+            }
+            // Burstは追いつくまで毎回遅れているので、1回の超過として数える
+            if !self.in_overrun {
+                self.overrun_count += 1;
+                warn!(
+                    "{}のポーリングが周期を超過:{:?}周期遅れ(超過{:?}回,スキップ{:?}周期)",
+                    self.name, missed, self.overrun_count, self.skipped_ticks
+                );
+            }
+            self.in_overrun = self.policy == OverrunPolicy::Burst;
+        } else {
+            self.in_overrun = false;
+        }
+        tokio::time::sleep_until(self.next).await;
+        let scheduled = self.next;
+        self.next += self.period;
+        scheduled
+    }
+}
+
+// 周期の異なる複数のスケジューラのうち、次に実行するものを待つ
+// 返り値はschedulersの位置
+pub async fn wait_next(schedulers: &mut [&mut PollScheduler]) -> usize {
+    let Some((position, _)) = schedulers
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, scheduler)| scheduler.get_deadline())
+    else {
+        return std::future::pending().await;
+    };
+    schedulers[position].tick().await;
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn burst_counts_one_overrun_until_caught_up() {
+        let mut scheduler = PollScheduler::new("test", 20, OverrunPolicy::Burst);
+        scheduler.tick().await;
+        std::thread::sleep(std::time::Duration::from_millis(90));
+        // 遅れた周期の分を続けて実行する
+        for _ in 0..4 {
+            scheduler.tick().await;
+        }
+        assert_eq!(scheduler.get_overrun_count(), 1);
+        assert_eq!(scheduler.get_skipped_ticks(), 0);
+    }
+
+    #[tokio::test]
+    async fn skip_moves_to_next_phase() {
+        let mut scheduler = PollScheduler::new("test", 20, OverrunPolicy::Skip);
+        let first = scheduler.tick().await;
+        std::thread::sleep(std::time::Duration::from_millis(90));
+        let scheduled = scheduler.tick().await;
+        assert_eq!(scheduler.get_overrun_count(), 1);
+        assert!(scheduler.get_skipped_ticks() >= 3);
+        // 元の位相のまま
+        assert_eq!((scheduled - first).as_millis() % 20, 0);
+        scheduler.tick().await;
+        assert_eq!(scheduler.get_overrun_count(), 1);
+    }
+
+    #[test]
+    fn report_counts_since_last_report() {
+        let mut scheduler = PollScheduler::new("test", 20, OverrunPolicy::Skip);
+        let start = Local::now();
+        assert!(scheduler.report(start).is_none());
+        scheduler.overrun_count = 2;
+        let diagnostics = scheduler
+            .report(start + chrono::Duration::seconds(DIAGNOSTICS_INTERVAL_SECOND))
+            .unwrap();
+        assert_eq!(diagnostics.overrun_count, 2);
+        scheduler.overrun_count = 3;
+        let diagnostics = scheduler
+            .report(start + chrono::Duration::seconds(DIAGNOSTICS_INTERVAL_SECOND * 2))
+            .unwrap();
+        assert_eq!(diagnostics.overrun_count, 1);
+        assert_eq!(diagnostics.total_overrun_count, 3);
+    }
+}
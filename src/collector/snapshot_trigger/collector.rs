@@ -0,0 +1,160 @@
+use chrono::Local;
+use influxdb2::models::DataPoint;
+use log::{debug, warn};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, MissedTickBehavior};
+
+use super::config::SnapshotTriggerConfig;
+use crate::collector::host_link::HostLinkSession;
+use crate::shift_calendar;
+
+// トリガーデバイスを高頻度で監視し、エッジを検出したときだけスナップショットを読み出す
+pub struct SnapshotTriggerCollector {
+    config: SnapshotTriggerConfig,
+    data_sender: mpsc::Sender<Vec<DataPoint>>,
+    thread: Option<TriggerThread>,
+}
+
+impl SnapshotTriggerCollector {
+    pub fn create_from_env(data_sender: mpsc::Sender<Vec<DataPoint>>) -> anyhow::Result<Self> {
+        let config = SnapshotTriggerConfig::create_from_env()?;
+        Ok(Self {
+            config,
+            data_sender,
+            thread: None,
+        })
+    }
+
+    pub async fn start_data_collection(&mut self) -> anyhow::Result<()> {
+        if self.thread.is_some() {
+            anyhow::bail!("start_data_collection can not execute: already watching")
+        }
+        let thread = TriggerThread::start(self.config.clone(), self.data_sender.clone());
+        self.thread = Some(thread);
+        debug!("SnapshotTriggerCollector collect start");
+        Ok(())
+    }
+
+    pub async fn stop_data_collection(&mut self) -> anyhow::Result<()> {
+        if let Some(thread) = self.thread.take() {
+            thread.stop().await?;
+        } else {
+            anyhow::bail!("stop_data_collection can not execute: not watching")
+        }
+        debug!("SnapshotTriggerCollector collect stop");
+        Ok(())
+    }
+}
+
+impl Drop for SnapshotTriggerCollector {
+    fn drop(&mut self) {
+        task::block_in_place(|| {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                if self.thread.is_some() {
+                    self.stop_data_collection().await.unwrap();
+                }
+            });
+        });
+    }
+}
+
+struct TriggerThread {
+    trigger_thread: JoinHandle<()>,
+    stop_sender: mpsc::Sender<()>,
+}
+
+impl TriggerThread {
+    fn start(config: SnapshotTriggerConfig, data_sender: mpsc::Sender<Vec<DataPoint>>) -> Self {
+        let (stop_sender, mut stop_receiver) = mpsc::channel(32);
+        let trigger_thread = tokio::spawn(async move {
+            loop {
+                let result = tokio::select! {
+                    _ = stop_receiver.recv() => break,
+                    result = watch_trigger(&config, &data_sender) => result,
+                };
+                if let Err(r) = result {
+                    warn!("スナップショットトリガーの監視エラー:{:?}", r);
+                }
+                // 再接続
+                tokio::select! {
+                    _ = stop_receiver.recv() => break,
+                    _ = tokio::time::sleep(Duration::from_secs(config.get_reconnect_interval())) => {}
+                }
+            }
+        });
+        Self {
+            trigger_thread,
+            stop_sender,
+        }
+    }
+
+    async fn stop(self) -> anyhow::Result<()> {
+        self.stop_sender.send(()).await?;
+        // 完了を待つ処理
+        self.trigger_thread.await?;
+        Ok(())
+    }
+}
+
+// 通信エラーが発生するまで監視を続ける
+async fn watch_trigger(
+    config: &SnapshotTriggerConfig,
+    data_sender: &mpsc::Sender<Vec<DataPoint>>,
+) -> anyhow::Result<()> {
+    let session = HostLinkSession::get(&config.get_target())?;
+    let read_plan = config.get_read_plan();
+
+    let trigger_device = config.get_trigger_device();
+    let mut last = session.read_device(&trigger_device).await?.as_i64();
+    debug!("トリガー監視開始:{}:{}", config.get_name(), trigger_device);
+
+    let mut interval = tokio::time::interval(Duration::from_millis(config.get_trigger_interval()));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let now = match timeout(Duration::from_secs(5), session.read_device(&trigger_device)).await
+        {
+            Ok(Ok(t)) => t.as_i64(),
+            Ok(Err(e)) => anyhow::bail!("error in read trigger device:{}", e),
+            Err(_) => anyhow::bail!("timeout in read trigger device"),
+        };
+        if !config.get_edge().is_triggered(last, now) {
+            last = now;
+            continue;
+        }
+        last = now;
+
+        let dt = Local::now();
+        let res = match timeout(Duration::from_secs(5), read_plan.read(&session)).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => anyhow::bail!("error in read snapshot:{}", e),
+            Err(_) => anyhow::bail!("timeout in read snapshot"),
+        };
+        let res: Vec<&str> = res.split(' ').collect();
+        let values = config.get_snapshot_devices().decode(&res)?;
+
+        let time = match dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("in match dt.timestamp_nanos_opt()"),
+        };
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder(config.get_measurement()), dt)
+                .tag("trigger", config.get_name())
+                .field("trigger_value", now);
+        for (name, value) in &values {
+            builder = value.add_field(builder, name);
+        }
+        data_sender
+            .send(vec![builder.timestamp(time).build()?])
+            .await?;
+
+        // PLCへの応答
+        if let Some((ack_device, ack_value)) = config.get_ack() {
+            session.write_device(&ack_device, ack_value).await?;
+        }
+        debug!("スナップショットを送信:{}", config.get_name());
+    }
+}
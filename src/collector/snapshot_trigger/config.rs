@@ -0,0 +1,132 @@
+use crate::collector::host_link::{Device, DeviceMap, HostLinkTarget, ReadMode, ReadPlan};
+
+// トリガーデバイスの監視間隔
+const DEFAULT_TRIGGER_INTERVAL: u64 = 20;
+const DEFAULT_MEASUREMENT: &str = "snapshot";
+// スナップショットを同じブロックにまとめるアドレスの隙間
+const SNAPSHOT_BLOCK_GAP: u32 = 16;
+// 通信エラー後の再接続間隔
+const RECONNECT_INTERVAL_SEC: u64 = 5;
+
+// rising : 0→0以外、falling : 0以外→0、change : 値の変化
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerEdge {
+    Rising,
+    Falling,
+    Change,
+}
+
+impl TriggerEdge {
+    pub fn is_triggered(&self, last: i64, now: i64) -> bool {
+        match self {
+            Self::Rising => last == 0 && now != 0,
+            Self::Falling => last != 0 && now == 0,
+            Self::Change => last != now,
+        }
+    }
+}
+
+// SnapshotTriggerName    : トリガー名(タグ)
+// SnapshotTriggerDevice  : 監視するデバイス ex) MR300、DM300.U
+// SnapshotTriggerEdge    : rising/falling/change 未設定ならビットはrising、ワードはchange
+// SnapshotTriggerDevices : トリガー時に読み出すデバイス ex) bag_length=DM500.U,seal_temp=DM502.U
+// SnapshotTriggerAckDevice, SnapshotTriggerAckValue : 読み出し後にPLCへ書き込む応答(任意)
+#[derive(Clone)]
+pub struct SnapshotTriggerConfig {
+    target: HostLinkTarget,
+    name: String,
+    trigger_device: Device,
+    edge: TriggerEdge,
+    trigger_interval: u64,
+    snapshot_devices: DeviceMap,
+    read_plan: ReadPlan,
+    ack: Option<(Device, i64)>,
+    measurement: String,
+}
+
+impl SnapshotTriggerConfig {
+    pub fn create_from_env() -> anyhow::Result<Self> {
+        let target = HostLinkTarget::create_from_env("SnapshotTrigger")?;
+        let name = std::env::var("SnapshotTriggerName")?;
+        let trigger_device = Device::parse(&std::env::var("SnapshotTriggerDevice")?)?;
+        let edge = match std::env::var("SnapshotTriggerEdge").as_deref() {
+            Ok("rising") => TriggerEdge::Rising,
+            Ok("falling") => TriggerEdge::Falling,
+            Ok("change") => TriggerEdge::Change,
+            Ok(t) => anyhow::bail!("SnapshotTriggerEdgeが不正:{}", t),
+            Err(_) if trigger_device.is_bit() => TriggerEdge::Rising,
+            Err(_) => TriggerEdge::Change,
+        };
+        let trigger_interval = match std::env::var("SnapshotTriggerInterval") {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_TRIGGER_INTERVAL,
+        };
+
+        let snapshot_devices = DeviceMap::create_from_env("SnapshotTriggerDevices")?;
+        if snapshot_devices.is_empty() {
+            anyhow::bail!("SnapshotTriggerDevicesが空")
+        }
+        // スナップショットはまとめて読み出すのでRDSを使う
+        let read_plan = ReadPlan::create(
+            &snapshot_devices.devices(),
+            ReadMode::Block,
+            SNAPSHOT_BLOCK_GAP,
+        )?;
+
+        let ack = match std::env::var("SnapshotTriggerAckDevice") {
+            Ok(t) => {
+                let value = match std::env::var("SnapshotTriggerAckValue") {
+                    Ok(t) => t.parse()?,
+                    Err(_) => 1,
+                };
+                Some((Device::parse(&t)?, value))
+            }
+            Err(_) => None,
+        };
+        let measurement = std::env::var("SnapshotTriggerMeasurement")
+            .unwrap_or_else(|_| DEFAULT_MEASUREMENT.to_string());
+
+        Ok(Self {
+            target,
+            name,
+            trigger_device,
+            edge,
+            trigger_interval,
+            snapshot_devices,
+            read_plan,
+            ack,
+            measurement,
+        })
+    }
+
+    pub fn get_target(&self) -> HostLinkTarget {
+        self.target.to_owned()
+    }
+    pub fn get_name(&self) -> String {
+        self.name.to_owned()
+    }
+    pub fn get_trigger_device(&self) -> Device {
+        self.trigger_device
+    }
+    pub fn get_edge(&self) -> TriggerEdge {
+        self.edge
+    }
+    pub fn get_trigger_interval(&self) -> u64 {
+        self.trigger_interval
+    }
+    pub fn get_snapshot_devices(&self) -> &DeviceMap {
+        &self.snapshot_devices
+    }
+    pub fn get_read_plan(&self) -> &ReadPlan {
+        &self.read_plan
+    }
+    pub fn get_ack(&self) -> Option<(Device, i64)> {
+        self.ack
+    }
+    pub fn get_measurement(&self) -> String {
+        self.measurement.to_owned()
+    }
+    pub fn get_reconnect_interval(&self) -> u64 {
+        RECONNECT_INTERVAL_SEC
+    }
+}
@@ -0,0 +1,7 @@
+mod collector;
+mod config;
+
+#[allow(unused_imports)]
+pub use collector::SnapshotTriggerCollector;
+#[allow(unused_imports)]
+pub use config::SnapshotTriggerConfig;
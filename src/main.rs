@@ -3,14 +3,19 @@ use log::{debug, error};
 use tokio::sync::mpsc;
 use tokio::time::Duration;
 
+mod alarm;
+mod api;
 mod collector;
 mod influxdb;
+mod processing;
 mod runner;
+mod shift_calendar;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     mylogger::init();
     dotenv::dotenv().ok();
+    shift_calendar::init_from_env()?;
     if let Err(r) = demo_cpb16_running().await {
         error!("{{:?}}:{:?}", r);
         anyhow::bail!("error at demo_cpb16_running")
@@ -0,0 +1,515 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Local, TimeZone};
+use influxdb2::models::data_point::DataPointBuilder;
+use influxdb2::models::DataPoint;
+
+use crate::shift_calendar;
+
+// 区間の長さのデフォルト(msec)
+const DEFAULT_WINDOW_MSEC: i64 = 1000;
+// 区間は0時から区切るので出力間隔は1日を割り切れる値にする
+const DAY_MSEC: i64 = 86_400_000;
+
+// 集計方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregateFunction {
+    Min,
+    Max,
+    Mean,
+    Last,
+    Count,
+    Sum,
+    // 標準偏差(母集団)
+    Std,
+    // 時間加重平均。次のサンプルまで値が続いていたとする
+    TimeWeightedMean,
+}
+
+impl AggregateFunction {
+    pub const ALL: &'static [AggregateFunction] = &[
+        Self::Min,
+        Self::Max,
+        Self::Mean,
+        Self::Last,
+        Self::Count,
+        Self::Sum,
+        Self::Std,
+        Self::TimeWeightedMean,
+    ];
+
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match Self::ALL.iter().find(|t| t.as_str() == value) {
+            Some(t) => Ok(*t),
+            None => anyhow::bail!("集計方法が不正:{}", value),
+        }
+    }
+
+    // フィールド名の接尾辞
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Mean => "mean",
+            Self::Last => "last",
+            Self::Count => "count",
+            Self::Sum => "sum",
+            Self::Std => "std",
+            Self::TimeWeightedMean => "twa",
+        }
+    }
+}
+
+// フィールド毎の集計の設定
+// {prefix}AggregateFields      : フィールド名と集計方法 ex) tempureture_1=min|max|mean,tempureture_2=twa
+//                                集計方法を省略した場合は全て。未設定の場合は集計しない
+// {prefix}AggregateWindowMsec  : 区間の長さ(msec)。デフォルト1000
+// {prefix}AggregateSlideMsec   : スライディングウィンドウの出力間隔(msec)。未設定の場合は区間が重ならない
+// {prefix}AggregateKeepRaw     : falseの場合は生データを送信しない。デフォルトtrue
+// {prefix}AggregateMeasurement : 出力先のmeasurement。デフォルトは{measurement}_aggregate
+#[derive(Clone, Debug)]
+pub struct AggregateConfig {
+    measurement: String,
+    window_msec: i64,
+    slide_msec: i64,
+    fields: Vec<(String, Vec<AggregateFunction>)>,
+    keep_raw: bool,
+}
+
+impl AggregateConfig {
+    pub fn create_from_env(prefix: &str, measurement: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(value) = std::env::var(format!("{}AggregateFields", prefix)) else {
+            return Ok(None);
+        };
+        let mut fields = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (name, functions) = match item.split_once('=') {
+                Some((name, functions)) => (
+                    name.trim(),
+                    functions
+                        .split('|')
+                        .map(|t| AggregateFunction::parse(t.trim()))
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                ),
+                None => (item, AggregateFunction::ALL.to_vec()),
+            };
+            if name.is_empty() || functions.is_empty() {
+                anyhow::bail!("{}AggregateFieldsが不正:{}", prefix, item)
+            }
+            fields.push((name.to_string(), functions));
+        }
+        if fields.is_empty() {
+            anyhow::bail!("{}AggregateFieldsが空", prefix)
+        }
+
+        let window_msec = match std::env::var(format!("{}AggregateWindowMsec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_WINDOW_MSEC,
+        };
+        let slide_msec = match std::env::var(format!("{}AggregateSlideMsec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => window_msec,
+        };
+        if window_msec <= 0 || slide_msec <= 0 || slide_msec > window_msec {
+            anyhow::bail!(
+                "{}AggregateWindowMsec,{}AggregateSlideMsecが不正:{}:{}",
+                prefix,
+                prefix,
+                window_msec,
+                slide_msec
+            )
+        }
+        if DAY_MSEC % slide_msec != 0 {
+            anyhow::bail!("集計の出力間隔は1日を割り切れる値を指定:{}", slide_msec)
+        }
+        let keep_raw = match std::env::var(format!("{}AggregateKeepRaw", prefix)).as_deref() {
+            Ok("true") | Err(_) => true,
+            Ok("false") => false,
+            Ok(t) => anyhow::bail!("{}AggregateKeepRawが不正:{}", prefix, t),
+        };
+        let measurement = std::env::var(format!("{}AggregateMeasurement", prefix))
+            .unwrap_or_else(|_| format!("{}_aggregate", measurement));
+
+        Ok(Some(Self {
+            measurement,
+            window_msec,
+            slide_msec,
+            fields,
+            keep_raw,
+        }))
+    }
+
+    pub fn get_fields(&self) -> Vec<String> {
+        self.fields
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+
+    pub fn get_keep_raw(&self) -> bool {
+        self.keep_raw
+    }
+}
+
+struct Sample {
+    time: i64,
+    // 設定したフィールドの順。受信データにない場合はNone
+    values: Vec<Option<f64>>,
+}
+
+// 時計に合わせた区間毎にフィールドを集計する
+// 区間[開始,終了)の集計は終了後の最初のサンプルで出力し、タイムスタンプは区間の開始とする
+// スライディングウィンドウの場合は出力間隔毎に直近の区間を集計する
+// 受信が途切れる場合(停止・切断)はflushで集計中の区間を出力してから次の受信を待つ
+pub struct Aggregator {
+    config: AggregateConfig,
+    samples: VecDeque<Sample>,
+    // 次に出力する区間の終了(ミリ秒タイムスタンプ)
+    next_end: Option<i64>,
+}
+
+impl Aggregator {
+    pub fn new(config: AggregateConfig) -> Self {
+        Self {
+            config,
+            samples: VecDeque::new(),
+            next_end: None,
+        }
+    }
+
+    // 受信毎に呼ぶ。区間が終了した場合は集計結果を返す
+    pub fn push(
+        &mut self,
+        dt: DateTime<Local>,
+        values: &[(&str, f64)],
+    ) -> anyhow::Result<Vec<DataPoint>> {
+        let time = dt.timestamp_millis();
+        let mut points = Vec::new();
+        // 時刻が戻った場合は集計をやり直す
+        if self.samples.back().is_some_and(|t| t.time > time) {
+            points = self.flush()?;
+        }
+        let slide = self.config.slide_msec;
+        let window = self.config.window_msec;
+        if self.next_end.is_none() {
+            self.next_end = Some(align(dt, slide) + slide);
+        }
+        while let Some(end) = self.next_end.filter(|t| time >= *t) {
+            if let Some(point) = self.make_point(end, end)? {
+                points.push(point);
+            }
+            let next_end = end + slide;
+            self.next_end = Some(next_end);
+            self.drop_before(next_end - window);
+            // 今回のサンプルより前の区間にサンプルがない場合は今回のサンプルの区間まで飛ばす
+            let has_sample = self
+                .samples
+                .back()
+                .is_some_and(|t| t.time >= next_end - window);
+            if time >= next_end && !has_sample {
+                self.samples.clear();
+                self.next_end = Some(align(dt, slide) + slide);
+            }
+        }
+
+        let values = self
+            .config
+            .fields
+            .iter()
+            .map(|(name, _)| values.iter().find(|t| t.0 == name).map(|t| t.1))
+            .collect();
+        self.samples.push_back(Sample { time, values });
+        Ok(points)
+    }
+
+    // 集計中の区間を最後のサンプルまでで出力し、集計をやり直す
+    pub fn flush(&mut self) -> anyhow::Result<Vec<DataPoint>> {
+        let mut points = Vec::new();
+        if let Some(last) = self.samples.back().map(|t| t.time) {
+            while let Some(end) = self
+                .next_end
+                .filter(|t| *t - self.config.window_msec <= last)
+            {
+                if let Some(point) = self.make_point(end, last)? {
+                    points.push(point);
+                }
+                self.next_end = Some(end + self.config.slide_msec);
+            }
+        }
+        self.samples.clear();
+        self.next_end = None;
+        Ok(points)
+    }
+
+    // 区間の開始以前のサンプルは時間加重平均のために1つだけ残す
+    fn drop_before(&mut self, start: i64) {
+        while self.samples.len() >= 2 && self.samples[1].time <= start {
+            self.samples.pop_front();
+        }
+    }
+
+    // data_endは最後のサンプルの値が続いていたとする時刻
+    fn make_point(&self, end: i64, data_end: i64) -> anyhow::Result<Option<DataPoint>> {
+        let start = end - self.config.window_msec;
+        let in_window: Vec<&Sample> = self
+            .samples
+            .iter()
+            .filter(|t| (start..end).contains(&t.time))
+            .collect();
+        let Some(first) = in_window.first() else {
+            return Ok(None);
+        };
+        // 区間の開始時点の値
+        let carry = self.samples.iter().rev().find(|t| t.time < start);
+        // 区間の開始前から受信していて、区間の終了まで受信した場合のみ全体を集計できている
+        let is_partial = (carry.is_none() && first.time > start) || data_end < end;
+
+        let Some(dt) = Local.timestamp_millis_opt(start).single() else {
+            anyhow::bail!("Aggregator::make_pointでエラー")
+        };
+        let time = match dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("Aggregator::make_pointでエラー"),
+        };
+        let mut builder =
+            shift_calendar::add_shift_tag(DataPoint::builder(&self.config.measurement), dt)
+                .tag("info_type", "aggregate")
+                .field("window_msec", self.config.window_msec)
+                .field("is_partial", is_partial);
+        for (index, (name, functions)) in self.config.fields.iter().enumerate() {
+            let values: Vec<(i64, f64)> = in_window
+                .iter()
+                .filter_map(|t| t.values[index].map(|v| (t.time, v)))
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            let carry = carry.and_then(|t| t.values[index]);
+            let stats = FieldStats::new(&values, carry, start, data_end.min(end));
+            for function in functions {
+                builder = stats.add_field(builder, name, *function);
+            }
+        }
+        Ok(Some(builder.timestamp(time).build()?))
+    }
+}
+
+// 1区間・1フィールドの集計値
+struct FieldStats {
+    count: i64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    last: f64,
+    sum: f64,
+    std: f64,
+    time_weighted_mean: f64,
+}
+
+impl FieldStats {
+    fn new(values: &[(i64, f64)], carry: Option<f64>, start: i64, data_end: i64) -> Self {
+        let count = values.len() as f64;
+        let sum: f64 = values.iter().map(|t| t.1).sum();
+        let mean = sum / count;
+        let variance = values.iter().map(|t| (t.1 - mean).powi(2)).sum::<f64>() / count;
+        let last = values[values.len() - 1].1;
+
+        // 各値は次のサンプル(最後の値はdata_end)まで続いていたとする
+        let mut weighted_sum = 0.0;
+        let mut weight = 0;
+        let mut previous = carry.map(|t| (start, t));
+        let ends = values.iter().map(|t| t.0).chain(std::iter::once(data_end));
+        for (end, current) in ends.zip(values.iter().map(Some).chain(std::iter::once(None))) {
+            if let Some((previous_time, previous_value)) = previous {
+                let duration = end - previous_time;
+                if duration > 0 {
+                    weighted_sum += previous_value * duration as f64;
+                    weight += duration;
+                }
+            }
+            previous = current.copied();
+        }
+        let time_weighted_mean = match weight {
+            0 => last,
+            _ => weighted_sum / weight as f64,
+        };
+
+        Self {
+            count: values.len() as i64,
+            min: values.iter().map(|t| t.1).fold(f64::INFINITY, f64::min),
+            max: values.iter().map(|t| t.1).fold(f64::NEG_INFINITY, f64::max),
+            mean,
+            last,
+            sum,
+            std: variance.sqrt(),
+            time_weighted_mean,
+        }
+    }
+
+    // フィールド名は{フィールド名}_{集計方法}。回数のみ整数
+    fn add_field(
+        &self,
+        builder: DataPointBuilder,
+        name: &str,
+        function: AggregateFunction,
+    ) -> DataPointBuilder {
+        let key = format!("{}_{}", name, function.as_str());
+        match function {
+            AggregateFunction::Count => builder.field(key, self.count),
+            AggregateFunction::Min => builder.field(key, self.min),
+            AggregateFunction::Max => builder.field(key, self.max),
+            AggregateFunction::Mean => builder.field(key, self.mean),
+            AggregateFunction::Last => builder.field(key, self.last),
+            AggregateFunction::Sum => builder.field(key, self.sum),
+            AggregateFunction::Std => builder.field(key, self.std),
+            AggregateFunction::TimeWeightedMean => builder.field(key, self.time_weighted_mean),
+        }
+    }
+}
+
+// 時計に合わせた区間の開始。0時を基準にするのでローカル時刻で揃える
+fn align(dt: DateTime<Local>, msec: i64) -> i64 {
+    let offset_msec = dt.offset().local_minus_utc() as i64 * 1000;
+    (dt.timestamp_millis() + offset_msec).div_euclid(msec) * msec - offset_msec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb2::models::WriteDataPoint;
+
+    // 0時に揃った時刻(msec)
+    const BASE_MSEC: i64 = 1_700_006_400_000;
+
+    fn aggregator(window_msec: i64, slide_msec: i64) -> Aggregator {
+        Aggregator::new(AggregateConfig {
+            measurement: "demo_machine_aggregate".to_string(),
+            window_msec,
+            slide_msec,
+            fields: vec![("temp".to_string(), AggregateFunction::ALL.to_vec())],
+            keep_raw: true,
+        })
+    }
+
+    fn at(msec: i64) -> DateTime<Local> {
+        Local.timestamp_millis_opt(BASE_MSEC + msec).unwrap()
+    }
+
+    // ラインプロトコルのフィールドとタイムスタンプ(msec)
+    fn parse(point: &DataPoint) -> (Vec<(String, String)>, i64) {
+        let mut line = Vec::new();
+        point.write_data_point_to(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        let parts: Vec<&str> = line.trim().split(' ').collect();
+        let fields = parts[1]
+            .split(',')
+            .map(|t| t.split_once('=').unwrap())
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let time = parts[2].parse::<i64>().unwrap() / 1_000_000;
+        (fields, time - BASE_MSEC)
+    }
+
+    fn field(fields: &[(String, String)], key: &str) -> String {
+        fields
+            .iter()
+            .find(|t| t.0 == key)
+            .map(|t| t.1.to_owned())
+            .unwrap_or_default()
+    }
+
+    fn number(fields: &[(String, String)], key: &str) -> f64 {
+        field(fields, key).trim_end_matches('i').parse().unwrap()
+    }
+
+    #[test]
+    fn tumbling_window_functions() {
+        let mut aggregator = aggregator(1000, 1000);
+        for (msec, value) in [(0, 1.0), (100, 3.0), (400, 2.0), (900, 6.0)] {
+            assert!(aggregator
+                .push(at(msec), &[("temp", value)])
+                .unwrap()
+                .is_empty());
+        }
+        let points = aggregator.push(at(1000), &[("temp", 10.0)]).unwrap();
+        assert_eq!(points.len(), 1);
+        let (fields, time) = parse(&points[0]);
+        assert_eq!(time, 0);
+        assert_eq!(field(&fields, "is_partial"), "f");
+        assert_eq!(field(&fields, "temp_count"), "4i");
+        assert_eq!(number(&fields, "temp_min"), 1.0);
+        assert_eq!(number(&fields, "temp_max"), 6.0);
+        assert_eq!(number(&fields, "temp_mean"), 3.0);
+        assert_eq!(number(&fields, "temp_last"), 6.0);
+        assert_eq!(number(&fields, "temp_sum"), 12.0);
+        assert!((number(&fields, "temp_std") - 3.5f64.sqrt()).abs() < 1e-9);
+        // 1.0×100 + 3.0×300 + 2.0×500 + 6.0×100
+        assert!((number(&fields, "temp_twa") - 2.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sliding_window() {
+        let mut aggregator = aggregator(2000, 1000);
+        aggregator.push(at(0), &[("temp", 1.0)]).unwrap();
+        aggregator.push(at(500), &[("temp", 2.0)]).unwrap();
+        let points = aggregator.push(at(1000), &[("temp", 3.0)]).unwrap();
+        // 受信前から始まる区間は一部のみ
+        let (fields, time) = parse(&points[0]);
+        assert_eq!(time, -1000);
+        assert_eq!(field(&fields, "is_partial"), "t");
+        assert_eq!(field(&fields, "temp_count"), "2i");
+        aggregator.push(at(1500), &[("temp", 4.0)]).unwrap();
+        let points = aggregator.push(at(2000), &[("temp", 5.0)]).unwrap();
+        let (fields, time) = parse(&points[0]);
+        assert_eq!(time, 0);
+        assert_eq!(field(&fields, "is_partial"), "f");
+        assert_eq!(field(&fields, "temp_count"), "4i");
+        assert_eq!(number(&fields, "temp_mean"), 2.5);
+        let points = aggregator.push(at(3000), &[("temp", 6.0)]).unwrap();
+        let (fields, time) = parse(&points[0]);
+        assert_eq!(time, 1000);
+        // 最後の値は区間の終了まで続いていたとする
+        assert_eq!(field(&fields, "temp_count"), "3i");
+        assert_eq!(number(&fields, "temp_twa"), 4.25);
+    }
+
+    #[test]
+    fn skip_windows_without_samples() {
+        let mut aggregator = aggregator(1000, 1000);
+        aggregator.push(at(0), &[("temp", 1.0)]).unwrap();
+        let points = aggregator.push(at(5500), &[("temp", 2.0)]).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(parse(&points[0]).1, 0);
+        let points = aggregator.push(at(6000), &[("temp", 3.0)]).unwrap();
+        let (fields, time) = parse(&points[0]);
+        assert_eq!(time, 5000);
+        assert_eq!(field(&fields, "is_partial"), "t");
+    }
+
+    #[test]
+    fn flush_partial_window() {
+        let mut aggregator = aggregator(1000, 1000);
+        aggregator.push(at(0), &[("temp", 1.0)]).unwrap();
+        aggregator.push(at(500), &[("temp", 3.0)]).unwrap();
+        let points = aggregator.flush().unwrap();
+        assert_eq!(points.len(), 1);
+        let (fields, time) = parse(&points[0]);
+        assert_eq!(time, 0);
+        assert_eq!(field(&fields, "is_partial"), "t");
+        assert_eq!(number(&fields, "temp_twa"), 1.0);
+        assert!(aggregator.flush().unwrap().is_empty());
+        // 時刻が戻った場合は集計中の区間を出力してやり直す
+        aggregator.push(at(2000), &[("temp", 1.0)]).unwrap();
+        let points = aggregator.push(at(1000), &[("temp", 2.0)]).unwrap();
+        assert_eq!(parse(&points[0]).1, 2000);
+    }
+
+    #[test]
+    fn parse_functions() {
+        assert_eq!(
+            AggregateFunction::parse("twa").unwrap(),
+            AggregateFunction::TimeWeightedMean
+        );
+        assert!(AggregateFunction::parse("median").is_err());
+    }
+}
@@ -0,0 +1,554 @@
+use chrono::{DateTime, Local};
+use influxdb2::models::DataPoint;
+
+use crate::shift_calendar;
+
+// ベースライン(指数移動平均)の時定数のデフォルト(sec)
+const DEFAULT_WINDOW_SECOND: f64 = 600.0;
+// 異常と判定するzスコアのデフォルト
+const DEFAULT_Z_THRESHOLD: f64 = 4.0;
+// 標準偏差の下限のデフォルト。値がほとんど変わらない場合にわずかな変化を異常としない
+const DEFAULT_MIN_STD: f64 = 1.0;
+// 判定を始めるまでに学習するサンプル数のデフォルト
+const DEFAULT_WARMUP_SAMPLES: u64 = 200;
+// 異常スコアの出力周期のデフォルト(sec)
+const DEFAULT_REPORT_INTERVAL_SECOND: i64 = 10;
+// zスコアの異常が続く時間の上限のデフォルト(sec)
+const DEFAULT_MAX_ACTIVE_SECOND: f64 = 1800.0;
+// 判定値がしきい値のこの割合を下回ったら異常の終了とする。しきい値付近でのばたつきを防ぐ
+const CLEAR_RATIO: f64 = 0.8;
+
+// 異常検知するフィールドの設定
+// {prefix}AnomalyFields         : フィールド名と変化率の上限(単位/sec) ex) tempureture_1=2.0,tempureture_2
+//                                 変化率の上限を省略した場合はzスコアのみ判定する
+// {prefix}AnomalyWindowSec      : ベースラインの時定数(sec)。デフォルト600
+// {prefix}AnomalyZThreshold     : 異常と判定するzスコア。デフォルト4.0
+// {prefix}AnomalyMinStd         : 標準偏差の下限(フィールドの単位)。デフォルト1.0
+// {prefix}AnomalyWarmupSamples  : 判定を始めるまでに学習するサンプル数。デフォルト200
+// {prefix}AnomalyReportSec      : 異常スコアの出力周期(sec)。デフォルト10
+// {prefix}AnomalyMaxActiveSec   : zスコアの異常がこれより続いた場合は値の水準が変わったとみなし
+//                                 ベースラインを学習し直す(sec)。デフォルト1800
+#[derive(Clone, Debug)]
+pub struct AnomalyConfig {
+    fields: Vec<(String, Option<f64>)>,
+    window_second: f64,
+    z_threshold: f64,
+    min_std: f64,
+    warmup_samples: u64,
+    report_interval: i64,
+    max_active_second: f64,
+}
+
+impl AnomalyConfig {
+    pub fn create_from_env(prefix: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(value) = std::env::var(format!("{}AnomalyFields", prefix)) else {
+            return Ok(None);
+        };
+        let mut fields = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (name, rate_limit) = match item.split_once('=') {
+                Some((name, limit)) => (name.trim(), Some(limit.trim().parse::<f64>()?)),
+                None => (item, None),
+            };
+            if name.is_empty() || rate_limit.is_some_and(|t| t <= 0.0) {
+                anyhow::bail!("{}AnomalyFieldsが不正:{}", prefix, item)
+            }
+            fields.push((name.to_string(), rate_limit));
+        }
+        if fields.is_empty() {
+            anyhow::bail!("{}AnomalyFieldsが空", prefix)
+        }
+
+        let window_second = match std::env::var(format!("{}AnomalyWindowSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_WINDOW_SECOND,
+        };
+        let z_threshold = match std::env::var(format!("{}AnomalyZThreshold", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_Z_THRESHOLD,
+        };
+        let min_std = match std::env::var(format!("{}AnomalyMinStd", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_MIN_STD,
+        };
+        let warmup_samples = match std::env::var(format!("{}AnomalyWarmupSamples", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_WARMUP_SAMPLES,
+        };
+        let report_interval = match std::env::var(format!("{}AnomalyReportSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_REPORT_INTERVAL_SECOND,
+        };
+        let max_active_second = match std::env::var(format!("{}AnomalyMaxActiveSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_MAX_ACTIVE_SECOND,
+        };
+        if window_second <= 0.0 || z_threshold <= 0.0 || min_std <= 0.0 || report_interval <= 0 {
+            anyhow::bail!(
+                "{}AnomalyWindowSec,{}AnomalyZThreshold,{}AnomalyMinStd,{}AnomalyReportSecが不正",
+                prefix,
+                prefix,
+                prefix,
+                prefix
+            )
+        }
+        if max_active_second <= 0.0 {
+            anyhow::bail!("{}AnomalyMaxActiveSecが不正:{}", prefix, max_active_second)
+        }
+        Ok(Some(Self {
+            fields,
+            window_second,
+            z_threshold,
+            min_std,
+            warmup_samples,
+            report_interval,
+            max_active_second,
+        }))
+    }
+
+    pub fn get_fields(&self) -> Vec<String> {
+        self.fields
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+}
+
+// 異常の種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnomalyKind {
+    // ベースラインからの外れ
+    ZScore,
+    // 前回の値からの変化が速すぎる
+    RateOfChange,
+}
+
+impl AnomalyKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ZScore => "zscore",
+            Self::RateOfChange => "rate_of_change",
+        }
+    }
+}
+
+// 異常の開始・終了
+#[derive(Clone, Debug)]
+pub struct AnomalyEvent {
+    pub dt: DateTime<Local>,
+    pub field: String,
+    pub kind: AnomalyKind,
+    // 開始はtrue、終了はfalse
+    pub is_active: bool,
+    pub value: f64,
+    // zスコアまたは変化率(単位/sec)
+    pub score: f64,
+    pub threshold: f64,
+    pub baseline_mean: f64,
+    pub baseline_std: f64,
+    // 異常が続いたためベースラインを学習し直して終了した場合はtrue
+    pub relearned: bool,
+}
+
+impl AnomalyEvent {
+    pub fn to_data_point(&self, measurement: &str) -> anyhow::Result<DataPoint> {
+        let time = match self.dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("AnomalyEvent::to_data_pointでエラー"),
+        };
+        let state = match self.is_active {
+            true => "start",
+            false => "end",
+        };
+        let point = shift_calendar::add_shift_tag(DataPoint::builder(measurement), self.dt)
+            .tag("info_type", "anomaly_event")
+            .tag("field", self.field.as_str())
+            .tag("kind", self.kind.as_str())
+            .field("state", state)
+            .field("is_active", self.is_active)
+            .field("value", self.value)
+            .field("score", self.score)
+            .field("threshold", self.threshold)
+            .field("baseline_mean", self.baseline_mean)
+            .field("baseline_std", self.baseline_std)
+            .field("relearned", self.relearned)
+            .timestamp(time)
+            .build()?;
+        Ok(point)
+    }
+}
+
+// 出力周期内のフィールド毎の異常スコア
+#[derive(Clone, Debug)]
+pub struct FieldScore {
+    pub name: String,
+    // zスコアの絶対値の最大
+    pub max_score: f64,
+    // 変化率の絶対値の最大(単位/sec)
+    pub max_rate: f64,
+    pub baseline_mean: Option<f64>,
+    pub baseline_std: f64,
+    // 学習が済んで判定している場合はtrue
+    pub is_ready: bool,
+    pub is_active: bool,
+}
+
+pub struct AnomalyScores {
+    pub dt: DateTime<Local>,
+    pub fields: Vec<FieldScore>,
+}
+
+impl AnomalyScores {
+    pub fn to_data_point(&self, measurement: &str) -> anyhow::Result<DataPoint> {
+        let time = match self.dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("AnomalyScores::to_data_pointでエラー"),
+        };
+        let mut builder = shift_calendar::add_shift_tag(DataPoint::builder(measurement), self.dt)
+            .tag("info_type", "anomaly_score");
+        for field in &self.fields {
+            let name = &field.name;
+            builder = builder
+                .field(format!("{}_score", name), field.max_score)
+                .field(format!("{}_max_rate", name), field.max_rate)
+                .field(format!("{}_baseline_std", name), field.baseline_std)
+                .field(format!("{}_ready", name), field.is_ready)
+                .field(format!("{}_anomaly", name), field.is_active);
+            if let Some(mean) = field.baseline_mean {
+                builder = builder.field(format!("{}_baseline_mean", name), mean);
+            }
+        }
+        Ok(builder.timestamp(time).build()?)
+    }
+}
+
+struct FieldDetector {
+    name: String,
+    rate_limit: Option<f64>,
+    // ベースラインの指数移動平均と分散
+    mean: Option<f64>,
+    variance: f64,
+    last_learned: Option<DateTime<Local>>,
+    learned_samples: u64,
+    // 変化率の判定に使う前回の値
+    last: Option<(DateTime<Local>, f64)>,
+    z_active: bool,
+    // zスコアの異常の開始時刻
+    z_since: Option<DateTime<Local>>,
+    rate_active: bool,
+    max_score: f64,
+    max_rate: f64,
+}
+
+impl FieldDetector {
+    fn new(name: &str, rate_limit: Option<f64>) -> Self {
+        Self {
+            name: name.to_string(),
+            rate_limit,
+            mean: None,
+            variance: 0.0,
+            last_learned: None,
+            learned_samples: 0,
+            last: None,
+            z_active: false,
+            z_since: None,
+            rate_active: false,
+            max_score: 0.0,
+            max_rate: 0.0,
+        }
+    }
+
+    fn std(&self, config: &AnomalyConfig) -> f64 {
+        self.variance.sqrt().max(config.min_std)
+    }
+
+    fn is_ready(&self, config: &AnomalyConfig) -> bool {
+        self.learned_samples >= config.warmup_samples
+    }
+
+    // 受信間隔が一定でなくても時定数が変わらないように重みを決める
+    fn learn(&mut self, dt: DateTime<Local>, value: f64, config: &AnomalyConfig) {
+        let Some(mean) = self.mean else {
+            self.mean = Some(value);
+            self.last_learned = Some(dt);
+            self.learned_samples = 1;
+            return;
+        };
+        let second = match self.last_learned.replace(dt) {
+            Some(t) => (dt - t).num_milliseconds() as f64 / 1000.0,
+            None => 0.0,
+        };
+        if second <= 0.0 {
+            return;
+        }
+        let alpha = 1.0 - (-second / config.window_second).exp();
+        let diff = value - mean;
+        let increment = alpha * diff;
+        self.mean = Some(mean + increment);
+        self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        self.learned_samples += 1;
+    }
+
+    fn push(
+        &mut self,
+        dt: DateTime<Local>,
+        value: f64,
+        learn: bool,
+        config: &AnomalyConfig,
+    ) -> Vec<AnomalyEvent> {
+        let mut events = Vec::new();
+        let std = self.std(config);
+        let mean = self.mean.unwrap_or(value);
+
+        // 学習前のベースラインに対するzスコア
+        // 学習しない受信(停止中等)は冷却等で値が変わるので判定せず、異常中なら終了とする
+        let score = (value - mean).abs() / std;
+        if learn && self.is_ready(config) {
+            self.max_score = self.max_score.max(score);
+            if let Some(is_active) = transition(self.z_active, score, config.z_threshold) {
+                self.z_active = is_active;
+                self.z_since = is_active.then_some(dt);
+                events.push(self.make_event(dt, AnomalyKind::ZScore, value, score, config));
+            }
+            // 設定値の変更等で値の水準が変わった場合に異常のままにならないよう、現在の値から学習し直す
+            let expired = self.z_since.is_some_and(|t| {
+                (dt - t).num_milliseconds() as f64 >= config.max_active_second * 1000.0
+            });
+            if expired {
+                self.mean = Some(value);
+                self.last_learned = None;
+                self.z_active = false;
+                self.z_since = None;
+                let mut event = self.make_event(dt, AnomalyKind::ZScore, value, score, config);
+                event.relearned = true;
+                events.push(event);
+            }
+        } else if self.z_active {
+            self.z_active = false;
+            self.z_since = None;
+            events.push(self.make_event(dt, AnomalyKind::ZScore, value, score, config));
+        }
+
+        if let Some(((last_dt, last_value), limit)) = self.last.zip(self.rate_limit) {
+            let second = (dt - last_dt).num_milliseconds() as f64 / 1000.0;
+            if second > 0.0 {
+                let rate = (value - last_value).abs() / second;
+                self.max_rate = self.max_rate.max(rate);
+                if let Some(is_active) = transition(self.rate_active, rate, limit) {
+                    self.rate_active = is_active;
+                    events.push(self.make_event(
+                        dt,
+                        AnomalyKind::RateOfChange,
+                        value,
+                        rate,
+                        config,
+                    ));
+                }
+            }
+        }
+        self.last = Some((dt, value));
+
+        // 異常中の値を学習するとベースラインが異常な値に引きずられる
+        // 学習しない間の時間は次の学習の重みに含めない
+        if learn && !self.z_active {
+            self.learn(dt, value, config);
+        } else {
+            self.last_learned = None;
+        }
+        events
+    }
+
+    fn make_event(
+        &self,
+        dt: DateTime<Local>,
+        kind: AnomalyKind,
+        value: f64,
+        score: f64,
+        config: &AnomalyConfig,
+    ) -> AnomalyEvent {
+        let (is_active, threshold) = match kind {
+            AnomalyKind::ZScore => (self.z_active, config.z_threshold),
+            AnomalyKind::RateOfChange => (self.rate_active, self.rate_limit.unwrap_or(0.0)),
+        };
+        AnomalyEvent {
+            dt,
+            field: self.name.to_owned(),
+            kind,
+            is_active,
+            value,
+            score,
+            threshold,
+            baseline_mean: self.mean.unwrap_or(value),
+            baseline_std: self.std(config),
+            relearned: false,
+        }
+    }
+
+    fn take_score(&mut self, config: &AnomalyConfig) -> FieldScore {
+        let score = FieldScore {
+            name: self.name.to_owned(),
+            max_score: self.max_score,
+            max_rate: self.max_rate,
+            baseline_mean: self.mean,
+            baseline_std: self.std(config),
+            is_ready: self.is_ready(config),
+            is_active: self.z_active || self.rate_active,
+        };
+        self.max_score = 0.0;
+        self.max_rate = 0.0;
+        score
+    }
+}
+
+// 異常の開始・終了が変わる場合は新しい状態を返す
+fn transition(is_active: bool, score: f64, threshold: f64) -> Option<bool> {
+    match is_active {
+        false if score > threshold => Some(true),
+        true if score < threshold * CLEAR_RATIO => Some(false),
+        _ => None,
+    }
+}
+
+// フィールド毎にベースライン(指数移動平均と分散)からのzスコアと変化率で異常を判定する
+// ベースラインの更新とzスコアの判定は学習を指定した受信のみで行い、変化率は全ての受信で判定する
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    fields: Vec<FieldDetector>,
+    report_start: Option<DateTime<Local>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        let fields = config
+            .fields
+            .iter()
+            .map(|(name, rate_limit)| FieldDetector::new(name, *rate_limit))
+            .collect();
+        Self {
+            config,
+            fields,
+            report_start: None,
+        }
+    }
+
+    // 受信毎に呼ぶ。異常の開始・終了を返す
+    pub fn push(
+        &mut self,
+        dt: DateTime<Local>,
+        values: &[(&str, f64)],
+        learn: bool,
+    ) -> Vec<AnomalyEvent> {
+        let mut events = Vec::new();
+        for field in self.fields.iter_mut() {
+            if let Some((_, value)) = values.iter().find(|t| t.0 == field.name) {
+                events.extend(field.push(dt, *value, learn, &self.config));
+            }
+        }
+        events
+    }
+
+    // 出力周期に達した場合は周期内の異常スコアを返す
+    pub fn report(&mut self, dt: DateTime<Local>) -> Option<AnomalyScores> {
+        let report_start = *self.report_start.get_or_insert(dt);
+        if (dt - report_start).num_seconds() < self.config.report_interval {
+            return None;
+        }
+        self.report_start = Some(dt);
+        let fields = self
+            .fields
+            .iter_mut()
+            .map(|t| t.take_score(&self.config))
+            .collect();
+        Some(AnomalyScores { dt, fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config() -> AnomalyConfig {
+        AnomalyConfig {
+            fields: vec![("temp".to_string(), None)],
+            window_second: 60.0,
+            z_threshold: 4.0,
+            min_std: 1.0,
+            warmup_samples: 10,
+            report_interval: 10,
+            max_active_second: 30.0,
+        }
+    }
+
+    fn at(second: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_700_000_000 + second, 0).unwrap()
+    }
+
+    // 0～19秒に100を学習させる
+    fn learned_detector() -> AnomalyDetector {
+        let mut detector = AnomalyDetector::new(config());
+        for second in 0..20 {
+            assert!(detector
+                .push(at(second), &[("temp", 100.0)], true)
+                .is_empty());
+        }
+        detector
+    }
+
+    #[test]
+    fn zscore_start_and_end() {
+        let mut detector = learned_detector();
+        let events = detector.push(at(20), &[("temp", 110.0)], true);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_active);
+        assert_eq!(events[0].kind, AnomalyKind::ZScore);
+        let events = detector.push(at(21), &[("temp", 100.0)], true);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].is_active);
+    }
+
+    #[test]
+    fn not_judged_while_not_learning() {
+        let mut detector = learned_detector();
+        // 停止中の冷却
+        assert!(detector.push(at(20), &[("temp", 50.0)], false).is_empty());
+        // 異常中に停止した場合は終了とする
+        let mut detector = learned_detector();
+        assert_eq!(detector.push(at(20), &[("temp", 110.0)], true).len(), 1);
+        let events = detector.push(at(21), &[("temp", 50.0)], false);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].is_active);
+        assert!(!events[0].relearned);
+    }
+
+    #[test]
+    fn relearn_after_max_active_duration() {
+        let mut detector = learned_detector();
+        assert!(detector.push(at(20), &[("temp", 110.0)], true)[0].is_active);
+        for second in 21..50 {
+            assert!(detector
+                .push(at(second), &[("temp", 110.0)], true)
+                .is_empty());
+        }
+        let events = detector.push(at(50), &[("temp", 110.0)], true);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].is_active);
+        assert!(events[0].relearned);
+        // 新しい水準では異常にならない
+        assert!(detector.push(at(51), &[("temp", 110.0)], true).is_empty());
+    }
+
+    #[test]
+    fn rate_of_change() {
+        let mut config = config();
+        config.fields = vec![("temp".to_string(), Some(2.0))];
+        let mut detector = AnomalyDetector::new(config);
+        assert!(detector.push(at(0), &[("temp", 100.0)], false).is_empty());
+        let events = detector.push(at(1), &[("temp", 105.0)], false);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AnomalyKind::RateOfChange);
+        assert!(events[0].is_active);
+        let events = detector.push(at(2), &[("temp", 105.5)], false);
+        assert!(!events[0].is_active);
+    }
+}
@@ -0,0 +1,360 @@
+use influxdb2::models::data_point::DataPointBuilder;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+// 1回の受信で増えうる最大数のデフォルト。これを超える増分は異常とする
+const DEFAULT_MAX_STEP: u64 = 1000;
+
+// 生産数などの積算カウンタの設定
+// {prefix}CounterMaxStep : 1回の受信で増えうる最大数。デフォルト1000
+#[derive(Clone, Debug)]
+pub struct CounterConfig {
+    // カウンタのビット幅。これを超えると0に戻る
+    bit_width: u32,
+    max_step: u64,
+}
+
+impl CounterConfig {
+    pub fn new(bit_width: u32, max_step: u64) -> Self {
+        Self {
+            bit_width,
+            max_step,
+        }
+    }
+
+    pub fn create_from_env(prefix: &str, bit_width: u32) -> anyhow::Result<Self> {
+        let max_step = match std::env::var(format!("{}CounterMaxStep", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_MAX_STEP,
+        };
+        // 1周の半分以上を許すと桁あふれと減少を区別できない
+        if max_step == 0 || max_step >= (1u64 << bit_width) / 2 {
+            anyhow::bail!("{}CounterMaxStepが不正:{}", prefix, max_step)
+        }
+        Ok(Self::new(bit_width, max_step))
+    }
+
+    fn modulo(&self) -> u64 {
+        1u64 << self.bit_width
+    }
+}
+
+// 増分の判定結果
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterEvent {
+    // 通常の増加
+    Normal,
+    // ビット幅を超えて0に戻った
+    Wrap,
+    // 稼働の開始等でカウンタが0から数え直された
+    Reset,
+    // 1回だけ値が飛んで元に戻った。増分は前後の値から求める
+    Glitch,
+    // 最大数を超えて増えたまま戻らない
+    Jump,
+    // 前後の値がどれとも整合しないので現在値から数え直す
+    Resync,
+    // 異常値の可能性があり次の受信で判定する
+    Pending,
+}
+
+// 受信1回分の増分
+#[derive(Clone, Copy, Debug)]
+pub struct CounterDelta {
+    pub delta: u64,
+    pub event: CounterEvent,
+}
+
+// 再起動後に引き継ぐ値
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CounterState {
+    pub last: Option<u64>,
+    pub total: u64,
+}
+
+// 出力までに発生した事象の回数
+#[derive(Clone, Debug, Default)]
+pub struct CounterFlags {
+    pub wraps: u32,
+    pub resets: u32,
+    pub glitches: u32,
+    pub jumps: u32,
+    pub resyncs: u32,
+}
+
+impl CounterFlags {
+    // 値の飛びや不整合があった場合はtrue
+    pub fn is_suspicious(&self) -> bool {
+        self.glitches + self.jumps + self.resyncs > 0
+    }
+
+    pub fn add_fields(&self, builder: DataPointBuilder, name: &str) -> DataPointBuilder {
+        builder
+            .field(format!("{}_wraps", name), self.wraps as i64)
+            .field(format!("{}_resets", name), self.resets as i64)
+            .field(format!("{}_glitches", name), self.glitches as i64)
+            .field(format!("{}_jumps", name), self.jumps as i64)
+            .field(format!("{}_resyncs", name), self.resyncs as i64)
+            .field(format!("{}_suspicious", name), self.is_suspicious())
+    }
+}
+
+// カウンタの値から増分と単調増加する積算値を求める
+// 減少は桁あふれ(ビット幅から判定)・数え直し・一時的な異常値を区別する
+// 桁あふれで説明できない減少や最大数を超える増加は保留し、次の値で判定する
+//  - 次の値が保留前の値から通常の増加 : 一時的な異常値(Glitch)
+//  - 次の値が保留した値から通常の増加 : 減少なら数え直し(Reset)、増加なら飛び(Jump)
+//  - どちらでもない                    : 現在値から数え直す(Resync)
+pub struct CounterTracker {
+    name: String,
+    config: CounterConfig,
+    last: Option<u64>,
+    pending: Option<u64>,
+    total: u64,
+    flags: CounterFlags,
+}
+
+impl CounterTracker {
+    pub fn new(name: &str, config: CounterConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            config,
+            last: None,
+            pending: None,
+            total: 0,
+            flags: CounterFlags::default(),
+        }
+    }
+
+    // 受信毎に呼ぶ。resetは稼働IDの変化や稼働開始等でPLCが0から数え直したことが分かっている場合にtrue
+    // その場合は現在値をそのまま増分とする
+    pub fn push(&mut self, value: i64, reset: bool) -> CounterDelta {
+        let modulo = self.config.modulo();
+        // 符号付きの場合も2の補数としてビット幅内に収める
+        let value = (value as u64) % modulo;
+        let last = self.last;
+
+        let result = match (last, reset) {
+            (_, true) => {
+                self.pending = None;
+                self.accept(value, value, CounterEvent::Reset)
+            }
+            // 初回の値は基準にするだけ
+            (None, false) => self.accept(value, 0, CounterEvent::Normal),
+            (Some(last), false) => match self.pending.take() {
+                None => self.judge(last, value),
+                Some(pending) => self.resolve(last, pending, value),
+            },
+        };
+        if matches!(
+            result.event,
+            CounterEvent::Glitch | CounterEvent::Jump | CounterEvent::Resync
+        ) {
+            warn!(
+                "カウンタの異常値:{}:{:?}:{:?}→{}",
+                self.name, result.event, last, value
+            );
+        }
+        result
+    }
+
+    pub fn get_name(&self) -> String {
+        self.name.to_owned()
+    }
+
+    pub fn get_total(&self) -> u64 {
+        self.total
+    }
+
+    // 最後に採用した値
+    pub fn get_last(&self) -> Option<u64> {
+        self.last
+    }
+
+    pub fn get_state(&self) -> CounterState {
+        CounterState {
+            last: self.last,
+            total: self.total,
+        }
+    }
+
+    // 次の値を基準にし直す
+    pub fn clear(&mut self) {
+        self.last = None;
+        self.pending = None;
+    }
+
+    // 保留中の値は引き継がない
+    pub fn restore(&mut self, state: CounterState) {
+        self.last = state.last;
+        self.total = state.total;
+        self.pending = None;
+    }
+
+    pub fn take_flags(&mut self) -> CounterFlags {
+        std::mem::take(&mut self.flags)
+    }
+
+    // 桁あふれを考慮した前進量。最大数を超える場合はNone
+    fn step(&self, from: u64, to: u64) -> Option<u64> {
+        let modulo = self.config.modulo();
+        let step = (to + modulo - from) % modulo;
+        (step <= self.config.max_step).then_some(step)
+    }
+
+    fn judge(&mut self, last: u64, value: u64) -> CounterDelta {
+        match self.step(last, value) {
+            Some(step) if value < last => self.accept(value, step, CounterEvent::Wrap),
+            Some(step) => self.accept(value, step, CounterEvent::Normal),
+            None => {
+                self.pending = Some(value);
+                CounterDelta {
+                    delta: 0,
+                    event: CounterEvent::Pending,
+                }
+            }
+        }
+    }
+
+    fn resolve(&mut self, last: u64, pending: u64, value: u64) -> CounterDelta {
+        if let Some(step) = self.step(last, value) {
+            return self.accept(value, step, CounterEvent::Glitch);
+        }
+        if self.step(pending, value).is_some() {
+            if pending < last {
+                // 保留した値の時点で0から数え直している
+                return self.accept(value, value, CounterEvent::Reset);
+            }
+            let step = (value + self.config.modulo() - last) % self.config.modulo();
+            return self.accept(value, step, CounterEvent::Jump);
+        }
+        self.accept(value, 0, CounterEvent::Resync)
+    }
+
+    fn accept(&mut self, value: u64, delta: u64, event: CounterEvent) -> CounterDelta {
+        self.last = Some(value);
+        self.total += delta;
+        match event {
+            CounterEvent::Wrap => self.flags.wraps += 1,
+            CounterEvent::Reset => self.flags.resets += 1,
+            CounterEvent::Glitch => self.flags.glitches += 1,
+            CounterEvent::Jump => self.flags.jumps += 1,
+            CounterEvent::Resync => self.flags.resyncs += 1,
+            CounterEvent::Normal | CounterEvent::Pending => {}
+        }
+        CounterDelta { delta, event }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16bit、1回の最大増分100
+    fn tracker() -> CounterTracker {
+        let mut tracker = CounterTracker::new("production", CounterConfig::new(16, 100));
+        tracker.push(1000, false);
+        tracker
+    }
+
+    fn push(tracker: &mut CounterTracker, value: i64) -> (u64, CounterEvent) {
+        let result = tracker.push(value, false);
+        (result.delta, result.event)
+    }
+
+    #[test]
+    fn first_value_is_baseline() {
+        let mut tracker = CounterTracker::new("production", CounterConfig::new(16, 100));
+        assert_eq!(push(&mut tracker, 1000), (0, CounterEvent::Normal));
+        assert_eq!(tracker.get_total(), 0);
+        assert_eq!(tracker.get_last(), Some(1000));
+    }
+
+    #[test]
+    fn normal_and_wrap() {
+        let mut tracker = tracker();
+        assert_eq!(push(&mut tracker, 1005), (5, CounterEvent::Normal));
+        assert_eq!(push(&mut tracker, 1005), (0, CounterEvent::Normal));
+        let mut tracker = CounterTracker::new("production", CounterConfig::new(16, 100));
+        tracker.push(65530, false);
+        assert_eq!(push(&mut tracker, 4), (10, CounterEvent::Wrap));
+        // 符号付きの値もビット幅内で扱う
+        assert_eq!(push(&mut tracker, 8), (4, CounterEvent::Normal));
+        let mut signed = CounterTracker::new("production", CounterConfig::new(16, 100));
+        signed.push(-6, false);
+        assert_eq!(signed.get_last(), Some(65530));
+        assert_eq!(tracker.take_flags().wraps, 1);
+    }
+
+    #[test]
+    fn known_reset() {
+        let mut tracker = tracker();
+        let result = tracker.push(3, true);
+        assert_eq!((result.delta, result.event), (3, CounterEvent::Reset));
+        assert_eq!(tracker.get_total(), 3);
+    }
+
+    #[test]
+    fn pending_then_glitch() {
+        let mut tracker = tracker();
+        assert_eq!(push(&mut tracker, 30000), (0, CounterEvent::Pending));
+        // 保留前の値から通常の増加
+        assert_eq!(push(&mut tracker, 1003), (3, CounterEvent::Glitch));
+        assert_eq!(tracker.get_total(), 3);
+        let flags = tracker.take_flags();
+        assert_eq!(flags.glitches, 1);
+        assert!(flags.is_suspicious());
+    }
+
+    #[test]
+    fn pending_then_reset() {
+        let mut tracker = tracker();
+        assert_eq!(push(&mut tracker, 2), (0, CounterEvent::Pending));
+        // 保留した値から通常の増加で、保留した値は減少
+        assert_eq!(push(&mut tracker, 5), (5, CounterEvent::Reset));
+        let flags = tracker.take_flags();
+        assert_eq!(flags.resets, 1);
+        assert!(!flags.is_suspicious());
+    }
+
+    #[test]
+    fn pending_then_jump() {
+        let mut tracker = tracker();
+        assert_eq!(push(&mut tracker, 1500), (0, CounterEvent::Pending));
+        assert_eq!(push(&mut tracker, 1510), (510, CounterEvent::Jump));
+        assert_eq!(tracker.get_total(), 510);
+        assert_eq!(tracker.take_flags().jumps, 1);
+    }
+
+    #[test]
+    fn pending_then_resync() {
+        let mut tracker = tracker();
+        assert_eq!(push(&mut tracker, 20000), (0, CounterEvent::Pending));
+        assert_eq!(push(&mut tracker, 40000), (0, CounterEvent::Resync));
+        // 数え直した値から通常の判定に戻る
+        assert_eq!(push(&mut tracker, 40002), (2, CounterEvent::Normal));
+        assert_eq!(tracker.get_total(), 2);
+        let flags = tracker.take_flags();
+        assert_eq!(flags.resyncs, 1);
+        assert!(flags.is_suspicious());
+    }
+
+    #[test]
+    fn restore_drops_pending() {
+        let mut tracker = tracker();
+        tracker.push(1010, false);
+        tracker.push(30000, false);
+        let state = tracker.get_state();
+        assert_eq!(
+            state,
+            CounterState {
+                last: Some(1010),
+                total: 10
+            }
+        );
+        let mut restored = CounterTracker::new("production", CounterConfig::new(16, 100));
+        restored.restore(state);
+        assert_eq!(push(&mut restored, 1012), (2, CounterEvent::Normal));
+        assert_eq!(restored.get_total(), 12);
+    }
+}
@@ -0,0 +1,364 @@
+use chrono::{DateTime, Local};
+use influxdb2::models::DataPoint;
+
+use crate::shift_calendar;
+
+// 変化がなくても送信する間隔のデフォルト(sec)
+const DEFAULT_MAX_SILENCE_SECOND: i64 = 60;
+// 圧縮率の出力周期のデフォルト(sec)
+const DEFAULT_REPORT_INTERVAL_SECOND: i64 = 60;
+
+// 不感帯
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deadband {
+    // 前回送信値との差
+    Absolute(f64),
+    // 前回送信値に対する割合(%)
+    Percent(f64),
+}
+
+impl Deadband {
+    // ex) 0.5 : 絶対値、2% : 割合
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let deadband = match value.strip_suffix('%') {
+            Some(t) => Self::Percent(t.trim().parse()?),
+            None => Self::Absolute(value.parse()?),
+        };
+        match deadband {
+            Self::Absolute(t) | Self::Percent(t) if t < 0.0 => {
+                anyhow::bail!("不感帯が不正:{}", value)
+            }
+            _ => Ok(deadband),
+        }
+    }
+
+    // 前回送信値から不感帯を超えて変化した場合はtrue
+    fn exceeds(&self, last: f64, value: f64) -> bool {
+        let change = (value - last).abs();
+        match self {
+            Self::Absolute(t) => change > *t,
+            Self::Percent(t) => change > last.abs() * t / 100.0,
+        }
+    }
+}
+
+// 変化時のみ送信する(Report by Exception)フィールドの設定
+// {prefix}DeadbandFields        : フィールド名と不感帯 ex) tempureture_1=2,tempureture_2=1.5%
+//                                 %を付けた場合は前回送信値に対する割合。未設定の場合は全て送信する
+// {prefix}DeadbandMaxSilenceSec : 変化がなくてもこの間隔で送信する(sec)。デフォルト60
+// {prefix}DeadbandReportSec     : 圧縮率の出力周期(sec)。デフォルト60
+#[derive(Clone, Debug)]
+pub struct DeadbandConfig {
+    fields: Vec<(String, Deadband)>,
+    max_silence_second: i64,
+    report_interval: i64,
+}
+
+impl DeadbandConfig {
+    pub fn create_from_env(prefix: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(value) = std::env::var(format!("{}DeadbandFields", prefix)) else {
+            return Ok(None);
+        };
+        let mut fields = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let Some((name, deadband)) = item.split_once('=') else {
+                anyhow::bail!("{}DeadbandFieldsが不正:{}", prefix, item)
+            };
+            fields.push((name.trim().to_string(), Deadband::parse(deadband.trim())?));
+        }
+        if fields.is_empty() {
+            anyhow::bail!("{}DeadbandFieldsが空", prefix)
+        }
+        let max_silence_second = match std::env::var(format!("{}DeadbandMaxSilenceSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_MAX_SILENCE_SECOND,
+        };
+        let report_interval = match std::env::var(format!("{}DeadbandReportSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_REPORT_INTERVAL_SECOND,
+        };
+        if max_silence_second <= 0 || report_interval <= 0 {
+            anyhow::bail!(
+                "{}DeadbandMaxSilenceSec,{}DeadbandReportSecが不正:{}:{}",
+                prefix,
+                prefix,
+                max_silence_second,
+                report_interval
+            )
+        }
+        Ok(Some(Self {
+            fields,
+            max_silence_second,
+            report_interval,
+        }))
+    }
+
+    pub fn get_fields(&self) -> Vec<String> {
+        self.fields
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+}
+
+// フィールド毎の受信数と送信数
+#[derive(Clone, Debug)]
+pub struct CompressionCount {
+    pub name: String,
+    pub received: u64,
+    pub written: u64,
+}
+
+impl CompressionCount {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            received: 0,
+            written: 0,
+        }
+    }
+
+    // 受信数/送信数。送信がない場合はNone
+    pub fn ratio(&self) -> Option<f64> {
+        (self.written > 0).then(|| self.received as f64 / self.written as f64)
+    }
+}
+
+// 出力周期内の圧縮の結果
+pub struct CompressionStats {
+    pub dt: DateTime<Local>,
+    pub method: &'static str,
+    pub counts: Vec<CompressionCount>,
+}
+
+impl CompressionStats {
+    pub fn to_data_point(&self, measurement: &str) -> anyhow::Result<DataPoint> {
+        let time = match self.dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("CompressionStats::to_data_pointでエラー"),
+        };
+        let mut builder = shift_calendar::add_shift_tag(DataPoint::builder(measurement), self.dt)
+            .tag("info_type", "compression")
+            .tag("method", self.method);
+        let mut total = CompressionCount::new("total");
+        for count in &self.counts {
+            total.received += count.received;
+            total.written += count.written;
+        }
+        for count in self.counts.iter().chain(std::iter::once(&total)) {
+            builder = builder
+                .field(format!("{}_received", count.name), count.received as i64)
+                .field(format!("{}_written", count.name), count.written as i64);
+            if let Some(ratio) = count.ratio() {
+                builder = builder.field(format!("{}_compression_ratio", count.name), ratio);
+            }
+        }
+        Ok(builder.timestamp(time).build()?)
+    }
+}
+
+struct DeadbandField {
+    name: String,
+    deadband: Deadband,
+    // 前回送信した時刻と値
+    last: Option<(DateTime<Local>, f64)>,
+    count: CompressionCount,
+}
+
+// フィールド毎に前回送信値から不感帯を超えて変化した場合のみ送信する
+// 変化がなくても最大間隔を超えた場合は送信し、値が途切れていないことを示す
+pub struct DeadbandFilter {
+    fields: Vec<DeadbandField>,
+    max_silence_msec: i64,
+    report_interval: i64,
+    report_start: Option<DateTime<Local>>,
+}
+
+impl DeadbandFilter {
+    pub fn new(config: DeadbandConfig) -> Self {
+        let fields = config
+            .fields
+            .iter()
+            .map(|(name, deadband)| DeadbandField {
+                name: name.to_owned(),
+                deadband: *deadband,
+                last: None,
+                count: CompressionCount::new(name),
+            })
+            .collect();
+        Self {
+            fields,
+            max_silence_msec: config.max_silence_second * 1000,
+            report_interval: config.report_interval,
+            report_start: None,
+        }
+    }
+
+    // 受信毎にフィールド毎に呼ぶ。送信する場合はtrue。設定していないフィールドは常にtrue
+    pub fn pass(&mut self, dt: DateTime<Local>, name: &str, value: f64) -> bool {
+        let Some(field) = self.fields.iter_mut().find(|t| t.name == name) else {
+            return true;
+        };
+        field.count.received += 1;
+        let pass = match field.last {
+            None => true,
+            Some((last_dt, last_value)) => {
+                (dt - last_dt).num_milliseconds() >= self.max_silence_msec
+                    || field.deadband.exceeds(last_value, value)
+            }
+        };
+        if pass {
+            field.last = Some((dt, value));
+            field.count.written += 1;
+        }
+        pass
+    }
+
+    // 停止等で受信が途切れる場合に呼ぶ。次の値は必ず送信する
+    pub fn reset(&mut self) {
+        for field in self.fields.iter_mut() {
+            field.last = None;
+        }
+    }
+
+    // 出力周期に達した場合は圧縮の結果を返す
+    pub fn report(&mut self, dt: DateTime<Local>) -> Option<CompressionStats> {
+        let report_start = *self.report_start.get_or_insert(dt);
+        if (dt - report_start).num_seconds() < self.report_interval {
+            return None;
+        }
+        self.report_start = Some(dt);
+        let counts = self
+            .fields
+            .iter_mut()
+            .map(|t| std::mem::replace(&mut t.count, CompressionCount::new(&t.name)))
+            .collect();
+        Some(CompressionStats {
+            dt,
+            method: "deadband",
+            counts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use influxdb2::models::WriteDataPoint;
+
+    fn deadband_filter(fields: &[(&str, Deadband)]) -> DeadbandFilter {
+        DeadbandFilter::new(DeadbandConfig {
+            fields: fields
+                .iter()
+                .map(|(name, deadband)| (name.to_string(), *deadband))
+                .collect(),
+            max_silence_second: 60,
+            report_interval: 60,
+        })
+    }
+
+    fn at(second: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_790_000_000, 0).unwrap() + Duration::seconds(second)
+    }
+
+    #[test]
+    fn parse_deadband() {
+        assert_eq!(Deadband::parse("0.5").unwrap(), Deadband::Absolute(0.5));
+        assert_eq!(Deadband::parse("2 %").unwrap(), Deadband::Percent(2.0));
+        assert!(Deadband::parse("-1").is_err());
+        assert!(Deadband::parse("-1%").is_err());
+        assert!(Deadband::parse("a").is_err());
+    }
+
+    #[test]
+    fn absolute_deadband() {
+        let mut filter = deadband_filter(&[("temp", Deadband::Absolute(1.0))]);
+        assert!(filter.pass(at(0), "temp", 10.0));
+        assert!(!filter.pass(at(1), "temp", 10.5));
+        // 不感帯と同じ変化は送信しない
+        assert!(!filter.pass(at(2), "temp", 9.0));
+        // 前回送信値との差で判定する
+        assert!(filter.pass(at(3), "temp", 11.5));
+        assert!(!filter.pass(at(4), "temp", 11.0));
+        // 設定していないフィールドは常に送信する
+        assert!(filter.pass(at(4), "pressure", 1.0));
+        assert!(filter.pass(at(5), "pressure", 1.0));
+    }
+
+    #[test]
+    fn percent_deadband() {
+        let mut filter = deadband_filter(&[("temp", Deadband::Percent(10.0))]);
+        assert!(filter.pass(at(0), "temp", 100.0));
+        assert!(!filter.pass(at(1), "temp", 109.0));
+        assert!(filter.pass(at(2), "temp", 111.0));
+        // 割合は前回送信値に対して求める
+        assert!(!filter.pass(at(3), "temp", 121.0));
+        assert!(filter.pass(at(4), "temp", 123.0));
+
+        // 前回送信値が0の場合はわずかな変化でも送信する
+        let mut filter = deadband_filter(&[("temp", Deadband::Percent(10.0))]);
+        assert!(filter.pass(at(0), "temp", 0.0));
+        assert!(!filter.pass(at(1), "temp", 0.0));
+        assert!(filter.pass(at(2), "temp", 0.01));
+    }
+
+    #[test]
+    fn force_after_max_silence_and_reset() {
+        let mut filter = deadband_filter(&[("temp", Deadband::Absolute(1.0))]);
+        assert!(filter.pass(at(0), "temp", 10.0));
+        assert!(!filter.pass(at(59), "temp", 10.0));
+        assert!(filter.pass(at(60), "temp", 10.0));
+        // 最大間隔は送信した時刻から数え直す
+        assert!(!filter.pass(at(61), "temp", 10.0));
+        assert!(!filter.pass(at(119), "temp", 10.0));
+
+        // 途切れた後の次の値は必ず送信する
+        filter.reset();
+        assert!(filter.pass(at(121), "temp", 10.0));
+        assert!(!filter.pass(at(122), "temp", 10.0));
+    }
+
+    #[test]
+    fn report_compression_count() {
+        let mut filter = deadband_filter(&[
+            ("temp", Deadband::Absolute(1.0)),
+            ("flow", Deadband::Absolute(0.1)),
+        ]);
+        assert!(filter.report(at(0)).is_none());
+        for second in 0..10 {
+            filter.pass(at(second), "temp", 10.0);
+            filter.pass(at(second), "flow", second as f64);
+        }
+        assert!(filter.report(at(59)).is_none());
+        let stats = filter.report(at(60)).unwrap();
+        assert_eq!(stats.method, "deadband");
+        let counts: Vec<(&str, u64, u64)> = stats
+            .counts
+            .iter()
+            .map(|t| (t.name.as_str(), t.received, t.written))
+            .collect();
+        assert_eq!(counts, vec![("temp", 10, 1), ("flow", 10, 10)]);
+        assert_eq!(stats.counts[0].ratio(), Some(10.0));
+
+        let mut line = Vec::new();
+        stats
+            .to_data_point("demo_machine")
+            .unwrap()
+            .write_data_point_to(&mut line)
+            .unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.contains("info_type=compression"));
+        assert!(line.contains("temp_compression_ratio=10"));
+        assert!(line.contains("total_received=20i"));
+        assert!(line.contains("total_written=11i"));
+
+        // 出力した後は数え直す
+        let stats = filter.report(at(120)).unwrap();
+        assert!(stats
+            .counts
+            .iter()
+            .all(|t| t.received == 0 && t.written == 0));
+        assert_eq!(stats.counts[0].ratio(), None);
+    }
+}
@@ -0,0 +1,17 @@
+// 収集したデータの加工・診断を行う部品
+// 各コレクターのデータマネージャーから利用する
+
+#[allow(dead_code)]
+pub mod aggregate;
+#[allow(dead_code)]
+pub mod anomaly;
+#[allow(dead_code)]
+pub mod counter;
+#[allow(dead_code)]
+pub mod deadband;
+#[allow(dead_code)]
+pub mod rate;
+#[allow(dead_code)]
+pub mod swinging_door;
+#[allow(dead_code)]
+pub mod tick_monitor;
@@ -0,0 +1,281 @@
+use chrono::{DateTime, Local};
+use influxdb2::models::data_point::DataPointBuilder;
+
+// 平滑化の時定数のデフォルト(sec)
+const DEFAULT_WINDOW_SECOND: f64 = 60.0;
+// 定格速度に対する速度低下の判定割合のデフォルト
+const DEFAULT_SPEED_LOSS_THRESHOLD: f64 = 0.95;
+
+// 生産数の増分と受信時刻から生産速度(個/分)とサイクルタイムを求める
+// {prefix}RateWindowSec       : 平滑化(指数移動平均)の時定数(sec)。デフォルト60
+// {prefix}RatedSpeed          : 定格速度(個/分)。設定されている場合は速度低下を判定する
+// {prefix}SpeedLossThreshold  : 定格速度に対してこの割合を下回ったら速度低下。デフォルト0.95
+#[derive(Clone, Debug)]
+pub struct RateConfig {
+    window_second: f64,
+    rated_speed: Option<f64>,
+    speed_loss_threshold: f64,
+}
+
+impl RateConfig {
+    pub fn create_from_env(prefix: &str) -> anyhow::Result<Self> {
+        let window_second = match std::env::var(format!("{}RateWindowSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_WINDOW_SECOND,
+        };
+        if window_second <= 0.0 {
+            anyhow::bail!("{}RateWindowSecが不正:{}", prefix, window_second)
+        }
+        let rated_speed = match std::env::var(format!("{}RatedSpeed", prefix)) {
+            Ok(t) => Some(t.parse()?),
+            Err(_) => None,
+        };
+        let speed_loss_threshold = match std::env::var(format!("{}SpeedLossThreshold", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_SPEED_LOSS_THRESHOLD,
+        };
+        Ok(Self {
+            window_second,
+            rated_speed,
+            speed_loss_threshold,
+        })
+    }
+}
+
+#[cfg(test)]
+impl RateConfig {
+    pub fn new(window_second: f64, rated_speed: Option<f64>) -> Self {
+        Self {
+            window_second,
+            rated_speed,
+            speed_loss_threshold: DEFAULT_SPEED_LOSS_THRESHOLD,
+        }
+    }
+}
+
+// チャンク毎に出力する生産速度
+#[derive(Clone, Debug, Default)]
+pub struct RateMetrics {
+    // 直近の受信間隔での生産速度(個/分)
+    pub instant_rate: f64,
+    // 指数移動平均した生産速度(個/分)
+    pub smoothed_rate: f64,
+    // チャンク内の稼働時間あたりの生産速度(個/分)
+    pub chunk_rate: f64,
+    // チャンク内の平均サイクルタイム(sec)。生産がない場合はNone
+    pub cycle_time: Option<f64>,
+    // 定格速度に対する平滑化した生産速度の割合。定格速度が未設定の場合はNone
+    pub speed_ratio: Option<f64>,
+    pub speed_loss: Option<bool>,
+}
+
+impl RateMetrics {
+    pub fn add_fields(&self, builder: DataPointBuilder) -> DataPointBuilder {
+        let mut builder = builder
+            .field("production_rate", self.instant_rate)
+            .field("production_rate_smoothed", self.smoothed_rate)
+            .field("chunk_production_rate", self.chunk_rate);
+        if let Some(cycle_time) = self.cycle_time {
+            builder = builder.field("cycle_time_second", cycle_time);
+        }
+        if let Some(speed_ratio) = self.speed_ratio {
+            builder = builder.field("speed_ratio", speed_ratio);
+        }
+        if let Some(speed_loss) = self.speed_loss {
+            builder = builder.field("speed_loss", speed_loss);
+        }
+        builder
+    }
+}
+
+pub struct RateCalculator {
+    config: RateConfig,
+    last_dt: Option<DateTime<Local>>,
+    instant_rate: f64,
+    smoothed_rate: Option<f64>,
+    chunk_count: u64,
+    chunk_second: f64,
+}
+
+impl RateCalculator {
+    pub fn new(config: RateConfig) -> Self {
+        Self {
+            config,
+            last_dt: None,
+            instant_rate: 0.0,
+            smoothed_rate: None,
+            chunk_count: 0,
+            chunk_second: 0.0,
+        }
+    }
+
+    // 稼働中の受信毎に前回からの生産数の増分を渡す
+    pub fn push(&mut self, dt: DateTime<Local>, delta: u32) {
+        let Some(last_dt) = self.last_dt.replace(dt) else {
+            return;
+        };
+        let second = (dt - last_dt).num_milliseconds() as f64 / 1000.0;
+        if second <= 0.0 {
+            return;
+        }
+        self.instant_rate = delta as f64 * 60.0 / second;
+        // 受信間隔が一定でなくても時定数が変わらないように重みを決める
+        let alpha = 1.0 - (-second / self.config.window_second).exp();
+        self.smoothed_rate = Some(match self.smoothed_rate {
+            Some(t) => t + alpha * (self.instant_rate - t),
+            None => self.instant_rate,
+        });
+        self.chunk_count += delta as u64;
+        self.chunk_second += second;
+    }
+
+    // 停止中は速度を計算しない。停止をまたいだ間隔は速度に含めない
+    pub fn pause(&mut self) {
+        self.last_dt = None;
+        self.instant_rate = 0.0;
+    }
+
+    // チャンクの出力時に呼ぶ。チャンク内の集計はリセットする
+    pub fn take_metrics(&mut self) -> RateMetrics {
+        let smoothed_rate = self.smoothed_rate.unwrap_or(0.0);
+        let chunk_rate = if self.chunk_second > 0.0 {
+            self.chunk_count as f64 * 60.0 / self.chunk_second
+        } else {
+            0.0
+        };
+        let cycle_time =
+            (self.chunk_count > 0).then(|| self.chunk_second / self.chunk_count as f64);
+        let speed_ratio = self
+            .config
+            .rated_speed
+            .filter(|t| *t > 0.0)
+            .map(|t| smoothed_rate / t);
+        // 停止中のチャンクは速度低下としない
+        let speed_loss =
+            speed_ratio.map(|t| self.last_dt.is_some() && t < self.config.speed_loss_threshold);
+        self.chunk_count = 0;
+        self.chunk_second = 0.0;
+        RateMetrics {
+            instant_rate: self.instant_rate,
+            smoothed_rate,
+            chunk_rate,
+            cycle_time,
+            speed_ratio,
+            speed_loss,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn rate_calculator(rated_speed: Option<f64>) -> RateCalculator {
+        RateCalculator::new(RateConfig {
+            window_second: 60.0,
+            rated_speed,
+            speed_loss_threshold: 0.95,
+        })
+    }
+
+    fn at(msec: i64) -> DateTime<Local> {
+        Local
+            .timestamp_millis_opt(1_790_000_000_000 + msec)
+            .unwrap()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn smoothing_with_irregular_intervals() {
+        let mut calculator = rate_calculator(None);
+        // 初回は基準にするだけ
+        calculator.push(at(0), 100);
+        calculator.push(at(10_000), 10);
+        let metrics = calculator.take_metrics();
+        assert_near(metrics.instant_rate, 60.0);
+        assert_near(metrics.smoothed_rate, 60.0);
+
+        calculator.push(at(40_000), 15);
+        let alpha = 1.0 - (-30.0f64 / 60.0).exp();
+        let metrics = calculator.take_metrics();
+        assert_near(metrics.instant_rate, 30.0);
+        assert_near(metrics.smoothed_rate, 60.0 + alpha * (30.0 - 60.0));
+
+        // 同じ速度なら受信間隔を分けても平滑化の結果は同じ
+        let mut split = rate_calculator(None);
+        split.push(at(0), 0);
+        split.push(at(10_000), 10);
+        split.push(at(30_000), 10);
+        split.push(at(40_000), 5);
+        assert_near(split.take_metrics().smoothed_rate, metrics.smoothed_rate);
+
+        // 同じ時刻の受信は無視する
+        calculator.push(at(40_000), 5);
+        assert_near(calculator.take_metrics().instant_rate, 30.0);
+    }
+
+    #[test]
+    fn pause_excludes_stopped_interval() {
+        let mut calculator = rate_calculator(Some(60.0));
+        calculator.push(at(0), 0);
+        calculator.push(at(10_000), 10);
+        calculator.pause();
+        let metrics = calculator.take_metrics();
+        assert_near(metrics.instant_rate, 0.0);
+        // 停止中は速度低下としない
+        assert_eq!(metrics.speed_loss, Some(false));
+
+        // 再開後の最初の受信は基準にするだけで、停止中の増分は含めない
+        calculator.push(at(100_000), 50);
+        calculator.push(at(110_000), 10);
+        let metrics = calculator.take_metrics();
+        assert_near(metrics.instant_rate, 60.0);
+        assert_near(metrics.chunk_rate, 60.0);
+        assert_near(metrics.cycle_time.unwrap(), 1.0);
+    }
+
+    #[test]
+    fn cycle_time_and_speed_loss() {
+        let mut calculator = rate_calculator(Some(60.0));
+        calculator.push(at(0), 0);
+        for i in 1..=6 {
+            calculator.push(at(i * 10_000), 5);
+        }
+        let metrics = calculator.take_metrics();
+        assert_near(metrics.chunk_rate, 30.0);
+        assert_near(metrics.cycle_time.unwrap(), 2.0);
+        assert_near(metrics.speed_ratio.unwrap(), 0.5);
+        assert_eq!(metrics.speed_loss, Some(true));
+
+        // チャンクの集計はリセットし、平滑化した速度は引き継ぐ
+        let metrics = calculator.take_metrics();
+        assert_near(metrics.chunk_rate, 0.0);
+        assert_eq!(metrics.cycle_time, None);
+        assert_near(metrics.smoothed_rate, 30.0);
+
+        // 定格速度に戻れば時定数の10倍後には速度低下ではない
+        for i in 7..=66 {
+            calculator.push(at(i * 10_000), 10);
+        }
+        let metrics = calculator.take_metrics();
+        assert!(metrics.speed_ratio.unwrap() > 0.95);
+        assert_eq!(metrics.speed_loss, Some(false));
+
+        // 定格速度がない場合は判定しない
+        let mut calculator = rate_calculator(None);
+        calculator.push(at(0), 0);
+        calculator.push(at(10_000), 5);
+        let metrics = calculator.take_metrics();
+        assert_eq!(metrics.speed_ratio, None);
+        assert_eq!(metrics.speed_loss, None);
+    }
+}
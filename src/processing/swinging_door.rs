@@ -0,0 +1,319 @@
+use chrono::{DateTime, Local};
+
+use super::deadband::{CompressionCount, CompressionStats};
+
+// 保存する最大間隔のデフォルト(sec)
+const DEFAULT_MAX_INTERVAL_SECOND: i64 = 60;
+// 圧縮率の出力周期のデフォルト(sec)
+const DEFAULT_REPORT_INTERVAL_SECOND: i64 = 60;
+
+// スイングドア圧縮するフィールドの設定
+// {prefix}SwingingDoorFields         : フィールド名と圧縮偏差 ex) tempureture_1=0.5,tempureture_2=0.2
+//                                      保存した点を結んだ直線から圧縮偏差以内で元の値を再現できる
+// {prefix}SwingingDoorMaxIntervalSec : 変化がなくてもこの間隔で保存する(sec)。デフォルト60
+// {prefix}SwingingDoorReportSec      : 圧縮率の出力周期(sec)。デフォルト60
+#[derive(Clone, Debug)]
+pub struct SwingingDoorConfig {
+    fields: Vec<(String, f64)>,
+    max_interval_second: i64,
+    report_interval: i64,
+}
+
+impl SwingingDoorConfig {
+    pub fn create_from_env(prefix: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(value) = std::env::var(format!("{}SwingingDoorFields", prefix)) else {
+            return Ok(None);
+        };
+        let mut fields = Vec::new();
+        for item in value.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let Some((name, deviation)) = item.split_once('=') else {
+                anyhow::bail!("{}SwingingDoorFieldsが不正:{}", prefix, item)
+            };
+            let deviation: f64 = deviation.trim().parse()?;
+            if deviation < 0.0 {
+                anyhow::bail!("{}SwingingDoorFieldsの圧縮偏差が不正:{}", prefix, item)
+            }
+            fields.push((name.trim().to_string(), deviation));
+        }
+        if fields.is_empty() {
+            anyhow::bail!("{}SwingingDoorFieldsが空", prefix)
+        }
+        let max_interval_second =
+            match std::env::var(format!("{}SwingingDoorMaxIntervalSec", prefix)) {
+                Ok(t) => t.parse()?,
+                Err(_) => DEFAULT_MAX_INTERVAL_SECOND,
+            };
+        let report_interval = match std::env::var(format!("{}SwingingDoorReportSec", prefix)) {
+            Ok(t) => t.parse()?,
+            Err(_) => DEFAULT_REPORT_INTERVAL_SECOND,
+        };
+        if max_interval_second <= 0 || report_interval <= 0 {
+            anyhow::bail!(
+                "{}SwingingDoorMaxIntervalSec,{}SwingingDoorReportSecが不正:{}:{}",
+                prefix,
+                prefix,
+                max_interval_second,
+                report_interval
+            )
+        }
+        Ok(Some(Self {
+            fields,
+            max_interval_second,
+            report_interval,
+        }))
+    }
+
+    pub fn get_fields(&self) -> Vec<String> {
+        self.fields
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+}
+
+struct DoorField {
+    name: String,
+    deviation: f64,
+    // 最後に保存した点
+    archived: Option<(DateTime<Local>, f64)>,
+    // 最後に受信した点。次の点でドアが開ききった場合に保存する
+    held: Option<(DateTime<Local>, f64)>,
+    // 保存した点から間の点-圧縮偏差への傾きの最大(下側のドア)
+    lower_slope: f64,
+    // 保存した点から間の点+圧縮偏差への傾きの最小(上側のドア)
+    upper_slope: f64,
+    count: CompressionCount,
+}
+
+impl DoorField {
+    fn archive(&mut self, point: (DateTime<Local>, f64)) -> (DateTime<Local>, f64) {
+        self.archived = Some(point);
+        self.lower_slope = f64::NEG_INFINITY;
+        self.upper_slope = f64::INFINITY;
+        self.count.written += 1;
+        point
+    }
+
+    fn slope_to(&self, dt: DateTime<Local>, value: f64) -> Option<(f64, f64)> {
+        let (archived_dt, archived_value) = self.archived?;
+        let elapsed = (dt - archived_dt).num_milliseconds() as f64;
+        (elapsed > 0.0).then_some((elapsed, value - archived_value))
+    }
+
+    // 保存した点から受信した点への直線が、間の点を全て圧縮偏差以内で通る場合はtrue
+    fn fits(&self, dt: DateTime<Local>, value: f64) -> bool {
+        match self.slope_to(dt, value) {
+            Some((elapsed, change)) => {
+                (self.lower_slope..=self.upper_slope).contains(&(change / elapsed))
+            }
+            None => true,
+        }
+    }
+
+    // 受信した点を間の点としてドアを狭める
+    fn narrow(&mut self, dt: DateTime<Local>, value: f64) {
+        if let Some((elapsed, change)) = self.slope_to(dt, value) {
+            self.lower_slope = self.lower_slope.max((change - self.deviation) / elapsed);
+            self.upper_slope = self.upper_slope.min((change + self.deviation) / elapsed);
+        }
+    }
+
+    fn push(
+        &mut self,
+        dt: DateTime<Local>,
+        value: f64,
+        max_interval_msec: i64,
+    ) -> Vec<(DateTime<Local>, f64)> {
+        self.count.received += 1;
+        let Some((archived_dt, _)) = self.archived else {
+            return vec![self.archive((dt, value))];
+        };
+        // 時刻が戻った場合は判定できないので捨てる
+        if dt <= self.held.map_or(archived_dt, |t| t.0) {
+            return Vec::new();
+        }
+
+        let mut output = Vec::new();
+        // 最大間隔を超えた場合は保留中の点を保存して区切る
+        if (dt - archived_dt).num_milliseconds() >= max_interval_msec {
+            if let Some(held) = self.held.take() {
+                output.push(self.archive(held));
+            }
+        }
+        if !self.fits(dt, value) {
+            // ドアが閉じたので直前の点を保存し、そこから新しいドアを開く
+            if let Some(held) = self.held.take() {
+                output.push(self.archive(held));
+            }
+        }
+        self.narrow(dt, value);
+        self.held = Some((dt, value));
+        output
+    }
+
+    // 保留中の点を保存して、次の点から圧縮をやり直す
+    fn flush(&mut self) -> Option<(DateTime<Local>, f64)> {
+        let held = self.held.take().map(|t| self.archive(t));
+        self.archived = None;
+        held
+    }
+}
+
+// スイングドア圧縮
+// 保存した点を支点に、間の点±圧縮偏差を通る傾きの範囲(ドア)を受信毎に狭める
+// 受信した点への傾きがドアの内側にある間は保存しない。外れた場合は直前の点を保存する
+// 保存した点を直線で結ぶと、間の点は全て圧縮偏差以内になる
+// 保存する点は受信より遅れるので、保存した時刻と値を返す
+pub struct SwingingDoor {
+    fields: Vec<DoorField>,
+    max_interval_msec: i64,
+    report_interval: i64,
+    report_start: Option<DateTime<Local>>,
+}
+
+impl SwingingDoor {
+    pub fn new(config: SwingingDoorConfig) -> Self {
+        let fields = config
+            .fields
+            .iter()
+            .map(|(name, deviation)| DoorField {
+                name: name.to_owned(),
+                deviation: *deviation,
+                archived: None,
+                held: None,
+                lower_slope: f64::NEG_INFINITY,
+                upper_slope: f64::INFINITY,
+                count: CompressionCount::new(name),
+            })
+            .collect();
+        Self {
+            fields,
+            max_interval_msec: config.max_interval_second * 1000,
+            report_interval: config.report_interval,
+            report_start: None,
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fields.iter().any(|t| t.name == name)
+    }
+
+    // 受信毎にフィールド毎に呼ぶ。保存する点を返す
+    pub fn push(
+        &mut self,
+        dt: DateTime<Local>,
+        name: &str,
+        value: f64,
+    ) -> Vec<(DateTime<Local>, f64)> {
+        match self.fields.iter_mut().find(|t| t.name == name) {
+            Some(field) => field.push(dt, value, self.max_interval_msec),
+            None => Vec::new(),
+        }
+    }
+
+    // 停止等で受信が途切れる場合に呼ぶ。保留中の点を保存して返す
+    pub fn flush(&mut self) -> Vec<(String, DateTime<Local>, f64)> {
+        self.fields
+            .iter_mut()
+            .filter_map(|t| t.flush().map(|(dt, value)| (t.name.to_owned(), dt, value)))
+            .collect()
+    }
+
+    // 出力周期に達した場合は圧縮の結果を返す
+    pub fn report(&mut self, dt: DateTime<Local>) -> Option<CompressionStats> {
+        let report_start = *self.report_start.get_or_insert(dt);
+        if (dt - report_start).num_seconds() < self.report_interval {
+            return None;
+        }
+        self.report_start = Some(dt);
+        let counts = self
+            .fields
+            .iter_mut()
+            .map(|t| std::mem::replace(&mut t.count, CompressionCount::new(&t.name)))
+            .collect();
+        Some(CompressionStats {
+            dt,
+            method: "swinging_door",
+            counts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn door(deviation: f64, max_interval_second: i64) -> SwingingDoor {
+        SwingingDoor::new(SwingingDoorConfig {
+            fields: vec![("temp".to_string(), deviation)],
+            max_interval_second,
+            report_interval: 60,
+        })
+    }
+
+    fn at(second: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_700_000_000 + second, 0).unwrap()
+    }
+
+    #[test]
+    fn keep_points_within_deviation() {
+        let mut door = door(0.5, 60);
+        assert_eq!(door.push(at(0), "temp", 10.0), vec![(at(0), 10.0)]);
+        for (second, value) in [(1, 10.2), (2, 9.9), (3, 10.1), (4, 10.0)] {
+            assert!(door.push(at(second), "temp", value).is_empty());
+        }
+        assert!(door.push(at(5), "humidity", 50.0).is_empty());
+        assert!(!door.contains("humidity"));
+    }
+
+    #[test]
+    fn archive_when_door_closes() {
+        let mut door = door(0.5, 60);
+        door.push(at(0), "temp", 0.0);
+        // 一定の傾きの間は保存しない
+        for second in 1..=3 {
+            assert!(door.push(at(second), "temp", second as f64).is_empty());
+        }
+        // 傾きが変わった場合は直前の点を保存する
+        assert_eq!(door.push(at(4), "temp", 3.0), vec![(at(3), 3.0)]);
+        assert!(door.push(at(5), "temp", 3.0).is_empty());
+        assert_eq!(door.flush(), vec![("temp".to_string(), at(5), 3.0)]);
+        // flushの後は次の点から圧縮をやり直す
+        assert_eq!(door.push(at(6), "temp", 5.0), vec![(at(6), 5.0)]);
+    }
+
+    #[test]
+    fn archive_at_max_interval() {
+        let mut door = door(0.5, 10);
+        door.push(at(0), "temp", 10.0);
+        for second in 1..10 {
+            assert!(door.push(at(second), "temp", 10.0).is_empty());
+        }
+        assert_eq!(door.push(at(10), "temp", 10.0), vec![(at(9), 10.0)]);
+    }
+
+    #[test]
+    fn drop_points_going_back() {
+        let mut door = door(0.5, 60);
+        door.push(at(10), "temp", 10.0);
+        door.push(at(11), "temp", 10.0);
+        assert!(door.push(at(11), "temp", 20.0).is_empty());
+        assert!(door.push(at(5), "temp", 20.0).is_empty());
+        assert_eq!(door.flush(), vec![("temp".to_string(), at(11), 10.0)]);
+    }
+
+    #[test]
+    fn report_compression() {
+        let mut door = door(0.5, 60);
+        assert!(door.report(at(0)).is_none());
+        for second in 0..10 {
+            door.push(at(second), "temp", 10.0);
+        }
+        assert!(door.report(at(59)).is_none());
+        let stats = door.report(at(60)).unwrap();
+        assert_eq!(stats.method, "swinging_door");
+        assert_eq!(stats.counts[0].received, 10);
+        assert_eq!(stats.counts[0].written, 1);
+    }
+}
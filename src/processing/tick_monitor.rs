@@ -0,0 +1,235 @@
+use chrono::{DateTime, Local};
+use influxdb2::models::DataPoint;
+
+use crate::shift_calendar;
+
+// PLCで一定周期に加算されるカウンタ(ティックレジスタ)を使って
+// ポーリングの取りこぼしと周期の揺らぎを診断する
+#[derive(Clone, Debug)]
+pub struct TickMonitorConfig {
+    // PLCがカウンタを加算する周期(msec)
+    pub tick_period: f64,
+    // カウンタのビット幅。これを超えると0に戻る
+    pub bit_width: u32,
+    // 診断結果を出力する周期(sec)
+    pub report_interval: i64,
+}
+
+#[derive(Debug, Default)]
+struct TickWindow {
+    polls: u32,
+    observed_ticks: u64,
+    expected_ticks: f64,
+    lost_samples: u64,
+    interval_sum: f64,
+    interval_square_sum: f64,
+    max_interval: f64,
+}
+
+pub struct TickMonitor {
+    config: TickMonitorConfig,
+    last: Option<(DateTime<Local>, u64)>,
+    window_start: Option<DateTime<Local>>,
+    window: TickWindow,
+}
+
+pub struct TickDiagnostics {
+    pub dt: DateTime<Local>,
+    pub polls: u32,
+    pub observed_ticks: u64,
+    pub expected_ticks: f64,
+    // ポーリングの間にPLC側で加算されて読み取れなかったサンプル数
+    pub lost_samples: u64,
+    // 1回のポーリングで取得できた実効的なサンプリング周期
+    pub effective_sample_period: f64,
+    pub mean_poll_interval: f64,
+    // ポーリング間隔の標準偏差
+    pub poll_jitter: f64,
+    pub max_poll_interval: f64,
+}
+
+impl TickMonitor {
+    pub fn new(config: TickMonitorConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            window_start: None,
+            window: TickWindow::default(),
+        }
+    }
+
+    // 稼働状態の切り替え等でポーリング周期が変わる場合は集計をやり直す
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.window_start = None;
+        self.window = TickWindow::default();
+    }
+
+    // 出力周期に達した場合は診断結果を返す
+    pub fn push(&mut self, dt: DateTime<Local>, tick: i64) -> Option<TickDiagnostics> {
+        let modulo = 1u64 << self.config.bit_width;
+        // 符号付きの場合も2の補数としてビット幅内に収める
+        let tick = (tick as u64) % modulo;
+        let Some((last_dt, last_tick)) = self.last.replace((dt, tick)) else {
+            self.window_start = Some(dt);
+            return None;
+        };
+
+        let interval = (dt - last_dt).num_microseconds().unwrap_or(0) as f64 / 1000.0;
+        let observed = (tick + modulo - last_tick) % modulo;
+
+        let window = &mut self.window;
+        window.polls += 1;
+        window.observed_ticks += observed;
+        window.expected_ticks += interval / self.config.tick_period;
+        window.lost_samples += observed.saturating_sub(1);
+        window.interval_sum += interval;
+        window.interval_square_sum += interval * interval;
+        window.max_interval = window.max_interval.max(interval);
+
+        let window_start = self.window_start.unwrap_or(dt);
+        if (dt - window_start).num_seconds() < self.config.report_interval {
+            return None;
+        }
+
+        let window = std::mem::take(&mut self.window);
+        self.window_start = Some(dt);
+        let polls = window.polls as f64;
+        let mean_poll_interval = window.interval_sum / polls;
+        let variance = (window.interval_square_sum / polls) - mean_poll_interval.powi(2);
+        let effective_sample_period = if window.observed_ticks == 0 {
+            0.0
+        } else {
+            // 取りこぼしがなければPLCの周期と同じになる
+            window.interval_sum / polls.min(window.observed_ticks as f64)
+        };
+
+        Some(TickDiagnostics {
+            dt,
+            polls: window.polls,
+            observed_ticks: window.observed_ticks,
+            expected_ticks: window.expected_ticks,
+            lost_samples: window.lost_samples,
+            effective_sample_period,
+            mean_poll_interval,
+            poll_jitter: variance.max(0.0).sqrt(),
+            max_poll_interval: window.max_interval,
+        })
+    }
+}
+
+impl TickDiagnostics {
+    pub fn to_data_point(&self, measurement: &str) -> anyhow::Result<DataPoint> {
+        let time = match self.dt.timestamp_nanos_opt() {
+            Some(t) => t,
+            None => anyhow::bail!("in match self.dt.timestamp_nanos_opt()"),
+        };
+        let point = shift_calendar::add_shift_tag(DataPoint::builder(measurement), self.dt)
+            .tag("info_type", "diagnostics")
+            .field("polls", self.polls as i64)
+            .field("observed_ticks", self.observed_ticks as i64)
+            .field("expected_ticks", self.expected_ticks)
+            .field("lost_samples", self.lost_samples as i64)
+            .field("effective_sample_period_ms", self.effective_sample_period)
+            .field("mean_poll_interval_ms", self.mean_poll_interval)
+            .field("poll_jitter_ms", self.poll_jitter)
+            .field("max_poll_interval_ms", self.max_poll_interval)
+            .timestamp(time)
+            .build()?;
+        Ok(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // 100msec毎に加算される16bitのカウンタを10秒毎に診断する
+    fn monitor() -> TickMonitor {
+        TickMonitor::new(TickMonitorConfig {
+            tick_period: 100.0,
+            bit_width: 16,
+            report_interval: 10,
+        })
+    }
+
+    fn at(msec: i64) -> DateTime<Local> {
+        Local
+            .timestamp_millis_opt(1_700_000_000_000 + msec)
+            .unwrap()
+    }
+
+    #[test]
+    fn no_loss_with_regular_polling() {
+        let mut monitor = monitor();
+        assert!(monitor.push(at(0), 0).is_none());
+        for k in 1..100 {
+            assert!(monitor.push(at(k * 100), k).is_none());
+        }
+        let diagnostics = monitor.push(at(10_000), 100).unwrap();
+        assert_eq!(diagnostics.polls, 100);
+        assert_eq!(diagnostics.observed_ticks, 100);
+        assert!((diagnostics.expected_ticks - 100.0).abs() < 1e-9);
+        assert_eq!(diagnostics.lost_samples, 0);
+        assert!((diagnostics.effective_sample_period - 100.0).abs() < 1e-9);
+        assert!((diagnostics.mean_poll_interval - 100.0).abs() < 1e-9);
+        assert!(diagnostics.poll_jitter < 1e-6);
+    }
+
+    #[test]
+    fn detect_missed_and_late_ticks() {
+        let mut monitor = monitor();
+        monitor.push(at(0), 0);
+        // 2回に1回ポーリングが遅れて2ティック進む
+        let mut tick = 0;
+        let mut time = 0;
+        let mut diagnostics = None;
+        for k in 0..70 {
+            let step = if k % 2 == 0 { 1 } else { 2 };
+            tick += step;
+            time += step * 100;
+            diagnostics = monitor.push(at(time), tick);
+            if diagnostics.is_some() {
+                break;
+            }
+        }
+        let diagnostics = diagnostics.unwrap();
+        assert_eq!(diagnostics.polls, 67);
+        assert_eq!(diagnostics.observed_ticks, 100);
+        assert_eq!(diagnostics.lost_samples, 33);
+        assert!((diagnostics.max_poll_interval - 200.0).abs() < 1e-9);
+        assert!((diagnostics.poll_jitter - 50.0).abs() < 0.1);
+        assert!(diagnostics.effective_sample_period > 149.0);
+    }
+
+    #[test]
+    fn wraparound_is_not_loss() {
+        let mut monitor = monitor();
+        monitor.push(at(0), 65534);
+        monitor.push(at(100), 65535);
+        monitor.push(at(200), 0);
+        // 符号付きで読み出した場合
+        monitor.push(at(300), -65535);
+        let diagnostics = monitor.push(at(10_000), 98).unwrap();
+        assert_eq!(diagnostics.lost_samples, 96);
+        assert_eq!(diagnostics.observed_ticks, 100);
+    }
+
+    #[test]
+    fn reset_discards_gap() {
+        let mut monitor = monitor();
+        monitor.push(at(0), 0);
+        monitor.push(at(100), 1);
+        // 再接続等で間が空いた場合はreset後の値を基準にし直す
+        monitor.reset();
+        assert!(monitor.push(at(60_000), 600).is_none());
+        for k in 1..100 {
+            assert!(monitor.push(at(60_000 + k * 100), 600 + k).is_none());
+        }
+        let diagnostics = monitor.push(at(70_000), 700).unwrap();
+        assert_eq!(diagnostics.polls, 100);
+        assert_eq!(diagnostics.lost_samples, 0);
+        assert!((diagnostics.max_poll_interval - 100.0).abs() < 1e-9);
+    }
+}
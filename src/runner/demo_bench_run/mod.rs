@@ -3,12 +3,19 @@ use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use tokio::time::Duration;
 
+use crate::alarm;
+use crate::api::{ApiServer, ApiState};
 use crate::collector::demo_cpb16::DemoCpb16Collector;
+use crate::collector::mqtt_sensor::MqttSensorCollector;
+use crate::collector::snapshot_trigger::SnapshotTriggerCollector;
 use crate::influxdb::InfluxDB;
 
 pub struct Runner {
     collector: Arc<Mutex<DemoCpb16Collector>>,
     database: InfluxDB,
+    mqtt_collector: Option<MqttSensorCollector>,
+    snapshot_collector: Option<SnapshotTriggerCollector>,
+    api_server: Option<ApiServer>,
 }
 
 impl Runner {
@@ -17,14 +24,53 @@ impl Runner {
         let mut database = InfluxDB::create_from_env()?;
         // senderはドロップされないのでdatabaseの終了処理は不要
         database.start_send_data(data_receiver).await?;
+        alarm::init_from_env(data_sender.clone())?;
+        // MQTTのセンサーはブローカーが設定されている場合のみ収集
+        let mqtt_collector = match std::env::var("MqttSensorBrokerHost") {
+            Ok(_) => {
+                let mut mqtt_collector = MqttSensorCollector::create_from_env(data_sender.clone())?;
+                mqtt_collector.start_data_collection().await?;
+                info!("start mqtt sensor collect");
+                Some(mqtt_collector)
+            }
+            Err(_) => None,
+        };
+        // スナップショットはトリガーデバイスが設定されている場合のみ収集
+        let snapshot_collector = match std::env::var("SnapshotTriggerDevice") {
+            Ok(_) => {
+                let mut snapshot_collector =
+                    SnapshotTriggerCollector::create_from_env(data_sender.clone())?;
+                snapshot_collector.start_data_collection().await?;
+                info!("start snapshot trigger collect");
+                Some(snapshot_collector)
+            }
+            Err(_) => None,
+        };
         let collector = DemoCpb16Collector::create_from_env(data_sender).await?;
 
+        // APIはAPI_ADDRESSが設定されている場合のみ起動
+        let api_server = match ApiServer::create_from_env() {
+            Ok(mut api_server) => {
+                let state = ApiState {
+                    demo_cpb16_setpoint_writer: Some(collector.get_setpoint_writer()),
+                    demo_cpb16_downtime: Some(collector.get_downtime_registry()),
+                    alarm: alarm::get().cloned(),
+                };
+                api_server.start(state).await?;
+                Some(api_server)
+            }
+            Err(_) => None,
+        };
+
         let collector = Arc::new(Mutex::new(collector));
         let database = database;
 
         Ok(Self {
             collector,
             database,
+            mqtt_collector,
+            snapshot_collector,
+            api_server,
         })
     }
 
@@ -38,13 +84,17 @@ impl Runner {
                 Ok(()) => break,
                 Err(r) => debug!("fail connection with PLC:{:?}", r),
             }
+            drop(collector);
+            update_plc_offline(true).await?;
             debug!("Reconnect after 20 seconds");
             wait(20).await;
         }
+        update_plc_offline(false).await?;
         info!("start data collect");
 
-        while let Some(()) = disconnect_receiver.recv().await {
-            warn!("The connection with the PLC has been lost");
+        while let Some(reason) = disconnect_receiver.recv().await {
+            warn!("The connection with the PLC has been lost:{}", reason);
+            update_plc_offline(true).await?;
             // コレクターの停止処理
             {
                 let mut collector = self.collector.lock().unwrap();
@@ -68,6 +118,7 @@ impl Runner {
                     }
                 }
             }
+            update_plc_offline(false).await?;
         }
 
         warn!("disconnect_sender was drop");
@@ -76,6 +127,16 @@ impl Runner {
     }
 }
 
+// PLCとの通信断をアラームのゲートウェイ内部の値として渡す
+// 通信断の間はPLCの最後の値でアラームを判定しない
+async fn update_plc_offline(offline: bool) -> anyhow::Result<()> {
+    if offline {
+        alarm::set_stale("demo_cpb16");
+    }
+    let value = if offline { 1.0 } else { 0.0 };
+    alarm::update(alarm::GATEWAY_SOURCE, &[(alarm::PLC_OFFLINE_TAG, value)]).await
+}
+
 async fn wait(sec: u64) {
     tokio::time::sleep(Duration::from_secs(sec)).await;
 }
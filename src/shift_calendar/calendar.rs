@@ -0,0 +1,429 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use serde::Deserialize;
+
+// シフトカレンダーの設定ファイル(JSON)
+// {
+//   "shifts": {
+//     "mon": [{ "name": "day", "start": "08:00", "end": "17:00",
+//               "breaks": [{ "start": "12:00", "end": "13:00" }] }],
+//     ...
+//   },
+//   "holidays": ["2026-01-01"],
+//   "maintenance": [{ "name": "pm", "start": "2026-10-20 08:00", "end": "2026-10-20 12:00" }]
+// }
+// shiftsのキーはmon,tue,wed,thu,fri,sat,sun。endがstart以前の場合は翌日に跨ぐシフト
+// holidaysの日に始まるシフトは無効
+#[derive(Deserialize)]
+struct CalendarFile {
+    #[serde(default)]
+    shifts: std::collections::HashMap<String, Vec<ShiftFile>>,
+    #[serde(default)]
+    holidays: Vec<String>,
+    #[serde(default)]
+    maintenance: Vec<MaintenanceFile>,
+}
+
+#[derive(Deserialize)]
+struct ShiftFile {
+    name: String,
+    start: String,
+    end: String,
+    #[serde(default)]
+    breaks: Vec<BreakFile>,
+}
+
+#[derive(Deserialize)]
+struct BreakFile {
+    start: String,
+    end: String,
+}
+
+#[derive(Deserialize)]
+struct MaintenanceFile {
+    #[serde(default)]
+    name: String,
+    start: String,
+    end: String,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// 計画停止の種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlannedDowntime {
+    // シフト内の休憩
+    Break,
+    // 休日
+    Holiday,
+    // 計画保全
+    Maintenance,
+    // シフト外
+    OffShift,
+}
+
+impl PlannedDowntime {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Break => "break",
+            Self::Holiday => "holiday",
+            Self::Maintenance => "maintenance",
+            Self::OffShift => "off_shift",
+        }
+    }
+}
+
+// 日付が決まったシフト
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShiftSpan {
+    pub name: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+}
+
+#[derive(Clone, Debug)]
+struct ShiftDefinition {
+    name: String,
+    start: NaiveTime,
+    length: Duration,
+    // シフト開始からの(開始, 終了)
+    breaks: Vec<(Duration, Duration)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShiftCalendar {
+    // 月曜始まりの曜日毎のシフト
+    week: Vec<Vec<ShiftDefinition>>,
+    holidays: Vec<NaiveDate>,
+    maintenance: Vec<(String, DateTime<Local>, DateTime<Local>)>,
+}
+
+impl ShiftCalendar {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let file: CalendarFile = serde_json::from_str(&text)?;
+        Self::from_file(file)
+    }
+
+    fn from_file(file: CalendarFile) -> anyhow::Result<Self> {
+        for key in file.shifts.keys() {
+            if !WEEKDAYS.contains(&key.as_str()) {
+                anyhow::bail!("シフトカレンダーの曜日が不正:{}", key)
+            }
+        }
+        let mut week = Vec::with_capacity(7);
+        for weekday in WEEKDAYS {
+            let mut shifts = Vec::new();
+            for shift in file.shifts.get(weekday).into_iter().flatten() {
+                shifts.push(ShiftDefinition::parse(shift)?);
+            }
+            week.push(shifts);
+        }
+        let holidays = file
+            .holidays
+            .iter()
+            .map(|t| Ok(NaiveDate::parse_from_str(t, "%Y-%m-%d")?))
+            .collect::<anyhow::Result<Vec<NaiveDate>>>()?;
+        let mut maintenance = Vec::new();
+        for window in &file.maintenance {
+            let start = parse_local_datetime(&window.start)?;
+            let end = parse_local_datetime(&window.end)?;
+            if end <= start {
+                anyhow::bail!("計画保全の期間が不正:{}～{}", window.start, window.end)
+            }
+            maintenance.push((window.name.clone(), start, end));
+        }
+        Ok(Self {
+            week,
+            holidays,
+            maintenance,
+        })
+    }
+
+    // dtを含むシフト。休日やシフト外ならNone
+    pub fn shift_at(&self, dt: DateTime<Local>) -> Option<ShiftSpan> {
+        self.spans_between(dt, dt + Duration::seconds(1))
+            .into_iter()
+            .find(|(span, _)| span.start <= dt && dt < span.end)
+            .map(|(span, _)| span)
+    }
+
+    // dtが計画停止中ならその種類
+    pub fn planned_downtime_at(&self, dt: DateTime<Local>) -> Option<PlannedDowntime> {
+        if self
+            .maintenance
+            .iter()
+            .any(|(_, start, end)| *start <= dt && dt < *end)
+        {
+            return Some(PlannedDowntime::Maintenance);
+        }
+        let found = self
+            .spans_between(dt, dt + Duration::seconds(1))
+            .into_iter()
+            .find(|(span, _)| span.start <= dt && dt < span.end);
+        match found {
+            Some((span, definition)) => {
+                let offset = dt - span.start;
+                let in_break = definition
+                    .breaks
+                    .iter()
+                    .any(|(start, end)| *start <= offset && offset < *end);
+                in_break.then_some(PlannedDowntime::Break)
+            }
+            None if self.holidays.contains(&dt.date_naive()) => Some(PlannedDowntime::Holiday),
+            None => Some(PlannedDowntime::OffShift),
+        }
+    }
+
+    // fromからtoまでの計画生産時間(秒)。休憩と計画保全を除く
+    pub fn planned_production_second(&self, from: DateTime<Local>, to: DateTime<Local>) -> f64 {
+        let mut total = 0;
+        for (span, definition) in self.spans_between(from, to) {
+            let mut intervals = vec![(span.start.max(from), span.end.min(to))];
+            for (start, end) in &definition.breaks {
+                intervals = subtract(intervals, (span.start + *start, span.start + *end));
+            }
+            for (_, start, end) in &self.maintenance {
+                intervals = subtract(intervals, (*start, *end));
+            }
+            total += intervals
+                .iter()
+                .map(|(start, end)| (*end - *start).num_milliseconds().max(0))
+                .sum::<i64>();
+        }
+        total as f64 / 1000.0
+    }
+
+    // fromからtoに重なるシフト
+    fn spans_between(
+        &self,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> Vec<(ShiftSpan, &ShiftDefinition)> {
+        let mut spans = Vec::new();
+        // 前日に始まって日を跨ぐシフトも対象
+        let mut date = from.date_naive() - Duration::days(1);
+        while date <= to.date_naive() {
+            if !self.holidays.contains(&date) {
+                let weekday = date.weekday().num_days_from_monday() as usize;
+                for definition in &self.week[weekday] {
+                    let Some(start) = local_datetime(date, definition.start) else {
+                        continue;
+                    };
+                    let end = start + definition.length;
+                    if start < to && from < end {
+                        let span = ShiftSpan {
+                            name: definition.name.clone(),
+                            start,
+                            end,
+                        };
+                        spans.push((span, definition));
+                    }
+                }
+            }
+            date += Duration::days(1);
+        }
+        spans
+    }
+}
+
+impl ShiftDefinition {
+    fn parse(shift: &ShiftFile) -> anyhow::Result<Self> {
+        let start = NaiveTime::parse_from_str(&shift.start, "%H:%M")?;
+        let end = NaiveTime::parse_from_str(&shift.end, "%H:%M")?;
+        let length = offset_from(start, end);
+        let mut breaks = Vec::new();
+        for item in &shift.breaks {
+            // シフト開始と同時に始まる休憩は翌日ではなくオフセット0
+            let break_start =
+                match offset_from(start, NaiveTime::parse_from_str(&item.start, "%H:%M")?) {
+                    t if t == Duration::days(1) => Duration::zero(),
+                    t => t,
+                };
+            let break_end = offset_from(start, NaiveTime::parse_from_str(&item.end, "%H:%M")?);
+            if break_end <= break_start || break_end > length {
+                anyhow::bail!(
+                    "休憩がシフト{}の範囲外:{}～{}",
+                    shift.name,
+                    item.start,
+                    item.end
+                )
+            }
+            breaks.push((break_start, break_end));
+        }
+        Ok(Self {
+            name: shift.name.clone(),
+            start,
+            length,
+            breaks,
+        })
+    }
+}
+
+// startからtimeまでの時間。timeがstart以前なら翌日の時刻とする
+fn offset_from(start: NaiveTime, time: NaiveTime) -> Duration {
+    if time > start {
+        time - start
+    } else {
+        time - start + Duration::days(1)
+    }
+}
+
+fn local_datetime(date: NaiveDate, time: NaiveTime) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&date.and_time(time)).earliest()
+}
+
+fn parse_local_datetime(text: &str) -> anyhow::Result<DateTime<Local>> {
+    let dt = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M")?;
+    match Local.from_local_datetime(&dt).earliest() {
+        Some(t) => Ok(t),
+        None => anyhow::bail!("時刻変換に失敗:{}", text),
+    }
+}
+
+// 区間の集合からcutを取り除く
+fn subtract(
+    intervals: Vec<(DateTime<Local>, DateTime<Local>)>,
+    cut: (DateTime<Local>, DateTime<Local>),
+) -> Vec<(DateTime<Local>, DateTime<Local>)> {
+    let mut result = Vec::new();
+    for (start, end) in intervals {
+        if cut.1 <= start || end <= cut.0 {
+            result.push((start, end));
+            continue;
+        }
+        if start < cut.0 {
+            result.push((start, cut.0));
+        }
+        if cut.1 < end {
+            result.push((cut.1, end));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-10-19は月曜。水曜は休日、火曜の8:00～10:00は計画保全
+    fn calendar() -> ShiftCalendar {
+        let shifts = r#"[
+            { "name": "day", "start": "08:00", "end": "17:00",
+              "breaks": [{ "start": "12:00", "end": "13:00" }] },
+            { "name": "night", "start": "22:00", "end": "06:00",
+              "breaks": [{ "start": "22:00", "end": "22:30" }] }
+        ]"#;
+        let text = format!(
+            r#"{{
+                "shifts": {{ "mon": {0}, "tue": {0}, "wed": {0} }},
+                "holidays": ["2026-10-21"],
+                "maintenance": [{{ "name": "pm", "start": "2026-10-20 08:00", "end": "2026-10-20 10:00" }}]
+            }}"#,
+            shifts
+        );
+        ShiftCalendar::from_file(serde_json::from_str(&text).unwrap()).unwrap()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn overnight_shift() {
+        let calendar = calendar();
+        let expected = Some(ShiftSpan {
+            name: "night".to_string(),
+            start: at(19, 22, 0),
+            end: at(20, 6, 0),
+        });
+        assert_eq!(calendar.shift_at(at(19, 23, 0)), expected);
+        assert_eq!(calendar.shift_at(at(20, 3, 0)), expected);
+        assert_eq!(calendar.shift_at(at(20, 6, 0)), None);
+        assert_eq!(
+            calendar.planned_downtime_at(at(20, 7, 0)),
+            Some(PlannedDowntime::OffShift)
+        );
+    }
+
+    #[test]
+    fn holiday() {
+        let calendar = calendar();
+        assert_eq!(calendar.shift_at(at(21, 9, 0)), None);
+        assert_eq!(
+            calendar.planned_downtime_at(at(21, 9, 0)),
+            Some(PlannedDowntime::Holiday)
+        );
+        // 前日に始まったシフトは休日に跨いでも有効
+        assert_eq!(
+            calendar.shift_at(at(21, 1, 0)).map(|t| t.start),
+            Some(at(20, 22, 0))
+        );
+        assert_eq!(calendar.planned_downtime_at(at(21, 1, 0)), None);
+        assert_eq!(
+            calendar.planned_production_second(at(21, 0, 0), at(22, 0, 0)),
+            6.0 * 3600.0
+        );
+    }
+
+    #[test]
+    fn subtract_break_and_maintenance() {
+        let calendar = calendar();
+        assert_eq!(
+            calendar.planned_downtime_at(at(19, 12, 30)),
+            Some(PlannedDowntime::Break)
+        );
+        assert_eq!(
+            calendar.planned_downtime_at(at(20, 9, 0)),
+            Some(PlannedDowntime::Maintenance)
+        );
+        assert_eq!(calendar.planned_downtime_at(at(20, 10, 0)), None);
+        assert_eq!(
+            calendar.planned_production_second(at(19, 8, 0), at(19, 17, 0)),
+            8.0 * 3600.0
+        );
+        assert_eq!(
+            calendar.planned_production_second(at(20, 8, 0), at(20, 17, 0)),
+            6.0 * 3600.0
+        );
+    }
+
+    #[test]
+    fn planned_second_across_midnight() {
+        let calendar = calendar();
+        assert_eq!(
+            calendar.planned_production_second(at(19, 23, 0), at(20, 2, 0)),
+            3.0 * 3600.0
+        );
+        // 夜勤は開始直後の休憩を除いて7.5時間
+        assert_eq!(
+            calendar.planned_production_second(at(19, 20, 0), at(20, 8, 0)),
+            7.5 * 3600.0
+        );
+    }
+
+    #[test]
+    fn break_at_shift_start() {
+        let calendar = calendar();
+        assert_eq!(
+            calendar.planned_downtime_at(at(19, 22, 0)),
+            Some(PlannedDowntime::Break)
+        );
+        assert_eq!(calendar.planned_downtime_at(at(19, 22, 30)), None);
+
+        let shift = |start: &str, end: &str| ShiftFile {
+            name: "day".to_string(),
+            start: "08:00".to_string(),
+            end: "17:00".to_string(),
+            breaks: vec![BreakFile {
+                start: start.to_string(),
+                end: end.to_string(),
+            }],
+        };
+        assert!(ShiftDefinition::parse(&shift("08:00", "08:10")).is_ok());
+        assert!(ShiftDefinition::parse(&shift("16:50", "17:00")).is_ok());
+        assert!(ShiftDefinition::parse(&shift("16:50", "17:10")).is_err());
+        assert!(ShiftDefinition::parse(&shift("07:50", "08:10")).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+// 工場のシフト・休憩・休日・計画保全のカレンダー
+// SHIFT_CALENDAR_PATHでJSONファイルを指定する。未設定の場合はカレンダーなし
+#[allow(dead_code)]
+mod calendar;
+
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Local};
+use influxdb2::models::data_point::DataPointBuilder;
+use log::info;
+
+#[allow(unused_imports)]
+pub use calendar::{PlannedDowntime, ShiftCalendar, ShiftSpan};
+
+// シフト外の時間のshiftタグ
+pub const OFF_SHIFT: &str = "off";
+
+static CALENDAR: OnceLock<Option<ShiftCalendar>> = OnceLock::new();
+
+// 起動時に1回読み込む
+pub fn init_from_env() -> anyhow::Result<()> {
+    let calendar = match std::env::var("SHIFT_CALENDAR_PATH") {
+        Ok(path) => {
+            let calendar = ShiftCalendar::load(&path)?;
+            info!("シフトカレンダーを読み込み:{}", path);
+            Some(calendar)
+        }
+        Err(_) => None,
+    };
+    if CALENDAR.set(calendar).is_err() {
+        anyhow::bail!("シフトカレンダーは読み込み済み")
+    }
+    Ok(())
+}
+
+pub fn get() -> Option<&'static ShiftCalendar> {
+    CALENDAR.get().and_then(|t| t.as_ref())
+}
+
+// カレンダーがある場合はdtのシフト名をshiftタグに付ける
+pub fn add_shift_tag(builder: DataPointBuilder, dt: DateTime<Local>) -> DataPointBuilder {
+    let Some(calendar) = get() else {
+        return builder;
+    };
+    match calendar.shift_at(dt) {
+        Some(span) => builder.tag("shift", span.name),
+        None => builder.tag("shift", OFF_SHIFT),
+    }
+}